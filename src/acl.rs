@@ -1,5 +1,7 @@
+use std::error;
 use std::fmt;
 use std::ops;
+use std::str;
 
 use std::string::ToString;
 
@@ -70,33 +72,56 @@ impl ops::BitOr for Permission {
 }
 
 
+/// The letters of the permissions in their conventional order, as used by the ZooKeeper CLI.
+const LETTERS: [(char, Permission); 5] = [('r', Permission::READ),
+                                          ('w', Permission::WRITE),
+                                          ('c', Permission::CREATE),
+                                          ('d', Permission::DELETE),
+                                          ('a', Permission::ADMIN)];
+
+/// Renders the permissions like the ZooKeeper CLI does, as letters in the order `"rwcda"`. No
+/// permissions render as an empty string.
 impl fmt::Display for Permission {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if *self == Permission::ALL {
-            write!(f, "ALL")
-        } else if *self == Permission::NONE {
-            write!(f, "NONE")
-        } else {
-            let mut first = true;
-            let mut tick = || {
-                if first {
-                    first = false;
-                    ""
-                } else {
-                    "|"
-                }
-            };
-
-            if self.can(Permission::READ)   { write!(f, "{}READ", tick())?; }
-            if self.can(Permission::WRITE)  { write!(f, "{}WRITE", tick())?; }
-            if self.can(Permission::CREATE) { write!(f, "{}CREATE", tick())?; }
-            if self.can(Permission::DELETE) { write!(f, "{}DELETE", tick())?; }
-            if self.can(Permission::ADMIN)  { write!(f, "{}ADMIN", tick())?; }
-            Ok(())
+        for &(letter, permission) in LETTERS.iter() {
+            if self.can(permission) {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses permissions from letters in any order, like `"cdrwa"` or `"rw"`, the inverse of the
+/// `Display` implementation.
+impl str::FromStr for Permission {
+    type Err = ParsePermissionError;
+
+    fn from_str(s: &str) -> Result<Permission, ParsePermissionError> {
+        let mut permissions = Permission::NONE;
+        for c in s.chars() {
+            match LETTERS.iter().find(|&&(letter, _)| letter == c) {
+                Some(&(_, permission)) => permissions = permissions | permission,
+                None => return Err(ParsePermissionError(c)),
+            }
         }
+        Ok(permissions)
     }
 }
 
+/// The error of parsing a `Permission` from a string, holding the character which does not stand
+/// for a permission.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParsePermissionError(pub char);
+
+impl fmt::Display for ParsePermissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown permission {:?}, expected one of \"rwcda\"", self.0)
+    }
+}
+
+impl error::Error for ParsePermissionError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,11 +144,33 @@ mod tests {
 
     #[test]
     fn permission_format() {
-        assert_eq!("ALL", Permission::ALL.to_string());
-        assert_eq!("NONE", Permission::NONE.to_string());
-        assert_eq!("READ|WRITE", (Permission::READ | Permission::WRITE).to_string());
-        assert_eq!("CREATE|DELETE", (Permission::CREATE | Permission::DELETE).to_string());
-        assert_eq!("ADMIN", Permission::ADMIN.to_string());
+        assert_eq!("rwcda", Permission::ALL.to_string());
+        assert_eq!("", Permission::NONE.to_string());
+        assert_eq!("rw", (Permission::WRITE | Permission::READ).to_string());
+        assert_eq!("cd", (Permission::CREATE | Permission::DELETE).to_string());
+        assert_eq!("a", Permission::ADMIN.to_string());
+    }
+
+    #[test]
+    fn permission_round_trip() {
+        let permissions = [Permission::ALL,
+                           Permission::NONE,
+                           Permission::READ,
+                           Permission::WRITE,
+                           Permission::CREATE,
+                           Permission::DELETE,
+                           Permission::ADMIN];
+        for &permission in permissions.iter() {
+            assert_eq!(permission.to_string().parse(), Ok(permission));
+        }
+        assert_eq!("cdrwa".parse(), Ok(Permission::ALL));
+        assert_eq!("wr".parse(), Ok(Permission::READ | Permission::WRITE));
+    }
+
+    #[test]
+    fn permission_parse_rejects_unknown_letters() {
+        assert_eq!("rz".parse::<Permission>(), Err(ParsePermissionError('z')));
+        assert_eq!("R".parse::<Permission>(), Err(ParsePermissionError('R')));
     }
 }
 
@@ -0,0 +1,144 @@
+//! Monitoring of servers through the four letter word commands, which are sent over a plain TCP
+//! connection to the client port instead of a session.
+//!
+//! Except for `srvr`, the commands have to be whitelisted with the `4lw.commands.whitelist`
+//! option of the server.
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// The `mntr` keys which only ever grow. All other numeric keys are exposed as gauges.
+const COUNTERS: [&str; 3] = ["zk_packets_received",
+                             "zk_packets_sent",
+                             "zk_fsync_threshold_exceed_count"];
+
+/// Send the four letter word `command` to the server at `addr`, and return its output.
+pub(crate) fn four_letter_word(addr: &SocketAddr,
+                               command: &str,
+                               timeout: Duration)
+                               -> io::Result<String> {
+    let mut stream = try!(TcpStream::connect_timeout(addr, timeout));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+    try!(stream.write_all(command.as_bytes()));
+    let mut output = String::new();
+    try!(stream.read_to_string(&mut output));
+    Ok(output)
+}
+
+/// Query the metrics of the server at `addr` with the `mntr` command, returning its key/value
+/// pairs in the order the server lists them, like `("zk_server_state", "leader")`.
+pub fn mntr(addr: &SocketAddr, timeout: Duration) -> io::Result<Vec<(String, String)>> {
+    let output = try!(four_letter_word(addr, "mntr", timeout));
+    if output.contains("is not executed because it is not in the whitelist") {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, output.trim().to_owned()));
+    }
+    Ok(parse_mntr(&output))
+}
+
+/// Query the metrics of the server at `addr` with the `mntr` command, and render them in the
+/// Prometheus text exposition format, so a server without the Prometheus metrics provider can be
+/// scraped. See `format_prometheus` for the format.
+pub fn prometheus_metrics(addr: &SocketAddr, timeout: Duration) -> io::Result<String> {
+    mntr(addr, timeout).map(|metrics| format_prometheus(&metrics))
+}
+
+/// Render `mntr` key/value pairs in the Prometheus text exposition format.
+///
+/// The names keep their `zk_` prefix. Numeric values become samples, typed as counters for the
+/// packet and fsync counts and as gauges otherwise. Other values, like `zk_server_state`, become
+/// a `value` label of a sample which is always `1`, like `zk_server_state{value="leader"} 1`.
+pub fn format_prometheus(metrics: &[(String, String)]) -> String {
+    let mut text = String::new();
+    for (key, value) in metrics {
+        let name = metric_name(key);
+        let kind = if COUNTERS.contains(&name.as_str()) { "counter" } else { "gauge" };
+        // Writing to a String can't fail
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        let _ = if value.parse::<f64>().is_ok() {
+            writeln!(text, "{} {}", name, value)
+        } else {
+            writeln!(text, "{}{{value=\"{}\"}} 1", name, escape_label(value))
+        };
+    }
+    text
+}
+
+/// Split the `key\tvalue` lines of the `mntr` output.
+fn parse_mntr(output: &str) -> Vec<(String, String)> {
+    output.lines()
+          .filter_map(|line| {
+              let mut parts = line.splitn(2, '\t');
+              match (parts.next(), parts.next()) {
+                  (Some(key), Some(value)) if !key.is_empty() => {
+                      Some((key.to_owned(), value.trim().to_owned()))
+                  }
+                  _ => None,
+              }
+          })
+          .collect()
+}
+
+/// Replace the characters which are not valid in a Prometheus metric name.
+fn metric_name(key: &str) -> String {
+    key.chars()
+       .enumerate()
+       .map(|(i, c)| {
+           if c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit()) {
+               c
+           } else {
+               '_'
+           }
+       })
+       .collect()
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_prometheus, parse_mntr};
+
+    const MNTR: &str = "zk_version\t3.6.2--803c7f1a12, built on 09/04/2020 12:44 GMT
+zk_avg_latency\t0.5
+zk_packets_received\t123
+zk_packets_sent\t122
+zk_num_alive_connections\t1
+zk_server_state\tleader
+zk_znode_count\t5
+";
+
+    #[test]
+    fn mntr_to_prometheus() {
+        let metrics = parse_mntr(MNTR);
+        assert_eq!(metrics.len(), 7);
+        assert_eq!(metrics[5], ("zk_server_state".to_owned(), "leader".to_owned()));
+
+        assert_eq!(format_prometheus(&metrics),
+                   "# TYPE zk_version gauge
+zk_version{value=\"3.6.2--803c7f1a12, built on 09/04/2020 12:44 GMT\"} 1
+# TYPE zk_avg_latency gauge
+zk_avg_latency 0.5
+# TYPE zk_packets_received counter
+zk_packets_received 123
+# TYPE zk_packets_sent counter
+zk_packets_sent 122
+# TYPE zk_num_alive_connections gauge
+zk_num_alive_connections 1
+# TYPE zk_server_state gauge
+zk_server_state{value=\"leader\"} 1
+# TYPE zk_znode_count gauge
+zk_znode_count 5
+");
+    }
+
+    #[test]
+    fn prometheus_sanitizes_names_and_labels() {
+        let metrics = vec![("zk_odd-key".to_owned(), "say \"hi\"".to_owned())];
+        assert_eq!(format_prometheus(&metrics),
+                   "# TYPE zk_odd_key gauge\nzk_odd_key{value=\"say \\\"hi\\\"\"} 1\n");
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Requests slower than this (on average) indicate a struggling ensemble.
+const LATENCY_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Backpressure only applies with at least this many requests pending.
+const PENDING_THRESHOLD: usize = 8;
+
+/// The weight of the latest latency in the moving average.
+const SMOOTHING: f64 = 0.2;
+
+/// Slows down the submission of requests while the ensemble is struggling, that is while replies
+/// are slow and requests keep piling up.
+///
+/// The average reply latency is tracked, and while it exceeds `LATENCY_THRESHOLD` with at least
+/// `PENDING_THRESHOLD` requests pending, new requests are delayed by the average latency, so a
+/// client cannot submit faster than the ensemble replies.
+#[derive(Default)]
+pub struct Backpressure {
+    pending: AtomicUsize,
+    /// Moving average of the reply latency in seconds.
+    latency: Mutex<f64>,
+}
+
+impl Backpressure {
+    /// Call before submitting a request, blocking while backpressure applies. Every call must be
+    /// followed by a call to `completed`.
+    pub fn submit(&self) {
+        if let Some(delay) = self.delay() {
+            debug!("ensemble is struggling, delaying request by {:?}", delay);
+            thread::sleep(delay);
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Like `submit`, but return `false` instead of blocking while backpressure applies. The
+    /// request is not to be submitted then, and `completed` not to be called for it.
+    pub fn try_submit(&self) -> bool {
+        if let Some(delay) = self.delay() {
+            debug!("ensemble is struggling, refusing request instead of delaying it by {:?}",
+                   delay);
+            return false;
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Call when the reply of a request arrived, `latency` after `submit` returned.
+    pub fn completed(&self, latency: Duration) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+
+        let latency = latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) * 1e-9;
+        let mut average = self.latency.lock().unwrap_or_else(|e| e.into_inner());
+        *average = *average * (1.0 - SMOOTHING) + latency * SMOOTHING;
+    }
+
+    fn delay(&self) -> Option<Duration> {
+        let average = *self.latency.lock().unwrap_or_else(|e| e.into_inner());
+        let average = Duration::from_nanos((average * 1e9) as u64);
+        if average > LATENCY_THRESHOLD && self.pending.load(Ordering::SeqCst) >= PENDING_THRESHOLD {
+            Some(average)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backpressure, PENDING_THRESHOLD};
+    use std::time::Duration;
+
+    #[test]
+    fn delays_on_high_latency_and_pending() {
+        let backpressure = Backpressure::default();
+        for _ in 0..PENDING_THRESHOLD + 10 {
+            backpressure.pending.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+        }
+        assert_eq!(backpressure.delay(), None);
+
+        // slow replies, while more requests are pending
+        for _ in 0..10 {
+            backpressure.completed(Duration::from_millis(500));
+        }
+        let delay = backpressure.delay().unwrap();
+        assert!(delay > Duration::from_millis(400), "{:?}", delay);
+        assert!(!backpressure.try_submit());
+
+        // fast replies lift the backpressure
+        for _ in 0..20 {
+            backpressure.completed(Duration::from_millis(1));
+        }
+        assert_eq!(backpressure.delay(), None);
+        assert!(backpressure.try_submit());
+    }
+
+    #[test]
+    fn no_delay_with_few_pending() {
+        let backpressure = Backpressure::default();
+        backpressure.submit();
+        backpressure.completed(Duration::from_secs(1));
+        backpressure.submit();
+        assert_eq!(backpressure.delay(), None);
+    }
+}
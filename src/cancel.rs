@@ -0,0 +1,90 @@
+use consts::ZkError;
+use proto::{ByteBuf, ReplyHeader};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use zookeeper::RawResponse;
+
+struct Inner {
+    cancelled: AtomicBool,
+    /// The xids and response channels of the requests waiting for their response.
+    pending: Mutex<Vec<(i32, SyncSender<RawResponse>)>>,
+}
+
+/// Cancels the requests of the `*_cancellable` operations of `ZooKeeper` cooperatively, like on a
+/// shutdown signal.
+///
+/// Once `cancel` is called, pending operations using the token return
+/// `Err(ZkError::OperationTimeout)` right away, and new ones fail without being sent. A request
+/// which has not been written to the server yet is dropped by the IO thread, while the reply to a
+/// request which has already been written is discarded when it arrives. That request may still
+/// take effect on the server.
+///
+/// Clones of a token share its state, so one of them can be handed to another thread to cancel
+/// the operations using the others.
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+impl CancellationToken {
+    /// Create a new token, which is not cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                pending: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Cancel all pending and future operations using this token.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let pending = {
+            let mut pending = self.inner.pending.lock().unwrap_or_else(|e| e.into_inner());
+            pending.drain(..).collect::<Vec<_>>()
+        };
+        for (xid, listener) in pending {
+            debug!("cancelling request xid={}", xid);
+            // A full channel already holds the response, so the caller returns anyway
+            let _ = listener.try_send(RawResponse {
+                header: ReplyHeader {
+                    xid,
+                    zxid: 0,
+                    err: ZkError::OperationTimeout as i32,
+                },
+                data: ByteBuf::new(vec![]),
+                len: 0,
+            });
+        }
+    }
+
+    /// Has `cancel` been called?
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Track a request waiting for its response on `listener`. Return `false` if the token is
+    /// already cancelled, in which case the request must not be sent.
+    pub(crate) fn register(&self, xid: i32, listener: SyncSender<RawResponse>) -> bool {
+        let mut pending = self.inner.pending.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_cancelled() {
+            return false;
+        }
+        pending.push((xid, listener));
+        true
+    }
+
+    /// Stop tracking the request once its response has been received.
+    pub(crate) fn unregister(&self, xid: i32) {
+        let mut pending = self.inner.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|&(pending_xid, _)| pending_xid != xid);
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> CancellationToken {
+        CancellationToken::new()
+    }
+}
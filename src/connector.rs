@@ -0,0 +1,121 @@
+use mio::Evented;
+use mio::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+/// A non-blocking connection to a server, as created by a `Connector`.
+pub trait Stream: Read + Write + Evented + Send {}
+
+impl<T> Stream for T where T: Read + Write + Evented + Send {}
+
+/// Creates the connections to the servers.
+///
+/// The default connects via TCP. Other implementations (like in-memory pipes or streams injecting
+/// faults) allow testing the client without a ZooKeeper cluster; see `ZooKeeperBuilder::connector`
+/// (requires the `connector` feature).
+pub trait Connector: Send {
+    /// Start connecting to the server at `addr`. Like a non-blocking TCP connect, this may return
+    /// before the connection is established, in which case the stream must become writable once
+    /// it is.
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<dyn Stream>>;
+}
+
+/// The default `Connector`, connecting via TCP.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpConnector;
+
+impl Connector for TcpConnector {
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(try!(TcpStream::connect(addr))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ReadBytesExt};
+    use consts::ZkState;
+    use std::net::SocketAddr;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use testing::memory_pipe;
+    use zookeeper::ZooKeeperBuilder;
+
+    #[test]
+    fn handshake_over_memory_pipe() {
+        let (connector, server) = memory_pipe();
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .session_timeout(Duration::from_secs(10))
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        assert_eq!(zk.session_timeout(), Duration::from_secs(10));
+        assert_eq!(zk.session_id(), 0);
+
+        let mut connect = server.accept();
+        assert_eq!(connect.read_i32::<BigEndian>().unwrap(), 0); // protocol version
+        assert_eq!(connect.read_i64::<BigEndian>().unwrap(), 0); // last zxid seen
+        assert_eq!(connect.read_i32::<BigEndian>().unwrap(), 10000); // timeout
+        assert_eq!(connect.read_i64::<BigEndian>().unwrap(), 0); // session id
+
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+        assert_eq!(zk.negotiated_session_timeout(), Some(Duration::from_secs(6)));
+        assert_eq!(zk.session_timeout(), Duration::from_secs(6));
+        assert_eq!(zk.session_id(), 0x1234);
+
+        let server = thread::spawn(move || {
+            // exists("/missing") is answered with NoNode
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 3);
+            server.reply(xid, 42, -101);
+
+            // closing the session on drop
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 43, 0);
+        });
+
+        assert_eq!(zk.exists("/missing", false), Ok(None));
+        assert_eq!(zk.last_zxid_seen(), 42);
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn custom_resolver_provides_addresses() {
+        let (connector, server) = memory_pipe();
+        let addrs = connector.addrs.clone();
+        let resolved: SocketAddr = "10.1.2.3:2181".parse().unwrap();
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("zk.service.consul:2181")
+                     .resolver(move |host: &str| {
+                         assert_eq!(host, "zk.service.consul:2181");
+                         Ok(vec![resolved])
+                     })
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        server.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+        assert_eq!(*addrs.lock().unwrap(), vec![resolved]);
+        assert_eq!(zk.connected_server(), Some(resolved));
+        let stats = zk.host_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].addr, stats[0].successes, stats[0].failures), (resolved, 1, 0));
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            server.reply(xid, 1, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+}
@@ -1,6 +1,36 @@
 use std::error::Error;
 use std::fmt;
 
+/// Implement the conversions between an enum with an `Unknown(i32)` fallback variant and its raw
+/// wire values.
+macro_rules! raw_enum_conversions {
+    ($name:ident { $($variant:ident = $raw:expr),* }) => {
+        impl $name {
+            /// Get the raw value used on the wire.
+            pub fn as_i32(&self) -> i32 {
+                match *self {
+                    $($name::$variant => $raw,)*
+                    $name::Unknown(raw) => raw,
+                }
+            }
+
+            /// Convert from a raw wire value, returning `None` if the value is not known.
+            pub fn from_i32(raw: i32) -> Option<$name> {
+                match raw {
+                    $($raw => Some($name::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl From<i32> for $name {
+            fn from(raw: i32) -> $name {
+                $name::from_i32(raw).unwrap_or($name::Unknown(raw))
+            }
+        }
+    }
+}
+
 /// Basic type for errors returned from the server.
 #[derive(Clone, Copy, Debug, EnumConvertFromInt, EnumError, PartialEq)]
 #[EnumConvertFromIntFallback = "Unimplemented"]
@@ -20,7 +50,10 @@ pub enum ZkError {
     ConnectionLoss = -4,
     /// A data inconsistency was found.
     DataInconsistency = -3,
-    /// Attempt to create ephemeral node on a local session.
+    /// Attempt to create ephemeral node on a local session. Whether sessions start out local is
+    /// configured on the server (`localSessionsEnabled`), the connect request has no way to ask
+    /// for a global session. With `localSessionsUpgradingEnabled` the server upgrades the session
+    /// instead of returning this error.
     EphemeralOnLocalSession = -120,
     /// Invalid `Acl` specified.
     InvalidACL = -114,
@@ -32,6 +65,9 @@ pub enum ZkError {
     NoAuth = -102,
     /// Ephemeral nodes may not have children.
     NoChildrenForEphemerals = -108,
+    /// The new ensemble configuration of a reconfiguration has no quorum of connected servers
+    /// which are up to date with the leader.
+    NewConfigNoQuorum = -13,
     /// Request to create node that already exists.
     NodeExists = -110,
     /// Attempted to read a node that does not exist.
@@ -44,6 +80,10 @@ pub enum ZkError {
     NoWatcher = -121,
     /// Operation timeout.
     OperationTimeout = -7,
+    /// Dynamic reconfiguration is disabled on the server (`reconfigEnabled`).
+    ReconfigDisabled = -123,
+    /// Another reconfiguration is in progress.
+    ReconfigInProgress = -14,
     /// A runtime inconsistency was found.
     RuntimeInconsistency = -2,
     /// The session has been expired by the server.
@@ -54,6 +94,10 @@ pub enum ZkError {
     /// other than to indicate a range. Specifically error codes greater than this value, but lesser
     /// than `APIError`, are system errors.
     SystemError = -1,
+    /// The operation was throttled and not executed at all. Also returned by the `*_async`
+    /// operations of `ZooKeeper` instead of blocking on the rate limit or the backpressure of the
+    /// client.
+    Throttled = -127,
     /// Operation is unimplemented.
     Unimplemented = -6
 }
@@ -84,57 +128,161 @@ pub enum CreateMode {
     /// the last child of a container is deleted, the container becomes a candidate to be deleted by
     /// the server at some point in the future. Given this property, you should be prepared to get
     /// `ZkError::NoNode` when creating children inside of this container node.
+    ///
+    /// Containers require ZooKeeper 3.5.1 or later; older servers return
+    /// `Err(ZkError::Unimplemented)`.
     Container = 4,
+    /// The znode will not be automatically deleted upon client's disconnect, but will be deleted
+    /// by the server once it has not been modified within its TTL and has no children. Only valid
+    /// with `ZooKeeper::create_ttl`, on servers with `zookeeper.extendedTypesEnabled`.
+    PersistentWithTtl = 5,
+    /// Like `PersistentWithTtl`, with the name of the znode appended with a monotonically
+    /// increasing number.
+    PersistentSequentialWithTtl = 6,
+}
+
+impl CreateMode {
+    /// Get the `CreateMode` for the given combination of flags, as the server would accept it.
+    ///
+    /// # Errors
+    /// Ephemeral nodes can neither be containers nor have a TTL, and containers can't be
+    /// sequential. Such combinations return `Err(ZkError::BadArguments)`, logging the conflict,
+    /// instead of being rejected by the server.
+    pub fn from_flags(ephemeral: bool, sequential: bool, container: bool, ttl: bool)
+                      -> Result<CreateMode, ZkError> {
+        if let Some(conflict) = Self::flags_conflict(ephemeral, sequential, container, ttl) {
+            warn!("invalid create mode: {}", conflict);
+            return Err(ZkError::BadArguments);
+        }
+        match (ephemeral, sequential, container, ttl) {
+            (_, false, _, true) => Ok(CreateMode::PersistentWithTtl),
+            (_, true, _, true) => Ok(CreateMode::PersistentSequentialWithTtl),
+            (_, _, true, _) => Ok(CreateMode::Container),
+            (false, false, _, _) => Ok(CreateMode::Persistent),
+            (true, false, _, _) => Ok(CreateMode::Ephemeral),
+            (false, true, _, _) => Ok(CreateMode::PersistentSequential),
+            (true, true, _, _) => Ok(CreateMode::EphemeralSequential),
+        }
+    }
+
+    /// Whether nodes of this mode have a TTL, and must be created with `ZooKeeper::create_ttl`.
+    pub fn is_ttl(&self) -> bool {
+        matches!(*self, CreateMode::PersistentWithTtl | CreateMode::PersistentSequentialWithTtl)
+    }
+
+    /// Describe the conflict of an invalid combination of flags.
+    fn flags_conflict(ephemeral: bool, sequential: bool, container: bool, ttl: bool)
+                      -> Option<&'static str> {
+        if ephemeral && ttl {
+            Some("ephemeral nodes can't have a TTL")
+        } else if ephemeral && container {
+            Some("ephemeral nodes can't be containers")
+        } else if container && ttl {
+            Some("containers can't have a TTL")
+        } else if container && sequential {
+            Some("containers can't be sequential")
+        } else {
+            None
+        }
+    }
+}
+
+/// The kind of a watch added with `ZooKeeper::add_watch`, which is not removed when it is
+/// triggered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddWatchMode {
+    /// Watches the node itself: its creation, deletion and data changes, and changes of its
+    /// children.
+    Persistent = 0,
+    /// Watches the node and all of its descendants, including descendants created after the watch
+    /// was added: their creation, deletion and data changes. No `NodeChildrenChanged` events are
+    /// sent for a recursive watch.
+    PersistentRecursive = 1,
+}
+
+/// The kind of watches to remove with `ZooKeeper::remove_watches`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatcherType {
+    /// The watches left by `get_children`.
+    Children = 1,
+    /// The watches left by `get_data` and `exists`.
+    Data = 2,
+    /// All watches, including the ones added with `ZooKeeper::add_watch`.
+    Any = 3,
 }
 
 /// Enumeration of states the client may be at a Watcher Event. It represents the state of the
 /// server at the time the event was generated.
-#[derive(Clone, Copy, Debug, EnumConvertFromInt, PartialEq)]
+#[derive(Clone, Copy, Debug, EnumDisplay, PartialEq)]
 pub enum KeeperState {
     /// The client is in the disconnected state - it is not connected to any server in the ensemble.
-    Disconnected = 0,
+    Disconnected,
     /// The client is in the connected state - it is connected to a server in the ensemble (one of
     /// the servers specified in the host connection parameter during ZooKeeper client creation).
-    SyncConnected = 3,
+    SyncConnected,
     /// Authentication has failed -- connection requires a new `ZooKeeper` instance.
-    AuthFailed = 4,
+    AuthFailed,
     /// The client is connected to a read-only server, that is the server which is not currently
     /// connected to the majority. The only operations allowed after receiving this state is read
     /// operations. This state is generated for read-only clients only since read/write clients
     /// aren't allowed to connect to read-only servers.
-    ConnectedReadOnly = 5,
+    ConnectedReadOnly,
     /// Used to notify clients that they are SASL-authenticated, so that they can perform ZooKeeper
     /// actions with their SASL-authorized permissions.
-    SaslAuthenticated = 6,
+    SaslAuthenticated,
     /// The serving cluster has expired this session. The ZooKeeper client connection (the session)
     /// is no longer valid. You must create a new client connection (instantiate a new `ZooKeeper`
     /// instance) if you with to access the ensemble.
-    Expired = -112
+    Expired,
+    /// A state unknown to this client, most likely sent by a newer server. Contains the raw value.
+    Unknown(i32),
 }
 
 /// Enumeration of types of events that may occur on the znode.
-#[derive(Clone, Copy, Debug, EnumConvertFromInt)]
+#[derive(Clone, Copy, Debug, EnumDisplay, PartialEq)]
 pub enum WatchedEventType {
     /// Nothing known has occurred on the znode. This value is issued as part of a `WatchedEvent`
     /// when the `KeeperState` changes.
-    None = -1,
+    None,
     /// Issued when a znode at a given path is created.
-    NodeCreated = 1,
+    NodeCreated,
     /// Issued when a znode at a given path is deleted.
-    NodeDeleted = 2,
+    NodeDeleted,
     /// Issued when the data of a watched znode are altered. This event value is issued whenever a
     /// *set* operation occurs without an actual contents check, so there is no guarantee the data
     /// actually changed.
-    NodeDataChanged = 3,
+    NodeDataChanged,
     /// Issued when the children of a watched znode are created or deleted. This event is not issued
     /// when the data within children is altered.
-    NodeChildrenChanged = 4,
+    NodeChildrenChanged,
     /// Issued when the client removes a data watcher.
-    DataWatchRemoved = 5,
+    DataWatchRemoved,
     /// Issued when the client removes a child watcher.
-    ChildWatchRemoved = 6
+    ChildWatchRemoved,
+    /// An event type unknown to this client, most likely sent by a newer server. Contains the raw
+    /// value.
+    Unknown(i32),
 }
 
+raw_enum_conversions!(KeeperState {
+    Disconnected = 0,
+    SyncConnected = 3,
+    AuthFailed = 4,
+    ConnectedReadOnly = 5,
+    SaslAuthenticated = 6,
+    Expired = -112
+});
+
+raw_enum_conversions!(WatchedEventType {
+    None = -1,
+    NodeCreated = 1,
+    NodeDeleted = 2,
+    NodeDataChanged = 3,
+    NodeChildrenChanged = 4,
+    DataWatchRemoved = 5,
+    ChildWatchRemoved = 6
+});
+
 /// Enumeration of states the client may be at any time.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ZkState {
@@ -159,3 +307,66 @@ pub enum ZkState {
     #[deprecated]
     NotConnected,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CreateMode, KeeperState, WatchedEventType, ZkError};
+
+    #[test]
+    fn zk_error_conversions() {
+        assert_eq!(ZkError::EphemeralOnLocalSession, ZkError::from(-120));
+        assert_eq!(ZkError::NoNode, ZkError::from(-101));
+        assert_eq!(ZkError::SessionExpired, ZkError::from(-112));
+        assert_eq!(ZkError::AuthFailed, ZkError::from(-115));
+        assert_eq!(ZkError::SessionMoved, ZkError::from(-118));
+        assert_eq!(ZkError::Throttled, ZkError::from(-127));
+        assert_eq!(ZkError::Unimplemented, ZkError::from(-999));
+    }
+
+    #[test]
+    fn raw_enum_conversions() {
+        assert_eq!(KeeperState::Expired, KeeperState::from(-112));
+        assert_eq!(-112, KeeperState::Expired.as_i32());
+        assert_eq!(Some(WatchedEventType::NodeDeleted), WatchedEventType::from_i32(2));
+        assert_eq!(4, WatchedEventType::NodeChildrenChanged.as_i32());
+        assert_eq!("NodeCreated", WatchedEventType::NodeCreated.to_string());
+    }
+
+    #[test]
+    fn raw_enum_unknown_values() {
+        assert_eq!(None, KeeperState::from_i32(42));
+        assert_eq!(KeeperState::Unknown(42), KeeperState::from(42));
+        assert_eq!(42, KeeperState::Unknown(42).as_i32());
+        assert_eq!(WatchedEventType::Unknown(-7), WatchedEventType::from(-7));
+        assert_eq!("Unknown(-7)", WatchedEventType::Unknown(-7).to_string());
+    }
+
+    #[test]
+    fn create_mode_from_flags() {
+        assert_eq!(Ok(CreateMode::Persistent), CreateMode::from_flags(false, false, false, false));
+        assert_eq!(Ok(CreateMode::EphemeralSequential),
+                   CreateMode::from_flags(true, true, false, false));
+        assert_eq!(Ok(CreateMode::PersistentSequential),
+                   CreateMode::from_flags(false, true, false, false));
+        assert_eq!(Ok(CreateMode::Container), CreateMode::from_flags(false, false, true, false));
+        assert_eq!(Ok(CreateMode::PersistentWithTtl),
+                   CreateMode::from_flags(false, false, false, true));
+        assert_eq!(Ok(CreateMode::PersistentSequentialWithTtl),
+                   CreateMode::from_flags(false, true, false, true));
+    }
+
+    #[test]
+    fn create_mode_from_invalid_flags() {
+        let invalid = [((true, false, false, true), "ephemeral nodes can't have a TTL"),
+                       ((true, true, false, true), "ephemeral nodes can't have a TTL"),
+                       ((true, false, true, false), "ephemeral nodes can't be containers"),
+                       ((false, false, true, true), "containers can't have a TTL"),
+                       ((false, true, true, false), "containers can't be sequential")];
+        for &((ephemeral, sequential, container, ttl), conflict) in &invalid {
+            assert_eq!(Some(conflict),
+                       CreateMode::flags_conflict(ephemeral, sequential, container, ttl));
+            assert_eq!(Err(ZkError::BadArguments),
+                       CreateMode::from_flags(ephemeral, sequential, container, ttl));
+        }
+    }
+}
@@ -1,3 +1,7 @@
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, TimeZone, Utc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 /// Statistics about a znode, similar to the UNIX `stat` structure.
 ///
 /// # Time in ZooKeeper
@@ -12,7 +16,7 @@
 ///   numbers of that node.
 /// - **Clock Time**: ZooKeeper does not use clock time to make decisions, but it uses it to put
 ///   timestamps into the `Stat` structure.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Stat {
     /// The transaction ID that created the znode.
     pub czxid: i64,
@@ -43,4 +47,88 @@ impl Stat {
     pub fn is_ephemeral(&self) -> bool {
         self.ephemeral_owner != 0
     }
+
+    /// The time the znode was created, converted from `ctime`.
+    pub fn created_at(&self) -> SystemTime {
+        millis_to_system_time(self.ctime)
+    }
+
+    /// The time the znode was last modified, converted from `mtime`.
+    pub fn modified_at(&self) -> SystemTime {
+        millis_to_system_time(self.mtime)
+    }
+
+    /// The time the znode was created, converted from `ctime`, or `None` if `ctime` is out of
+    /// the range of `DateTime`. Requires the `chrono` feature.
+    #[cfg(feature = "chrono")]
+    pub fn ctime_datetime(&self) -> Option<DateTime<Utc>> {
+        millis_to_datetime(self.ctime)
+    }
+
+    /// The time the znode was last modified, converted from `mtime`, or `None` if `mtime` is out
+    /// of the range of `DateTime`. Requires the `chrono` feature.
+    #[cfg(feature = "chrono")]
+    pub fn mtime_datetime(&self) -> Option<DateTime<Utc>> {
+        millis_to_datetime(self.mtime)
+    }
+}
+
+fn millis_to_system_time(millis: i64) -> SystemTime {
+    if millis >= 0 {
+        UNIX_EPOCH + Duration::from_millis(millis as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_millis(millis.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(feature = "chrono")]
+fn millis_to_datetime(millis: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stat;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn stat(ctime: i64, mtime: i64) -> Stat {
+        Stat {
+            czxid: 0,
+            mzxid: 0,
+            ctime,
+            mtime,
+            version: 0,
+            cversion: 0,
+            aversion: 0,
+            ephemeral_owner: 0,
+            data_length: 0,
+            num_children: 0,
+            pzxid: 0,
+        }
+    }
+
+    #[test]
+    fn system_times() {
+        let stat = stat(1_500_000_000_123, -1_000);
+        assert_eq!(stat.created_at(), UNIX_EPOCH + Duration::from_millis(1_500_000_000_123));
+        assert_eq!(stat.modified_at(), UNIX_EPOCH - Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_datetimes() {
+        use chrono::{TimeZone, Timelike, Utc};
+
+        let stat = stat(1_500_000_000_123, 0);
+        let expected = Utc.with_ymd_and_hms(2017, 7, 14, 2, 40, 0).unwrap();
+        let ctime = stat.ctime_datetime().unwrap();
+        assert_eq!(ctime.with_nanosecond(0), Some(expected));
+        assert_eq!(ctime.timestamp_subsec_millis(), 123);
+        assert_eq!(stat.mtime_datetime(), Utc.with_ymd_and_hms(1970, 1, 1, 0, 0, 0).single());
+
+        // out of the range of DateTime
+        let stat = self::stat(i64::MAX, i64::MIN);
+        assert_eq!(stat.ctime_datetime(), None);
+        assert_eq!(stat.mtime_datetime(), None);
+    }
 }
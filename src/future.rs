@@ -0,0 +1,118 @@
+//! Futures of the requests sent by the `*_async` operations of `ZooKeeper`.
+use consts::ZkError;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::task::{Context, Poll, Waker};
+use zookeeper::{RawResponse, ZkResult};
+
+type WakerSlot = Arc<Mutex<Option<Waker>>>;
+
+/// Travels along with a request to the IO thread, and wakes the task polling its `ZkFuture` once
+/// the IO thread drops the request: after passing on its response, or when the request is lost
+/// with the connection.
+pub(crate) struct Completion(WakerSlot);
+
+impl Completion {
+    pub fn new() -> (Completion, WakerSlot) {
+        let waker = Arc::new(Mutex::new(None));
+        (Completion(waker.clone()), waker)
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        if let Some(waker) = self.0.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            waker.wake();
+        }
+    }
+}
+
+enum State<T> {
+    /// The request could not be sent.
+    Failed(ZkError),
+    Sent {
+        response: Receiver<RawResponse>,
+        waker: WakerSlot,
+        /// Turns the response, or the failure to receive it, into the result.
+        parse: Box<dyn FnOnce(ZkResult<RawResponse>) -> ZkResult<T> + Send>,
+    },
+    Done,
+}
+
+/// The result of a request which is in flight, returned by the `*_async` operations of
+/// `ZooKeeper`.
+///
+/// Resolves to the result of the corresponding blocking operation once the response arrives, or
+/// to `Err(ZkError::ConnectionLoss)` if the connection is lost before. The future can be awaited
+/// on any executor, or waited for by blocking the current thread with `wait`. The request is sent
+/// when the future is created, so dropping the future does not cancel it.
+///
+/// Creating the future never blocks. If a limit of the client applies, where the blocking
+/// operations would wait, the future fails right away instead: with `ZkError::Throttled` for
+/// `ZooKeeperBuilder::rate_limit` and `ZooKeeperBuilder::adaptive_backpressure`, and with
+/// `ZkError::ConnectionLoss` if the queue of `ZooKeeperBuilder::offline_queue_limit` is full.
+#[must_use = "the result of the request is lost unless the future is awaited"]
+pub struct ZkFuture<T> {
+    state: State<T>,
+}
+
+impl<T> ZkFuture<T> {
+    pub(crate) fn new<F>(response: Receiver<RawResponse>, waker: WakerSlot, parse: F) -> ZkFuture<T>
+        where F: FnOnce(ZkResult<RawResponse>) -> ZkResult<T> + Send + 'static
+    {
+        ZkFuture {
+            state: State::Sent {
+                response,
+                waker,
+                parse: Box::new(parse),
+            },
+        }
+    }
+
+    pub(crate) fn failed(err: ZkError) -> ZkFuture<T> {
+        ZkFuture { state: State::Failed(err) }
+    }
+
+    /// Block the current thread until the response arrives, and return the result.
+    pub fn wait(self) -> ZkResult<T> {
+        match self.state {
+            State::Failed(err) => Err(err),
+            State::Sent { response, parse, .. } => {
+                parse(response.recv().map_err(|_| ZkError::ConnectionLoss))
+            }
+            State::Done => panic!("ZkFuture waited for after completion"),
+        }
+    }
+}
+
+impl<T> Future for ZkFuture<T> {
+    type Output = ZkResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<ZkResult<T>> {
+        let this = self.get_mut();
+        let received = match this.state {
+            State::Failed(err) => {
+                this.state = State::Done;
+                return Poll::Ready(Err(err));
+            }
+            State::Sent { ref response, ref waker, .. } => {
+                // Set before checking for the response, so a response arriving in between wakes
+                // the task
+                *waker.lock().unwrap_or_else(|e| e.into_inner()) = Some(cx.waker().clone());
+                match response.try_recv() {
+                    Ok(response) => Ok(response),
+                    Err(TryRecvError::Empty) => return Poll::Pending,
+                    Err(TryRecvError::Disconnected) => Err(ZkError::ConnectionLoss),
+                }
+            }
+            State::Done => panic!("ZkFuture polled after completion"),
+        };
+        match mem::replace(&mut this.state, State::Done) {
+            State::Sent { parse, .. } => Poll::Ready(parse(received)),
+            _ => unreachable!(),
+        }
+    }
+}
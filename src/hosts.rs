@@ -0,0 +1,359 @@
+use consts::ZkError;
+use zookeeper::ZkResult;
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Turns the `host:port` entries of the connect string into the addresses of the servers, see
+/// `ZooKeeperBuilder::resolver`.
+///
+/// Any `Fn(&str) -> io::Result<Vec<SocketAddr>>` closure is a resolver.
+pub trait Resolver: Send {
+    /// Return the addresses of the servers of the connect string entry `host` (like
+    /// `zk.example.com:2181`). An entry may resolve to several servers.
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+impl<F> Resolver for F
+    where F: Fn(&str) -> io::Result<Vec<SocketAddr>> + Send
+{
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        self(host)
+    }
+}
+
+/// The default `Resolver`, using the system's resolution (`ToSocketAddrs`).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        host.to_socket_addrs().map(|addrs| addrs.collect())
+    }
+}
+
+/// The recent connection attempts to a server, see `ZooKeeper::host_stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostStat {
+    /// The address of the server.
+    pub addr: SocketAddr,
+    /// How many connections to the server were established.
+    pub successes: u64,
+    /// How many attempts to connect to the server failed.
+    pub failures: u64,
+    /// How many attempts failed in a row, since the last connection was established or the
+    /// server was last skipped.
+    pub consecutive_failures: u32,
+    /// When the last connection to the server was established.
+    pub last_success: Option<Instant>,
+    /// When the last attempt to connect to the server failed.
+    pub last_failure: Option<Instant>,
+    /// Until when the server is skipped after failing repeatedly, see
+    /// `ZooKeeperBuilder::host_circuit_breaker`.
+    pub skipped_until: Option<Instant>,
+}
+
+impl HostStat {
+    fn new(addr: SocketAddr) -> HostStat {
+        HostStat {
+            addr,
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            last_success: None,
+            last_failure: None,
+            skipped_until: None,
+        }
+    }
+}
+
+/// Provides the servers to connect to, round-robin.
+///
+/// The hosts of the connect string are resolved when the provider is created, each of them into
+/// all of its addresses. With a non-zero `refresh_interval`, they are resolved again when picking a
+/// server after the interval has passed, so addresses which change over time (like the pods of a
+/// Kubernetes service) are picked up.
+/// Resolution only ever happens while picking a server for a (re)connect, never on a timer, so a
+/// short interval does not put any load on the DNS while the connection is healthy.
+pub struct HostProvider {
+    hosts: Vec<String>,
+    addrs: Vec<SocketAddr>,
+    index: usize,
+    resolver: Box<dyn Resolver>,
+    refresh_interval: Duration,
+    resolved_at: Instant,
+    /// Addresses to skip while picking a server, until the given instant.
+    avoided: Vec<(SocketAddr, Instant)>,
+    stats: Vec<HostStat>,
+    /// Skip a server for the cooldown after this many consecutive failures.
+    circuit_breaker: Option<(u32, Duration)>,
+}
+
+impl HostProvider {
+    #[cfg(test)]
+    pub fn new(hosts: Vec<String>, refresh_interval: Duration) -> ZkResult<HostProvider> {
+        Self::with_resolver(hosts, refresh_interval, Box::new(SystemResolver))
+    }
+
+    pub fn with_resolver(hosts: Vec<String>,
+                         refresh_interval: Duration,
+                         resolver: Box<dyn Resolver>)
+                         -> ZkResult<HostProvider> {
+        let mut addrs = Vec::with_capacity(hosts.len());
+        for host in &hosts {
+            match resolver.resolve(host) {
+                Ok(ref resolved) if !resolved.is_empty() => Self::extend(&mut addrs, resolved),
+                Ok(_) => {
+                    error!("{} resolved to no address", host);
+                    return Err(ZkError::BadArguments);
+                }
+                Err(err) => {
+                    error!("Failed to resolve {}: {}", host, err);
+                    return Err(ZkError::BadArguments);
+                }
+            }
+        }
+
+        Ok(HostProvider {
+            hosts,
+            addrs,
+            index: 0,
+            resolver,
+            refresh_interval,
+            resolved_at: Instant::now(),
+            avoided: Vec::new(),
+            stats: Vec::new(),
+            circuit_breaker: None,
+        })
+    }
+
+    /// Skip a server for `cooldown` once `failures` consecutive attempts to connect to it failed.
+    pub fn set_circuit_breaker(&mut self, failures: u32, cooldown: Duration) {
+        self.circuit_breaker = Some((failures, cooldown));
+    }
+
+    fn refresh(&mut self) {
+        self.resolved_at = Instant::now();
+
+        let mut addrs = Vec::with_capacity(self.hosts.len());
+        for host in &self.hosts {
+            match self.resolver.resolve(host) {
+                Ok(resolved) => Self::extend(&mut addrs, &resolved),
+                Err(err) => warn!("Failed to resolve {}, skipping it: {}", host, err),
+            }
+        }
+
+        // Keep the previous addresses rather than having nothing to connect to
+        if addrs.is_empty() {
+            warn!("Failed to resolve any host, keeping {:?}", self.addrs);
+        } else {
+            if addrs != self.addrs {
+                info!("Hosts resolved to {:?}", addrs);
+            }
+            self.addrs = addrs;
+        }
+    }
+
+    /// Add the `resolved` addresses which are not in `addrs` yet.
+    fn extend(addrs: &mut Vec<SocketAddr>, resolved: &[SocketAddr]) {
+        for addr in resolved {
+            if !addrs.contains(addr) {
+                addrs.push(*addr);
+            }
+        }
+    }
+
+    fn stat_mut(&mut self, addr: SocketAddr) -> &mut HostStat {
+        match self.stats.iter().position(|stat| stat.addr == addr) {
+            Some(i) => &mut self.stats[i],
+            None => {
+                self.stats.push(HostStat::new(addr));
+                self.stats.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Record that a connection to `addr` was established.
+    pub fn record_success(&mut self, addr: SocketAddr) {
+        let stat = self.stat_mut(addr);
+        stat.successes += 1;
+        stat.consecutive_failures = 0;
+        stat.last_success = Some(Instant::now());
+    }
+
+    /// Record that an attempt to connect to `addr` failed, skipping it for a while if it failed
+    /// too often in a row.
+    pub fn record_failure(&mut self, addr: SocketAddr) {
+        let now = Instant::now();
+        let circuit_breaker = self.circuit_breaker;
+        let stat = self.stat_mut(addr);
+        stat.failures += 1;
+        stat.consecutive_failures += 1;
+        stat.last_failure = Some(now);
+        let until = match circuit_breaker {
+            Some((failures, cooldown)) if stat.consecutive_failures >= failures => now + cooldown,
+            _ => return,
+        };
+        warn!("{:?} failed {} times in a row, skipping it for a while",
+              addr,
+              stat.consecutive_failures);
+        stat.consecutive_failures = 0;
+        stat.skipped_until = Some(until);
+        self.avoid(addr, until);
+    }
+
+    /// Return the history of the servers which were connected to so far.
+    pub fn stats(&self) -> Vec<HostStat> {
+        self.stats.clone()
+    }
+
+    /// Skip `addr` while picking a server until `until`, unless all servers are avoided.
+    pub fn avoid(&mut self, addr: SocketAddr, until: Instant) {
+        self.avoided.retain(|&(avoided, _)| avoided != addr);
+        self.avoided.push((addr, until));
+    }
+
+    pub fn is_avoided(&self, addr: &SocketAddr) -> bool {
+        let now = Instant::now();
+        self.avoided.iter().any(|&(avoided, until)| avoided == *addr && until > now)
+    }
+
+    pub fn get(&mut self) -> SocketAddr {
+        if self.refresh_interval > Duration::from_secs(0) &&
+           self.resolved_at.elapsed() >= self.refresh_interval {
+            self.refresh();
+        }
+
+        let now = Instant::now();
+        self.avoided.retain(|&(_, until)| until > now);
+
+        if self.index >= self.addrs.len() {
+            self.index = 0;
+        }
+        let mut addr = self.addrs[self.index];
+        for _ in 0..self.addrs.len() {
+            addr = self.addrs[self.index];
+            self.index = (self.index + 1) % self.addrs.len();
+            if !self.is_avoided(&addr) {
+                return addr;
+            }
+        }
+        warn!("All hosts are avoided, connecting to {} anyway", addr);
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HostProvider;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn provider(interval: Duration) -> (HostProvider, Arc<Mutex<SocketAddr>>) {
+        let resolved = Arc::new(Mutex::new("10.0.0.1:2181".parse().unwrap()));
+        let resolved1 = resolved.clone();
+        let provider = HostProvider::with_resolver(vec!["zk:2181".to_owned()],
+                                                   interval,
+                                                   Box::new(move |_: &str| {
+                                                       Ok(vec![*resolved1.lock().unwrap()])
+                                                   }))
+                           .unwrap();
+        (provider, resolved)
+    }
+
+    #[test]
+    fn refresh_picks_up_new_address() {
+        let (mut hosts, resolved) = provider(Duration::from_millis(10));
+        assert_eq!(hosts.get(), "10.0.0.1:2181".parse().unwrap());
+
+        *resolved.lock().unwrap() = "10.0.0.2:2181".parse().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(hosts.get(), "10.0.0.2:2181".parse().unwrap());
+    }
+
+    #[test]
+    fn zero_interval_never_refreshes() {
+        let (mut hosts, resolved) = provider(Duration::from_secs(0));
+        *resolved.lock().unwrap() = "10.0.0.2:2181".parse().unwrap();
+        assert_eq!(hosts.get(), "10.0.0.1:2181".parse().unwrap());
+    }
+
+    #[test]
+    fn avoided_hosts_are_skipped_until_cooldown() {
+        let hosts = vec!["10.0.0.1:2181".to_owned(), "10.0.0.2:2181".to_owned()];
+        let mut hosts = HostProvider::new(hosts, Duration::from_secs(0)).unwrap();
+        let first: SocketAddr = "10.0.0.1:2181".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:2181".parse().unwrap();
+
+        hosts.avoid(first, Instant::now() + Duration::from_millis(20));
+        assert_eq!(hosts.get(), second);
+        assert_eq!(hosts.get(), second);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(hosts.get(), first);
+        assert_eq!(hosts.get(), second);
+    }
+
+    #[test]
+    fn all_hosts_avoided() {
+        let (mut hosts, _) = provider(Duration::from_secs(0));
+        let addr: SocketAddr = "10.0.0.1:2181".parse().unwrap();
+        hosts.avoid(addr, Instant::now() + Duration::from_secs(60));
+        assert_eq!(hosts.get(), addr);
+    }
+
+    #[test]
+    fn entries_expand_to_all_addresses() {
+        let hosts = vec!["zk-a:2181".to_owned(), "zk-b:2181".to_owned()];
+        let resolver = |host: &str| {
+            Ok(match host {
+                "zk-a:2181" => vec!["10.0.0.1:2181".parse().unwrap(),
+                                    "10.0.0.2:2181".parse().unwrap()],
+                _ => vec!["10.0.0.2:2181".parse().unwrap(), "10.0.0.3:2181".parse().unwrap()],
+            })
+        };
+        let interval = Duration::from_secs(0);
+        let mut hosts = HostProvider::with_resolver(hosts, interval, Box::new(resolver)).unwrap();
+        let picked: Vec<SocketAddr> = (0..3).map(|_| hosts.get()).collect();
+        assert_eq!(picked,
+                   vec!["10.0.0.1:2181".parse().unwrap(),
+                        "10.0.0.2:2181".parse().unwrap(),
+                        "10.0.0.3:2181".parse().unwrap()]);
+
+        let failing = |_: &str| Err(io::ErrorKind::NotFound.into());
+        assert!(HostProvider::with_resolver(vec!["zk:2181".to_owned()],
+                                            Duration::from_secs(0),
+                                            Box::new(failing))
+                    .is_err());
+    }
+
+    #[test]
+    fn failing_host_is_skipped_during_cooldown() {
+        let hosts = vec!["10.0.0.1:2181".to_owned(), "10.0.0.2:2181".to_owned()];
+        let mut hosts = HostProvider::new(hosts, Duration::from_secs(0)).unwrap();
+        hosts.set_circuit_breaker(2, Duration::from_millis(20));
+        let first: SocketAddr = "10.0.0.1:2181".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:2181".parse().unwrap();
+
+        // a single failure does not trip the breaker
+        hosts.record_failure(first);
+        assert_eq!(hosts.get(), first);
+        hosts.record_failure(first);
+        assert_eq!(hosts.get(), second);
+        assert_eq!(hosts.get(), second);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(hosts.get(), first);
+        hosts.record_success(first);
+
+        let stats = hosts.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].addr, stats[0].successes, stats[0].failures), (first, 1, 2));
+        assert_eq!(stats[0].consecutive_failures, 0);
+        assert!(stats[0].skipped_until.is_some());
+    }
+}
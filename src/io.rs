@@ -1,13 +1,14 @@
-use consts::{ZkError, ZkState};
-use proto::{ByteBuf, ConnectRequest, ConnectResponse, OpCode, ReadFrom, ReplyHeader, RequestHeader,
-            WriteTo};
-use watch::WatchMessage;
-use zookeeper::{RawResponse, RawRequest};
+use consts::{KeeperState, ZkError, ZkState};
+use proto::{to_len_prefixed_buf, AuthRequest, ByteBuf, ConnectRequest, ConnectResponse, FrameCodec,
+            OpCode, ReadFrom, ReplyHeader, RequestHeader, WriteTo, AUTH_XID, NOTIFICATION_XID,
+            PING_XID};
+use watch::{WatchMessage, WatchType};
+use zookeeper::{random_u64, RawResponse, RawRequest};
 use listeners::ListenerSet;
+use hosts::{HostProvider, HostStat};
+use connector::{Connector, Stream};
 
-use byteorder::{BigEndian, ByteOrder};
 use bytes::{Buf, Bytes, BytesMut};
-use mio::net::TcpStream;
 use mio::*;
 use mio_extras::channel::{Sender, Receiver, channel};
 use mio_extras::timer::{Timer, Timeout};
@@ -16,23 +17,20 @@ use std::io;
 use std::io::{Cursor, ErrorKind};
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 
 const ZK: Token = Token(1);
 const TIMER: Token = Token(2);
 const CHANNEL: Token = Token(3);
+const CONTROL: Token = Token(4);
 
 use try_io::{TryRead, TryWrite};
 
 lazy_static! {
     static ref PING: ByteBuf =
-    RequestHeader{xid: -2, opcode: OpCode::Ping}.to_len_prefixed_buf().unwrap();
-}
-
-struct Hosts {
-    addrs: Vec<SocketAddr>,
-    index: usize,
+    RequestHeader{xid: PING_XID, opcode: OpCode::Ping}.to_len_prefixed_buf().unwrap();
 }
 
 #[inline]
@@ -40,42 +38,189 @@ fn pollopt() -> PollOpt {
     PollOpt::edge() | PollOpt::oneshot()
 }
 
-impl Hosts {
-    fn new(addrs: Vec<SocketAddr>) -> Hosts {
-        Hosts {
-            addrs: addrs,
-            index: 0,
-        }
-    }
+/// Describes one attempt of the client to reconnect to a server, see
+/// `ZooKeeper::add_reconnect_listener`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectEvent {
+    /// The server the client is (re)connecting to.
+    pub host: SocketAddr,
+    /// The number of the attempt since the connection was lost, starting at 1.
+    pub attempt: usize,
+    /// How far the attempt got.
+    pub outcome: ReconnectOutcome,
+}
 
-    fn get(&mut self) -> &SocketAddr {
-        let addr = &self.addrs[self.index];
-        if self.addrs.len() == self.index + 1 {
-            self.index = 0;
-        } else {
-            self.index += 1;
+/// The progress of a reconnect attempt, see `ReconnectEvent`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReconnectOutcome {
+    /// The client started connecting to the server.
+    Started,
+    /// The session has been established with the server.
+    Connected,
+    /// The server could not be reached, or it did not respond in time.
+    Failed,
+}
+
+/// The state of the connection to the ensemble, see `ZooKeeper::connection_state`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConnectionState {
+    /// The client is establishing its first session.
+    #[default]
+    Connecting,
+    /// The session is established, possibly with a read-only server. Operations are sent right
+    /// away.
+    Connected,
+    /// The connection was lost, and the client is reconnecting to resume its session. Operations
+    /// are queued until it succeeds, and fail if the session expires in the meantime.
+    Reconnecting,
+    /// The session expired, so its ephemeral nodes and watches are gone. The client may establish
+    /// a new session unless it is closed.
+    Expired,
+    /// The handle is closed, or gave up on the connection. Operations fail.
+    Closed,
+}
+
+/// What the IO thread knows about the current session, shared with the `ZooKeeper` handle.
+#[derive(Clone, Debug, Default)]
+pub struct SessionState {
+    /// The session timeout negotiated with the server, `None` until a session is established.
+    pub timeout: Option<Duration>,
+    /// The id of the session, 0 until a session is established.
+    pub session_id: i64,
+    /// The password of the session, empty until a session is established.
+    pub passwd: Vec<u8>,
+    /// The protocol version negotiated with the server, `None` until a connection is established.
+    pub protocol_version: Option<i32>,
+    /// The zxid of the latest response received from the server.
+    pub last_zxid: i64,
+    /// The server of the current connection, `None` until a session is established.
+    pub server: Option<SocketAddr>,
+    /// How many times a connection was established, to tell reconnects apart.
+    pub connections: u64,
+    /// When the current session was established, `None` until a session is established.
+    pub established_at: Option<Instant>,
+    /// The connection history of the servers.
+    pub hosts: Vec<HostStat>,
+    /// The state of the connection, updated before the state listeners are notified.
+    pub state: ConnectionState,
+    /// Whether the client is connected to a read-only server.
+    pub read_only: bool,
+}
+
+/// What the IO thread does after it panicked (like in a bug of the client, or in a state
+/// listener), see `ZooKeeperBuilder::io_failure_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IoFailurePolicy {
+    /// Close the handle and its session, like `ZooKeeper::close`: pending and future operations
+    /// fail with `ZkError::ConnectionLoss`, and the state listeners are notified with
+    /// `ZkState::Closed`.
+    #[default]
+    Close,
+    /// Keep the IO thread running and reconnect, re-establishing the session if it has not
+    /// expired. Pending operations fail with `ZkError::ConnectionLoss`, like after losing the
+    /// connection.
+    Restart,
+}
+
+/// How long the client waits between attempts to reconnect, see
+/// `ZooKeeperBuilder::reconnect_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReconnectPolicy {
+    /// Try the next server right away.
+    #[default]
+    Immediate,
+    /// Try the next server right away after losing the connection, but wait `base` before the
+    /// second attempt, and twice as long before each further one, up to `max`. With `jitter`,
+    /// each wait is picked at random between half of it and all of it, so clients which lost
+    /// their connections at the same time don't reconnect in lockstep.
+    ExponentialBackoff {
+        /// The wait before the second attempt.
+        base: Duration,
+        /// The longest wait.
+        max: Duration,
+        /// Whether to randomize the waits.
+        jitter: bool,
+    },
+}
+
+impl ReconnectPolicy {
+    /// The wait before the given attempt since the connection was lost, starting at 1.
+    fn delay(&self, attempt: usize) -> Duration {
+        match *self {
+            ReconnectPolicy::Immediate => Duration::from_secs(0),
+            ReconnectPolicy::ExponentialBackoff { base, max, jitter } => {
+                if attempt <= 1 {
+                    return Duration::from_secs(0);
+                }
+                let factor = 1u32 << (attempt - 2).min(31);
+                let delay = base.checked_mul(factor).map_or(max, |delay| delay.min(max));
+                if jitter {
+                    let random = random_u64() % 1024;
+                    delay / 2 + delay / 2 * random as u32 / 1024
+                } else {
+                    delay
+                }
+            }
         }
-        addr
     }
 }
 
+/// Instructions from the `ZooKeeper` handle to the IO thread, besides requests.
+pub enum IoControl {
+    /// Skip the server while picking one to connect to, for the given cooldown. If connected to
+    /// it, reconnect to another one.
+    AvoidHost(SocketAddr, Duration),
+    /// Stop without closing the session, see `ZooKeeper::detach`.
+    Detach,
+    /// Panic in the IO thread, to test `IoFailurePolicy`.
+    #[cfg(test)]
+    Panic,
+}
+
+/// The connection options of `ZooKeeperBuilder` which concern the IO thread.
+pub struct IoOptions {
+    pub session_timeout: Duration,
+    pub require_exact_timeout: bool,
+    pub min_protocol_version: Option<i32>,
+    pub connector: Box<dyn Connector>,
+    pub failure_policy: IoFailurePolicy,
+    /// The id and password of a session to resume, instead of creating a new one.
+    pub session: Option<(i64, Vec<u8>)>,
+    pub reconnect_policy: ReconnectPolicy,
+    /// Whether to accept connections to servers which are partitioned from the quorum.
+    pub read_only: bool,
+    /// The `scheme` and `auth` information to send along with every connection handshake.
+    pub auth: Vec<(String, Vec<u8>)>,
+}
+
 #[derive(Clone, Debug)]
 enum ZkTimeout {
     Ping,
     Connect,
+    /// The end of the wait before the next reconnect attempt.
+    Reconnect,
 }
 
 pub struct ZkIo {
-    sock: TcpStream,
+    sock: Box<dyn Stream>,
+    connector: Box<dyn Connector>,
     state: ZkState,
-    hosts: Hosts,
+    hosts: HostProvider,
     buffer: VecDeque<RawRequest>,
     inflight: VecDeque<RawRequest>,
     response: BytesMut,
+    codec: FrameCodec,
     ping_timeout: Option<Timeout>,
     conn_timeout: Option<Timeout>,
+    reconnect_timeout: Option<Timeout>,
     timer: Timer<ZkTimeout>,
     timeout_ms: u64,
+    requested_timeout_ms: u64,
+    require_exact_timeout: bool,
+    min_protocol_version: Option<i32>,
+    read_only: bool,
+    auth: Vec<(String, Vec<u8>)>,
+    session: Arc<Mutex<SessionState>>,
     ping_timeout_duration: Duration,
     conn_timeout_duration: Duration,
     watch_sender: mpsc::Sender<WatchMessage>,
@@ -83,87 +228,195 @@ pub struct ZkIo {
     zxid: i64,
     ping_sent: Instant,
     state_listeners: ListenerSet<ZkState>,
+    reconnect_listeners: ListenerSet<ReconnectEvent>,
+    host: SocketAddr,
+    reconnect_attempt: usize,
+    reconnect_policy: ReconnectPolicy,
     poll: Poll,
     shutdown: bool,
+    /// Whether the session expired since the last connection.
+    session_expired: bool,
+    /// Whether the default watcher was told about the current connection, so it is told once
+    /// about losing it.
+    connected: bool,
+    failure_policy: IoFailurePolicy,
     tx: Sender<RawRequest>,
     rx: Receiver<RawRequest>,
+    control_tx: Sender<IoControl>,
+    control_rx: Receiver<IoControl>,
 }
 
 impl ZkIo {
     pub fn new(
-        addrs: Vec<SocketAddr>,
-        ping_timeout_duration: Duration,
+        mut hosts: HostProvider,
+        options: IoOptions,
         watch_sender: mpsc::Sender<WatchMessage>,
-        state_listeners: ListenerSet<ZkState>
+        state_listeners: ListenerSet<ZkState>,
+        reconnect_listeners: ListenerSet<ReconnectEvent>,
+        session: Arc<Mutex<SessionState>>
     ) -> ZkIo {
         trace!("ZkIo::new");
+        let IoOptions {
+            session_timeout: ping_timeout_duration,
+            require_exact_timeout,
+            min_protocol_version,
+            connector,
+            failure_policy,
+            session: resumed,
+            reconnect_policy,
+            read_only,
+            auth,
+        } = options;
         let timeout_ms = ping_timeout_duration.as_secs() * 1000 +
             ping_timeout_duration.subsec_nanos() as u64 / 1000000;
         let (tx, rx) = channel();
+        let (control_tx, control_rx) = channel();
+        let host = hosts.get();
 
         let mut zkio = ZkIo {
-            sock: TcpStream::connect(&addrs[0]).unwrap(), // TODO I need a socket here, sorry.
+            sock: connector.connect(&host).unwrap(), // TODO I need a socket here, sorry.
+            connector,
             state: ZkState::Connecting,
-            hosts: Hosts::new(addrs),
+            hosts: hosts,
             buffer: VecDeque::new(),
             inflight: VecDeque::new(),
             // TODO server reads max up to 1MB, otherwise drops the connection,
             // size should be 1MB + tcp rcvBufsize
             response: BytesMut::with_capacity(1024 * 1024 * 2),
+            codec: FrameCodec::default(),
             ping_timeout: None,
             conn_timeout: None,
+            reconnect_timeout: None,
             ping_timeout_duration: ping_timeout_duration,
             conn_timeout_duration: Duration::from_secs(2),
             timeout_ms: timeout_ms,
+            requested_timeout_ms: timeout_ms,
+            require_exact_timeout,
+            min_protocol_version,
+            read_only,
+            auth,
+            session,
             watch_sender: watch_sender,
-            conn_resp: ConnectResponse::initial(timeout_ms),
+            conn_resp: match resumed {
+                Some((session_id, passwd)) => {
+                    ConnectResponse::resume(timeout_ms, session_id, passwd)
+                }
+                None => ConnectResponse::initial(timeout_ms),
+            },
             zxid: 0,
             ping_sent: Instant::now(),
             state_listeners: state_listeners,
+            reconnect_listeners,
+            host,
+            reconnect_attempt: 0,
             // TODO add error handling to this method in subsequent commit.
             // There's already another unwrap which needs to be addressed.
             poll: Poll::new().unwrap(),
             shutdown: false,
+            session_expired: false,
+            connected: false,
+            failure_policy,
+            reconnect_policy,
             timer: Timer::default(),
             tx: tx,
             rx: rx,
+            control_tx,
+            control_rx,
         };
 
-        let request = zkio.connect_request();
-        zkio.buffer.push_back(request);
+        zkio.queue_handshake();
         zkio
     }
 
     fn reregister(&mut self, interest: Ready) {
+        if self.is_waiting_to_reconnect() {
+            // The socket is the lost one, the next attempt registers a new one
+            return;
+        }
         self.poll
-            .reregister(&self.sock, ZK, interest, pollopt())
+            .reregister(&*self.sock, ZK, interest, pollopt())
             .expect("Failed to register ZK handle");
     }
 
+    /// Lock the session state shared with the `ZooKeeper` handle.
+    fn session(&self) -> MutexGuard<'_, SessionState> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
     fn notify_state(&self, old_state: ZkState, new_state: ZkState) {
+        {
+            let mut session = self.session();
+            #[allow(deprecated)]
+            let state = match new_state {
+                ZkState::Connected | ZkState::ConnectedReadOnly => ConnectionState::Connected,
+                _ if self.session_expired => ConnectionState::Expired,
+                ZkState::Connecting | ZkState::NotConnected | ZkState::Associating => {
+                    if session.connections > 0 {
+                        ConnectionState::Reconnecting
+                    } else {
+                        ConnectionState::Connecting
+                    }
+                }
+                ZkState::Closed | ZkState::AuthFailed => ConnectionState::Closed,
+            };
+            session.state = state;
+            session.read_only = new_state == ZkState::ConnectedReadOnly;
+        }
         if new_state != old_state {
             self.state_listeners.notify(&new_state);
         }
     }
 
-    fn handle_response(&mut self) {
-        loop {
-            if self.response.len() <= 4 {
-                return;
-            }
+    /// Tell the default watcher about the state of the connection.
+    fn notify_keeper_state(&self, state: KeeperState) {
+        if self.watch_sender.send(WatchMessage::State(state)).is_err() {
+            debug!("event thread is gone, not delivering {:?}", state);
+        }
+    }
 
-            let len = BigEndian::read_i32(&self.response[..4]) as usize;
+    /// Tell the default watcher that the connection was lost, once per established connection.
+    fn notify_disconnected(&mut self) {
+        if mem::replace(&mut self.connected, false) {
+            self.notify_keeper_state(KeeperState::Disconnected);
+        }
+    }
 
-            trace!("Response chunk len = {} buf len is {}", len, self.response.len());
+    /// Record the outcome of the connection attempt to the current host.
+    fn record_connect(&mut self, success: bool) {
+        if success {
+            self.hosts.record_success(self.host);
+        } else {
+            self.hosts.record_failure(self.host);
+        }
+        self.session().hosts = self.hosts.stats();
+    }
 
-            if self.response.len() - 4 < len {
-                return;
-            } else {
-                self.response.advance(4);
-                let bytes = self.response.split_to(len);
-                self.handle_chunk(bytes.freeze());
+    fn notify_reconnect(&self, outcome: ReconnectOutcome) {
+        if self.reconnect_attempt > 0 {
+            self.reconnect_listeners.notify(&ReconnectEvent {
+                host: self.host,
+                attempt: self.reconnect_attempt,
+                outcome,
+            });
+        }
+    }
 
-                self.response.reserve(1024 * 1024 * 2);
+    fn handle_response(&mut self) {
+        loop {
+            match self.codec.decode(&mut self.response) {
+                Ok(Some(bytes)) => {
+                    self.handle_chunk(bytes);
+                    self.response.reserve(1024 * 1024 * 2);
+                }
+                Ok(None) => {
+                    trace!("Incomplete response chunk, buf len is {}", self.response.len());
+                    return;
+                }
+                Err(e) => {
+                    warn!("Failed to read response frame: {}, reconnecting", e);
+                    self.reconnect();
+                    return;
+                }
             }
         }
     }
@@ -178,31 +431,57 @@ impl ZkIo {
             let header = match ReplyHeader::read_from(&mut data) {
                 Ok(header) => header,
                 Err(e) => {
-                    warn!("Failed to parse ReplyHeader {:?}", e);
-                    self.inflight.pop_front();
+                    // The frame has been consumed as a whole, so the connection stays in sync:
+                    // only fail the request this reply was meant for
+                    match self.inflight.pop_front() {
+                        Some(request) => {
+                            warn!("Failed to parse ReplyHeader of {:?} request xid={}: {:?}",
+                                  request.opcode,
+                                  request.xid(),
+                                  e);
+                            let response = RawResponse {
+                                header: ReplyHeader {
+                                    xid: request.xid(),
+                                    zxid: 0,
+                                    err: ZkError::MarshallingError as i32,
+                                },
+                                data: Cursor::new(Vec::new()),
+                                len: len + FrameCodec::HEADER_LEN,
+                            };
+                            self.send_response(request, response);
+                        }
+                        None => warn!("Failed to parse ReplyHeader {:?}", e),
+                    }
                     return;
                 }
             };
             if header.zxid > 0 {
                 // Update last-seen zxid when this is a request response
                 self.zxid = header.zxid;
+                self.session().last_zxid = header.zxid;
             }
             let response = RawResponse {
                 header: header,
                 data: Cursor::new(data.bytes().to_vec()),
+                len: len + FrameCodec::HEADER_LEN,
             }; // TODO COPY!
             match response.header.xid {
-                -1 => {
+                NOTIFICATION_XID => {
                     trace!("handle_response Got a watch event!");
                     self.watch_sender.send(WatchMessage::Event(response)).unwrap();
                 }
-                -2 => {
+                PING_XID => {
                     trace!("Got ping response in {:?}",
                            self.ping_sent.elapsed());
                     self.inflight.pop_front();
                 }
                 _ => {
                     match self.inflight.pop_front() {
+                        Some(ref request) if request.opcode == OpCode::Auth &&
+                                            request.listener.is_none() &&
+                                            response.header.err != 0 => {
+                            self.auth_failed(response.header.err);
+                        }
                         Some(request) => {
                             if request.opcode == OpCode::CloseSession {
                                 let old_state = self.state;
@@ -229,15 +508,49 @@ impl ZkIo {
             };
 
             let old_state = self.state;
+            self.session().protocol_version = Some(conn_resp.protocol_version);
 
             if conn_resp.timeout == 0 {
                 info!("session {} expired", self.conn_resp.session_id);
                 self.conn_resp.session_id = 0;
+                self.session_expired = true;
                 self.state = ZkState::NotConnected;
+                self.notify_keeper_state(KeeperState::Expired);
+                // The requests were meant for the expired session, not for the next one
+                self.fail_pending(ZkError::SessionExpired);
+            } else if self.require_exact_timeout &&
+                      conn_resp.timeout != self.requested_timeout_ms {
+                error!("Server negotiated a session timeout of {}ms instead of the required {}ms",
+                       conn_resp.timeout,
+                       self.requested_timeout_ms);
+                let negotiated = Duration::from_millis(conn_resp.timeout);
+                self.session().timeout = Some(negotiated);
+                self.close_rejected_session(conn_resp.session_id);
+            } else if self.min_protocol_version
+                          .is_some_and(|min_version| conn_resp.protocol_version < min_version) {
+                error!("Server negotiated protocol version {} instead of at least {:?}",
+                       conn_resp.protocol_version,
+                       self.min_protocol_version);
+                self.close_rejected_session(conn_resp.session_id);
             } else {
+                let new_session = conn_resp.session_id != self.conn_resp.session_id;
                 self.conn_resp = conn_resp;
+                self.session_expired = false;
                 info!("Connected: {:?}", self.conn_resp);
                 self.timeout_ms = self.conn_resp.timeout;
+                let reconnected = {
+                    let mut session = self.session();
+                    // A resumed session counts as established by this client as well
+                    if new_session || session.established_at.is_none() {
+                        session.established_at = Some(Instant::now());
+                    }
+                    session.timeout = Some(Duration::from_millis(self.timeout_ms));
+                    session.session_id = self.conn_resp.session_id;
+                    session.passwd = self.conn_resp.passwd.clone();
+                    session.server = Some(self.host);
+                    session.connections += 1;
+                    session.connections > 1
+                };
                 self.ping_timeout_duration = Duration::from_millis(self.conn_resp.timeout / 3 * 2);
 
                 self.state = if self.conn_resp.read_only {
@@ -245,22 +558,112 @@ impl ZkIo {
                 } else {
                     ZkState::Connected
                 };
+
+                // Only reconnects follow a Disconnected event
+                if reconnected {
+                    self.notify_keeper_state(if self.conn_resp.read_only {
+                        KeeperState::ConnectedReadOnly
+                    } else {
+                        KeeperState::SyncConnected
+                    });
+                }
+                self.connected = true;
+
+                self.record_connect(true);
+                self.notify_reconnect(ReconnectOutcome::Connected);
+                self.reconnect_attempt = 0;
             }
 
             self.notify_state(old_state, self.state);
         }
     }
 
+    /// Give up on the connection, as the server rejected the authentication information of the
+    /// handshake, and closes the connection.
+    fn auth_failed(&mut self, err: i32) {
+        error!("Server rejected the authentication information: {:?}", ZkError::from(err));
+        self.fail_pending(ZkError::AuthFailed);
+        let old_state = self.state;
+        self.state = ZkState::AuthFailed;
+        self.notify_state(old_state, self.state);
+        self.notify_keeper_state(KeeperState::AuthFailed);
+        self.shutdown = true;
+    }
+
+    /// Close the session the server just established, as the client does not accept it. The
+    /// server would keep the session until it times out otherwise. The request is written right
+    /// away, without waiting for the response.
+    fn close_rejected_session(&mut self, session_id: i64) {
+        info!("Closing rejected session {}", session_id);
+        self.write_close_session();
+        self.state = ZkState::Closed;
+        self.shutdown = true;
+    }
+
+    /// Write a CloseSession request right away, like `ZooKeeper::close` sends it, without waiting
+    /// for the response.
+    fn write_close_session(&mut self) {
+        let header = RequestHeader {
+            xid: 0,
+            opcode: OpCode::CloseSession,
+        };
+        let mut request = header.to_len_prefixed_buf().unwrap();
+        if let Err(e) = self.sock.try_write_buf(&mut request) {
+            warn!("Failed to close the session: {:?}", e);
+        }
+    }
+
+    /// Fail the operation of a request without sending it.
+    fn send_error(&self, request: RawRequest, err: ZkError) {
+        let header = ReplyHeader {
+            xid: request.xid(),
+            zxid: 0,
+            err: err as i32,
+        };
+        let response = RawResponse {
+            header: header,
+            data: ByteBuf::new(vec![]),
+            len: 0,
+        };
+        self.send_response(request, response);
+    }
+
+    /// Fail the operations of all requests which were sent or wait to be.
+    fn fail_pending(&mut self, err: ZkError) {
+        let pending: Vec<_> = self.inflight.drain(..).chain(self.buffer.drain(..)).collect();
+        for request in pending {
+            self.send_error(request, err);
+        }
+    }
+
     fn send_response(&self, request: RawRequest, response: RawResponse) {
+        let (zxid, err) = (response.header.zxid, response.header.err);
         match request.listener {
             Some(ref listener) => {
                 trace!("send_response Opcode is {:?}", request.opcode);
-                listener.send(response).unwrap();
+                if let Err(err) = listener.send(response) {
+                    if request.is_cancelled() {
+                        debug!("discarding response to cancelled request {:?}", request.opcode);
+                    } else {
+                        warn!("response receiver for {:?} has gone away: {:?}",
+                              request.opcode,
+                              err);
+                    }
+                }
             }
             None => info!("Nobody is interested in response {:?}", request.opcode),
         }
-        if let Some(watch) = request.watch {
-            self.watch_sender.send(WatchMessage::Watch(watch)).unwrap();
+        match request.watch {
+            Some(WatchMessage::Watch(watch, _)) => {
+                // Like the server, only leave an exist watch on a missing node
+                let exist_on_missing = watch.watch_type == WatchType::Exist &&
+                                       err == ZkError::NoNode as i32;
+                if err == 0 || exist_on_missing {
+                    self.watch_sender.send(WatchMessage::Watch(watch, zxid)).unwrap();
+                }
+            }
+            Some(message) => self.watch_sender.send(message).unwrap(),
+            None => {}
         }
     }
 
@@ -272,6 +675,7 @@ impl ZkIo {
             ZkTimeout::Connect => {
                 mem::replace(&mut self.conn_timeout , None)
             },
+            ZkTimeout::Reconnect => self.reconnect_timeout.take(),
         };
         if let Some(timeout) = timeout {
             trace!("clear_timeout: {:?}", atype);
@@ -291,71 +695,156 @@ impl ZkIo {
                 let duration = self.conn_timeout_duration.clone();
                 self.conn_timeout = Some(self.timer.set_timeout(duration, atype));
             },
+            ZkTimeout::Reconnect => unreachable!("see wait_to_reconnect"),
         }
+        self.reregister_timer();
+    }
+
+    /// Connect once `delay` has passed, without blocking the IO thread meanwhile.
+    fn wait_to_reconnect(&mut self, delay: Duration) {
+        self.clear_timeout(ZkTimeout::Reconnect);
+        debug!("Waiting {:?} before reconnecting", delay);
+        self.reconnect_timeout = Some(self.timer.set_timeout(delay, ZkTimeout::Reconnect));
+        self.reregister_timer();
+    }
+
+    fn is_waiting_to_reconnect(&self) -> bool {
+        self.reconnect_timeout.is_some()
+    }
+
+    fn reregister_timer(&mut self) {
         self.poll.reregister(&self.timer, TIMER, Ready::readable(), pollopt())
             .expect("Reregister TIMER");
     }
 
     fn reconnect(&mut self) {
         trace!("reconnect");
+        self.notify_disconnected();
+        if self.state == ZkState::Connecting {
+            // the previous attempt did not get through
+            self.record_connect(false);
+            self.notify_reconnect(ReconnectOutcome::Failed);
+        }
         let old_state = self.state;
         self.state = ZkState::Connecting;
         self.notify_state(old_state, self.state);
 
         info!("Establishing Zk connection");
 
-        // TODO only until session times out
-        loop {
-            self.buffer.clear();
+        self.clear_timeout(ZkTimeout::Reconnect);
+        if !self.is_session_timed_out() {
+            // Dropping the requests fails their operations with ConnectionLoss, except for the
+            // ones in the offline queue, which wait for the session across reconnect attempts
+            self.buffer.retain(|request| request.queue_slot.is_some());
             self.inflight.clear();
-            self.response.clear(); // TODO drop all read bytes once RingBuf.clear() is merged
+        }
+        self.response.clear(); // TODO drop all read bytes once RingBuf.clear() is merged
+        self.connect_next();
+    }
 
-            // Check if the session is still alive according to our knowledge
-            if self.ping_sent.elapsed().as_secs() * 1000 > self.timeout_ms {
+    /// Connect to the next server, once the wait the reconnect policy asks for has passed.
+    fn connect_next(&mut self) {
+        loop {
+            if self.is_session_timed_out() {
                 warn!("Zk session timeout, closing io event loop");
+                self.session_expired = true;
+                self.fail_pending(ZkError::SessionExpired);
                 self.state = ZkState::Closed;
                 self.notify_state(ZkState::Connecting, self.state);
                 self.shutdown = true;
-                break;
+                return;
             }
 
             self.clear_timeout(ZkTimeout::Ping);
             self.clear_timeout(ZkTimeout::Connect);
-            {
-                let host = self.hosts.get();
-                info!("Connecting to new server {:?}", host);
-                self.sock = match TcpStream::connect(host) {
-                    Ok(sock) => sock,
-                    Err(e) => {
-                        error!("Failed to connect {:?}: {:?}", host, e);
-                        continue;
-                    }
-                };
-                info!("Started connecting to {:?}", host);
+
+            // Give a recovering ensemble some rest, but not beyond the session timeout
+            let delay = self.reconnect_policy.delay(self.reconnect_attempt + 1);
+            if delay > Duration::from_secs(0) {
+                let remaining = Duration::from_millis(self.timeout_ms)
+                                    .checked_sub(self.ping_sent.elapsed())
+                                    .unwrap_or_default();
+                self.wait_to_reconnect(delay.min(remaining));
+                return;
+            }
+            if self.connect() {
+                return;
             }
-            self.start_timeout(ZkTimeout::Connect);
+        }
+    }
 
-            let request = self.connect_request();
-            self.buffer.push_back(request);
+    /// Whether the session is gone according to our knowledge, as the server can't have heard of
+    /// us for longer than its timeout.
+    fn is_session_timed_out(&self) -> bool {
+        self.ping_sent.elapsed().as_secs() * 1000 > self.timeout_ms
+    }
+
+    /// Start connecting to the next server. Returns whether the connection could be opened.
+    fn connect(&mut self) -> bool {
+        self.host = self.hosts.get();
+        self.reconnect_attempt += 1;
+        info!("Connecting to new server {:?}", self.host);
+        self.notify_reconnect(ReconnectOutcome::Started);
+        self.sock = match self.connector.connect(&self.host) {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("Failed to connect {:?}: {:?}", self.host, e);
+                self.record_connect(false);
+                self.notify_reconnect(ReconnectOutcome::Failed);
+                return false;
+            }
+        };
+        info!("Started connecting to {:?}", self.host);
+        self.start_timeout(ZkTimeout::Connect);
 
+        // Requests sent while waiting to reconnect follow the handshake
+        self.queue_handshake();
 
-            // Register the new socket
-            let pollopt = PollOpt::edge() | PollOpt::oneshot();
-            self.poll.register(&self.sock, ZK, Ready::all(), pollopt)
-                .expect("Register ZK");
+        // Register the new socket
+        let pollopt = PollOpt::edge() | PollOpt::oneshot();
+        self.poll.register(&*self.sock, ZK, Ready::all(), pollopt)
+            .expect("Register ZK");
+        true
+    }
 
-            break;
+    /// Queue the connect request, followed by the authentication information, ahead of all other
+    /// requests. The server applies the authentication to the session before any of them.
+    fn queue_handshake(&mut self) {
+        for (scheme, auth) in self.auth.iter().rev() {
+            let header = RequestHeader {
+                xid: AUTH_XID,
+                opcode: OpCode::Auth,
+            };
+            let req = AuthRequest {
+                typ: 0,
+                scheme: scheme.clone(),
+                auth: auth.clone(),
+            };
+            self.buffer.push_front(RawRequest {
+                opcode: OpCode::Auth,
+                data: to_len_prefixed_buf(header, req).unwrap(),
+                listener: None,
+                completion: None,
+                watch: None,
+                cancel: None,
+                queue_slot: None,
+            });
         }
+        let request = self.connect_request();
+        self.buffer.push_front(request);
     }
 
     fn connect_request(&self) -> RawRequest {
-        let conn_req = ConnectRequest::from(&self.conn_resp, self.zxid);
+        let conn_req = ConnectRequest::from(&self.conn_resp, self.zxid, self.read_only);
         let buf = conn_req.to_len_prefixed_buf().unwrap();
         RawRequest {
             opcode: OpCode::Auth,
             data: buf,
             listener: None,
+            completion: None,
             watch: None,
+            cancel: None,
+            queue_slot: None,
         }
     }
 
@@ -366,15 +855,36 @@ impl ZkIo {
             ZK => self.ready_zk(ready),
             TIMER => self.ready_timer(ready),
             CHANNEL => self.ready_channel(ready),
+            CONTROL => self.ready_control(ready),
             _ => unreachable!(),
         }
     }
 
     fn ready_zk(&mut self, ready: Ready) {
+        if self.is_waiting_to_reconnect() {
+            trace!("Ignoring the lost connection");
+            return;
+        }
         self.clear_timeout(ZkTimeout::Ping);
 
         if ready.is_writable() {
             while let Some(mut request) = self.buffer.pop_front() {
+                if request.data.position() == 0 && request.is_cancelled() {
+                    debug!("dropping cancelled request {:?}", request.opcode);
+                    continue;
+                }
+                if request.queue_slot.is_some() && !self.connected {
+                    // Requests queued while offline wait for the session, so they stay in the queue
+                    self.buffer.push_front(request);
+                    break;
+                }
+                if let Some(slot) = request.queue_slot.take() {
+                    if !slot.claim() {
+                        debug!("dropping request {:?} rejected from the offline queue",
+                               request.opcode);
+                        continue;
+                    }
+                }
                 match self.sock.try_write_buf(&mut request.data) {
                     Ok(Some(0)) => {
                         warn!("Connection closed: write");
@@ -441,6 +951,7 @@ impl ZkIo {
             //         Err(e) => panic!("Reader/Writer: Event died {}", e)
             //     }
             // }
+            self.notify_disconnected();
             let old_state = self.state;
             self.state = ZkState::NotConnected;
             self.notify_state(old_state, self.state);
@@ -456,7 +967,7 @@ impl ZkIo {
         // Not sure that we need to write, but we always need to read, because of watches
         // If the output buffer has no content, we don't need to write again
         let mut interest = Ready::all();
-        if self.buffer.is_empty() {
+        if self.buffer.is_empty() || self.is_holding_queued() {
             interest.remove(Ready::writable());
         }
 
@@ -464,6 +975,11 @@ impl ZkIo {
         self.reregister(interest);
     }
 
+    /// Whether the next request to write was queued while offline, and waits for the session.
+    fn is_holding_queued(&self) -> bool {
+        !self.connected && self.buffer.front().is_some_and(|r| r.queue_slot.is_some())
+    }
+
     fn is_idle(&self) -> bool {
         self.inflight.is_empty() && self.buffer.is_empty()
     }
@@ -475,17 +991,9 @@ impl ZkIo {
             match self.state {
                 ZkState::Closed => {
                     // If zk is unavailable, respond with a ConnectionLoss error.
-                    let header = ReplyHeader {
-                        xid: 0,
-                        zxid: 0,
-                        err: ZkError::ConnectionLoss as i32,
-                    };
-                    let response = RawResponse {
-                        header: header,
-                        data: ByteBuf::new(vec![]),
-                    };
-                    self.send_response(request, response);
+                    self.send_error(request, ZkError::ConnectionLoss);
                 },
+                ZkState::AuthFailed => self.send_error(request, ZkError::AuthFailed),
                 _ => {
                     // Otherwise, queue request for processing.
                     if self.buffer.is_empty() {
@@ -500,6 +1008,37 @@ impl ZkIo {
             .expect("Reregister CHANNEL");
     }
 
+    fn ready_control(&mut self, _: Ready) {
+        while let Ok(control) = self.control_rx.try_recv() {
+            match control {
+                IoControl::AvoidHost(addr, cooldown) => {
+                    info!("Avoiding {:?} for {:?}", addr, cooldown);
+                    self.hosts.avoid(addr, Instant::now() + cooldown);
+                    if self.host == addr && self.state != ZkState::Closed {
+                        info!("Moving off {:?}", addr);
+                        self.reconnect();
+                    }
+                }
+                IoControl::Detach => {
+                    info!("Detaching from session {}", self.conn_resp.session_id);
+                    // Dropping the requests fails their operations with ConnectionLoss
+                    self.buffer.clear();
+                    self.inflight.clear();
+                    let old_state = self.state;
+                    self.state = ZkState::Closed;
+                    self.notify_state(old_state, self.state);
+                    self.shutdown = true;
+                    return;
+                }
+                #[cfg(test)]
+                IoControl::Panic => panic!("injected panic in the IO thread"),
+            }
+        }
+
+        self.poll.reregister(&self.control_rx, CONTROL, Ready::readable(), pollopt())
+            .expect("Reregister CONTROL");
+    }
+
     fn ready_timer(&mut self, _: Ready) {
         trace!("ready_timer thread={:?}", ::std::thread::current().id());
 
@@ -510,12 +1049,15 @@ impl ZkIo {
                     self.clear_timeout(ZkTimeout::Ping);
                     if self.inflight.is_empty() {
                         // No inflight request indicates an idle connection. Send a ping.
-                        trace!("Pinging {:?}", self.sock.peer_addr().unwrap());
+                        trace!("Pinging {:?}", self.host);
                         self.tx.send(RawRequest {
                             opcode: OpCode::Ping,
                             data: PING.clone(),
                             listener: None,
+                            completion: None,
                             watch: None,
+                            cancel: None,
+                            queue_slot: None,
                         }).unwrap();
                         self.ping_sent = Instant::now();
                     }
@@ -528,8 +1070,16 @@ impl ZkIo {
                         self.reconnect();
                     }
                 },
+                Some(ZkTimeout::Reconnect) => {
+                    trace!("handle reconnect timeout");
+                    self.clear_timeout(ZkTimeout::Reconnect);
+                    if self.state == ZkState::Connecting && !self.connect() {
+                        self.connect_next();
+                    }
+                },
                 None => {
-                    if self.ping_timeout.is_some() || self.conn_timeout.is_some() {
+                    if self.ping_timeout.is_some() || self.conn_timeout.is_some()
+                       || self.reconnect_timeout.is_some() {
                         trace!("Spurious timer");
                         self.poll.reregister(&self.timer, TIMER, Ready::readable(), pollopt())
                             .expect("Reregister TIMER");
@@ -544,17 +1094,55 @@ impl ZkIo {
         self.tx.clone()
     }
 
+    pub fn control_sender(&self) -> Sender<IoControl> {
+        self.control_tx.clone()
+    }
+
     pub fn run(mut self) -> io::Result<()> {
         let mut events = Events::with_capacity(128);
 
         // Register Initial Interest
-        self.poll.register(&self.sock, ZK, Ready::all(), pollopt())
+        self.poll.register(&*self.sock, ZK, Ready::all(), pollopt())
             .expect("Register ZK");
         self.poll.register(&self.timer, TIMER, Ready::readable(), pollopt())
             .expect("Register TIMER");
         self.poll.register(&self.rx, CHANNEL, Ready::readable(), pollopt())
             .expect("Register CHANNEL");
+        self.poll.register(&self.control_rx, CONTROL, Ready::readable(), pollopt())
+            .expect("Register CONTROL");
 
+        loop {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| self.poll_events(&mut events)));
+            match (result, self.failure_policy) {
+                (Ok(result), _) => return result,
+                (Err(_), IoFailurePolicy::Restart) => {
+                    error!("IO thread panicked, reconnecting");
+                    self.state = ZkState::NotConnected;
+                    self.reconnect();
+                    // The panic may have struck before any of the handles were registered again
+                    self.poll.reregister(&self.rx, CHANNEL, Ready::readable(), pollopt())?;
+                    self.poll.reregister(&self.control_rx, CONTROL, Ready::readable(), pollopt())?;
+                }
+                (Err(_), IoFailurePolicy::Close) => {
+                    error!("IO thread panicked, closing");
+                    // The request would follow a partially written one, which garbles both
+                    let partial = self.buffer.front().is_some_and(|r| r.data.position() > 0);
+                    if self.connected && !partial {
+                        self.write_close_session();
+                    }
+                    // Dropping the requests fails their operations with ConnectionLoss
+                    self.buffer.clear();
+                    self.inflight.clear();
+                    let old_state = self.state;
+                    self.state = ZkState::Closed;
+                    self.notify_state(old_state, self.state);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn poll_events(&mut self, events: &mut Events) -> io::Result<()> {
         loop {
             // Handle loop shutdown
             if self.shutdown {
@@ -562,10 +1150,10 @@ impl ZkIo {
             }
 
             // Wait for events
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(events, None)?;
 
             // Process events
-            for event in &events {
+            for event in &*events {
                 self.ready(event.token(), event.readiness());
             }
         }
@@ -573,3 +1161,754 @@ impl ZkIo {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionState, IoControl, IoFailurePolicy, ReconnectEvent, ReconnectOutcome,
+                ReconnectPolicy};
+    use acl::Acl;
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use consts::{KeeperState, ZkError, ZkState};
+    use proto::{BufferReader, CreateRequest, CreateResponse, DeleteRequest, EmptyResponse, OpCode};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use testing::{connected, memory_pipe, memory_pipes, reply_data, stat, RefusingConnector};
+    use watch::WatchedEvent;
+    use zookeeper::{ZooKeeper, ZooKeeperBuilder};
+
+    #[test]
+    fn exponential_backoff_delays() {
+        let ms = Duration::from_millis;
+        let policy = ReconnectPolicy::ExponentialBackoff {
+            base: ms(100),
+            max: ms(1000),
+            jitter: false,
+        };
+        let delays: Vec<_> = (1..7).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(delays, vec![ms(0), ms(100), ms(200), ms(400), ms(800), ms(1000)]);
+        assert_eq!(policy.delay(1000), ms(1000));
+        assert_eq!(ReconnectPolicy::Immediate.delay(5), ms(0));
+
+        let policy = ReconnectPolicy::ExponentialBackoff {
+            base: ms(100),
+            max: ms(1000),
+            jitter: true,
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(3);
+            assert!(ms(100) <= delay && delay <= ms(200), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn concurrent_requests_over_memory_pipe() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ZooKeeper>();
+
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+
+        // answer each get_data with the requested path, in the order of the requests
+        let server = thread::spawn(move || {
+            let mut last_xid = 0;
+            for _ in 0..100 {
+                let mut request = server.read_frame();
+                let xid = request.read_i32::<BigEndian>().unwrap();
+                assert!(xid > last_xid, "xid {} written after {}", xid, last_xid);
+                last_xid = xid;
+                assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+                let len = request.read_i32::<BigEndian>().unwrap() as usize;
+                let path = request.get_ref()[12..12 + len].to_vec();
+
+                let mut frame = Vec::new();
+                frame.write_i32::<BigEndian>(xid).unwrap();
+                frame.write_i64::<BigEndian>(10).unwrap();
+                frame.write_i32::<BigEndian>(0).unwrap();
+                frame.write_i32::<BigEndian>(path.len() as i32).unwrap();
+                frame.extend(path);
+                frame.extend(&[0; 68]); // Stat
+                server.write_frame(&frame);
+            }
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 11, 0);
+        });
+
+        let clients: Vec<_> = (0..100)
+                                  .map(|i| {
+                                      let zk = zk.clone();
+                                      thread::spawn(move || {
+                                          let path = format!("/node{}", i);
+                                          let (data, _) = zk.get_data(&path, false).unwrap();
+                                          assert_eq!(data, path.into_bytes());
+                                      })
+                                  })
+                                  .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pipelined_requests_keep_submission_order() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+
+        // a server which applies create and delete requests to a set of nodes in wire order
+        let server = thread::spawn(move || {
+            let mut nodes = Vec::new();
+            let mut last_xid = 0;
+            loop {
+                let mut request = server.read_frame();
+                let xid = request.read_i32::<BigEndian>().unwrap();
+                let opcode = request.read_i32::<BigEndian>().unwrap();
+                if opcode == -11 {
+                    server.reply(xid, 0, 0);
+                    return nodes;
+                }
+                assert!(xid > last_xid, "xid {} written after {}", xid, last_xid);
+                last_xid = xid;
+                let len = request.read_i32::<BigEndian>().unwrap() as usize;
+                let path = String::from_utf8(request.get_ref()[12..12 + len].to_vec()).unwrap();
+                let mut frame = Vec::new();
+                frame.write_i32::<BigEndian>(xid).unwrap();
+                frame.write_i64::<BigEndian>(xid as i64).unwrap();
+                match (opcode, nodes.contains(&path)) {
+                    (1, false) => {
+                        frame.write_i32::<BigEndian>(0).unwrap();
+                        frame.write_i32::<BigEndian>(len as i32).unwrap();
+                        frame.extend(path.as_bytes());
+                        nodes.push(path);
+                    }
+                    (1, true) => frame.write_i32::<BigEndian>(ZkError::NodeExists as i32).unwrap(),
+                    (2, true) => {
+                        frame.write_i32::<BigEndian>(0).unwrap();
+                        nodes.retain(|node| *node != path);
+                    }
+                    (2, false) => frame.write_i32::<BigEndian>(ZkError::NoNode as i32).unwrap(),
+                    _ => panic!("unexpected opcode {}", opcode),
+                }
+                server.write_frame(&frame);
+            }
+        });
+
+        // submit all requests before collecting any response
+        let mut pending = Vec::new();
+        for i in 0..10 {
+            let path = format!("/node{}", i);
+            let create = CreateRequest {
+                path: path.clone(),
+                data: vec![],
+                acl: Acl::open_unsafe().clone(),
+                flags: 0,
+            };
+            let delete = DeleteRequest { path, version: -1 };
+            pending.push((zk.submit(OpCode::Create, create, None).unwrap(),
+                          zk.submit(OpCode::Delete, delete, None).unwrap()));
+        }
+        for (i, (create, delete)) in pending.into_iter().enumerate() {
+            let created = ZooKeeper::receive::<CreateResponse>(create).unwrap();
+            assert_eq!(created.path, format!("/node{}", i));
+            ZooKeeper::receive::<EmptyResponse>(delete).unwrap();
+        }
+
+        drop(zk);
+        // every delete was applied after its create
+        assert!(server.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn io_thread_panic_closes_handle() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+
+        zk.io_control(IoControl::Panic);
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Closed));
+        // the session is closed like by `close`, so the server doesn't keep it until it expires
+        let mut request = server.read_frame();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+        // operations fail right away instead of waiting for a response forever
+        assert_eq!(zk.get_data("/node", false), Err(ZkError::ConnectionLoss));
+        assert_eq!(zk.close(), Err(ZkError::ConnectionLoss));
+    }
+
+    #[test]
+    fn io_thread_panic_restarts_io_thread() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .io_failure_policy(IoFailurePolicy::Restart)
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        zk.io_control(IoControl::Panic);
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+
+        // the session is re-established with the next server
+        let second = servers.remove(0);
+        let mut connect = second.accept();
+        connect.set_position(16);
+        assert_eq!(connect.read_i64::<BigEndian>().unwrap(), 0x1234); // session id
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 3); // exists
+            second.reply(xid, 1, ZkError::NoNode as i32);
+
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 2, 0);
+        });
+        assert_eq!(zk.exists("/node", false), Ok(None));
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn malformed_replies_only_fail_their_request() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let server = thread::spawn(move || {
+            // a valid ReplyHeader followed by garbage
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(10).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            frame.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef, 0x00]);
+            server.write_frame(&frame);
+
+            // a frame too short for a ReplyHeader
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_u16::<BigEndian>(0xffff).unwrap();
+            server.write_frame(&frame);
+
+            reply_data(&server, 11, 0, b"data");
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 12, 0);
+        });
+
+        assert_eq!(zk.get_data("/garbage", false), Err(ZkError::MarshallingError));
+        assert_eq!(zk.get_data("/truncated", false), Err(ZkError::MarshallingError));
+        // the connection stays in sync for the following requests
+        assert_eq!(zk.get_data("/node", false), Ok((b"data".to_vec(), stat(5, 11))));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connection_state_tells_reconnects_apart() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        assert_eq!(zk.connection_state(), ConnectionState::Connecting);
+
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+        assert_eq!(zk.connection_state(), ConnectionState::Connected);
+
+        // the server goes down, and the next one does not answer yet
+        let second = servers.remove(0);
+        first.close();
+        loop {
+            if state_rx.recv_timeout(Duration::from_secs(5)).unwrap() == ZkState::Connecting {
+                break;
+            }
+        }
+        assert_eq!(zk.connection_state(), ConnectionState::Reconnecting);
+
+        second.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+        assert_eq!(zk.connection_state(), ConnectionState::Connected);
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 1, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn disconnected_is_delivered_once_per_connection_loss() {
+        let (connector, mut servers) = memory_pipes(3);
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx = Mutex::new(event_tx);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .watcher(move |event: WatchedEvent| {
+                         event_tx.lock().unwrap().send(event.keeper_state).unwrap();
+                     })
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        // the next server fails the connection attempt, the one after it lets the client in
+        let second = servers.remove(0);
+        let third = servers.remove(0);
+        let started = Instant::now();
+        first.close();
+        assert_eq!(event_rx.recv_timeout(Duration::from_secs(5)), Ok(KeeperState::Disconnected));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        second.read_frame();
+        second.close();
+        third.accept();
+        assert_eq!(event_rx.recv_timeout(Duration::from_secs(5)), Ok(KeeperState::SyncConnected));
+
+        let server = thread::spawn(move || {
+            let mut request = third.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            third.reply(xid, 1, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn session_uptime_resets_with_new_session() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        assert_eq!(zk.session_uptime(), None);
+
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept_session(0x1234);
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let established_at = zk.session_established_at().unwrap();
+        let uptime = zk.session_uptime().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(zk.session_uptime().unwrap() >= uptime + Duration::from_millis(50));
+
+        // the connection is lost, and the next server hands out a new session
+        let second = servers.remove(0);
+        first.close();
+        let mut connect = second.accept_session(0x5678);
+        connect.set_position(16);
+        assert_eq!(connect.read_i64::<BigEndian>().unwrap(), 0x1234); // session id
+        loop {
+            let state = state_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            if state == ZkState::Connected {
+                break;
+            }
+        }
+        assert!(zk.session_established_at().unwrap() > established_at);
+        assert!(zk.session_uptime().unwrap() < Duration::from_millis(50));
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 1, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn current_epoch_follows_leader_elections() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let zk = Arc::new(zk);
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+        assert_eq!(zk.current_epoch(), 0);
+
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.exists("/node", false));
+        let mut request = first.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        first.reply(xid, 1 << 32 | 7, -101);
+        assert_eq!(client.join().unwrap(), Ok(None));
+        assert_eq!(zk.current_epoch(), 1);
+
+        // the client reconnects to a server which has elected a new leader in the meantime
+        let second = servers.remove(0);
+        first.close();
+        let mut connect = second.accept();
+        connect.set_position(4);
+        assert_eq!(connect.read_i64::<BigEndian>().unwrap(), 1 << 32 | 7); // last zxid seen
+        loop {
+            if state_rx.recv_timeout(Duration::from_secs(5)).unwrap() == ZkState::Connected {
+                break;
+            }
+        }
+
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.exists("/node", false));
+        let mut request = second.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        second.reply(xid, 2 << 32 | 1, -101);
+        assert_eq!(client.join().unwrap(), Ok(None));
+        assert_eq!(zk.current_epoch(), 2);
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 2 << 32 | 2, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn resume_session_and_expire() {
+        let (connector, mut servers) = memory_pipes(2);
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx = Mutex::new(event_tx);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .session(0x77, vec![7; 16])
+                     .watcher(move |event: WatchedEvent| {
+                         event_tx.lock().unwrap().send(event.keeper_state).unwrap();
+                     })
+                     .connect()
+                     .unwrap();
+
+        // the server has forgotten the session
+        let first = servers.remove(0);
+        let mut connect = first.read_frame();
+        connect.set_position(16);
+        assert_eq!(connect.read_i64::<BigEndian>().unwrap(), 0x77);
+        assert_eq!(connect.read_buffer().unwrap(), vec![7; 16]);
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap(); // timeout
+        frame.write_i64::<BigEndian>(0).unwrap();
+        frame.write_i32::<BigEndian>(16).unwrap();
+        frame.extend(&[0; 16]);
+        frame.write_u8(0).unwrap();
+        first.write_frame(&frame);
+        assert_eq!(event_rx.recv_timeout(Duration::from_secs(5)), Ok(KeeperState::Expired));
+        first.close();
+
+        // so the client creates a new one
+        let second = servers.remove(0);
+        let mut connect = second.accept_session(0x99);
+        connect.set_position(16);
+        assert_eq!(connect.read_i64::<BigEndian>().unwrap(), 0);
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        while zk.connection_state() != ConnectionState::Connected {
+            state_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(zk.session_id(), 0x99);
+        assert_eq!(zk.session_password(), vec![0; 16]);
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 1, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pending_requests_fail_when_the_session_expired() {
+        let (connector, mut servers) = memory_pipes(2);
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx = Mutex::new(event_tx);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .session(0x77, vec![7; 16])
+                     .watcher(move |event: WatchedEvent| {
+                         event_tx.lock().unwrap().send(event.keeper_state).unwrap();
+                     })
+                     .connect()
+                     .unwrap();
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.get_data("/node", false));
+
+        // the request follows the connect request, but the server has forgotten the session
+        let first = servers.remove(0);
+        first.read_frame();
+        let mut request = first.read_frame();
+        request.set_position(4);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap(); // timeout
+        frame.write_i64::<BigEndian>(0).unwrap();
+        frame.write_i32::<BigEndian>(16).unwrap();
+        frame.extend(&[0; 16]);
+        frame.write_u8(0).unwrap();
+        first.write_frame(&frame);
+        assert_eq!(event_rx.recv_timeout(Duration::from_secs(5)), Ok(KeeperState::Expired));
+        assert_eq!(client.join().unwrap(), Err(ZkError::SessionExpired));
+        first.close();
+
+        let second = servers.remove(0);
+        second.accept_session(0x99);
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 1, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pending_requests_fail_when_the_session_timed_out() {
+        let (connector, mut servers) = memory_pipes(1);
+        // the client connects, then fails to reconnect until the session timed out
+        let connector = RefusingConnector {
+            inner: connector,
+            attempts: Mutex::new(0),
+            refused: 1..usize::MAX,
+        };
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .reconnect_policy(ReconnectPolicy::ExponentialBackoff {
+                         base: Duration::from_secs(20),
+                         max: Duration::from_secs(20),
+                         jitter: false,
+                     })
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let server = servers.remove(0);
+        server.read_frame();
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        frame.write_i32::<BigEndian>(1000).unwrap(); // timeout
+        frame.write_i64::<BigEndian>(0x1234).unwrap();
+        frame.write_i32::<BigEndian>(16).unwrap();
+        frame.extend(&[0; 16]);
+        frame.write_u8(0).unwrap();
+        server.write_frame(&frame);
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        server.close();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+        // the request waits for the next attempt, which the session does not live to see
+        assert_eq!(zk.get_data("/node", false), Err(ZkError::SessionExpired));
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Closed));
+    }
+
+    #[test]
+    fn reconnect_backs_off_exponentially() {
+        let (connector, mut servers) = memory_pipes(2);
+        // the client connects, then fails four times to reconnect
+        let connector = RefusingConnector {
+            inner: connector,
+            attempts: Mutex::new(0),
+            refused: 1..5,
+        };
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .reconnect_policy(ReconnectPolicy::ExponentialBackoff {
+                         base: Duration::from_millis(20),
+                         max: Duration::from_secs(1),
+                         jitter: false,
+                     })
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let (started_tx, started_rx) = mpsc::channel();
+        zk.add_reconnect_listener(move |event: ReconnectEvent| {
+            if event.outcome == ReconnectOutcome::Started {
+                started_tx.send((event.attempt, Instant::now())).unwrap();
+            }
+        });
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let second = servers.remove(0);
+        first.close();
+        let started: Vec<_> = (0..5)
+                                  .map(|_| started_rx.recv_timeout(Duration::from_secs(5)).unwrap())
+                                  .collect();
+        assert_eq!(started.iter().map(|&(attempt, _)| attempt).collect::<Vec<_>>(),
+                   vec![1, 2, 3, 4, 5]);
+        // 20ms, 40ms, 80ms and 160ms
+        for (i, pair) in started.windows(2).enumerate() {
+            let interval = pair[1].1 - pair[0].1;
+            assert!(interval >= Duration::from_millis(20 << i), "{:?} at {}", interval, i);
+        }
+        second.accept();
+        loop {
+            if state_rx.recv_timeout(Duration::from_secs(5)).unwrap() == ZkState::Connected {
+                break;
+            }
+        }
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 1, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn io_thread_stays_responsive_while_backing_off() {
+        let (connector, mut servers) = memory_pipes(1);
+        // the client connects, then fails to reconnect right away
+        let connector = RefusingConnector {
+            inner: connector,
+            attempts: Mutex::new(0),
+            refused: 1..2,
+        };
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .reconnect_policy(ReconnectPolicy::ExponentialBackoff {
+                         base: Duration::from_secs(20),
+                         max: Duration::from_secs(20),
+                         jitter: false,
+                     })
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let (failed_tx, failed_rx) = mpsc::channel();
+        zk.add_reconnect_listener(move |event: ReconnectEvent| {
+            if event.outcome == ReconnectOutcome::Failed {
+                failed_tx.send(event.attempt).unwrap();
+            }
+        });
+        let server = servers.remove(0);
+        server.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        server.close();
+        assert_eq!(failed_rx.recv_timeout(Duration::from_secs(5)), Ok(1));
+        // the IO thread now waits 20s before the next attempt, but still handles instructions
+        zk.detach();
+        loop {
+            let state = state_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            if state == ZkState::Closed {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn reject_changed_session_timeout() {
+        let (connector, server) = memory_pipe();
+        let server = thread::spawn(move || {
+            // the server clamps the timeout to 6s
+            server.accept();
+            let mut request = server.read_frame();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0);
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+        });
+        let result = ZooKeeperBuilder::new()
+                         .connect_string("127.0.0.1:2181")
+                         .connector(connector)
+                         .session_timeout(Duration::from_secs(10))
+                         .require_exact_timeout(true)
+                         .connect();
+        assert_eq!(result.err(), Some(ZkError::BadArguments));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reject_old_protocol_version() {
+        let (connector, server) = memory_pipe();
+        let server = thread::spawn(move || {
+            server.accept_with(0, 0x1234);
+            let mut request = server.read_frame();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0);
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+        });
+        let result = ZooKeeperBuilder::new()
+                         .connect_string("127.0.0.1:2181")
+                         .connector(connector)
+                         .min_protocol_version(1)
+                         .connect();
+        assert_eq!(result.err(), Some(ZkError::BadArguments));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn accept_new_protocol_version() {
+        let (connector, server) = memory_pipe();
+        let server = thread::spawn(move || {
+            server.accept_with(1, 0x1234);
+            server
+        });
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .min_protocol_version(1)
+                     .connect()
+                     .unwrap();
+        assert_eq!(zk.protocol_version(), Some(1));
+
+        let server = server.join().unwrap();
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 1, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+}
@@ -2,34 +2,72 @@
 #![deny(unused_mut)]
 extern crate byteorder;
 extern crate bytes;
+#[cfg(feature = "chrono")]
+extern crate chrono;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
 extern crate mio;
 extern crate mio_extras;
+#[cfg(feature = "serde_json")]
+#[macro_use]
+extern crate serde_json;
 extern crate snowflake;
 #[macro_use]
 extern crate zookeeper_derive;
 
 pub use acl::*;
+pub use cancel::CancellationToken;
+pub use connector::{Connector, Stream, TcpConnector};
 pub use consts::*;
 pub use data::*;
-pub use zookeeper::{ZkResult, ZooKeeper};
+pub use future::ZkFuture;
+pub use hosts::{HostStat, Resolver, SystemResolver};
+pub use io::{ConnectionState, IoFailurePolicy, ReconnectEvent, ReconnectOutcome, ReconnectPolicy};
+pub use metrics::Metrics;
+pub use multi::{Op, OpResult};
+pub use offline_queue::QueueOverflowPolicy;
+pub use paths::{sequence_number, sort_by_sequence};
+pub use proto::OpCode;
+pub use quorum::{QuorumConfig, QuorumRole, ServerEntry};
+pub use server_role::ServerRole;
+pub use transaction::Transaction;
+pub use view::ConsistentView;
+pub use zookeeper::{CreateOutcome, StringDecoding, ZkErrorWithContext, ZkResult, ZooKeeper,
+                    ZooKeeperBuilder};
 pub use zookeeper_ext::ZooKeeperExt;
-pub use watch::{Watch, WatchedEvent, Watcher, WatchType};
+pub use watch::{BoxedWatcher, NoopWatcher, RegisteredWatches, Watch, WatchContext, WatchedEvent,
+                Watcher, WatchType};
 
 pub use listeners::Subscription;
 
 mod acl;
+mod backpressure;
+mod cancel;
+mod connector;
 mod consts;
 mod data;
+mod future;
+mod hosts;
 mod io;
 mod listeners;
+mod metrics;
+mod multi;
+mod offline_queue;
 mod paths;
 mod proto;
+mod quorum;
+mod rate_limit;
+mod server_role;
+mod transaction;
 mod watch;
 mod zookeeper;
 mod zookeeper_ext;
 mod try_io;
+mod view;
+#[cfg(test)]
+mod testing;
+pub mod admin;
 pub mod recipes;
+pub mod record;
@@ -46,7 +46,8 @@ impl<T> ListenerSet<T> where T: Send + Clone
     }
 
     pub fn notify(&self, payload: &T) {
-        let listeners = self.listeners.lock().unwrap();
+        // A listener which panicked must not stop the others from being notified
+        let listeners = self.listeners.lock().unwrap_or_else(|e| e.into_inner());
 
         for listener in listeners.values() {
             listener(payload.clone())
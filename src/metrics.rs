@@ -0,0 +1,24 @@
+use consts::ZkError;
+use proto::OpCode;
+use std::time::Duration;
+
+/// Receives measurements of the requests sent by a `ZooKeeper` handle, like to export them to a
+/// monitoring system or to attribute the traffic to the operations causing it. See
+/// `ZooKeeperBuilder::metrics`.
+///
+/// Requests which are pipelined internally (like the per-child requests of
+/// `ZooKeeper::get_children_with_stats`) are not reported.
+pub trait Metrics: Send + Sync {
+    /// Called once the response to a request arrived, or the request failed without one.
+    ///
+    /// `latency` is the time from submitting the request to receiving its response, which for
+    /// the `*_async` operations is when their future resolved. `bytes_out` and `bytes_in` are the
+    /// sizes of the request and the response on the wire, including their length prefix;
+    /// `bytes_in` is 0 if no response was received. `err` is the error of the operation, if any.
+    fn on_response(&self,
+                   opcode: OpCode,
+                   latency: Duration,
+                   bytes_out: usize,
+                   bytes_in: usize,
+                   err: Option<ZkError>);
+}
@@ -0,0 +1,104 @@
+use acl::Acl;
+use consts::CreateMode;
+use data::Stat;
+
+/// A single operation of a transaction. See `ZooKeeper::commit` for more information, and
+/// `ZooKeeper::commit_read` for the read operations.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    /// Create a node, like `ZooKeeper::create`.
+    Create {
+        /// The path of the node to create.
+        path: String,
+        /// The initial data of the node.
+        data: Vec<u8>,
+        /// The ACL of the node.
+        acl: Vec<Acl>,
+        /// The behavior of the created node.
+        mode: CreateMode,
+    },
+    /// Delete a node, like `ZooKeeper::delete`.
+    Delete {
+        /// The path of the node to delete.
+        path: String,
+        /// The expected version of the node, `None` matches any version.
+        version: Option<i32>,
+    },
+    /// Set the data of a node, like `ZooKeeper::set_data`.
+    SetData {
+        /// The path of the node to update.
+        path: String,
+        /// The new data of the node.
+        data: Vec<u8>,
+        /// The expected version of the node, `None` matches any version.
+        version: Option<i32>,
+    },
+    /// Check that a node exists and (optionally) has the given version, without changing it.
+    Check {
+        /// The path of the node to check.
+        path: String,
+        /// The expected version of the node, `None` matches any version.
+        version: Option<i32>,
+    },
+    /// Read the data of a node, like `ZooKeeper::get_data`. Only valid in a read transaction.
+    GetData {
+        /// The path of the node to read.
+        path: String,
+    },
+    /// List the children of a node, like `ZooKeeper::get_children`. Only valid in a read
+    /// transaction.
+    GetChildren {
+        /// The path of the node whose children to list.
+        path: String,
+    },
+}
+
+impl Op {
+    /// The path of the node this operation acts on.
+    pub fn path(&self) -> &str {
+        match *self {
+            Op::Create { ref path, .. } |
+            Op::Delete { ref path, .. } |
+            Op::SetData { ref path, .. } |
+            Op::Check { ref path, .. } |
+            Op::GetData { ref path } |
+            Op::GetChildren { ref path } => path,
+        }
+    }
+
+    /// Whether this operation only reads, so it belongs into a read transaction
+    /// (`ZooKeeper::commit_read`) rather than into a transaction of writes (`ZooKeeper::commit`).
+    pub fn is_read(&self) -> bool {
+        matches!(*self, Op::GetData { .. } | Op::GetChildren { .. })
+    }
+}
+
+/// The result of a single successful operation of a transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpResult {
+    /// Result of `Op::Create`, containing the actual path of the created node.
+    Create {
+        /// The actual path of the created node (differs from the requested one for sequential
+        /// nodes).
+        path: String,
+    },
+    /// Result of `Op::SetData`, containing the new `Stat` of the node.
+    SetData {
+        /// The `Stat` of the node after the update.
+        stat: Stat,
+    },
+    /// Result of `Op::Delete` and `Op::Check`, which do not return anything.
+    Empty,
+    /// Result of `Op::GetData`, containing the data and the `Stat` of the node.
+    GetData {
+        /// The data of the node.
+        data: Vec<u8>,
+        /// The `Stat` of the node.
+        stat: Stat,
+    },
+    /// Result of `Op::GetChildren`, containing the names of the children of the node.
+    GetChildren {
+        /// The names of the children, in no particular order.
+        children: Vec<String>,
+    },
+}
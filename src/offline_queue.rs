@@ -0,0 +1,217 @@
+use consts::ZkError;
+use proto::{ByteBuf, ReplyHeader};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::SyncSender;
+use std::time::Duration;
+use zookeeper::{RawResponse, ZkResult};
+
+/// How often a thread blocked on a full queue checks whether the client is connected again.
+const BLOCK_RECHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What happens to a request submitted while the client is not connected and the queue of such
+/// requests is full, see `ZooKeeperBuilder::offline_queue_limit`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum QueueOverflowPolicy {
+    /// Block the submitting thread until a queued request has been sent or failed, or the client
+    /// is connected again. The `*_async` operations of `ZooKeeper` don't block, but fail like
+    /// with `RejectNewest`.
+    #[default]
+    Block,
+    /// Fail the submitted request with `ZkError::ConnectionLoss` without queueing it.
+    RejectNewest,
+    /// Fail the oldest queued request with `ZkError::ConnectionLoss` to make room for the
+    /// submitted one. The failed request is not sent.
+    RejectOldest,
+}
+
+struct Queued {
+    id: u64,
+    xid: i32,
+    listener: SyncSender<RawResponse>,
+}
+
+struct Queue {
+    next_id: u64,
+    requests: VecDeque<Queued>,
+}
+
+/// Bounds the requests submitted while the client is not connected, which pile up until it
+/// reconnects. Shared between the `ZooKeeper` handle, which reserves a `QueueSlot` per request,
+/// and the IO thread, which claims the slot of a request before writing it.
+pub struct OfflineQueue {
+    limit: usize,
+    policy: QueueOverflowPolicy,
+    queue: Mutex<Queue>,
+    room: Condvar,
+}
+
+impl OfflineQueue {
+    pub fn new(limit: usize, policy: QueueOverflowPolicy) -> OfflineQueue {
+        OfflineQueue {
+            limit,
+            policy,
+            queue: Mutex::new(Queue {
+                next_id: 0,
+                requests: VecDeque::new(),
+            }),
+            room: Condvar::new(),
+        }
+    }
+
+    /// Reserve a slot for the request with the given response channel, applying the overflow
+    /// policy if the queue is full. Return `None` if `offline` turns `false` while blocked, in
+    /// which case the request needs no slot. The xid of the request is set with `QueueSlot::assign`
+    /// once it is known.
+    pub fn reserve<F>(queue: &Arc<OfflineQueue>,
+                      listener: &SyncSender<RawResponse>,
+                      offline: F)
+                      -> ZkResult<Option<QueueSlot>>
+        where F: Fn() -> bool
+    {
+        Self::reserve_with(queue, queue.policy, listener, offline)
+    }
+
+    /// Reserve a slot like `reserve`, but fail with `ZkError::ConnectionLoss` instead of blocking
+    /// if the queue is full and the policy is `QueueOverflowPolicy::Block`.
+    pub fn try_reserve(queue: &Arc<OfflineQueue>,
+                       listener: &SyncSender<RawResponse>)
+                       -> ZkResult<Option<QueueSlot>> {
+        let policy = match queue.policy {
+            QueueOverflowPolicy::Block => QueueOverflowPolicy::RejectNewest,
+            policy => policy,
+        };
+        Self::reserve_with(queue, policy, listener, || true)
+    }
+
+    fn reserve_with<F>(queue: &Arc<OfflineQueue>,
+                       policy: QueueOverflowPolicy,
+                       listener: &SyncSender<RawResponse>,
+                       offline: F)
+                       -> ZkResult<Option<QueueSlot>>
+        where F: Fn() -> bool
+    {
+        let mut guard = queue.queue.lock().unwrap_or_else(|e| e.into_inner());
+        while guard.requests.len() >= queue.limit.max(1) {
+            match policy {
+                QueueOverflowPolicy::Block => {
+                    if !offline() {
+                        return Ok(None);
+                    }
+                    guard = queue.room
+                                 .wait_timeout(guard, BLOCK_RECHECK_INTERVAL)
+                                 .unwrap_or_else(|e| e.into_inner())
+                                 .0;
+                }
+                QueueOverflowPolicy::RejectNewest => {
+                    debug!("offline queue is full, rejecting the request");
+                    return Err(ZkError::ConnectionLoss);
+                }
+                QueueOverflowPolicy::RejectOldest => {
+                    let oldest = guard.requests.pop_front().unwrap();
+                    debug!("offline queue is full, rejecting request xid={}", oldest.xid);
+                    // A full channel already holds the response, so the caller returns anyway
+                    let _ = oldest.listener.try_send(RawResponse {
+                        header: ReplyHeader {
+                            xid: oldest.xid,
+                            zxid: 0,
+                            err: ZkError::ConnectionLoss as i32,
+                        },
+                        data: ByteBuf::new(vec![]),
+                        len: 0,
+                    });
+                }
+            }
+        }
+        let id = guard.next_id;
+        guard.next_id += 1;
+        guard.requests.push_back(Queued {
+            id,
+            xid: 0,
+            listener: listener.clone(),
+        });
+        Ok(Some(QueueSlot {
+            queue: queue.clone(),
+            id,
+        }))
+    }
+
+    /// Set the xid of the request in the slot, unless it was rejected already.
+    fn assign(&self, id: u64, xid: i32) {
+        let mut guard = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(queued) = guard.requests.iter_mut().find(|queued| queued.id == id) {
+            queued.xid = xid;
+        }
+    }
+
+    /// Remove the slot, returning whether it was still queued.
+    fn remove(&self, id: u64) -> bool {
+        let mut guard = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let queued = match guard.requests.iter().position(|queued| queued.id == id) {
+            Some(index) => guard.requests.remove(index).is_some(),
+            None => false,
+        };
+        self.room.notify_all();
+        queued
+    }
+}
+
+/// The place of a request in the `OfflineQueue`, which is freed once the request is written or
+/// dropped.
+pub struct QueueSlot {
+    queue: Arc<OfflineQueue>,
+    id: u64,
+}
+
+impl QueueSlot {
+    /// Set the xid of the request, which fails with it if it is rejected.
+    pub fn assign(&self, xid: i32) {
+        self.queue.assign(self.id, xid);
+    }
+
+    /// Take the request out of the queue before writing it. Return `false` if it was rejected to
+    /// make room for a newer one, in which case it must not be written.
+    pub fn claim(self) -> bool {
+        self.queue.remove(self.id)
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.queue.remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn reject_oldest_fails_the_oldest_request() {
+        let queue = Arc::new(OfflineQueue::new(2, QueueOverflowPolicy::RejectOldest));
+        let (tx1, rx1) = sync_channel(1);
+        let (tx2, rx2) = sync_channel(1);
+        let (tx3, rx3) = sync_channel(1);
+        let slot1 = OfflineQueue::reserve(&queue, &tx1, || true).unwrap().unwrap();
+        slot1.assign(1);
+        let slot2 = OfflineQueue::reserve(&queue, &tx2, || true).unwrap().unwrap();
+        slot2.assign(2);
+        let slot3 = OfflineQueue::reserve(&queue, &tx3, || true).unwrap().unwrap();
+        slot3.assign(3);
+
+        let response = rx1.try_recv().unwrap();
+        assert_eq!(response.header.xid, 1);
+        assert_eq!(response.header.err, ZkError::ConnectionLoss as i32);
+        assert!(rx2.try_recv().is_err());
+        assert!(rx3.try_recv().is_err());
+
+        assert!(!slot1.claim());
+        assert!(slot2.claim());
+        drop(slot3);
+        // both places are free again
+        let _slot4 = OfflineQueue::reserve(&queue, &tx1, || true).unwrap().unwrap();
+        let _slot5 = OfflineQueue::reserve(&queue, &tx2, || true).unwrap().unwrap();
+        assert!(rx1.try_recv().is_err());
+    }
+}
@@ -7,9 +7,301 @@ pub fn make_path(parent: &str, child: &str) -> String {
     }
 }
 
+/// Split a path into its parent and the node name.
+pub fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Make a path received from the server relative to the chroot, which itself becomes "/". Paths
+/// outside the chroot, like the configuration node, are kept.
+pub fn strip_chroot(chroot: &str, path: &str) -> String {
+    within_chroot(chroot, path).unwrap_or_else(|| path.to_owned())
+}
+
+/// Like `strip_chroot`, but `None` for paths outside the chroot.
+pub fn within_chroot(chroot: &str, path: &str) -> Option<String> {
+    match path.strip_prefix(chroot) {
+        Some("") => Some("/".to_owned()),
+        Some(relative) if relative.starts_with('/') => Some(relative.to_owned()),
+        _ => None,
+    }
+}
+
+/// The prefix of node names created by `ZooKeeper::create_protected` with the given id.
+pub fn protected_prefix(id: &str) -> String {
+    format!("_c_{}-", id)
+}
+
+/// Find the node created by `ZooKeeper::create_protected` with the given id among `children`.
+pub fn find_protected<'a>(children: &'a [String], id: &str) -> Option<&'a str> {
+    let prefix = protected_prefix(id);
+    children.iter().map(|child| child.as_str()).find(|child| child.starts_with(&prefix))
+}
+
+/// Return the sequence number of a node created with a sequential `CreateMode`, which is the
+/// suffix the server appends to its name: the counter of the parent, formatted with `%010d`.
+///
+/// The counter is a signed 32 bit integer, which wraps around to negative numbers after
+/// 2147483647, like `-2147483648` and `-000000001`. Counters of 10 digits after the wrap-around
+/// look like a `-` separator followed by a positive counter, so they are only recognized as
+/// negative if the name before them ends with a `-` itself (like `lock--2147483648` for the
+/// prefix `lock-`).
+///
+/// Returns `None` if the path does not end with a sequence number.
+///
+/// ```
+/// use zookeeper::sequence_number;
+///
+/// assert_eq!(sequence_number("/lock/_c_1234-lock-0000000042"), Some(42));
+/// assert_eq!(sequence_number("/lock/lock--2147483648"), Some(-2147483648));
+/// assert_eq!(sequence_number("/lock"), None);
+/// ```
+pub fn sequence_number(path: &str) -> Option<i32> {
+    const DIGITS: usize = 10;
+
+    let (_, name) = split_path(path);
+    if name.len() < DIGITS || !name.is_char_boundary(name.len() - DIGITS) {
+        return None;
+    }
+    let (prefix, suffix) = name.split_at(name.len() - DIGITS);
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if suffix.starts_with('-') && digits(&suffix[1..]) {
+        // Padded to 10 characters including the sign
+        suffix.parse().ok()
+    } else if digits(suffix) {
+        if prefix.ends_with("--") && !suffix.starts_with('0') {
+            // Unpadded, with the sign before the 10 digits
+            name[name.len() - DIGITS - 1..].parse().ok()
+        } else {
+            suffix.parse().ok()
+        }
+    } else {
+        None
+    }
+}
+
+/// Sort the names (or paths) of sequential nodes by their sequence numbers (see
+/// `sequence_number`), like to find the order of the contenders of a lock.
+///
+/// Unlike sorting the names as strings, this keeps the order across the wrap-around of the
+/// counter: if the sequence numbers contain both numbers near the maximum and negative numbers
+/// near the minimum, the negative numbers were created later and sort last. This assumes the
+/// nodes were created less than 2^31 sequence numbers apart. Names without a sequence number
+/// sort after all others, keeping their relative order.
+///
+/// ```
+/// use zookeeper::sort_by_sequence;
+///
+/// let mut names = vec!["lock--2147483648", "lock-2147483647", "lock-2147483646"];
+/// sort_by_sequence(&mut names);
+/// assert_eq!(names, vec!["lock-2147483646", "lock-2147483647", "lock--2147483648"]);
+/// ```
+pub fn sort_by_sequence<S: AsRef<str>>(names: &mut [S]) {
+    const QUARTER: i32 = 1 << 30;
+
+    let sequences: Vec<i32> =
+        names.iter().filter_map(|name| sequence_number(name.as_ref())).collect();
+    let wrapped = sequences.iter().any(|&seq| seq >= QUARTER) &&
+                  sequences.iter().any(|&seq| seq < -QUARTER);
+    names.sort_by_key(|name| {
+        sequence_number(name.as_ref()).map_or((1, 0), |seq| {
+            if wrapped && seq < 0 {
+                (0, i64::from(seq) + (1 << 32))
+            } else {
+                (0, i64::from(seq))
+            }
+        })
+    });
+}
+
+/// Match a node name against a glob `pattern`, which supports `*` (any number of characters), `?`
+/// (a single character), and character classes like `[abc]`, `[a-z]` and `[!0-9]`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Backtracking to the last `*` suffices, as a later `*` always supersedes an earlier one
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some(&'*') => {
+                star = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some(&'?') => Some(1),
+            Some(&'[') => match_class(&pattern[p..], name[n]),
+            Some(&c) if c == name[n] => Some(1),
+            _ => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((star_p, star_n))) => {
+                p = star_p + 1;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Match `c` against the character class at the start of `pattern`. Returns the length of the
+/// class if it matches.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negated = match pattern.get(i) {
+        Some(&'!') | Some(&'^') => {
+            i += 1;
+            true
+        }
+        _ => false,
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match pattern.get(i) {
+            // an unterminated class never matches
+            None => return None,
+            Some(&']') if !first => break,
+            Some(&lo) => {
+                match (pattern.get(i + 1), pattern.get(i + 2)) {
+                    (Some(&'-'), Some(&hi)) if hi != ']' => {
+                        matched |= lo <= c && c <= hi;
+                        i += 3;
+                    }
+                    _ => {
+                        matched |= lo == c;
+                        i += 1;
+                    }
+                }
+            }
+        }
+        first = false;
+    }
+
+    if matched != negated {
+        Some(i + 1)
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn make_path_tests() {
     assert_eq!("/a/b", make_path("/a", "b"));
     assert_eq!("/a/b", make_path("/a/", "b"));
 }
+
+#[cfg(test)]
+#[test]
+fn strip_chroot_tests() {
+    assert_eq!("/", strip_chroot("/app", "/app"));
+    assert_eq!("/node", strip_chroot("/app", "/app/node"));
+    assert_eq!("/zookeeper/config", strip_chroot("/app", "/zookeeper/config"));
+    assert_eq!("/application", strip_chroot("/app", "/application"));
+}
+
+#[cfg(test)]
+#[test]
+fn within_chroot_tests() {
+    assert_eq!(Some("/".to_owned()), within_chroot("/app", "/app"));
+    assert_eq!(Some("/node".to_owned()), within_chroot("/app", "/app/node"));
+    assert_eq!(None, within_chroot("/app", "/application"));
+}
+
+#[cfg(test)]
+#[test]
+fn split_path_tests() {
+    assert_eq!(("/", "a"), split_path("/a"));
+    assert_eq!(("/a", "b"), split_path("/a/b"));
+}
+
+#[cfg(test)]
+#[test]
+fn find_protected_tests() {
+    let children = vec!["_c_other-lock-0000000001".to_owned(),
+                        "_c_mine-lock-0000000007".to_owned(),
+                        "lock-0000000003".to_owned()];
+    let found = find_protected(&children, "mine");
+    assert_eq!(found, Some("_c_mine-lock-0000000007"));
+    assert_eq!(found.and_then(sequence_number), Some(7));
+    assert_eq!(find_protected(&children, "missing"), None);
+}
+
+#[cfg(test)]
+#[test]
+fn sequence_number_tests() {
+    assert_eq!(Some(7), sequence_number("/locks/lock-0000000007"));
+    assert_eq!(Some(2147483647), sequence_number("/lock-2147483647"));
+    assert_eq!(None, sequence_number("/locks/lock"));
+    assert_eq!(None, sequence_number("/locks/lock-00000x0007"));
+    assert_eq!(None, sequence_number("/locks/lock-9999999999"));
+
+    // after the wrap-around of the counter
+    assert_eq!(Some(-2147483648), sequence_number("/locks/lock--2147483648"));
+    assert_eq!(Some(-1000000000), sequence_number("/locks/lock--1000000000"));
+    assert_eq!(Some(-1), sequence_number("/locks/lock--000000001"));
+    assert_eq!(Some(-999999999), sequence_number("/locks/lock-999999999"));
+    assert_eq!(Some(1000000000), sequence_number("/locks/lock-1000000000"));
+    assert_eq!(None, sequence_number("/locks/lock-00-0000001"));
+}
+
+#[cfg(test)]
+#[test]
+fn sort_by_sequence_tests() {
+    let mut names = vec!["lock-0000000010", "lock-0000000002", "other", "lock-0000000001"];
+    sort_by_sequence(&mut names);
+    assert_eq!(names, vec!["lock-0000000001", "lock-0000000002", "lock-0000000010", "other"]);
+
+    // across the wrap-around, the negative numbers were created last
+    let mut names = vec!["lock--000000001",
+                         "lock--2147483647",
+                         "lock-2147483646",
+                         "lock--2147483648",
+                         "lock-2147483647"];
+    sort_by_sequence(&mut names);
+    assert_eq!(names,
+               vec!["lock-2147483646",
+                    "lock-2147483647",
+                    "lock--2147483648",
+                    "lock--2147483647",
+                    "lock--000000001"]);
+
+    // negative numbers without the wrap-around sort numerically
+    let mut names = vec!["lock-0000000001", "lock--000000005", "lock--000000001"];
+    sort_by_sequence(&mut names);
+    assert_eq!(names, vec!["lock--000000005", "lock--000000001", "lock-0000000001"]);
+}
+
+#[cfg(test)]
+#[test]
+fn glob_match_tests() {
+    let children = ["lock-0000000001", "lock-0000000002", "worker-01", "worker-1a", "worker-123",
+                    "leader", "_c_1234-lock-0000000003"];
+    let matches = |pattern| {
+        children.iter().cloned().filter(|child| glob_match(pattern, child)).collect::<Vec<_>>()
+    };
+
+    assert_eq!(vec!["lock-0000000001", "lock-0000000002"], matches("lock-*"));
+    assert_eq!(vec!["worker-01", "worker-1a"], matches("worker-??"));
+    assert_eq!(vec!["worker-01"], matches("worker-[0-9][0-9]"));
+    assert_eq!(vec!["worker-1a"], matches("worker-?[!0-9]"));
+    assert_eq!(vec!["leader"], matches("[kl]eader"));
+    assert_eq!(vec!["lock-0000000001", "_c_1234-lock-0000000003"], matches("*lock-*[13]"));
+    assert_eq!(children.to_vec(), matches("*"));
+    assert!(matches("worker-[0-9").is_empty());
+    assert!(glob_match("", ""));
+    assert!(!glob_match("", "a"));
+}
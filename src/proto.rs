@@ -1,8 +1,10 @@
 use acl::{Acl, Permission};
-use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
-use consts::{KeeperState, WatchedEventType};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BigEndian};
+use bytes::{Buf, Bytes, BytesMut};
+use consts::{CreateMode, KeeperState, WatchedEventType, ZkError};
 use data::Stat;
-use std::convert::From;
+use multi::{Op, OpResult};
+use std::convert::{From, TryFrom};
 use std::io::{Cursor, Read, Write, Result, Error, ErrorKind};
 use watch::WatchedEvent;
 
@@ -18,8 +20,77 @@ pub enum OpCode {
     GetChildren = 8,
     GetData = 4,
     SetData = 5,
+    Sync = 9,
     Ping = 11,
+    GetChildren2 = 12,
+    Check = 13,
+    Multi = 14,
+    Create2 = 15,
+    Reconfig = 16,
+    RemoveWatches = 18,
+    CreateContainer = 19,
+    CreateTtl = 21,
+    MultiRead = 22,
+    SetWatches = 101,
+    GetEphemerals = 103,
+    GetAllChildrenNumber = 104,
+    AddWatch = 106,
     CloseSession = -11,
+    Error = -1,
+}
+
+impl OpCode {
+    /// Whether the operation modifies the tree, which read-only servers refuse.
+    pub fn is_write(self) -> bool {
+        matches!(self,
+                 OpCode::Create | OpCode::Create2 | OpCode::CreateContainer | OpCode::CreateTtl |
+                 OpCode::Delete | OpCode::SetData | OpCode::SetAcl | OpCode::Multi |
+                 OpCode::Reconfig)
+    }
+}
+
+/// The xid of the watch events sent by the server.
+pub const NOTIFICATION_XID: i32 = -1;
+/// The xid of pings and their replies.
+pub const PING_XID: i32 = -2;
+/// The xid of authentication requests and their replies.
+pub const AUTH_XID: i32 = -4;
+
+impl TryFrom<i32> for OpCode {
+    /// The unknown operation code.
+    type Error = i32;
+
+    fn try_from(raw: i32) -> ::std::result::Result<OpCode, i32> {
+        Ok(match raw {
+            100 => OpCode::Auth,
+            1 => OpCode::Create,
+            2 => OpCode::Delete,
+            3 => OpCode::Exists,
+            6 => OpCode::GetAcl,
+            7 => OpCode::SetAcl,
+            8 => OpCode::GetChildren,
+            4 => OpCode::GetData,
+            5 => OpCode::SetData,
+            9 => OpCode::Sync,
+            11 => OpCode::Ping,
+            12 => OpCode::GetChildren2,
+            13 => OpCode::Check,
+            14 => OpCode::Multi,
+            15 => OpCode::Create2,
+            16 => OpCode::Reconfig,
+            18 => OpCode::RemoveWatches,
+            19 => OpCode::CreateContainer,
+            21 => OpCode::CreateTtl,
+            22 => OpCode::MultiRead,
+            101 => OpCode::SetWatches,
+            103 => OpCode::GetEphemerals,
+            104 => OpCode::GetAllChildrenNumber,
+            106 => OpCode::AddWatch,
+            -11 => OpCode::CloseSession,
+            -1 => OpCode::Error,
+            raw => return Err(raw),
+        })
+    }
 }
 
 pub type ByteBuf = Cursor<Vec<u8>>;
@@ -32,64 +103,151 @@ pub trait WriteTo {
     fn write_to(&self, writer: &mut dyn Write) -> Result<()>;
 
     fn to_len_prefixed_buf(&self) -> Result<ByteBuf> {
-        let mut buf = Cursor::new(Vec::new());
-        buf.set_position(4);
-        try!(self.write_to(&mut buf));
-        let len = buf.position() - 4;
-        buf.set_position(0);
-        try!(buf.write_i32::<BigEndian>(len as i32));
-        buf.set_position(0);
-        Ok(buf)
+        write_frame(|buf| self.write_to(buf))
     }
 }
 
 pub fn to_len_prefixed_buf<Request: WriteTo>(rh: RequestHeader, req: Request) -> Result<ByteBuf> {
-    let mut buf = Cursor::new(Vec::new());
-    buf.set_position(4);
-    try!(rh.write_to(&mut buf));
-    try!(req.write_to(&mut buf));
-    let len = buf.position() - 4;
-    buf.set_position(0);
-    try!(buf.write_i32::<BigEndian>(len as i32));
-    buf.set_position(0);
-    Ok(buf)
+    write_frame(|buf| {
+        try!(rh.write_to(buf));
+        req.write_to(buf)
+    })
 }
 
-fn error(msg: &str) -> Error {
-    Error::new(ErrorKind::InvalidInput, msg)
+/// Write a payload and prefix it with its length.
+fn write_frame<F: FnOnce(&mut Vec<u8>) -> Result<()>>(write: F) -> Result<ByteBuf> {
+    let mut payload = Vec::new();
+    try!(write(&mut payload));
+    Ok(Cursor::new(try!(FrameCodec::default().encode(&payload))))
 }
 
-trait StringReader: Read {
+/// The framing of the protocol: every request and response is prefixed with its length as an
+/// `i32`, which doesn't count itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    /// The length of the prefix of every frame.
+    pub const HEADER_LEN: usize = 4;
+
+    /// A codec for frames of at most `max_frame_size` bytes, not counting the length prefix.
+    pub fn new(max_frame_size: usize) -> FrameCodec {
+        FrameCodec { max_frame_size }
+    }
+
+    /// Prefix `payload` with its length.
+    ///
+    /// A payload larger than the maximum frame size is rejected with `ErrorKind::InvalidInput`.
+    pub fn encode(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        try!(self.check_len(payload.len(), ErrorKind::InvalidInput));
+        let mut frame = Vec::with_capacity(Self::HEADER_LEN + payload.len());
+        try!(frame.write_i32::<BigEndian>(payload.len() as i32));
+        frame.extend_from_slice(payload);
+        Ok(frame)
+    }
+
+    /// Take the payload of the first frame out of `buf`, or return `None` and leave `buf` as it
+    /// is while the frame is still incomplete.
+    ///
+    /// A frame announcing a negative length, or a length above the maximum frame size, is
+    /// rejected with `ErrorKind::InvalidData`: the stream can't be resynchronized after it.
+    pub fn decode(&self, buf: &mut BytesMut) -> Result<Option<Bytes>> {
+        if buf.len() < Self::HEADER_LEN {
+            return Ok(None);
+        }
+        let len = BigEndian::read_i32(&buf[..Self::HEADER_LEN]);
+        if len < 0 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("negative frame length {}", len)));
+        }
+        try!(self.check_len(len as usize, ErrorKind::InvalidData));
+        if buf.len() - Self::HEADER_LEN < len as usize {
+            return Ok(None);
+        }
+        buf.advance(Self::HEADER_LEN);
+        Ok(Some(buf.split_to(len as usize).freeze()))
+    }
+
+    fn check_len(&self, len: usize, kind: ErrorKind) -> Result<()> {
+        if len > self.max_frame_size {
+            return Err(Error::new(kind,
+                                  format!("frame of {} bytes exceeds the limit of {} bytes",
+                                          len,
+                                          self.max_frame_size)));
+        }
+        Ok(())
+    }
+}
+
+impl Default for FrameCodec {
+    /// A codec only limited by the range of the length prefix.
+    fn default() -> FrameCodec {
+        FrameCodec::new(i32::MAX as usize)
+    }
+}
+
+/// Read the path of a request encoded by `to_len_prefixed_buf`, for requests which have one.
+pub fn request_path(opcode: OpCode, buf: &ByteBuf) -> Option<String> {
+    match opcode {
+        OpCode::Create | OpCode::Create2 | OpCode::CreateTtl | OpCode::CreateContainer |
+        OpCode::Delete | OpCode::Exists | OpCode::GetAcl | OpCode::SetAcl | OpCode::GetChildren |
+        OpCode::GetChildren2 | OpCode::GetData | OpCode::SetData | OpCode::Check | OpCode::Sync |
+        OpCode::AddWatch | OpCode::RemoveWatches | OpCode::GetEphemerals |
+        OpCode::GetAllChildrenNumber => {
+            // skip the length prefix and the RequestHeader
+            let mut reader = Cursor::new(buf.get_ref().as_slice());
+            reader.set_position(12);
+            reader.read_string().ok()
+        }
+        _ => None,
+    }
+}
+
+/// The opcode of a request creating a node of the given `mode`, which would otherwise be sent as
+/// `opcode`. The server only creates containers through their own opcode, and treats the
+/// `Container` flag of a plain create as a persistent node.
+pub fn create_opcode(mode: CreateMode, opcode: OpCode) -> OpCode {
+    match mode {
+        CreateMode::Container => OpCode::CreateContainer,
+        _ => opcode,
+    }
+}
+
+pub trait StringReader: Read {
     fn read_string(&mut self) -> Result<String>;
 }
 
 pub trait BufferReader: Read {
     fn read_buffer(&mut self) -> Result<Vec<u8>>;
+
+    /// Like `read_buffer`, but a negative length (a null buffer) is `None`.
+    fn read_optional_buffer(&mut self) -> Result<Option<Vec<u8>>>;
 }
 
 impl<R: Read> StringReader for R {
     fn read_string(&mut self) -> Result<String> {
         let raw = try!(self.read_buffer());
-        Ok(String::from_utf8(raw).unwrap())
+        String::from_utf8(raw).map_err(|_| Error::new(ErrorKind::InvalidData, "invalid UTF-8"))
     }
 }
 
 // A buffer is an u8 string prefixed with it's length as i32
 impl<R: Read> BufferReader for R {
     fn read_buffer(&mut self) -> Result<Vec<u8>> {
+        Ok(try!(self.read_optional_buffer()).unwrap_or_default())
+    }
+
+    fn read_optional_buffer(&mut self) -> Result<Option<Vec<u8>>> {
         let len = try!(self.read_i32::<BigEndian>());
-        let len = if len < 0 {
-            0
-        } else {
-            len as usize
-        };
-        let mut buf = vec![0; len];
-        let read = try!(self.read(&mut buf));
-        if read == len {
-            Ok(buf)
-        } else {
-            Err(error("read_buffer failed"))
+        if len < 0 {
+            return Ok(None);
         }
+        let mut buf = vec![0; len as usize];
+        // The buffer may arrive in several reads, so assemble it completely
+        try!(self.read_exact(&mut buf));
+        Ok(Some(buf))
     }
 }
 
@@ -157,6 +315,22 @@ impl ReadFrom for Stat {
     }
 }
 
+impl WriteTo for Stat {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        try!(writer.write_i64::<BigEndian>(self.czxid));
+        try!(writer.write_i64::<BigEndian>(self.mzxid));
+        try!(writer.write_i64::<BigEndian>(self.ctime));
+        try!(writer.write_i64::<BigEndian>(self.mtime));
+        try!(writer.write_i32::<BigEndian>(self.version));
+        try!(writer.write_i32::<BigEndian>(self.cversion));
+        try!(writer.write_i32::<BigEndian>(self.aversion));
+        try!(writer.write_i64::<BigEndian>(self.ephemeral_owner));
+        try!(writer.write_i32::<BigEndian>(self.data_length));
+        try!(writer.write_i32::<BigEndian>(self.num_children));
+        writer.write_i64::<BigEndian>(self.pzxid)
+    }
+}
+
 pub struct ConnectRequest {
     protocol_version: i32,
     last_zxid_seen: i64,
@@ -167,14 +341,15 @@ pub struct ConnectRequest {
 }
 
 impl ConnectRequest {
-    pub fn from(conn_resp: &ConnectResponse, last_zxid_seen: i64) -> ConnectRequest {
+    pub fn from(conn_resp: &ConnectResponse, last_zxid_seen: i64, read_only: bool)
+                -> ConnectRequest {
         ConnectRequest {
             protocol_version: conn_resp.protocol_version,
             last_zxid_seen: last_zxid_seen,
             timeout: conn_resp.timeout as i32,
             session_id: conn_resp.session_id,
             passwd: conn_resp.passwd.clone(),
-            read_only: conn_resp.read_only,
+            read_only,
         }
     }
 }
@@ -193,10 +368,10 @@ impl WriteTo for ConnectRequest {
 
 #[derive(Debug)]
 pub struct ConnectResponse {
-    protocol_version: i32,
+    pub protocol_version: i32,
     pub timeout: u64, // is handled as i32
     pub session_id: i64,
-    passwd: Vec<u8>,
+    pub passwd: Vec<u8>,
     pub read_only: bool,
 }
 
@@ -210,6 +385,15 @@ impl ConnectResponse {
             read_only: false,
         }
     }
+
+    /// Like `initial`, but asking to resume the session with the given id and password.
+    pub fn resume(timeout: u64, session_id: i64, passwd: Vec<u8>) -> ConnectResponse {
+        ConnectResponse {
+            session_id,
+            passwd,
+            ..ConnectResponse::initial(timeout)
+        }
+    }
 }
 
 impl ReadFrom for ConnectResponse {
@@ -271,6 +455,26 @@ impl WriteTo for CreateRequest {
     }
 }
 
+pub struct CreateTtlRequest {
+    pub path: String,
+    pub data: Vec<u8>,
+    pub acl: Vec<Acl>,
+    pub flags: i32,
+    /// In milliseconds.
+    pub ttl: i64,
+}
+
+impl WriteTo for CreateTtlRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        try!(self.path.write_to(writer));
+        try!(self.data.write_to(writer));
+        try!(self.acl.write_to(writer));
+        try!(writer.write_i32::<BigEndian>(self.flags));
+        try!(writer.write_i64::<BigEndian>(self.ttl));
+        Ok(())
+    }
+}
+
 pub struct CreateResponse {
     pub path: String,
 }
@@ -281,6 +485,92 @@ impl ReadFrom for CreateResponse {
     }
 }
 
+pub struct Create2Response {
+    pub path: String,
+    pub stat: Stat,
+}
+
+impl ReadFrom for Create2Response {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Create2Response> {
+        Ok(Create2Response {
+            path: try!(reader.read_string()),
+            stat: try!(Stat::read_from(reader)),
+        })
+    }
+}
+
+pub struct AddWatchRequest {
+    pub path: String,
+    pub mode: i32,
+}
+
+impl WriteTo for AddWatchRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        try!(self.path.write_to(writer));
+        try!(writer.write_i32::<BigEndian>(self.mode));
+        Ok(())
+    }
+}
+
+pub struct RemoveWatchesRequest {
+    pub path: String,
+    pub typ: i32,
+}
+
+impl WriteTo for RemoveWatchesRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        try!(self.path.write_to(writer));
+        try!(writer.write_i32::<BigEndian>(self.typ));
+        Ok(())
+    }
+}
+
+pub struct SetWatchesRequest {
+    pub relative_zxid: i64,
+    pub data_watches: Vec<String>,
+    pub exist_watches: Vec<String>,
+    pub child_watches: Vec<String>,
+}
+
+impl WriteTo for SetWatchesRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        try!(writer.write_i64::<BigEndian>(self.relative_zxid));
+        try!(self.data_watches.write_to(writer));
+        try!(self.exist_watches.write_to(writer));
+        try!(self.child_watches.write_to(writer));
+        Ok(())
+    }
+}
+
+pub struct ReconfigRequest {
+    pub joining_servers: String,
+    pub leaving_servers: String,
+    pub new_members: String,
+    pub cur_config_id: i64,
+}
+
+impl WriteTo for ReconfigRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        try!(self.joining_servers.write_to(writer));
+        try!(self.leaving_servers.write_to(writer));
+        try!(self.new_members.write_to(writer));
+        try!(writer.write_i64::<BigEndian>(self.cur_config_id));
+        Ok(())
+    }
+}
+
+pub struct SyncRequest {
+    pub path: String,
+}
+
+impl WriteTo for SyncRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        self.path.write_to(writer)
+    }
+}
+
+pub type SyncResponse = CreateResponse;
+
 pub struct DeleteRequest {
     pub path: String,
     pub version: i32,
@@ -389,7 +679,7 @@ pub struct GetChildrenResponse {
 impl ReadFrom for GetChildrenResponse {
     fn read_from<R: Read>(reader: &mut R) -> Result<GetChildrenResponse> {
         let len = try!(reader.read_i32::<BigEndian>());
-        let mut children = Vec::with_capacity(len as usize);
+        let mut children = Vec::with_capacity(len.max(0) as usize);
         for _ in 0..len {
             children.push(try!(reader.read_string()));
         }
@@ -397,6 +687,60 @@ impl ReadFrom for GetChildrenResponse {
     }
 }
 
+pub struct GetAllChildrenNumberRequest {
+    pub path: String,
+}
+
+impl WriteTo for GetAllChildrenNumberRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        self.path.write_to(writer)
+    }
+}
+
+pub struct GetAllChildrenNumberResponse {
+    pub total: i32,
+}
+
+impl ReadFrom for GetAllChildrenNumberResponse {
+    fn read_from<R: Read>(reader: &mut R) -> Result<GetAllChildrenNumberResponse> {
+        Ok(GetAllChildrenNumberResponse { total: try!(reader.read_i32::<BigEndian>()) })
+    }
+}
+
+pub struct GetEphemeralsRequest {
+    pub prefix: String,
+}
+
+impl WriteTo for GetEphemeralsRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        self.prefix.write_to(writer)
+    }
+}
+
+pub struct GetEphemeralsResponse {
+    pub ephemerals: Vec<String>,
+}
+
+impl ReadFrom for GetEphemeralsResponse {
+    fn read_from<R: Read>(reader: &mut R) -> Result<GetEphemeralsResponse> {
+        let ephemerals = try!(GetChildrenResponse::read_from(reader)).children;
+        Ok(GetEphemeralsResponse { ephemerals })
+    }
+}
+
+pub struct GetChildren2Response {
+    pub children: Vec<String>,
+    pub stat: Stat,
+}
+
+impl ReadFrom for GetChildren2Response {
+    fn read_from<R: Read>(reader: &mut R) -> Result<GetChildren2Response> {
+        let children = try!(GetChildrenResponse::read_from(reader)).children;
+        let stat = try!(Stat::read_from(reader));
+        Ok(GetChildren2Response { children, stat })
+    }
+}
+
 pub type GetDataRequest = StringAndBoolRequest;
 
 pub struct GetDataResponse {
@@ -411,6 +755,19 @@ impl ReadFrom for GetDataResponse {
     }
 }
 
+/// Like `GetDataResponse`, but keeps null data (`None`) apart from empty data.
+pub struct GetDataRawResponse {
+    pub data_stat: (Option<Vec<u8>>, Stat),
+}
+
+impl ReadFrom for GetDataRawResponse {
+    fn read_from<R: Read>(reader: &mut R) -> Result<GetDataRawResponse> {
+        let data = try!(reader.read_optional_buffer());
+        let stat = try!(Stat::read_from(reader));
+        Ok(GetDataRawResponse { data_stat: (data, stat) })
+    }
+}
+
 pub struct AuthRequest {
     pub typ: i32,
     pub scheme: String,
@@ -451,6 +808,439 @@ impl ReadFrom for WatchedEvent {
             event_type: event_type,
             keeper_state: state,
             path: Some(path),
+            watch_type: None,
         })
     }
 }
+
+/// Precedes every entry of a multi request and response. The entries are terminated by a header
+/// with `done` set.
+struct MultiHeader {
+    typ: i32,
+    done: bool,
+    err: i32,
+}
+
+impl MultiHeader {
+    fn entry(opcode: OpCode) -> MultiHeader {
+        MultiHeader {
+            typ: opcode as i32,
+            done: false,
+            err: -1,
+        }
+    }
+
+    fn done() -> MultiHeader {
+        MultiHeader {
+            typ: -1,
+            done: true,
+            err: -1,
+        }
+    }
+}
+
+impl WriteTo for MultiHeader {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        try!(writer.write_i32::<BigEndian>(self.typ));
+        try!(writer.write_u8(self.done as u8));
+        try!(writer.write_i32::<BigEndian>(self.err));
+        Ok(())
+    }
+}
+
+fn read_transaction_header<R: Read>(reader: &mut R) -> Result<MultiHeader> {
+    Ok(MultiHeader {
+        typ: try!(reader.read_i32::<BigEndian>()),
+        done: try!(reader.read_u8()) != 0,
+        err: try!(reader.read_i32::<BigEndian>()),
+    })
+}
+
+/// The operations of a transaction, with their paths already prefixed with the chroot.
+pub struct TransactionRequest {
+    pub ops: Vec<Op>,
+}
+
+impl WriteTo for TransactionRequest {
+    fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        for op in &self.ops {
+            match *op {
+                Op::Create { ref path, ref data, ref acl, mode } => {
+                    try!(MultiHeader::entry(create_opcode(mode, OpCode::Create)).write_to(writer));
+                    try!(path.write_to(writer));
+                    try!(data.write_to(writer));
+                    try!(acl.write_to(writer));
+                    try!(writer.write_i32::<BigEndian>(mode as i32));
+                }
+                Op::Delete { ref path, version } => {
+                    try!(MultiHeader::entry(OpCode::Delete).write_to(writer));
+                    try!(path.write_to(writer));
+                    try!(writer.write_i32::<BigEndian>(version.unwrap_or(-1)));
+                }
+                Op::SetData { ref path, ref data, version } => {
+                    try!(MultiHeader::entry(OpCode::SetData).write_to(writer));
+                    try!(path.write_to(writer));
+                    try!(data.write_to(writer));
+                    try!(writer.write_i32::<BigEndian>(version.unwrap_or(-1)));
+                }
+                Op::Check { ref path, version } => {
+                    try!(MultiHeader::entry(OpCode::Check).write_to(writer));
+                    try!(path.write_to(writer));
+                    try!(writer.write_i32::<BigEndian>(version.unwrap_or(-1)));
+                }
+                Op::GetData { ref path } => {
+                    try!(MultiHeader::entry(OpCode::GetData).write_to(writer));
+                    try!(path.write_to(writer));
+                    try!(writer.write_u8(0)); // watch
+                }
+                Op::GetChildren { ref path } => {
+                    try!(MultiHeader::entry(OpCode::GetChildren).write_to(writer));
+                    try!(path.write_to(writer));
+                    try!(writer.write_u8(0)); // watch
+                }
+            }
+        }
+        MultiHeader::done().write_to(writer)
+    }
+}
+
+pub struct TransactionResponse {
+    pub responses: Vec<::std::result::Result<OpResult, ZkError>>,
+}
+
+impl ReadFrom for TransactionResponse {
+    /// The reader holds exactly one response frame. A response which ends before the terminating
+    /// header, or which has bytes left after it, is rejected as a whole; as only this frame is
+    /// affected, the connection itself stays in sync.
+    fn read_from<R: Read>(reader: &mut R) -> Result<TransactionResponse> {
+        let mut responses = Vec::new();
+        loop {
+            let header = try!(read_transaction_header(reader));
+            if header.done {
+                break;
+            }
+            let response = match OpCode::try_from(header.typ) {
+                Ok(OpCode::Create) => Ok(OpResult::Create { path: try!(reader.read_string()) }),
+                // The result of a container, create2 or TTL create, with the Stat of the node
+                Ok(OpCode::Create2) => {
+                    let path = try!(reader.read_string());
+                    try!(Stat::read_from(reader));
+                    Ok(OpResult::Create { path })
+                }
+                Ok(OpCode::SetData) => {
+                    Ok(OpResult::SetData { stat: try!(Stat::read_from(reader)) })
+                }
+                Ok(OpCode::Delete) | Ok(OpCode::Check) => Ok(OpResult::Empty),
+                Ok(OpCode::GetData) => {
+                    let data = try!(reader.read_buffer());
+                    Ok(OpResult::GetData {
+                        data,
+                        stat: try!(Stat::read_from(reader)),
+                    })
+                }
+                Ok(OpCode::GetChildren) => {
+                    let children = try!(GetChildrenResponse::read_from(reader)).children;
+                    Ok(OpResult::GetChildren { children })
+                }
+                Ok(OpCode::Error) => {
+                    // An aborted transaction reports an error for every operation: the ones before
+                    // the failing operation report 0, as they succeeded before being rolled back,
+                    // and the ones after it `RuntimeInconsistency`.
+                    match try!(reader.read_i32::<BigEndian>()) {
+                        0 => Err(ZkError::RuntimeInconsistency),
+                        err => Err(ZkError::from(err)),
+                    }
+                }
+                _ => {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                                          format!("invalid type {} in multi response",
+                                                  header.typ)))
+                }
+            };
+            responses.push(response);
+        }
+
+        if try!(reader.read(&mut [0])) != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "trailing bytes in multi response"));
+        }
+
+        Ok(TransactionResponse { responses })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcode_round_trip() {
+        let opcodes = [OpCode::Auth, OpCode::Create, OpCode::Delete, OpCode::Exists,
+                       OpCode::GetAcl, OpCode::SetAcl, OpCode::GetChildren, OpCode::GetData,
+                       OpCode::SetData, OpCode::Sync, OpCode::Ping, OpCode::GetChildren2,
+                       OpCode::Check, OpCode::Multi, OpCode::Create2, OpCode::Reconfig,
+                       OpCode::RemoveWatches, OpCode::CreateContainer, OpCode::CreateTtl,
+                       OpCode::MultiRead, OpCode::SetWatches, OpCode::GetEphemerals,
+                       OpCode::GetAllChildrenNumber, OpCode::AddWatch, OpCode::CloseSession,
+                       OpCode::Error];
+        for &opcode in opcodes.iter() {
+            assert_eq!(OpCode::try_from(opcode as i32), Ok(opcode));
+        }
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        assert_eq!(OpCode::try_from(0), Err(0));
+        assert_eq!(OpCode::try_from(PING_XID), Err(PING_XID));
+        assert_eq!(OpCode::try_from(1000), Err(1000));
+    }
+
+    #[test]
+    fn get_children2_response() {
+        let mut buf = Vec::new();
+        vec!["a".to_owned(), "b".to_owned()].write_to(&mut buf).unwrap();
+        let stat = Stat {
+            czxid: 1,
+            mzxid: 1,
+            ctime: 0,
+            mtime: 0,
+            version: 0,
+            cversion: 2,
+            aversion: 0,
+            ephemeral_owner: 0,
+            data_length: 0,
+            num_children: 2,
+            pzxid: 3,
+        };
+        stat.write_to(&mut buf).unwrap();
+
+        let response = GetChildren2Response::read_from(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(response.children, vec!["a".to_owned(), "b".to_owned()]);
+        assert_eq!(response.stat, stat);
+    }
+
+    #[test]
+    fn read_optional_buffer_null_vs_empty() {
+        let mut reader = Cursor::new(vec![0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        assert_eq!(reader.read_optional_buffer().unwrap(), None);
+        assert_eq!(reader.read_optional_buffer().unwrap(), Some(vec![]));
+
+        // read_buffer still reads a null buffer as empty
+        let mut reader = Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(reader.read_buffer().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn read_string_rejects_invalid_utf8() {
+        let mut reader = Cursor::new(vec![0, 0, 0, 2, 0xff, 0xfe]);
+        assert_eq!(reader.read_string().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    /// Yields one byte per read, like a response arriving in many small segments.
+    struct ByteByByte<R>(R);
+
+    impl<R: Read> Read for ByteByByte<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn get_children_response_from_short_reads() {
+        let names: Vec<String> = (0..5000).map(|i| format!("child-{:010}", i)).collect();
+        let mut buf = Vec::new();
+        names.write_to(&mut buf).unwrap();
+
+        let response = GetChildrenResponse::read_from(&mut ByteByByte(Cursor::new(buf))).unwrap();
+        assert_eq!(response.children, names);
+    }
+
+    /// Yields at most 1000 bytes per read, like a large payload arriving over TCP.
+    struct Chunked<R>(R);
+
+    impl<R: Read> Read for Chunked<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let len = buf.len().min(1000);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn read_buffer_from_chunks() {
+        let data: Vec<u8> = (0..200 * 1024).map(|i| i as u8).collect();
+        let mut buf = Vec::new();
+        data.write_to(&mut buf).unwrap();
+
+        assert_eq!(Chunked(Cursor::new(buf.clone())).read_buffer().unwrap(), data);
+
+        // a buffer which ends early is an error rather than truncated data
+        buf.truncate(100 * 1024);
+        let err = Chunked(Cursor::new(buf)).read_buffer().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    fn write_header(buf: &mut Vec<u8>, typ: i32, done: bool, err: i32) {
+        buf.write_i32::<BigEndian>(typ).unwrap();
+        buf.write_u8(done as u8).unwrap();
+        buf.write_i32::<BigEndian>(err).unwrap();
+    }
+
+    fn multi_response() -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_header(&mut buf, OpCode::Create as i32, false, 0);
+        "/node".to_owned().write_to(&mut buf).unwrap();
+        write_header(&mut buf, OpCode::Check as i32, false, 0);
+        write_header(&mut buf, OpCode::Delete as i32, false, 0);
+        write_header(&mut buf, -1, true, -1);
+        buf
+    }
+
+    #[test]
+    fn frame_codec_round_trip() {
+        let codec = FrameCodec::new(16);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&codec.encode(b"first").unwrap());
+        buf.extend_from_slice(&codec.encode(b"").unwrap());
+        let second = codec.encode(b"second").unwrap();
+        buf.extend_from_slice(&second[..7]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"first")));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Bytes::new()));
+        // a partial frame stays in the buffer until the rest arrives
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), 7);
+        buf.extend_from_slice(&second[7..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"second")));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn frame_codec_rejects_oversized_frames() {
+        let codec = FrameCodec::new(4);
+        assert_eq!(codec.encode(b"12345").unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // rejected by the header alone, without waiting for the payload
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&FrameCodec::default().encode(b"12345").unwrap()[..6]);
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn request_path_from_buf() {
+        let header = |opcode| RequestHeader { xid: 7, opcode };
+        let req = GetDataRequest {
+            path: "/missing".to_owned(),
+            watch: false,
+        };
+        let buf = to_len_prefixed_buf(header(OpCode::GetData), req).unwrap();
+        assert_eq!(request_path(OpCode::GetData, &buf), Some("/missing".to_owned()));
+
+        let buf = to_len_prefixed_buf(header(OpCode::CloseSession), EmptyRequest).unwrap();
+        assert_eq!(request_path(OpCode::CloseSession, &buf), None);
+    }
+
+    #[test]
+    fn reconfig_request() {
+        let req = ReconfigRequest {
+            joining_servers: "server.4=host4:2888:3888;2181".to_owned(),
+            leaving_servers: "".to_owned(),
+            new_members: "".to_owned(),
+            cur_config_id: -1,
+        };
+        let mut buf = Vec::new();
+        req.write_to(&mut buf).unwrap();
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(reader.read_string().unwrap(), "server.4=host4:2888:3888;2181");
+        assert_eq!(reader.read_string().unwrap(), "");
+        assert_eq!(reader.read_string().unwrap(), "");
+        assert_eq!(reader.read_i64::<BigEndian>().unwrap(), -1);
+        assert_eq!(reader.position() as usize, reader.get_ref().len());
+    }
+
+    #[test]
+    fn transaction_response() {
+        let response = TransactionResponse::read_from(&mut Cursor::new(multi_response())).unwrap();
+        assert_eq!(response.responses,
+                   vec![Ok(OpResult::Create { path: "/node".to_owned() }),
+                        Ok(OpResult::Empty),
+                        Ok(OpResult::Empty)]);
+    }
+
+    #[test]
+    fn transaction_response_create2() {
+        let stat = Stat::read_from(&mut Cursor::new(vec![0; 68])).unwrap();
+        let mut buf = Vec::new();
+        write_header(&mut buf, OpCode::Create2 as i32, false, 0);
+        "/container".to_owned().write_to(&mut buf).unwrap();
+        stat.write_to(&mut buf).unwrap();
+        write_header(&mut buf, -1, true, -1);
+
+        let response = TransactionResponse::read_from(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(response.responses,
+                   vec![Ok(OpResult::Create { path: "/container".to_owned() })]);
+    }
+
+    #[test]
+    fn transaction_response_reads() {
+        let stat = Stat::read_from(&mut Cursor::new(vec![0; 68])).unwrap();
+        let mut buf = Vec::new();
+        write_header(&mut buf, OpCode::GetData as i32, false, 0);
+        vec![1u8, 2].write_to(&mut buf).unwrap();
+        stat.write_to(&mut buf).unwrap();
+        write_header(&mut buf, OpCode::GetChildren as i32, false, 0);
+        vec!["a".to_owned()].write_to(&mut buf).unwrap();
+        // reads fail independently of each other
+        write_header(&mut buf, OpCode::Error as i32, false, -1);
+        buf.write_i32::<BigEndian>(ZkError::NoNode as i32).unwrap();
+        write_header(&mut buf, -1, true, -1);
+
+        let response = TransactionResponse::read_from(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(response.responses,
+                   vec![Ok(OpResult::GetData {
+                            data: vec![1, 2],
+                            stat,
+                        }),
+                        Ok(OpResult::GetChildren { children: vec!["a".to_owned()] }),
+                        Err(ZkError::NoNode)]);
+    }
+
+    #[test]
+    fn transaction_response_aborted() {
+        let mut buf = Vec::new();
+        for &err in &[0, ZkError::NoNode as i32, ZkError::RuntimeInconsistency as i32] {
+            write_header(&mut buf, OpCode::Error as i32, false, err);
+            buf.write_i32::<BigEndian>(err).unwrap();
+        }
+        write_header(&mut buf, -1, true, -1);
+
+        let response = TransactionResponse::read_from(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(response.responses,
+                   vec![Err(ZkError::RuntimeInconsistency),
+                        Err(ZkError::NoNode),
+                        Err(ZkError::RuntimeInconsistency)]);
+    }
+
+    #[test]
+    fn transaction_response_truncated() {
+        let mut frame = multi_response();
+        frame.truncate(12);
+        assert!(TransactionResponse::read_from(&mut Cursor::new(frame)).is_err());
+
+        // the next frame is unaffected by the broken one
+        let response = TransactionResponse::read_from(&mut Cursor::new(multi_response())).unwrap();
+        assert_eq!(response.responses.len(), 3);
+    }
+
+    #[test]
+    fn transaction_response_trailing_bytes() {
+        let mut frame = multi_response();
+        frame.push(0);
+        assert!(TransactionResponse::read_from(&mut Cursor::new(frame)).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use consts::ZkError;
+use zookeeper::ZkResult;
+
+/// The path of the node holding the dynamic configuration of the ensemble.
+pub const CONFIG_NODE: &str = "/zookeeper/config";
+
+/// Whether a server votes in the ensemble. See `ServerEntry`.
+#[derive(Clone, Copy, Debug, EnumDisplay, PartialEq)]
+pub enum QuorumRole {
+    /// The server votes, and may become the leader.
+    Participant,
+    /// The server follows the leader without voting.
+    Observer,
+}
+
+/// A server of the ensemble, from a `server.N=host:port:port[:role][;[client host:]client port]`
+/// line of the configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerEntry {
+    /// The id of the server, `N` in `server.N`.
+    pub id: u64,
+    /// The host the other servers reach the server at.
+    pub host: String,
+    /// The port the followers connect to the leader at.
+    pub quorum_port: u16,
+    /// The port of the leader election.
+    pub election_port: u16,
+    pub role: QuorumRole,
+    /// The address clients connect to, `None` if the line has no client port.
+    pub client_host: Option<String>,
+    pub client_port: Option<u16>,
+}
+
+/// The configuration of the ensemble, as stored in `/zookeeper/config` (see
+/// `ZooKeeper::get_config`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuorumConfig {
+    pub servers: Vec<ServerEntry>,
+    /// The version of the configuration, from its `version=` line (in hex), or -1 if it has none.
+    /// This is what `ZooKeeper::reconfig` expects as `from_config`.
+    pub version: i64,
+}
+
+impl QuorumConfig {
+    /// Parse the data of the configuration node. Lines other than the server entries and the
+    /// version, like the weights of hierarchical quorums, are skipped.
+    ///
+    /// # Errors
+    /// If the data is not UTF-8, or a server entry or the version is malformed,
+    /// `Err(ZkError::MarshallingError)` is returned.
+    pub fn parse(data: &[u8]) -> ZkResult<QuorumConfig> {
+        let config = try!(::std::str::from_utf8(data).map_err(|_| ZkError::MarshallingError));
+        let mut servers = Vec::new();
+        let mut version = -1;
+        for line in config.lines().map(str::trim) {
+            if let Some(hex) = line.strip_prefix("version=") {
+                version = try!(i64::from_str_radix(hex, 16).map_err(|_| ZkError::MarshallingError));
+            } else if let Some(entry) = line.strip_prefix("server.") {
+                servers.push(try!(parse_server(entry).ok_or_else(|| {
+                    warn!("Malformed server entry in the configuration: {:?}", line);
+                    ZkError::MarshallingError
+                })));
+            }
+        }
+        Ok(QuorumConfig { servers, version })
+    }
+}
+
+/// Parse a server entry without its `server.` prefix.
+fn parse_server(entry: &str) -> Option<ServerEntry> {
+    let mut parts = entry.splitn(2, '=');
+    let id = parts.next().and_then(|id| id.parse().ok())?;
+    let mut addresses = parts.next()?.splitn(2, ';');
+    let (host, rest) = split_host(addresses.next()?)?;
+    let mut ports = rest.split(':');
+    let quorum_port = ports.next().and_then(|port| port.parse().ok())?;
+    let election_port = ports.next().and_then(|port| port.parse().ok())?;
+    let role = match ports.next() {
+        None | Some("participant") => QuorumRole::Participant,
+        Some("observer") => QuorumRole::Observer,
+        Some(_) => return None,
+    };
+    if ports.next().is_some() {
+        return None;
+    }
+    let (client_host, client_port) = match addresses.next() {
+        None => (None, None),
+        Some(client) => {
+            match client.rfind(':') {
+                Some(i) => {
+                    let host = client[..i].trim_start_matches('[').trim_end_matches(']');
+                    (Some(host.to_owned()), Some(client[i + 1..].parse().ok()?))
+                }
+                None => (None, Some(client.parse().ok()?)),
+            }
+        }
+    };
+    Some(ServerEntry {
+        id,
+        host: host.to_owned(),
+        quorum_port,
+        election_port,
+        role,
+        client_host,
+        client_port,
+    })
+}
+
+/// Split `host:rest` into the host, without brackets if it is an IPv6 address, and the rest.
+fn split_host(address: &str) -> Option<(&str, &str)> {
+    if let Some(bracketed) = address.strip_prefix('[') {
+        let end = bracketed.find("]:")?;
+        Some((&bracketed[..end], &bracketed[end + 2..]))
+    } else {
+        let end = address.find(':')?;
+        Some((&address[..end], &address[end + 1..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QuorumConfig, QuorumRole, ServerEntry};
+    use consts::ZkError;
+
+    #[test]
+    fn parse_config() {
+        let config = QuorumConfig::parse(b"server.1=10.0.0.1:2888:3888:participant;0.0.0.0:2181
+server.2=[::1]:2889:3889:observer;2182
+server.3=zk3:2890:3890
+version=10000000a
+")
+                         .unwrap();
+        assert_eq!(config.version, 0x10000000a);
+        assert_eq!(config.servers,
+                   vec![ServerEntry {
+                            id: 1,
+                            host: "10.0.0.1".to_owned(),
+                            quorum_port: 2888,
+                            election_port: 3888,
+                            role: QuorumRole::Participant,
+                            client_host: Some("0.0.0.0".to_owned()),
+                            client_port: Some(2181),
+                        },
+                        ServerEntry {
+                            id: 2,
+                            host: "::1".to_owned(),
+                            quorum_port: 2889,
+                            election_port: 3889,
+                            role: QuorumRole::Observer,
+                            client_host: None,
+                            client_port: Some(2182),
+                        },
+                        ServerEntry {
+                            id: 3,
+                            host: "zk3".to_owned(),
+                            quorum_port: 2890,
+                            election_port: 3890,
+                            role: QuorumRole::Participant,
+                            client_host: None,
+                            client_port: None,
+                        }]);
+    }
+
+    #[test]
+    fn parse_malformed_config() {
+        assert_eq!(QuorumConfig::parse(b"").unwrap().version, -1);
+        for config in &["server.1=zk1:2888",
+                        "server.x=zk1:2888:3888",
+                        "server.1=zk1:2888:3888:boss",
+                        "server.1=zk1:2888:3888;port",
+                        "version=xyz"] {
+            assert_eq!(QuorumConfig::parse(config.as_bytes()), Err(ZkError::MarshallingError));
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket backing `ZooKeeperBuilder::rate_limit`, holding up to one second worth of
+/// tokens.
+pub struct RateLimiter {
+    ops_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(ops_per_sec: u32) -> RateLimiter {
+        let ops_per_sec = f64::from(ops_per_sec.max(1));
+        RateLimiter {
+            ops_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: ops_per_sec,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token, blocking until one is available.
+    pub fn acquire(&self) {
+        // The lock is held while sleeping, so waiting callers are served in turn
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        self.refill(&mut bucket);
+
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / self.ops_per_sec;
+            trace!("rate limit reached, waiting {}s", wait);
+            thread::sleep(Duration::from_nanos((wait * 1e9) as u64));
+            bucket.tokens = 1.0;
+            bucket.refilled_at = Instant::now();
+        }
+        bucket.tokens -= 1.0;
+    }
+
+    /// Take a token if one is available, returning `false` instead of blocking otherwise.
+    pub fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        self.refill(&mut bucket);
+
+        if bucket.tokens < 1.0 {
+            trace!("rate limit reached, refusing the request");
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Add the tokens accrued since the last refill.
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        bucket.tokens = (bucket.tokens + elapsed * self.ops_per_sec).min(self.ops_per_sec);
+        bucket.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn paces_to_rate() {
+        let limiter = RateLimiter::new(100);
+        let start = Instant::now();
+        // the first 100 are the initial burst, the next 50 take half a second
+        for _ in 0..150 {
+            limiter.acquire();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn try_acquire_does_not_wait() {
+        let limiter = RateLimiter::new(10);
+        for _ in 0..10 {
+            assert!(limiter.try_acquire());
+        }
+        let start = Instant::now();
+        assert!(!limiter.try_acquire());
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,236 @@
+//! Atomic values stored in a znode.
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use consts::{WatchedEventType, ZkError};
+use data::Stat;
+use listeners::Subscription;
+use super::{is_disconnected, rearm, rearm_on_connect, RearmingWatch};
+use watch::WatchedEvent;
+use zookeeper::{ZkResult, ZooKeeper};
+
+struct Inner {
+    zk: Arc<ZooKeeper>,
+    path: String,
+    /// The cached data of the node and its version, `None` if the node does not exist.
+    cached: Mutex<Option<(Vec<u8>, i32)>>,
+    watch: RearmingWatch,
+}
+
+/// A value stored in the data of a single, frequently updated znode (like a shared counter),
+/// which keeps a local copy of the data and its version.
+///
+/// Updates are written with the cached version, so an update needs a single round trip as long as
+/// the cache is current. Only if another client changed the node in the meantime, the update fails
+/// with `ZkError::BadVersion`, and the node is read again before retrying. A data watch on the node
+/// keeps the cache up to date with changes of other clients, until the value is dropped. The server
+/// drops the watch whenever the client disconnects, so the node is read and watched again once the
+/// client is connected again.
+pub struct CachedAtomicValue {
+    inner: Arc<Inner>,
+    subscription: Subscription,
+}
+
+impl CachedAtomicValue {
+    /// Create a new atomic value stored in the node of the given `path`, which must exist.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    pub fn new(zk: Arc<ZooKeeper>, path: &str) -> ZkResult<CachedAtomicValue> {
+        let inner = Arc::new(Inner {
+            watch: RearmingWatch::new(&zk),
+            zk,
+            path: path.to_owned(),
+            cached: Mutex::new(None),
+        });
+        try!(Self::refresh(&inner));
+        let weak_inner = Arc::downgrade(&inner);
+        let subscription = rearm_on_connect(&inner.zk, &inner.watch, move || {
+            if weak_inner.upgrade().is_some_and(|inner| inner.watch.is_lost()) {
+                Self::reload(weak_inner.clone());
+            }
+        });
+        Ok(CachedAtomicValue {
+            inner,
+            subscription,
+        })
+    }
+
+    /// A watcher which reads the node again when it changes, unless the value was dropped.
+    fn watcher(inner: &Arc<Inner>) -> impl Fn(WatchedEvent) + Clone + Send + 'static {
+        let weak_inner = Arc::downgrade(inner);
+        move |event: WatchedEvent| {
+            if event.event_type == WatchedEventType::None {
+                return;
+            }
+            let weak_inner = weak_inner.clone();
+            // Don't block the event thread with the request
+            thread::spawn(move || Self::reload(weak_inner));
+        }
+    }
+
+    fn reload(inner: Weak<Inner>) {
+        if let Some(inner) = inner.upgrade() {
+            match Self::refresh(&inner) {
+                Ok(()) => {}
+                Err(err) if is_disconnected(err) => {
+                    warn!("lost the watch on {}, watching it again once connected", inner.path);
+                }
+                Err(err) => {
+                    warn!("error refreshing atomic value {}, no longer watching it: {:?}",
+                          inner.path,
+                          err);
+                    inner.watch.stop();
+                }
+            }
+        }
+    }
+
+    /// Read the node, leaving a watch which reads it again when it changes.
+    fn refresh(inner: &Arc<Inner>) -> ZkResult<()> {
+        let watcher = Self::watcher(inner);
+        let result = rearm(&inner.path, || inner.zk.get_data_w(&inner.path, watcher.clone()));
+        inner.watch.set_armed(result.is_ok());
+        match result {
+            Ok((data, stat)) => {
+                inner.store(data, &stat);
+                Ok(())
+            }
+            Err(ZkError::NoNode) => {
+                *inner.cached() = None;
+                Err(ZkError::NoNode)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Return the cached data of the node, or `None` if it does not exist.
+    pub fn get(&self) -> Option<Vec<u8>> {
+        self.inner.cached().as_ref().map(|(data, _)| data.clone())
+    }
+
+    /// Replace the data of the node with `transform` applied to the current data. Return the
+    /// `Stat` of the node after the update.
+    ///
+    /// `transform` is called again for every retry after a concurrent update, up to
+    /// `ZooKeeperBuilder::max_update_attempts` times.
+    ///
+    /// # Errors
+    /// If the node does not exist, `Err(ZkError::NoNode)` will be returned.
+    ///
+    /// If the node kept changing concurrently, `Err(ZkError::BadVersion)` will be returned.
+    pub fn update<F>(&self, transform: F) -> ZkResult<Stat>
+        where F: Fn(&[u8]) -> Vec<u8>
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let current = self.inner.cached().clone();
+            let (data, version) = match current {
+                Some(current) => current,
+                None => {
+                    let (data, stat) = try!(self.inner.zk.get_data(&self.inner.path, false));
+                    self.inner.store(data.clone(), &stat);
+                    (data, stat.version)
+                }
+            };
+
+            let data = transform(&data);
+            match self.inner.zk.set_data(&self.inner.path, data.clone(), Some(version)) {
+                Ok(stat) => {
+                    self.inner.store(data, &stat);
+                    return Ok(stat);
+                }
+                Err(ZkError::BadVersion) if attempts < self.inner.zk.max_update_attempts() => {
+                    debug!("{} changed concurrently, reading it again", self.inner.path);
+                    *self.inner.cached() = None;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Set the data of the node, regardless of its current data.
+    pub fn set(&self, data: Vec<u8>) -> ZkResult<Stat> {
+        self.update(|_| data.clone())
+    }
+}
+
+impl Drop for CachedAtomicValue {
+    fn drop(&mut self) {
+        self.inner.zk.remove_listener(self.subscription);
+    }
+}
+
+impl Inner {
+    fn cached(&self) -> MutexGuard<'_, Option<(Vec<u8>, i32)>> {
+        self.cached.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Update the cache unless it already holds a newer version.
+    fn store(&self, data: Vec<u8>, stat: &Stat) {
+        let mut cached = self.cached();
+        match *cached {
+            Some((_, version)) if version > stat.version => {}
+            _ => *cached = Some((data, stat.version)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CachedAtomicValue;
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use data::Stat;
+    use proto::{BufferReader, StringReader, WriteTo};
+    use std::sync::Arc;
+    use std::thread;
+    use testing::{connected, data_changed, reply_data, stat, MemoryServer};
+    use zookeeper::ZooKeeperBuilder;
+
+    /// Answer a setData request of `data` with `version`, with the stat of the next version.
+    fn reply_set_data(server: &MemoryServer, zxid: i64, data: &[u8], version: i32) {
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 5);
+        assert_eq!(request.read_string().unwrap(), "/counter");
+        assert_eq!(request.read_buffer().unwrap(), data);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), version);
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(zxid).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        Stat { version: version + 1, ..stat(5, zxid) }.write_to(&mut frame).unwrap();
+        server.write_frame(&frame);
+    }
+
+    #[test]
+    fn consecutive_updates_use_the_cached_version() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || CachedAtomicValue::new(zk1, "/counter").unwrap());
+        reply_data(&server, 10, 0, &[0]);
+        let counter = Arc::new(client.join().unwrap());
+
+        // each update writes right away, with the version the last one returned
+        for i in 0..3u8 {
+            let counter1 = counter.clone();
+            let client = thread::spawn(move || counter1.update(|data| vec![data[0] + 1]));
+            reply_set_data(&server, 11 + i as i64, &[i + 1], i as i32);
+            assert_eq!(client.join().unwrap().unwrap().version, i as i32 + 1);
+        }
+        assert_eq!(counter.get(), Some(vec![3]));
+
+        // once the value is dropped, a change no longer reads the node again
+        drop(counter);
+        data_changed(&server, 20, "/counter");
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 21, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+}
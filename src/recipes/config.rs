@@ -0,0 +1,408 @@
+//! Typed configuration stored in a znode, reloaded when it changes.
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use consts::{WatchedEventType, ZkError};
+use listeners::{ListenerSet, Subscription};
+use super::{is_disconnected, rearm, rearm_on_connect, RearmingWatch};
+use watch::WatchedEvent;
+use zookeeper::{ZkResult, ZooKeeper};
+
+/// Decodes the data of a configuration node into a `T`.
+///
+/// Any `Fn(&[u8]) -> ZkResult<T>` closure is a codec.
+pub trait Codec<T>: Send + Sync {
+    /// Decode the data of the node.
+    ///
+    /// # Errors
+    /// Return `Err(ZkError::MarshallingError)` if `data` is not a valid `T`; the previous value of
+    /// the config is kept then.
+    fn decode(&self, data: &[u8]) -> ZkResult<T>;
+}
+
+impl<T, F> Codec<T> for F
+    where F: Fn(&[u8]) -> ZkResult<T> + Send + Sync
+{
+    fn decode(&self, data: &[u8]) -> ZkResult<T> {
+        self(data)
+    }
+}
+
+/// An event of a `WatchedConfig`, passed to the listeners added with `on_change`.
+#[derive(Debug)]
+pub enum ConfigEvent<T> {
+    /// The node changed, and its data was decoded into the new value.
+    Changed(Arc<T>),
+    /// The node changed, but reading or decoding it failed. The previous value is kept.
+    Error(ZkError),
+    /// The watch on the node could not be left again, for another reason than a lost connection,
+    /// so no further changes are picked up. The previous value is kept.
+    Stopped(ZkError),
+}
+
+impl<T> Clone for ConfigEvent<T> {
+    fn clone(&self) -> ConfigEvent<T> {
+        match *self {
+            ConfigEvent::Changed(ref value) => ConfigEvent::Changed(value.clone()),
+            ConfigEvent::Error(err) => ConfigEvent::Error(err),
+            ConfigEvent::Stopped(err) => ConfigEvent::Stopped(err),
+        }
+    }
+}
+
+struct Inner<T>
+    where T: Send + Sync
+{
+    zk: Arc<ZooKeeper>,
+    path: String,
+    codec: Box<dyn Codec<T>>,
+    current: RwLock<Arc<T>>,
+    listeners: ListenerSet<ConfigEvent<T>>,
+    watch: RearmingWatch,
+}
+
+/// A value decoded from the data of a znode, which is decoded again whenever the node changes
+/// (like a configuration which is reloaded while running).
+///
+/// If the changed data can't be decoded (or the node was deleted), the last decoded value is kept
+/// and the listeners are notified of the error instead. The node keeps being watched, so a later
+/// valid change is picked up again. The server drops the watch whenever the client disconnects, so
+/// the node is read and watched again once the client is connected again; if the watch can't be
+/// left for another reason, the listeners are notified with `ConfigEvent::Stopped`.
+pub struct WatchedConfig<T>
+    where T: Send + Sync + 'static
+{
+    inner: Arc<Inner<T>>,
+    subscription: Subscription,
+}
+
+impl<T> WatchedConfig<T>
+    where T: Send + Sync + 'static
+{
+    /// Read and decode the node of the given `path`, watching it for changes.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned. If its data
+    /// can't be decoded, the error of the codec will be returned.
+    pub fn new<C>(zk: Arc<ZooKeeper>, path: &str, codec: C) -> ZkResult<WatchedConfig<T>>
+        where C: Codec<T> + 'static
+    {
+        let (data, _) = try!(zk.get_data(path, false));
+        let value = try!(codec.decode(&data));
+        let inner = Arc::new(Inner {
+            watch: RearmingWatch::new(&zk),
+            zk,
+            path: path.to_owned(),
+            codec: Box::new(codec),
+            current: RwLock::new(Arc::new(value)),
+            listeners: ListenerSet::new(),
+        });
+        // Read the node once more with a watch, in case it changed in the meantime
+        try!(Self::refresh(&inner));
+        let weak_inner = Arc::downgrade(&inner);
+        let subscription = rearm_on_connect(&inner.zk, &inner.watch, move || {
+            if weak_inner.upgrade().is_some_and(|inner| inner.watch.is_lost()) {
+                Self::reload(weak_inner.clone());
+            }
+        });
+        Ok(WatchedConfig {
+            inner,
+            subscription,
+        })
+    }
+
+    /// A watcher which reloads the node when it changes.
+    fn watcher(inner: &Arc<Inner<T>>) -> impl Fn(WatchedEvent) + Send + 'static {
+        let weak_inner = Arc::downgrade(inner);
+        move |event: WatchedEvent| {
+            if event.event_type == WatchedEventType::None {
+                return;
+            }
+            let weak_inner = weak_inner.clone();
+            // Don't block the event thread with the request
+            thread::spawn(move || Self::reload(weak_inner));
+        }
+    }
+
+    /// Read the node, leaving a watch which reads it again when it changes.
+    fn watch(inner: &Arc<Inner<T>>) -> ZkResult<Vec<u8>> {
+        let result = rearm(&inner.path, || inner.zk.get_data_w(&inner.path, Self::watcher(inner)));
+        inner.watch.set_armed(result.is_ok());
+        result.map(|(data, _)| data)
+    }
+
+    /// Decode the data returned by `watch` into the current value.
+    fn refresh(inner: &Arc<Inner<T>>) -> ZkResult<()> {
+        let data = try!(Self::watch(inner));
+        let value = Arc::new(try!(inner.codec.decode(&data)));
+        *inner.current.write().unwrap_or_else(|e| e.into_inner()) = value;
+        Ok(())
+    }
+
+    fn reload(inner: Weak<Inner<T>>) {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let data = match Self::watch(&inner) {
+            Ok(data) => data,
+            Err(ZkError::NoNode) => {
+                warn!("{} was deleted, keeping the last value", inner.path);
+                inner.listeners.notify(&ConfigEvent::Error(ZkError::NoNode));
+                // Wait for the node to be created again
+                let watcher = || inner.zk.exists_w(&inner.path, Self::watcher(&inner));
+                match rearm(&inner.path, watcher) {
+                    Ok(None) => inner.watch.set_armed(true),
+                    // Created again in the meantime, the watch covers later changes only
+                    Ok(Some(_)) => Self::reload(Arc::downgrade(&inner)),
+                    Err(err) => Self::lose(&inner, err),
+                }
+                return;
+            }
+            Err(err) => return Self::lose(&inner, err),
+        };
+        match inner.codec.decode(&data) {
+            Ok(value) => {
+                let value = Arc::new(value);
+                *inner.current.write().unwrap_or_else(|e| e.into_inner()) = value.clone();
+                inner.listeners.notify(&ConfigEvent::Changed(value));
+            }
+            Err(err) => {
+                warn!("error decoding {}, keeping the last value: {:?}", inner.path, err);
+                inner.listeners.notify(&ConfigEvent::Error(err));
+            }
+        }
+    }
+
+    /// Handle the error of leaving the watch again, which is given up on unless the connection is
+    /// gone.
+    fn lose(inner: &Inner<T>, err: ZkError) {
+        if is_disconnected(err) {
+            warn!("lost the watch on {}, watching it again once connected", inner.path);
+            inner.watch.set_armed(false);
+            return;
+        }
+        error!("error watching {}, no longer picking up changes: {:?}", inner.path, err);
+        inner.watch.stop();
+        inner.listeners.notify(&ConfigEvent::Stopped(err));
+    }
+
+    /// Whether a watch on the node is left, so its changes are picked up. `false` while the client
+    /// is not connected (until the node was watched again), and once the listeners were notified
+    /// with `ConfigEvent::Stopped`.
+    pub fn is_watching(&self) -> bool {
+        self.inner.watch.is_armed()
+    }
+
+    /// Return the latest value which was decoded successfully.
+    pub fn current(&self) -> Arc<T> {
+        self.inner.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Add a listener which is notified of each change of the node, from a background thread.
+    pub fn on_change<Listener>(&self, listener: Listener) -> Subscription
+        where Listener: Fn(ConfigEvent<T>) + Send + 'static
+    {
+        self.inner.listeners.subscribe(listener)
+    }
+
+    /// Remove a listener added with `on_change`.
+    pub fn remove_listener(&self, sub: Subscription) {
+        self.inner.listeners.unsubscribe(sub)
+    }
+}
+
+impl<T> Drop for WatchedConfig<T>
+    where T: Send + Sync + 'static
+{
+    fn drop(&mut self) {
+        self.inner.zk.remove_listener(self.subscription);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigEvent, WatchedConfig};
+    use byteorder::{BigEndian, ReadBytesExt};
+    use consts::ZkError;
+    use consts::ZkState;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use testing::{connected, data_changed, memory_pipes, reply_data};
+    use zookeeper::{ZooKeeper, ZooKeeperBuilder};
+
+    /// Wait until the client counts `data` data watches.
+    fn wait_for_data_watches(zk: &ZooKeeper, data: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while zk.registered_watches().data != data {
+            assert!(Instant::now() < deadline, "{:?}", zk.registered_watches());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn watched_config_retries_rearming_the_watch() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            WatchedConfig::new(zk1, "/cfg", |data: &[u8]| Ok(data.to_vec())).unwrap()
+        });
+        reply_data(&server, 10, 0, b"1");
+        reply_data(&server, 11, 0, b"1");
+        let config = client.join().unwrap();
+        assert!(config.is_watching());
+        let (event_tx, event_rx) = mpsc::channel();
+        config.on_change(move |event| event_tx.send(event).unwrap());
+
+        // the connection is lost while leaving the watch again, which is retried
+        data_changed(&server, 12, "/cfg");
+        reply_data(&server, 13, -4, b"");
+        reply_data(&server, 14, 0, b"2");
+        match event_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConfigEvent::Changed(value) => assert_eq!(*value, b"2".to_vec()),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(config.is_watching());
+
+        // the watch keeps working after the retry, until leaving it fails for good
+        data_changed(&server, 15, "/cfg");
+        reply_data(&server, 16, -102, b"");
+        match event_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConfigEvent::Stopped(err) => assert_eq!(err, ZkError::NoAuth),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(!config.is_watching());
+        assert_eq!(*config.current(), b"2".to_vec());
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 20, 0);
+        });
+        drop(config);
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watched_config_rearms_the_watch_once_connected() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let zk = Arc::new(zk);
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            WatchedConfig::new(zk1, "/cfg", |data: &[u8]| Ok(data.to_vec())).unwrap()
+        });
+        reply_data(&first, 10, 0, b"1");
+        reply_data(&first, 11, 0, b"1");
+        let config = client.join().unwrap();
+        wait_for_data_watches(&zk, 1);
+        assert_eq!(zk.registered_watches().rearming, 1);
+        let (event_tx, event_rx) = mpsc::channel();
+        config.on_change(move |event| event_tx.send(event).unwrap());
+
+        // leaving the watch again keeps failing while the connection is lost
+        data_changed(&first, 12, "/cfg");
+        for zxid in 13..16 {
+            reply_data(&first, zxid, -4, b"");
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while config.is_watching() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(zk.registered_watches().data, 0);
+        assert_eq!(zk.registered_watches().rearming, 1);
+
+        // once connected again, the node is read and watched again
+        first.close();
+        let second = servers.remove(0);
+        second.accept();
+        reply_data(&second, 16, 0, b"2");
+        match event_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConfigEvent::Changed(value) => assert_eq!(*value, b"2".to_vec()),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(config.is_watching());
+        wait_for_data_watches(&zk, 1);
+
+        drop(config);
+        assert_eq!(zk.registered_watches().rearming, 0);
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 20, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watched_config_rearms_an_idle_watch_once_reconnected() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let zk = Arc::new(zk);
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            WatchedConfig::new(zk1, "/cfg", |data: &[u8]| Ok(data.to_vec())).unwrap()
+        });
+        reply_data(&first, 10, 0, b"1");
+        reply_data(&first, 11, 0, b"1");
+        let config = client.join().unwrap();
+        assert!(config.is_watching());
+        let (event_tx, event_rx) = mpsc::channel();
+        config.on_change(move |event| event_tx.send(event).unwrap());
+
+        // the server drops the watch along with the connection, though no event was pending
+        first.close();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while config.is_watching() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // once connected again, the node is read and watched again
+        let second = servers.remove(0);
+        second.accept();
+        reply_data(&second, 12, 0, b"2");
+        match event_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConfigEvent::Changed(value) => assert_eq!(*value, b"2".to_vec()),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(config.is_watching());
+
+        drop(config);
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 20, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+}
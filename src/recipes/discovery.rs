@@ -0,0 +1,358 @@
+//! Service discovery, similar to
+//! [Curator's](http://curator.apache.org/curator-x-discovery/index.html).
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use acl::Acl;
+use consts::{CreateMode, ZkError, ZkState};
+use listeners::Subscription;
+use paths::make_path;
+use watch::WatchedEvent;
+use zookeeper::{ZkResult, ZooKeeper};
+use zookeeper_ext::ZooKeeperExt;
+
+/// The default base path of the services.
+#[cfg(feature = "serde_json")]
+const DEFAULT_BASE_PATH: &str = "/services";
+
+/// An instance of a service, registered at `<base path>/<name>/<id>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceInstance {
+    /// The name of the service.
+    pub name: String,
+    /// The id of this instance, unique among the instances of the service.
+    pub id: String,
+    /// The host the instance is reachable at.
+    pub host: String,
+    /// The port the instance is reachable at.
+    pub port: u16,
+    /// Additional metadata of the instance.
+    pub payload: String,
+}
+
+/// Converts the metadata of a `ServiceInstance` to and from the data of its node.
+pub trait InstanceCodec: Send + Sync {
+    /// Encode `instance` as the data of its node.
+    fn encode(&self, instance: &ServiceInstance) -> ZkResult<Vec<u8>>;
+
+    /// Decode the data of the node of the instance `id` of the service `name`.
+    ///
+    /// # Errors
+    /// Return `Err(ZkError::MarshallingError)` if `data` is not a valid instance; `query` skips
+    /// the instance then.
+    fn decode(&self, name: &str, id: &str, data: &[u8]) -> ZkResult<ServiceInstance>;
+}
+
+/// Encodes instances as JSON objects with the fields `name`, `id`, `address`, `port` and
+/// `payload`. Requires the `serde_json` feature (enabled by default).
+#[cfg(feature = "serde_json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "serde_json")]
+impl InstanceCodec for JsonCodec {
+    fn encode(&self, instance: &ServiceInstance) -> ZkResult<Vec<u8>> {
+        let json = json!({
+            "name": instance.name,
+            "id": instance.id,
+            "address": instance.host,
+            "port": instance.port,
+            "payload": instance.payload,
+        });
+        ::serde_json::to_vec(&json).map_err(|_| ZkError::MarshallingError)
+    }
+
+    fn decode(&self, name: &str, id: &str, data: &[u8]) -> ZkResult<ServiceInstance> {
+        let json: ::serde_json::Value = try!(::serde_json::from_slice(data)
+                                                 .map_err(|_| ZkError::MarshallingError));
+        let host = try!(json["address"].as_str().ok_or(ZkError::MarshallingError));
+        let port = try!(json["port"].as_u64().ok_or(ZkError::MarshallingError));
+        if port > u64::from(u16::MAX) {
+            return Err(ZkError::MarshallingError);
+        }
+        Ok(ServiceInstance {
+            name: name.to_owned(),
+            id: id.to_owned(),
+            host: host.to_owned(),
+            port: port as u16,
+            payload: json["payload"].as_str().unwrap_or("").to_owned(),
+        })
+    }
+}
+
+/// The queried instances of each service, along with a generation counter per service which
+/// tells whether a watch invalidated the instances while they were read.
+#[derive(Default)]
+struct Cache {
+    instances: HashMap<String, Vec<ServiceInstance>>,
+    generations: HashMap<String, u64>,
+}
+
+impl Cache {
+    /// Drop the instances of every service, including those which are being read.
+    fn invalidate(&mut self) {
+        self.instances.clear();
+        for generation in self.generations.values_mut() {
+            *generation += 1;
+        }
+    }
+}
+
+/// Registers service instances as ephemeral nodes at `<base path>/<name>/<id>`, and queries the
+/// instances registered by others.
+///
+/// Registered instances are created again when the client reconnects, in case the session
+/// expired in the meantime. Queried instances are cached until a child watch reports a change of
+/// the instances of the service, or until the client disconnects, as the server drops the watches
+/// along with the connection.
+pub struct ServiceDiscovery {
+    zk: Arc<ZooKeeper>,
+    base_path: String,
+    codec: Arc<dyn InstanceCodec>,
+    registered: Arc<Mutex<Vec<ServiceInstance>>>,
+    cache: Arc<Mutex<Cache>>,
+    subscription: Subscription,
+}
+
+impl ServiceDiscovery {
+    /// Create a discovery with the base path `/services`, encoding instances as JSON.
+    #[cfg(feature = "serde_json")]
+    pub fn new(zk: Arc<ZooKeeper>) -> ServiceDiscovery {
+        Self::with_codec(zk, DEFAULT_BASE_PATH, JsonCodec)
+    }
+
+    /// Create a discovery with the given base path, encoding instances with `codec`.
+    pub fn with_codec<C>(zk: Arc<ZooKeeper>, base_path: &str, codec: C) -> ServiceDiscovery
+        where C: InstanceCodec + 'static
+    {
+        let codec: Arc<dyn InstanceCodec> = Arc::new(codec);
+        let registered = Arc::new(Mutex::new(Vec::new()));
+        let cache = Arc::new(Mutex::new(Cache::default()));
+
+        let weak_zk = Arc::downgrade(&zk);
+        let (listener_path, listener_codec) = (base_path.to_owned(), codec.clone());
+        let (listener_registered, listener_cache) = (registered.clone(), cache.clone());
+        let subscription = zk.add_listener(move |state| {
+            if state != ZkState::Connected {
+                // The instances are no longer watched
+                listener_cache.lock().unwrap_or_else(|e| e.into_inner()).invalidate();
+                return;
+            }
+            let (zk, base_path) = (weak_zk.clone(), listener_path.clone());
+            let (codec, registered) = (listener_codec.clone(), listener_registered.clone());
+            // Don't block the IO thread with the requests
+            thread::spawn(move || {
+                let zk = match zk.upgrade() {
+                    Some(zk) => zk,
+                    None => return,
+                };
+                let instances = registered.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                for instance in instances {
+                    match Self::create(&zk, &base_path, &*codec, &instance) {
+                        Ok(()) | Err(ZkError::NodeExists) => {}
+                        Err(err) => warn!("error registering {:?} again: {:?}", instance, err),
+                    }
+                }
+            });
+        });
+
+        ServiceDiscovery {
+            zk,
+            base_path: base_path.to_owned(),
+            codec,
+            registered,
+            cache,
+            subscription,
+        }
+    }
+
+    fn instance_path(base_path: &str, name: &str, id: &str) -> String {
+        make_path(&make_path(base_path, name), id)
+    }
+
+    fn create(zk: &ZooKeeper,
+              base_path: &str,
+              codec: &dyn InstanceCodec,
+              instance: &ServiceInstance)
+              -> ZkResult<()> {
+        let data = try!(codec.encode(instance));
+        try!(zk.ensure_path(&make_path(base_path, &instance.name)));
+        try!(zk.create(&Self::instance_path(base_path, &instance.name, &instance.id),
+                       data,
+                       Acl::open_unsafe().clone(),
+                       CreateMode::Ephemeral));
+        Ok(())
+    }
+
+    /// Register `instance`, creating its ephemeral node.
+    ///
+    /// # Errors
+    /// If an instance with the same name and id is registered already,
+    /// `Err(ZkError::NodeExists)` will be returned.
+    pub fn register(&self, instance: ServiceInstance) -> ZkResult<()> {
+        try!(Self::create(&self.zk, &self.base_path, &*self.codec, &instance));
+        self.registered.lock().unwrap_or_else(|e| e.into_inner()).push(instance);
+        Ok(())
+    }
+
+    /// Unregister the instance `id` of the service `name`, deleting its node.
+    pub fn unregister(&self, name: &str, id: &str) -> ZkResult<()> {
+        self.registered
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|instance| instance.name != name || instance.id != id);
+        match self.zk.delete(&Self::instance_path(&self.base_path, name, id), None) {
+            Ok(()) | Err(ZkError::NoNode) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Return the registered instances of the service `name`, in no particular order.
+    ///
+    /// Instances whose data can't be decoded are skipped.
+    pub fn query(&self, name: &str) -> ZkResult<Vec<ServiceInstance>> {
+        let generation = {
+            let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(instances) = cache.instances.get(name) {
+                return Ok(instances.clone());
+            }
+            // Tracked from now on, so a disconnect invalidates the instances being read
+            *cache.generations.entry(name.to_owned()).or_insert(0)
+        };
+
+        let cache = self.cache.clone();
+        let watched_name = name.to_owned();
+        let watcher = move |_: WatchedEvent| {
+            let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+            cache.instances.remove(&watched_name);
+            *cache.generations.entry(watched_name.clone()).or_insert(0) += 1;
+        };
+        let service_path = make_path(&self.base_path, name);
+        let ids = match self.zk.get_children_w(&service_path, watcher) {
+            Ok(ids) => ids,
+            Err(ZkError::NoNode) => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut instances = Vec::with_capacity(ids.len());
+        for id in ids {
+            let data = match self.zk.get_data(&make_path(&service_path, &id), false) {
+                Ok((data, _)) => data,
+                Err(ZkError::NoNode) => continue,
+                Err(err) => return Err(err),
+            };
+            match self.codec.decode(name, &id, &data) {
+                Ok(instance) => instances.push(instance),
+                Err(err) => warn!("skipping instance {} of {}: {:?}", id, name, err),
+            }
+        }
+
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.generations.get(name).cloned().unwrap_or(0) == generation {
+            cache.instances.insert(name.to_owned(), instances.clone());
+        }
+        Ok(instances)
+    }
+}
+
+impl Drop for ServiceDiscovery {
+    fn drop(&mut self) {
+        self.zk.remove_listener(self.subscription);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstanceCodec, ServiceDiscovery, ServiceInstance};
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use consts::{ZkError, ZkState};
+    use proto::{StringReader, WriteTo};
+    use std::str;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+    use testing::{memory_pipes, reply_data, MemoryServer};
+    use zookeeper::{ZkResult, ZooKeeperBuilder};
+
+    /// Stores the host of an instance as the data of its node.
+    struct HostCodec;
+
+    impl InstanceCodec for HostCodec {
+        fn encode(&self, instance: &ServiceInstance) -> ZkResult<Vec<u8>> {
+            Ok(instance.host.as_bytes().to_vec())
+        }
+
+        fn decode(&self, name: &str, id: &str, data: &[u8]) -> ZkResult<ServiceInstance> {
+            let host = try!(str::from_utf8(data).map_err(|_| ZkError::MarshallingError));
+            Ok(ServiceInstance {
+                name: name.to_owned(),
+                id: id.to_owned(),
+                host: host.to_owned(),
+                port: 0,
+                payload: String::new(),
+            })
+        }
+    }
+
+    /// Answer a getChildren request on `/services/api` with the single instance `one`.
+    fn reply_children(server: &MemoryServer, zxid: i64) {
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 8);
+        assert_eq!(request.read_string().unwrap(), "/services/api");
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(zxid).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        vec!["one".to_owned()].write_to(&mut frame).unwrap();
+        server.write_frame(&frame);
+    }
+
+    #[test]
+    fn disconnect_invalidates_the_queried_instances() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let zk = Arc::new(zk);
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let discovery = Arc::new(ServiceDiscovery::with_codec(zk.clone(), "/services", HostCodec));
+        let query = |discovery: &Arc<ServiceDiscovery>| {
+            let discovery = discovery.clone();
+            thread::spawn(move || discovery.query("api").unwrap()[0].host.clone())
+        };
+        let client = query(&discovery);
+        reply_children(&first, 10);
+        reply_data(&first, 10, 0, b"a");
+        assert_eq!(client.join().unwrap(), "a");
+
+        // the server drops the child watch along with the connection, so the instances are read
+        // again once connected
+        first.close();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+        let second = servers.remove(0);
+        second.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+        let client = query(&discovery);
+        reply_children(&second, 11);
+        reply_data(&second, 11, 0, b"b");
+        assert_eq!(client.join().unwrap(), "b");
+
+        drop(discovery);
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 20, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+}
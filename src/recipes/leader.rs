@@ -0,0 +1,221 @@
+//! Leader election, like the `LeaderLatch` of Curator.
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use acl::Acl;
+use consts::{CreateMode, WatchedEventType, ZkError, ZkState};
+use io::ConnectionState;
+use listeners::{ListenerSet, Subscription};
+use paths::make_path;
+use recipes::{watch_predecessor, Contender, REARM_RETRY_DELAY};
+use watch::WatchedEvent;
+use zookeeper::{ZkResult, ZooKeeper};
+use zookeeper_ext::ZooKeeperExt;
+
+struct Inner {
+    zk: Arc<ZooKeeper>,
+    path: String,
+    /// The node of this latch, `None` unless started.
+    node: Mutex<Option<String>>,
+    /// Held while checking the leadership, so a lost node is created again only once.
+    checking: Mutex<()>,
+    leader: AtomicBool,
+    listeners: ListenerSet<bool>,
+}
+
+/// Takes part in an election among all latches which use the same path, of which at most one is
+/// the leader at a time.
+///
+/// Every latch creates an ephemeral sequential node under the path, and the one with the lowest
+/// sequence number is the leader. The others each watch the node right before their own, so only
+/// the next latch checks again when the leader goes away. While the connection is lost, the latch
+/// gives up its leadership, as its session may expire in the meantime; after reconnecting it
+/// checks again, and creates its node again if the session did expire.
+pub struct LeaderLatch {
+    inner: Arc<Inner>,
+    subscription: Mutex<Option<Subscription>>,
+}
+
+impl LeaderLatch {
+    /// Create a latch for the election at the given `path`, which is created when the latch is
+    /// started.
+    pub fn new(zk: Arc<ZooKeeper>, path: &str) -> LeaderLatch {
+        LeaderLatch {
+            inner: Arc::new(Inner {
+                zk,
+                path: path.to_owned(),
+                node: Mutex::new(None),
+                checking: Mutex::new(()),
+                leader: AtomicBool::new(false),
+                listeners: ListenerSet::new(),
+            }),
+            subscription: Mutex::new(None),
+        }
+    }
+
+    /// Join the election, creating the node of the latch. Does nothing if the latch is started
+    /// already.
+    pub fn start(&self) -> ZkResult<()> {
+        let mut subscription = self.subscription.lock().unwrap_or_else(|e| e.into_inner());
+        if subscription.is_some() {
+            return Ok(());
+        }
+        try!(self.inner.zk.ensure_path(&self.inner.path));
+        let node = try!(Self::create(&self.inner));
+        *self.inner.node.lock().unwrap_or_else(|e| e.into_inner()) = Some(node);
+
+        let weak_inner = Arc::downgrade(&self.inner);
+        *subscription = Some(self.inner.zk.add_listener(move |state| {
+            match state {
+                ZkState::Connected => {
+                    let weak_inner = weak_inner.clone();
+                    // Don't block the IO thread with the requests
+                    thread::spawn(move || Self::check(weak_inner));
+                }
+                _ => {
+                    if let Some(inner) = weak_inner.upgrade() {
+                        Self::set_leader(&inner, false);
+                    }
+                }
+            }
+        }));
+        Self::check(Arc::downgrade(&self.inner));
+        Ok(())
+    }
+
+    fn create(inner: &Inner) -> ZkResult<String> {
+        inner.zk.create_protected(&make_path(&inner.path, "latch-"),
+                                  vec![],
+                                  Acl::open_unsafe().clone(),
+                                  CreateMode::EphemeralSequential)
+    }
+
+    /// Check whether the node of the latch has the lowest sequence number, watching its
+    /// predecessor otherwise.
+    fn check(inner: Weak<Inner>) {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let _checking = inner.checking.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(err) = Self::try_check(&inner) {
+            Self::set_leader(&inner, false);
+            if inner.zk.connection_state() != ConnectionState::Connected {
+                // The state listener checks again once connected
+                warn!("error checking the leadership of {}: {:?}", inner.path, err);
+                return;
+            }
+            warn!("error checking the leadership of {}, retrying: {:?}", inner.path, err);
+            let weak_inner = Arc::downgrade(&inner);
+            thread::spawn(move || {
+                thread::sleep(REARM_RETRY_DELAY);
+                Self::check(weak_inner)
+            });
+        }
+    }
+
+    fn try_check(inner: &Arc<Inner>) -> ZkResult<()> {
+        loop {
+            let node = match *inner.node.lock().unwrap_or_else(|e| e.into_inner()) {
+                Some(ref node) => node.clone(),
+                // Closed
+                None => return Ok(()),
+            };
+            let weak_inner = Arc::downgrade(inner);
+            let watcher = || {
+                let weak_inner = weak_inner.clone();
+                move |event: WatchedEvent| {
+                    if event.event_type == WatchedEventType::None {
+                        return;
+                    }
+                    let weak_inner = weak_inner.clone();
+                    // Don't block the event thread with the requests
+                    thread::spawn(move || Self::check(weak_inner));
+                }
+            };
+            match try!(watch_predecessor(&inner.zk, &inner.path, &node, watcher)) {
+                Contender::First => {
+                    Self::set_leader(inner, true);
+                    return Ok(());
+                }
+                Contender::Waiting(predecessor) => {
+                    Self::set_leader(inner, false);
+                    debug!("{} waits for {}", node, predecessor);
+                    return Ok(());
+                }
+                Contender::Gone => {
+                    // The session expired, taking the node with it
+                    info!("{} is gone, joining the election again", node);
+                    let node = try!(Self::create(inner));
+                    let mut current = inner.node.lock().unwrap_or_else(|e| e.into_inner());
+                    if current.is_none() {
+                        // Closed in the meantime
+                        drop(current);
+                        return Self::delete(inner, &node);
+                    }
+                    *current = Some(node);
+                }
+            }
+        }
+    }
+
+    fn delete(inner: &Inner, node: &str) -> ZkResult<()> {
+        match inner.zk.delete(node, None) {
+            Ok(()) | Err(ZkError::NoNode) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn set_leader(inner: &Inner, leader: bool) {
+        if inner.leader.swap(leader, Ordering::SeqCst) != leader {
+            info!("{} leadership of {}", if leader { "acquired" } else { "lost" }, inner.path);
+            inner.listeners.notify(&leader);
+        }
+    }
+
+    /// Whether the latch is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.inner.leader.load(Ordering::SeqCst)
+    }
+
+    /// Return the path of the node of the latch, `None` unless it is started.
+    pub fn node(&self) -> Option<String> {
+        self.inner.node.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Add a listener which is notified with `true` when the latch becomes the leader, and with
+    /// `false` when it loses the leadership. It is called from a background thread.
+    pub fn on_change<Listener>(&self, listener: Listener) -> Subscription
+        where Listener: Fn(bool) + Send + 'static
+    {
+        self.inner.listeners.subscribe(listener)
+    }
+
+    /// Remove a listener added with `on_change`.
+    pub fn remove_listener(&self, sub: Subscription) {
+        self.inner.listeners.unsubscribe(sub)
+    }
+
+    /// Leave the election, deleting the node of the latch. The latch can be started again.
+    pub fn close(&self) -> ZkResult<()> {
+        let subscription = self.subscription.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(subscription) = subscription {
+            self.inner.zk.remove_listener(subscription);
+        }
+        let _checking = self.inner.checking.lock().unwrap_or_else(|e| e.into_inner());
+        let node = self.inner.node.lock().unwrap_or_else(|e| e.into_inner()).take();
+        Self::set_leader(&self.inner, false);
+        match node {
+            Some(node) => Self::delete(&self.inner, &node),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for LeaderLatch {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            warn!("error leaving the election of {}: {:?}", self.inner.path, err);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! A distributed lock, like the `InterProcessMutex` of Curator.
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use acl::Acl;
+use consts::{CreateMode, ZkError, ZkState};
+use paths::make_path;
+use recipes::{watch_predecessor, Contender};
+use watch::WatchedEvent;
+use zookeeper::{ZkResult, ZooKeeper};
+use zookeeper_ext::ZooKeeperExt;
+
+/// A lock shared by all clients which use the same path, so at most one of them holds it at a
+/// time.
+///
+/// Every contender creates an ephemeral sequential node under the path, and the one with the
+/// lowest sequence number holds the lock. The others each watch the node right before their own,
+/// so releasing the lock wakes only the next contender. If the session of the holder ends, its
+/// node is deleted by the server, which releases the lock as well.
+pub struct ZkLock {
+    zk: Arc<ZooKeeper>,
+    path: String,
+}
+
+/// Holds a `ZkLock` until it is dropped, which deletes the node of the holder.
+pub struct LockGuard {
+    zk: Arc<ZooKeeper>,
+    node: String,
+}
+
+impl ZkLock {
+    /// Create a lock at the given `path`, which is created when the lock is first acquired.
+    pub fn new(zk: Arc<ZooKeeper>, path: &str) -> ZkLock {
+        ZkLock {
+            zk,
+            path: path.to_owned(),
+        }
+    }
+
+    /// Acquire the lock, blocking until it is held.
+    pub fn lock(&self) -> ZkResult<LockGuard> {
+        self.acquire(None).map(|guard| guard.expect("no deadline to miss"))
+    }
+
+    /// Acquire the lock like `lock`, but give up after `timeout`, returning `None` if the lock is
+    /// still held by another client.
+    pub fn try_lock(&self, timeout: Duration) -> ZkResult<Option<LockGuard>> {
+        self.acquire(Some(Instant::now() + timeout))
+    }
+
+    fn acquire(&self, deadline: Option<Instant>) -> ZkResult<Option<LockGuard>> {
+        try!(self.zk.ensure_path(&self.path));
+        let node = try!(self.zk.create_protected(&make_path(&self.path, "lock-"),
+                                                 vec![],
+                                                 Acl::open_unsafe().clone(),
+                                                 CreateMode::EphemeralSequential));
+        let guard = LockGuard {
+            zk: self.zk.clone(),
+            node,
+        };
+        // Dropping the guard withdraws from the contention
+        match self.wait(&guard.node, deadline) {
+            Ok(true) => Ok(Some(guard)),
+            Ok(false) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Wait until `node` has the lowest sequence number, returning `false` if the deadline passes
+    /// first.
+    fn wait(&self, node: &str, deadline: Option<Instant>) -> ZkResult<bool> {
+        let (tx, rx) = mpsc::channel();
+        let state_tx = tx.clone();
+        // The server drops the watch on the predecessor along with the connection, so look again
+        // once connected. Looking again fails once the session is gone.
+        let subscription = self.zk.add_listener(move |state| {
+            if state != ZkState::Connecting {
+                let _ = state_tx.send(());
+            }
+        });
+        let result = self.wait_for(node, deadline, &tx, &rx);
+        self.zk.remove_listener(subscription);
+        result
+    }
+
+    fn wait_for(&self,
+                node: &str,
+                deadline: Option<Instant>,
+                tx: &Sender<()>,
+                rx: &Receiver<()>)
+                -> ZkResult<bool> {
+        loop {
+            let watcher = || {
+                let tx = tx.clone();
+                move |_: WatchedEvent| {
+                    let _ = tx.send(());
+                }
+            };
+            let predecessor = match try!(watch_predecessor(&self.zk, &self.path, node, watcher)) {
+                Contender::First => return Ok(true),
+                Contender::Waiting(predecessor) => predecessor,
+                // The session ended, taking the node with it
+                Contender::Gone => return Err(ZkError::NoNode),
+            };
+            debug!("{} waits for {}", node, predecessor);
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    match rx.recv_timeout(deadline - now) {
+                        Ok(()) => {}
+                        Err(RecvTimeoutError::Timeout) => return Ok(false),
+                        Err(RecvTimeoutError::Disconnected) => return Err(ZkError::ConnectionLoss),
+                    }
+                }
+                None => {
+                    if rx.recv().is_err() {
+                        return Err(ZkError::ConnectionLoss);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl LockGuard {
+    /// Return the path of the node which holds the lock.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        match self.zk.delete(&self.node, None) {
+            Ok(()) | Err(ZkError::NoNode) => {}
+            Err(err) => warn!("error releasing lock {}: {:?}", self.node, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ZkLock;
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use consts::ZkState;
+    use proto::{StringReader, WriteTo};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+    use testing::{memory_pipes, stat, MemoryServer};
+    use zookeeper::ZooKeeperBuilder;
+
+    const NODE: &str = "/locks/_c_0-lock-0000000002";
+    const PREDECESSOR: &str = "lock-0000000001";
+
+    /// Answer the next request, which is of `opcode` on a path starting with `path`, with the
+    /// body written by `body`.
+    fn reply_to<F>(server: &MemoryServer, opcode: i32, path: &str, zxid: i64, body: F)
+        where F: FnOnce(&mut Vec<u8>)
+    {
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), opcode);
+        let request_path = request.read_string().unwrap();
+        assert!(request_path.starts_with(path), "{} on {}", opcode, request_path);
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(zxid).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        body(&mut frame);
+        server.write_frame(&frame);
+    }
+
+    fn children(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn lock_watches_the_predecessor_again_once_reconnected() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let lock = ZkLock::new(Arc::new(zk), "/locks");
+        let client = thread::spawn(move || lock.lock().unwrap());
+        reply_to(&first, 1, "/locks", 10, |frame| "/locks".to_owned().write_to(frame).unwrap());
+        reply_to(&first, 1, "/locks/_c_", 11, |frame| NODE.to_owned().write_to(frame).unwrap());
+        reply_to(&first, 8, "/locks", 11, |frame| {
+            children(&[PREDECESSOR, "_c_0-lock-0000000002"]).write_to(frame).unwrap()
+        });
+        reply_to(&first, 3, "/locks/lock-0000000001", 11, |frame| {
+            stat(5, 5).write_to(frame).unwrap()
+        });
+
+        // the watch on the predecessor is lost along with the connection, and the predecessor is
+        // deleted meanwhile
+        first.close();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+        let second = servers.remove(0);
+        second.accept();
+        reply_to(&second, 8, "/locks", 12, |frame| {
+            children(&["_c_0-lock-0000000002"]).write_to(frame).unwrap()
+        });
+        let guard = client.join().unwrap();
+        assert_eq!(guard.node(), NODE);
+
+        let server = thread::spawn(move || {
+            reply_to(&second, 2, NODE, 13, |_| {});
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 20, 0);
+        });
+        drop(guard);
+        server.join().unwrap();
+    }
+}
@@ -1,2 +1,149 @@
 //! Extended ZooKeeper recipes from [Apache Curator](http://curator.apache.org/).
+pub mod atomic;
 pub mod cache;
+pub mod config;
+pub mod discovery;
+pub mod leader;
+pub mod lock;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use consts::{ZkError, ZkState};
+use listeners::Subscription;
+use paths::{make_path, sort_by_sequence, split_path};
+use watch::Watcher;
+use zookeeper::{ZkResult, ZooKeeper};
+
+/// How often a recipe tries to re-arm a watch before giving up on it.
+const REARM_ATTEMPTS: usize = 3;
+/// The delay between the attempts to re-arm a watch.
+const REARM_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Call `rearm`, which leaves a watch on `path`, again after transient errors (a lost connection
+/// or a timeout), up to `REARM_ATTEMPTS` times. Any other error means the watch is gone.
+pub(crate) fn rearm<T, F>(path: &str, mut rearm: F) -> ZkResult<T>
+    where F: FnMut() -> ZkResult<T>
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match rearm() {
+            Err(err @ ZkError::ConnectionLoss) |
+            Err(err @ ZkError::OperationTimeout) if attempts < REARM_ATTEMPTS => {
+                debug!("error re-arming the watch on {}, retrying: {:?}", path, err);
+                thread::sleep(REARM_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether re-arming a watch failed because the connection is gone, so the watch can be left
+/// again once the client is connected.
+pub(crate) fn is_disconnected(err: ZkError) -> bool {
+    matches!(err, ZkError::ConnectionLoss | ZkError::SessionExpired)
+}
+
+/// The watch of a recipe which is left again after each event. It counts as a re-arming watch in
+/// `ZooKeeper::registered_watches` until it is stopped or dropped.
+struct RearmingWatch {
+    /// Shared with the state listener of `rearm_on_connect`, which clears it on a disconnect.
+    armed: Arc<AtomicBool>,
+    stopped: AtomicBool,
+    count: Arc<AtomicUsize>,
+}
+
+impl RearmingWatch {
+    fn new(zk: &ZooKeeper) -> RearmingWatch {
+        let count = zk.rearming_watches();
+        count.fetch_add(1, Ordering::SeqCst);
+        RearmingWatch {
+            armed: Arc::new(AtomicBool::new(false)),
+            stopped: AtomicBool::new(false),
+            count,
+        }
+    }
+
+    /// Record whether leaving the watch succeeded.
+    fn set_armed(&self, armed: bool) {
+        self.armed.store(armed, Ordering::SeqCst);
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.load(Ordering::SeqCst)
+    }
+
+    /// Whether the watch was lost along with the connection, so it is to be left again.
+    fn is_lost(&self) -> bool {
+        !self.is_armed() && !self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Give up on the watch, as leaving it failed for good.
+    fn stop(&self) {
+        self.set_armed(false);
+        if !self.stopped.swap(true, Ordering::SeqCst) {
+            self.count.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for RearmingWatch {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Add a state listener which calls `rearm` from a background thread whenever the client
+/// connects, to leave the watches again which were lost along with the connection. The server
+/// drops the watches of a client when it disconnects, even while the client is idle, so `watch`
+/// counts as lost as soon as the client is no longer connected.
+fn rearm_on_connect<F>(zk: &ZooKeeper, watch: &RearmingWatch, rearm: F) -> Subscription
+    where F: Fn() + Send + Sync + 'static
+{
+    let armed = watch.armed.clone();
+    let rearm = Arc::new(rearm);
+    zk.add_listener(move |state| {
+        if state == ZkState::Connected {
+            let rearm = rearm.clone();
+            // Don't block the IO thread with the requests
+            thread::spawn(move || rearm());
+        } else {
+            armed.store(false, Ordering::SeqCst);
+        }
+    })
+}
+
+/// Where a contender stands, which created an ephemeral sequential node to queue up.
+enum Contender {
+    /// The node has the lowest sequence number.
+    First,
+    /// The given node comes right before, and is watched.
+    Waiting(String),
+    /// The node is gone, as its session ended.
+    Gone,
+}
+
+/// Find where `node` stands among the children of `dir`, leaving a watch made by `watcher` on its
+/// predecessor unless it is first.
+fn watch_predecessor<W, F>(zk: &ZooKeeper, dir: &str, node: &str, mut watcher: F)
+                           -> ZkResult<Contender>
+    where W: Watcher + 'static,
+          F: FnMut() -> W
+{
+    let name = split_path(node).1;
+    loop {
+        let mut children = try!(zk.get_children(dir, false));
+        sort_by_sequence(&mut children);
+        let predecessor = match children.iter().position(|child| child == name) {
+            Some(0) => return Ok(Contender::First),
+            Some(i) => make_path(dir, &children[i - 1]),
+            None => return Ok(Contender::Gone),
+        };
+        if try!(zk.exists_w(&predecessor, watcher())).is_some() {
+            return Ok(Contender::Waiting(predecessor));
+        }
+        // The predecessor went away since listing the children
+    }
+}
@@ -0,0 +1,126 @@
+//! Serialization of records in the jute format, used by the wire protocol as well as by the
+//! transaction logs and snapshots of the server.
+//!
+//! All numbers are big endian. Buffers and strings are prefixed with their length as `i32`, and
+//! vectors with their number of elements; a length of `-1` encodes a null buffer or vector.
+use acl::Acl;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use data::Stat;
+use proto::{BufferReader, ReadFrom, StringReader, WriteTo};
+use std::io::{Read, Result, Write};
+
+/// Write `buf` prefixed with its length.
+pub fn write_buffer(writer: &mut dyn Write, buf: &[u8]) -> Result<()> {
+    try!(writer.write_i32::<BigEndian>(buf.len() as i32));
+    writer.write_all(buf)
+}
+
+/// Read a buffer prefixed with its length. A null buffer is read as an empty one.
+pub fn read_buffer<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    reader.read_buffer()
+}
+
+/// Write `s` as an UTF-8 buffer prefixed with its length.
+pub fn write_string(writer: &mut dyn Write, s: &str) -> Result<()> {
+    write_buffer(writer, s.as_bytes())
+}
+
+/// Read an UTF-8 string prefixed with its length.
+pub fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    reader.read_string()
+}
+
+/// Write the elements of `vec` with `write`, prefixed with their number.
+pub fn write_vector<T, F>(writer: &mut dyn Write, vec: &[T], mut write: F) -> Result<()>
+    where F: FnMut(&mut dyn Write, &T) -> Result<()>
+{
+    try!(writer.write_i32::<BigEndian>(vec.len() as i32));
+    for elem in vec {
+        try!(write(writer, elem));
+    }
+    Ok(())
+}
+
+/// Read a vector prefixed with the number of its elements, reading each with `read`. A null
+/// vector is read as an empty one.
+pub fn read_vector<R, T, F>(reader: &mut R, mut read: F) -> Result<Vec<T>>
+    where R: Read,
+          F: FnMut(&mut R) -> Result<T>
+{
+    let len = try!(reader.read_i32::<BigEndian>());
+    let mut vec = Vec::with_capacity(len.max(0) as usize);
+    for _ in 0..len {
+        vec.push(try!(read(reader)));
+    }
+    Ok(vec)
+}
+
+/// Write a `Stat` record.
+pub fn write_stat(writer: &mut dyn Write, stat: &Stat) -> Result<()> {
+    stat.write_to(writer)
+}
+
+/// Read a `Stat` record.
+pub fn read_stat<R: Read>(reader: &mut R) -> Result<Stat> {
+    Stat::read_from(reader)
+}
+
+/// Write an `Acl` record.
+pub fn write_acl(writer: &mut dyn Write, acl: &Acl) -> Result<()> {
+    acl.write_to(writer)
+}
+
+/// Read an `Acl` record.
+pub fn read_acl<R: Read>(reader: &mut R) -> Result<Acl> {
+    Acl::read_from(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use acl::Permission;
+    use std::io::Cursor;
+
+    #[test]
+    fn stat_round_trip() {
+        let stat = Stat {
+            czxid: 1,
+            mzxid: 2,
+            ctime: 3,
+            mtime: 4,
+            version: 5,
+            cversion: 6,
+            aversion: 7,
+            ephemeral_owner: 8,
+            data_length: 9,
+            num_children: 10,
+            pzxid: 11,
+        };
+        let mut buf = Vec::new();
+        write_stat(&mut buf, &stat).unwrap();
+        assert_eq!(buf.len(), 68);
+        assert_eq!(read_stat(&mut Cursor::new(buf)).unwrap(), stat);
+    }
+
+    #[test]
+    fn acl_vector_round_trip() {
+        let acls = vec![Acl::new(Permission::ALL, "world", "anyone"),
+                        Acl::new(Permission::READ | Permission::WRITE, "digest", "user:hash")];
+        let mut buf = Vec::new();
+        write_vector(&mut buf, &acls, write_acl).unwrap();
+        let read = read_vector(&mut Cursor::new(buf), read_acl).unwrap();
+        assert_eq!(read, acls);
+    }
+
+    #[test]
+    fn string_and_buffer_round_trip() {
+        let mut buf = Vec::new();
+        write_string(&mut buf, "/zk").unwrap();
+        write_buffer(&mut buf, &[1, 2, 3]).unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 3]);
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_string(&mut reader).unwrap(), "/zk");
+        assert_eq!(read_buffer(&mut reader).unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,84 @@
+use admin::four_letter_word;
+use consts::ZkError;
+use zookeeper::ZkResult;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The role of a server in the ensemble. See `ZooKeeper::connected_server_role`.
+#[derive(Clone, Copy, Debug, EnumDisplay, PartialEq)]
+pub enum ServerRole {
+    /// The server is the leader of the ensemble.
+    Leader,
+    /// The server is a voting member following the leader.
+    Follower,
+    /// The server is a non-voting member following the leader, which may lag behind more.
+    Observer,
+    /// The server runs on its own, not as part of an ensemble.
+    Standalone,
+}
+
+/// Ask the server at `addr` for its role, using the `srvr` four letter word command (which is
+/// whitelisted by default).
+pub fn query_server_role(addr: &SocketAddr, timeout: Duration) -> ZkResult<ServerRole> {
+    let output = match four_letter_word(addr, "srvr", timeout) {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("Failed to query the role of {}: {:?}", addr, err);
+            return Err(ZkError::ConnectionLoss);
+        }
+    };
+
+    parse_srvr_mode(&output).ok_or_else(|| {
+        warn!("Unexpected srvr output of {}: {:?}", addr, output);
+        ZkError::MarshallingError
+    })
+}
+
+/// Extract the role from the `Mode:` line of the `srvr` output.
+fn parse_srvr_mode(output: &str) -> Option<ServerRole> {
+    output.lines()
+          .filter_map(|line| {
+              let mut parts = line.splitn(2, ':');
+              match (parts.next(), parts.next()) {
+                  (Some("Mode"), Some(mode)) => Some(mode.trim()),
+                  _ => None,
+              }
+          })
+          .next()
+          .and_then(|mode| {
+              match mode {
+                  "leader" => Some(ServerRole::Leader),
+                  "follower" => Some(ServerRole::Follower),
+                  "observer" => Some(ServerRole::Observer),
+                  "standalone" => Some(ServerRole::Standalone),
+                  _ => None,
+              }
+          })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_srvr_mode, ServerRole};
+
+    const SRVR: &str = "Zookeeper version: 3.6.2--803c7f1a12, built on 09/04/2020 12:44 GMT
+Latency min/avg/max: 0/0.0/0
+Received: 1
+Sent: 0
+Connections: 1
+Outstanding: 0
+Zxid: 0x100000000
+Mode: observer
+Node count: 5
+";
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!(parse_srvr_mode(SRVR), Some(ServerRole::Observer));
+        assert_eq!(parse_srvr_mode(&SRVR.replace("observer", "leader")), Some(ServerRole::Leader));
+        assert_eq!(parse_srvr_mode("Mode: follower\n"), Some(ServerRole::Follower));
+        assert_eq!(parse_srvr_mode("Mode: standalone"), Some(ServerRole::Standalone));
+        assert_eq!(parse_srvr_mode("srvr is not executed because it is not in the whitelist."),
+                   None);
+    }
+}
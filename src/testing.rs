@@ -0,0 +1,302 @@
+//! An in-memory server end for testing the client without a ZooKeeper cluster.
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use connector::{Connector, Stream};
+use consts::ZkState;
+use data::Stat;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use proto::WriteTo;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{self, Context, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+use zookeeper::{ZooKeeper, ZooKeeperBuilder};
+
+type Pipe = Arc<Mutex<VecDeque<u8>>>;
+
+/// The client end of an in-memory connection.
+struct MemoryStream {
+    inbound: Pipe,
+    outbound: Pipe,
+    closed: Arc<AtomicBool>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inbound = self.inbound.lock().unwrap();
+        if inbound.is_empty() {
+            if self.closed.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(inbound.len());
+        for (byte, read) in buf.iter_mut().zip(inbound.drain(..len)) {
+            *byte = read;
+        }
+        if inbound.is_empty() {
+            self.readiness.set_readiness(Ready::writable()).unwrap();
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.lock().unwrap().extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for MemoryStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+                -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+                  -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
+
+/// The server end of an in-memory connection, driven by the test.
+pub struct MemoryServer {
+    pub inbound: Pipe,
+    outbound: Pipe,
+    closed: Arc<AtomicBool>,
+    readiness: SetReadiness,
+}
+
+impl MemoryServer {
+    pub fn read_frame(&self) -> Cursor<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            {
+                let mut inbound = self.inbound.lock().unwrap();
+                if inbound.len() >= 4 {
+                    let len = Cursor::new(inbound.iter().take(4).cloned().collect::<Vec<_>>())
+                                  .read_i32::<BigEndian>()
+                                  .unwrap() as usize;
+                    if inbound.len() >= 4 + len {
+                        let frame = inbound.drain(..4 + len).skip(4).collect();
+                        return Cursor::new(frame);
+                    }
+                }
+            }
+            assert!(Instant::now() < deadline, "no frame from the client");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    pub fn write_frame(&self, frame: &[u8]) {
+        {
+            let mut outbound = self.outbound.lock().unwrap();
+            outbound.write_i32::<BigEndian>(frame.len() as i32).unwrap();
+            outbound.extend(frame);
+        }
+        self.readiness.set_readiness(Ready::readable() | Ready::writable()).unwrap();
+    }
+
+    /// Read the `ConnectRequest` and establish the session, returning the request.
+    pub fn accept(&self) -> Cursor<Vec<u8>> {
+        self.accept_session(0x1234)
+    }
+
+    pub fn accept_session(&self, session_id: i64) -> Cursor<Vec<u8>> {
+        self.accept_with(0, session_id)
+    }
+
+    /// Accept the connection, negotiating the given protocol version.
+    pub fn accept_with(&self, protocol_version: i32, session_id: i64) -> Cursor<Vec<u8>> {
+        let connect = self.read_frame();
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(protocol_version).unwrap();
+        frame.write_i32::<BigEndian>(6000).unwrap();
+        frame.write_i64::<BigEndian>(session_id).unwrap();
+        frame.write_i32::<BigEndian>(16).unwrap();
+        frame.extend(&[0; 16]);
+        frame.write_u8(0).unwrap();
+        self.write_frame(&frame);
+        connect
+    }
+
+    /// Close the connection, like a server going away.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.readiness.set_readiness(Ready::readable() | Ready::writable()).unwrap();
+    }
+
+    pub fn reply(&self, xid: i32, zxid: i64, err: i32) {
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(zxid).unwrap();
+        frame.write_i32::<BigEndian>(err).unwrap();
+        self.write_frame(&frame);
+    }
+}
+
+/// Hands out one stream per connection attempt.
+pub struct MemoryConnector {
+    streams: Mutex<VecDeque<MemoryStream>>,
+    /// The addresses of the connection attempts.
+    pub addrs: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl Connector for MemoryConnector {
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<dyn Stream>> {
+        self.addrs.lock().unwrap().push(*addr);
+        match self.streams.lock().unwrap().pop_front() {
+            Some(stream) => Ok(Box::new(stream)),
+            None => Err(io::ErrorKind::ConnectionRefused.into()),
+        }
+    }
+}
+
+/// Refuses the connection attempts whose number (starting at 0) is in `refused`.
+pub struct RefusingConnector {
+    pub inner: MemoryConnector,
+    pub attempts: Mutex<usize>,
+    pub refused: Range<usize>,
+}
+
+impl Connector for RefusingConnector {
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<dyn Stream>> {
+        let mut attempts = self.attempts.lock().unwrap();
+        *attempts += 1;
+        if self.refused.contains(&(*attempts - 1)) {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        } else {
+            self.inner.connect(addr)
+        }
+    }
+}
+
+pub fn memory_pipe() -> (MemoryConnector, MemoryServer) {
+    let (connector, mut servers) = memory_pipes(1);
+    (connector, servers.remove(0))
+}
+
+/// A connector for `count` consecutive connections, along with their server ends.
+pub fn memory_pipes(count: usize) -> (MemoryConnector, Vec<MemoryServer>) {
+    let mut streams = VecDeque::new();
+    let mut servers = Vec::new();
+    for _ in 0..count {
+        let to_server = Arc::new(Mutex::new(VecDeque::new()));
+        let to_client = Arc::new(Mutex::new(VecDeque::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (registration, readiness) = Registration::new2();
+        readiness.set_readiness(Ready::writable()).unwrap();
+
+        servers.push(MemoryServer {
+            inbound: to_server.clone(),
+            outbound: to_client.clone(),
+            closed: closed.clone(),
+            readiness: readiness.clone(),
+        });
+        streams.push_back(MemoryStream {
+            inbound: to_client,
+            outbound: to_server,
+            closed,
+            registration,
+            readiness,
+        });
+    }
+    let connector = MemoryConnector {
+        streams: Mutex::new(streams),
+        addrs: Arc::new(Mutex::new(Vec::new())),
+    };
+    (connector, servers)
+}
+
+/// Connect with `builder` over an in-memory pipe, establishing the session.
+pub fn connected(builder: ZooKeeperBuilder) -> (ZooKeeper, MemoryServer) {
+    let (connector, server) = memory_pipe();
+    let zk = builder.connect_string("127.0.0.1:2181").connector(connector).connect().unwrap();
+    let (state_tx, state_rx) = mpsc::channel();
+    let sub = zk.add_listener(move |state| state_tx.send(state).unwrap());
+    server.accept();
+    assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+    zk.remove_listener(sub);
+    (zk, server)
+}
+
+/// Answer a getData request with `data`, or with the error `err` if it is not 0.
+pub fn reply_data(server: &MemoryServer, zxid: i64, err: i32, data: &[u8]) {
+    let mut request = server.read_frame();
+    let xid = request.read_i32::<BigEndian>().unwrap();
+    assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+    if err != 0 {
+        return server.reply(xid, zxid, err);
+    }
+    let mut frame = Vec::new();
+    frame.write_i32::<BigEndian>(xid).unwrap();
+    frame.write_i64::<BigEndian>(zxid).unwrap();
+    frame.write_i32::<BigEndian>(0).unwrap();
+    data.to_vec().write_to(&mut frame).unwrap();
+    stat(5, zxid).write_to(&mut frame).unwrap();
+    server.write_frame(&frame);
+}
+
+pub fn data_changed(server: &MemoryServer, zxid: i64, path: &str) {
+    let mut frame = Vec::new();
+    frame.write_i32::<BigEndian>(-1).unwrap();
+    frame.write_i64::<BigEndian>(zxid).unwrap();
+    frame.write_i32::<BigEndian>(0).unwrap();
+    frame.write_i32::<BigEndian>(3).unwrap(); // NodeDataChanged
+    frame.write_i32::<BigEndian>(3).unwrap(); // SyncConnected
+    path.to_owned().write_to(&mut frame).unwrap();
+    server.write_frame(&frame);
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+/// Poll `future` on the current thread until it resolves.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            task::Poll::Ready(output) => return output,
+            task::Poll::Pending => thread::park(),
+        }
+    }
+}
+
+pub fn stat(czxid: i64, mzxid: i64) -> Stat {
+    Stat {
+        czxid,
+        mzxid,
+        ctime: 0,
+        mtime: 0,
+        version: 0,
+        cversion: 0,
+        aversion: 0,
+        ephemeral_owner: 0,
+        data_length: 0,
+        num_children: 0,
+        pzxid: czxid,
+    }
+}
@@ -0,0 +1,92 @@
+use acl::Acl;
+use consts::CreateMode;
+use multi::{Op, OpResult};
+use zookeeper::{ZkResult, ZooKeeper};
+
+/// Collects the operations of a transaction, which are committed atomically by `commit`. See
+/// `ZooKeeper::transaction`.
+///
+/// ```no_run
+/// # use zookeeper::{ZooKeeper, ZkResult};
+/// # fn run(zk: &ZooKeeper) -> ZkResult<()> {
+/// let results = zk.transaction()
+///                 .create("/config", vec![1])
+///                 .check("/config", Some(0))
+///                 .set_data("/config", vec![2], None)
+///                 .commit()?;
+/// # Ok(())
+/// # }
+/// ```
+#[must_use = "the operations are not applied unless the transaction is committed"]
+pub struct Transaction<'a> {
+    zk: &'a ZooKeeper,
+    ops: Vec<Op>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(zk: &'a ZooKeeper) -> Transaction<'a> {
+        Transaction { zk, ops: Vec::new() }
+    }
+
+    /// Create a persistent node with `Acl::open_unsafe`, like `Op::Create`.
+    pub fn create(self, path: &str, data: Vec<u8>) -> Transaction<'a> {
+        self.create_with(path, data, Acl::open_unsafe().clone(), CreateMode::Persistent)
+    }
+
+    /// Create a node with the given ACL and mode, like `Op::Create`.
+    pub fn create_with(self,
+                       path: &str,
+                       data: Vec<u8>,
+                       acl: Vec<Acl>,
+                       mode: CreateMode)
+                       -> Transaction<'a> {
+        self.op(Op::Create {
+            path: path.to_owned(),
+            data,
+            acl,
+            mode,
+        })
+    }
+
+    /// Check that a node exists and (optionally) has the given version, like `Op::Check`.
+    pub fn check(self, path: &str, version: Option<i32>) -> Transaction<'a> {
+        self.op(Op::Check {
+            path: path.to_owned(),
+            version,
+        })
+    }
+
+    /// Set the data of a node, like `Op::SetData`.
+    pub fn set_data(self, path: &str, data: Vec<u8>, version: Option<i32>) -> Transaction<'a> {
+        self.op(Op::SetData {
+            path: path.to_owned(),
+            data,
+            version,
+        })
+    }
+
+    /// Delete a node, like `Op::Delete`.
+    pub fn delete(self, path: &str, version: Option<i32>) -> Transaction<'a> {
+        self.op(Op::Delete {
+            path: path.to_owned(),
+            version,
+        })
+    }
+
+    /// Add any operation.
+    pub fn op(mut self, op: Op) -> Transaction<'a> {
+        self.ops.push(op);
+        self
+    }
+
+    /// The operations collected so far, in the order they are applied.
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// Execute the operations atomically, returning their results in the order they were added.
+    /// See `ZooKeeper::commit`, which documents the errors.
+    pub fn commit(self) -> ZkResult<Vec<OpResult>> {
+        self.zk.commit(&self.ops)
+    }
+}
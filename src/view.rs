@@ -0,0 +1,35 @@
+use data::Stat;
+use zookeeper::{ZkResult, ZooKeeper};
+
+/// Reads which reflect at least a given zxid. See `ZooKeeper::consistent_view`.
+pub struct ConsistentView<'a> {
+    zk: &'a ZooKeeper,
+    zxid: i64,
+}
+
+impl<'a> ConsistentView<'a> {
+    pub(crate) fn new(zk: &'a ZooKeeper, zxid: i64) -> ConsistentView<'a> {
+        ConsistentView { zk, zxid }
+    }
+
+    /// The zxid the server had reached when the view was created, which is at least the one it
+    /// was requested for.
+    pub fn zxid(&self) -> i64 {
+        self.zxid
+    }
+
+    /// Like `ZooKeeper::exists`, without leaving a watch.
+    pub fn exists(&self, path: &str) -> ZkResult<Option<Stat>> {
+        self.zk.exists(path, false)
+    }
+
+    /// Like `ZooKeeper::get_children`, without leaving a watch.
+    pub fn get_children(&self, path: &str) -> ZkResult<Vec<String>> {
+        self.zk.get_children(path, false)
+    }
+
+    /// Like `ZooKeeper::get_data`, without leaving a watch.
+    pub fn get_data(&self, path: &str) -> ZkResult<(Vec<u8>, Stat)> {
+        self.zk.get_data(path, false)
+    }
+}
@@ -1,10 +1,24 @@
-use consts::{KeeperState, WatchedEventType};
+use consts::{KeeperState, WatchedEventType, WatcherType, ZkError};
 use consts::WatchedEventType::{NodeCreated, NodeDataChanged, NodeDeleted, NodeChildrenChanged};
-use proto::ReadFrom;
-use zookeeper::RawResponse;
-use std::sync::mpsc::{self, Sender, Receiver};
-use std::collections::HashMap;
+use data::Stat;
+use proto::{to_len_prefixed_buf, ExistsRequest, OpCode, ReadFrom, RemoveWatchesRequest,
+            RequestHeader, StatResponse};
+use io::{ConnectionState, SessionState};
+use paths::strip_chroot;
+use recipes::{is_disconnected, rearm};
+use zookeeper::{RawRequest, RawResponse, RequestSender, Xid, ZkResult};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, sync_channel, Sender, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, ThreadId};
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::mem;
+
+/// How long the watch audit waits for the `Stat` of a node at most. Events are not delivered
+/// meanwhile, as the audit runs on the event thread.
+const AUDIT_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Represents a change on the ZooKeeper that a `Watcher` is able to respond to.
 ///
@@ -18,10 +32,14 @@ pub struct WatchedEvent {
     pub keeper_state: KeeperState,
     /// The path of the znode that was involved. This will be `None` for session-related triggers.
     pub path: Option<String>,
+    /// The type of the `Watch` this event is delivered to, to tell which kind of watch to set
+    /// again (a `NodeDeleted` event triggers data and exist watches alike). `None` for events
+    /// delivered to the default `Watcher` or to persistent watches.
+    pub watch_type: Option<WatchType>,
 }
 
 /// Describes what a `Watch` is looking for.
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum WatchType {
     /// Watching for changes to children.
     Child,
@@ -38,7 +56,7 @@ pub struct Watch {
     /// The type of changes this watch is looking for.
     pub watch_type: WatchType,
     /// The handler for this watch, to call when it is triggered.
-    pub watcher: Box<dyn Watcher>,
+    pub watcher: BoxedWatcher,
 }
 
 /// The interface for handling events when a `Watch` triggers.
@@ -47,6 +65,17 @@ pub trait Watcher: Send {
     fn handle(&self, WatchedEvent);
 }
 
+/// A type-erased `Watcher`.
+pub type BoxedWatcher = Box<dyn Watcher + Send>;
+
+/// A `Watcher` which ignores all events, for connections which are not interested in them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopWatcher;
+
+impl Watcher for NoopWatcher {
+    fn handle(&self, _: WatchedEvent) {}
+}
+
 impl<F> Watcher for F where F: Fn(WatchedEvent) + Send
 {
     fn handle(&self, event: WatchedEvent) {
@@ -54,28 +83,351 @@ impl<F> Watcher for F where F: Fn(WatchedEvent) + Send
     }
 }
 
+/// A data watch which pushes its events onto a channel and leaves itself again on the node, until
+/// the receiver is dropped. See `ZooKeeper::watch_data`.
+#[derive(Clone)]
+pub struct ChannelWatcher {
+    requests: Arc<RequestSender>,
+    rearmer: Weak<Rearmer>,
+    /// Tells the clones of the watcher apart from other watchers.
+    id: usize,
+    /// Shared by the clones. The watch left before a disconnect stays registered, so it fires
+    /// along with the one left after it, and only the first of them is passed on.
+    armed: Arc<AtomicBool>,
+    path: String,
+    /// The path of the node including the chroot.
+    server_path: String,
+    tx: Sender<WatchedEvent>,
+}
+
+impl ChannelWatcher {
+    pub fn new(requests: Arc<RequestSender>,
+               rearmer: &Arc<Rearmer>,
+               path: String,
+               server_path: String,
+               tx: Sender<WatchedEvent>)
+               -> ChannelWatcher {
+        ChannelWatcher {
+            requests,
+            rearmer: Arc::downgrade(rearmer),
+            id: rearmer.next_id.fetch_add(1, Ordering::Relaxed),
+            armed: Arc::new(AtomicBool::new(true)),
+            path,
+            server_path,
+            tx,
+        }
+    }
+
+    /// Leave the watch again, with an exists request which also covers the node being created
+    /// again after it was deleted.
+    fn rearm(&self) -> ZkResult<()> {
+        let req = ExistsRequest {
+            path: self.server_path.clone(),
+            watch: true,
+        };
+        let watch = Watch {
+            path: self.path.clone(),
+            watch_type: WatchType::Exist,
+            watcher: Box::new(self.clone()),
+        };
+        let (tx, rx) = sync_channel(1);
+        try!(self.requests.send(Xid::Next, |xid| {
+            let header = RequestHeader {
+                xid,
+                opcode: OpCode::Exists,
+            };
+            let data = try!(to_len_prefixed_buf(header, req)
+                                .map_err(|_| ZkError::MarshallingError));
+            Ok(RawRequest {
+                opcode: OpCode::Exists,
+                data,
+                listener: Some(tx),
+                completion: None,
+                watch: Some(WatchMessage::Watch(watch, 0)),
+                cancel: None,
+                queue_slot: None,
+            })
+        }));
+        let response = try!(rx.recv().map_err(|_| ZkError::ConnectionLoss));
+        match response.header.err {
+            0 => Ok(()),
+            err => {
+                match ZkError::from(err) {
+                    // The watch waits for the node to be created again
+                    ZkError::NoNode => Ok(()),
+                    err => Err(err),
+                }
+            }
+        }
+    }
+}
+
+impl Watcher for ChannelWatcher {
+    fn handle(&self, event: WatchedEvent) {
+        if !self.armed.swap(false, Ordering::SeqCst) {
+            debug!("watch on {} was triggered already", self.path);
+            return;
+        }
+        if self.tx.send(event).is_err() {
+            debug!("receiver of the watch on {} has gone away", self.path);
+            return;
+        }
+        match self.rearmer.upgrade() {
+            // Don't block the event thread with the request
+            Some(rearmer) => rearmer.send(RearmMessage::Rearm(self.clone())),
+            None => debug!("client of the watch on {} has gone away", self.path),
+        }
+    }
+}
+
+enum RearmMessage {
+    /// The watch was left on the node.
+    Armed(ChannelWatcher),
+    /// The watch was triggered, and is to be left again.
+    Rearm(ChannelWatcher),
+    /// The client is connected again, after the server dropped the watches along with the
+    /// connection.
+    Connected,
+}
+
+/// Leaves the watches of the `ChannelWatcher`s of a client again, one at a time on a thread of its
+/// own: after each event, and all of them once the client is connected again.
+pub struct Rearmer {
+    tx: Mutex<Sender<RearmMessage>>,
+    next_id: AtomicUsize,
+}
+
+impl Rearmer {
+    /// Create the rearmer, along with the worker to run on its thread. The worker stops once the
+    /// rearmer is dropped, closing the channels of the watches it keeps.
+    pub fn new() -> (Rearmer, RearmWorker) {
+        let (tx, rx) = mpsc::channel();
+        let rearmer = Rearmer {
+            tx: Mutex::new(tx),
+            next_id: AtomicUsize::new(0),
+        };
+        let worker = RearmWorker {
+            rx,
+            watchers: Vec::new(),
+        };
+        (rearmer, worker)
+    }
+
+    /// Keep `watcher`, whose watch was just left on the node, to leave it again once connected.
+    pub fn armed(&self, watcher: ChannelWatcher) {
+        self.send(RearmMessage::Armed(watcher));
+    }
+
+    /// Leave all watches again, as the client is connected again.
+    pub fn connected(&self) {
+        self.send(RearmMessage::Connected);
+    }
+
+    fn send(&self, message: RearmMessage) {
+        let _ = self.tx.lock().unwrap_or_else(|e| e.into_inner()).send(message);
+    }
+}
+
+/// Runs the requests of a `Rearmer`.
+pub struct RearmWorker {
+    rx: Receiver<RearmMessage>,
+    /// The watchers whose receivers are (as far as known) still there.
+    watchers: Vec<ChannelWatcher>,
+}
+
+impl RearmWorker {
+    pub fn run(mut self) {
+        while let Ok(message) = self.rx.recv() {
+            match message {
+                RearmMessage::Armed(watcher) => self.keep(watcher),
+                RearmMessage::Rearm(watcher) => self.rearm(watcher),
+                RearmMessage::Connected => {
+                    for watcher in mem::replace(&mut self.watchers, Vec::new()) {
+                        // Changes while disconnected triggered no event, so tell the receiver to
+                        // read the node again
+                        let event = WatchedEvent {
+                            event_type: WatchedEventType::None,
+                            keeper_state: KeeperState::SyncConnected,
+                            path: Some(watcher.path.clone()),
+                            watch_type: None,
+                        };
+                        if watcher.tx.send(event).is_err() {
+                            debug!("receiver of the watch on {} has gone away", watcher.path);
+                            continue;
+                        }
+                        self.rearm(watcher);
+                    }
+                }
+            }
+        }
+    }
+
+    fn keep(&mut self, watcher: ChannelWatcher) {
+        self.watchers.retain(|kept| kept.id != watcher.id);
+        self.watchers.push(watcher);
+    }
+
+    fn rearm(&mut self, watcher: ChannelWatcher) {
+        // Armed before the request, as its event may be dispatched before the response
+        watcher.armed.store(true, Ordering::SeqCst);
+        match rearm(&watcher.path, || watcher.rearm()) {
+            Ok(()) => self.keep(watcher),
+            Err(err) if is_disconnected(err) => {
+                warn!("lost the watch on {}, watching it again once connected", watcher.path);
+                self.keep(watcher);
+            }
+            Err(err) => {
+                // Dropping the watcher closes the channel, so the receiver learns about it
+                error!("error watching {}, closing the channel of its events: {:?}",
+                       watcher.path,
+                       err);
+                self.watchers.retain(|kept| kept.id != watcher.id);
+            }
+        }
+    }
+}
+
+/// How many watches the client has registered, see `ZooKeeper::registered_watches`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegisteredWatches {
+    /// Data watches which are not triggered yet.
+    pub data: usize,
+    /// Watches for the creation of a node which are not triggered yet.
+    pub exist: usize,
+    /// Watches of the children of a node which are not triggered yet.
+    pub child: usize,
+    /// Persistent watches, see `ZooKeeper::add_watch`.
+    pub persistent: usize,
+    /// Watches of recipes which are left again after each event, until they stop or the recipe is
+    /// dropped (like the watch of a `recipes::config::WatchedConfig`). The watch they currently
+    /// leave is counted by its type as well.
+    pub rearming: usize,
+}
+
+/// Passed to the callback of a persistent watch, to control the watch from within the callback.
+/// See `ZooKeeper::add_watch`.
+#[derive(Clone, Debug, Default)]
+pub struct WatchContext {
+    removed: Arc<AtomicBool>,
+}
+
+impl WatchContext {
+    /// Remove the watch: the callback is not called for any further event.
+    pub fn remove_watch(&self) {
+        self.removed.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether `remove_watch` was called.
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::SeqCst)
+    }
+}
+
+/// The callback of a persistent watch.
+pub type PersistentWatcher = Box<dyn Fn(WatchedEvent, &WatchContext) + Send>;
+
+/// A watch which is not removed when it is triggered.
+pub struct PersistentWatch {
+    pub path: String,
+    pub watcher: PersistentWatcher,
+    pub context: WatchContext,
+    /// Whether the events of the descendants of `path` trigger the watch as well.
+    pub recursive: bool,
+}
+
 pub enum WatchMessage {
     Event(RawResponse),
-    Watch(Watch),
+    /// A watch to register, along with the zxid of the response which set it (filled in by the IO
+    /// thread). Changes with a higher zxid trigger the watch.
+    Watch(Watch, i64),
+    Persistent(PersistentWatch),
+    /// The server removed the watches of the path.
+    Removed(String),
+    /// The server removed the watches of the given type of the path on request of
+    /// `ZooKeeper::remove_watches`, so they are dropped without being called.
+    Unregister(String, WatcherType),
+    /// The connection was lost or re-established, to tell the default watcher.
+    State(KeeperState),
 }
 
-pub struct ZkWatch<W: Watcher> {
-    watcher: W,
-    watches: HashMap<String, Vec<Watch>>,
+/// A replacement for the watcher receiving all events which no specific `Watch` was registered
+/// for, handed from the `ZooKeeper` handle to the event thread. The event thread owns the watcher
+/// in use, so replacing it never waits for a callback in progress (which may be the one replacing
+/// it).
+pub type DefaultWatcher = Arc<Mutex<Option<BoxedWatcher>>>;
+
+/// Stops the delivery of events once the `ZooKeeper` handle is closed. Shared between the handle
+/// and the event thread.
+#[derive(Default)]
+pub struct EventGate {
+    closed: AtomicBool,
+    delivering: Mutex<()>,
+    event_thread: Mutex<Option<ThreadId>>,
+}
+
+impl EventGate {
+    /// Stop delivering events. Once this returns, no callback is running or will run, unless this
+    /// is called from a callback itself.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        let event_thread = *self.event_thread.lock().unwrap_or_else(|e| e.into_inner());
+        if event_thread != Some(thread::current().id()) {
+            // wait for a callback in progress
+            let _delivering = self.delivering.lock().unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn deliver<F: FnOnce()>(&self, deliver: F) {
+        let _delivering = self.delivering.lock().unwrap_or_else(|e| e.into_inner());
+        if self.closed.load(Ordering::SeqCst) {
+            debug!("Dropping event of closed handle");
+        } else {
+            deliver();
+        }
+    }
+}
+
+pub struct ZkWatch {
+    watcher: BoxedWatcher,
+    replacement: DefaultWatcher,
+    gate: Arc<EventGate>,
+    /// The registered watches, along with the zxid they were set at.
+    watches: HashMap<String, Vec<(Watch, i64)>>,
+    persistent: HashMap<String, Vec<PersistentWatch>>,
+    /// Paths whose watches are being removed on the server, which may still send events for them.
+    removing: HashSet<String>,
+    /// The number of watches, updated after each change for `ZooKeeper::registered_watches`.
+    registered: Arc<Mutex<RegisteredWatches>>,
     chroot: Option<String>,
+    requests: Option<Arc<RequestSender>>,
+    /// The state of the session, to only audit the watches while connected.
+    session: Option<Arc<Mutex<SessionState>>>,
+    audit_interval: Option<Duration>,
     tx: Sender<WatchMessage>,
     rx: Receiver<WatchMessage>,
 }
 
-impl<W: Watcher> ZkWatch<W> {
-    pub fn new(watcher: W, chroot: Option<String>) -> Self {
+impl ZkWatch {
+    pub fn new(watcher: BoxedWatcher,
+               chroot: Option<String>,
+               audit_interval: Option<Duration>)
+               -> Self {
         trace!("ZkWatch::new");
         let (tx, rx) = mpsc::channel();
 
         ZkWatch {
             watches: HashMap::new(),
-            watcher: watcher,
+            persistent: HashMap::new(),
+            removing: HashSet::new(),
+            registered: Arc::new(Mutex::new(RegisteredWatches::default())),
+            watcher,
+            replacement: Arc::new(Mutex::new(None)),
+            gate: Arc::new(EventGate::default()),
             chroot: chroot,
+            requests: None,
+            session: None,
+            audit_interval,
             tx,
             rx
         }
@@ -85,12 +437,54 @@ impl<W: Watcher> ZkWatch<W> {
         self.tx.clone()
     }
 
+    pub fn default_watcher(&self) -> DefaultWatcher {
+        self.replacement.clone()
+    }
+
+    pub fn gate(&self) -> Arc<EventGate> {
+        self.gate.clone()
+    }
+
+    pub fn registered(&self) -> Arc<Mutex<RegisteredWatches>> {
+        self.registered.clone()
+    }
+
+    /// Set the channel to the IO thread, for removing persistent watches on the server and for
+    /// auditing the watches.
+    pub fn set_requests(&mut self, requests: Arc<RequestSender>) {
+        self.requests = Some(requests);
+    }
+
+    /// Set the state of the session, which is shared with the IO thread.
+    pub fn set_session(&mut self, session: Arc<Mutex<SessionState>>) {
+        self.session = Some(session);
+    }
+
     pub fn run(mut self) -> io::Result<()> {
-        while let Ok(msg) = self.rx.recv() {
-            self.process_message(msg);
-        }
+        *self.gate.event_thread.lock().unwrap() = Some(thread::current().id());
+        let interval = match self.audit_interval {
+            Some(interval) => interval,
+            None => {
+                while let Ok(msg) = self.rx.recv() {
+                    self.process_message(msg);
+                }
+                return Ok(());
+            }
+        };
 
-        Ok(())
+        let mut next_audit = Instant::now() + interval;
+        loop {
+            let timeout = next_audit.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(timeout) {
+                Ok(msg) => self.process_message(msg),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.audit(interval.min(AUDIT_TIMEOUT));
+                    self.count_watches();
+                    next_audit = Instant::now() + interval;
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
     }
 
     fn process_message(&mut self, message: WatchMessage) {
@@ -103,7 +497,8 @@ impl<W: Watcher> ZkWatch<W> {
                         match WatchedEvent::read_from(&mut data) {
                             Ok(mut event) => {
                                 self.cut_chroot(&mut event);
-                                self.dispatch(&event);
+                                let gate = self.gate.clone();
+                                gate.deliver(|| self.dispatch(&event));
                             }
                             Err(e) => error!("Failed to parse WatchedEvent {:?}", e),
                         }
@@ -111,46 +506,297 @@ impl<W: Watcher> ZkWatch<W> {
                     e => error!("WatchedEvent.error {:?}", e),
                 }
             }
-            WatchMessage::Watch(watch) => {
-                self.watches.entry(watch.path.clone()).or_insert(vec![]).push(watch);
+            WatchMessage::Watch(watch, zxid) => {
+                self.watches.entry(watch.path.clone()).or_insert(vec![]).push((watch, zxid));
+            }
+            WatchMessage::Persistent(watch) => {
+                self.persistent.entry(watch.path.clone()).or_insert(vec![]).push(watch);
+            }
+            WatchMessage::Removed(path) => {
+                self.removing.remove(&path);
+            }
+            WatchMessage::Unregister(path, watcher_type) => self.unregister(&path, watcher_type),
+            WatchMessage::State(keeper_state) => {
+                let event = WatchedEvent {
+                    event_type: WatchedEventType::None,
+                    keeper_state,
+                    path: None,
+                    watch_type: None,
+                };
+                let gate = self.gate.clone();
+                gate.deliver(|| self.dispatch(&event));
+            }
+        }
+        self.count_watches();
+    }
+
+    fn count_watches(&self) {
+        let mut registered = self.registered.lock().unwrap_or_else(|e| e.into_inner());
+        registered.data = 0;
+        registered.exist = 0;
+        registered.child = 0;
+        for (watch, _) in self.watches.values().flatten() {
+            match watch.watch_type {
+                WatchType::Data => registered.data += 1,
+                WatchType::Exist => registered.exist += 1,
+                WatchType::Child => registered.child += 1,
+            }
+        }
+        registered.persistent = self.persistent.values().map(Vec::len).sum();
+    }
+
+    fn unregister(&mut self, path: &str, watcher_type: WatcherType) {
+        if let Some(mut watches) = self.watches.remove(path) {
+            watches.retain(|(watch, _)| {
+                !matches!((watcher_type, watch.watch_type),
+                          (WatcherType::Any, _) |
+                          (WatcherType::Children, WatchType::Child) |
+                          (WatcherType::Data, WatchType::Data) |
+                          (WatcherType::Data, WatchType::Exist))
+            });
+            if !watches.is_empty() {
+                self.watches.insert(path.to_owned(), watches);
             }
         }
+        if watcher_type == WatcherType::Any {
+            self.persistent.remove(path);
+        }
     }
 
     fn cut_chroot(&self, event: &mut WatchedEvent) {
         if let Some(ref chroot) = self.chroot {
             if event.path.is_some() {
-                event.path = Some(event.path.as_ref().unwrap()[chroot.len()..].to_owned());
+                event.path = Some(strip_chroot(chroot, event.path.as_ref().unwrap()));
             }
         }
     }
 
     fn dispatch(&mut self, event: &WatchedEvent) {
         debug!("{:?}", event);
+        let persistent = self.dispatch_persistent(event);
         if let Some(watches) = self.find_watches(&event) {
             for watch in watches.into_iter() {
-                watch.watcher.handle(event.clone())
+                let mut event = event.clone();
+                event.watch_type = Some(watch.watch_type);
+                watch.watcher.handle(event)
+            }
+        } else if !persistent && !self.is_removing(event) {
+            let replacement = self.replacement.lock().unwrap_or_else(|e| e.into_inner()).take();
+            if let Some(watcher) = replacement {
+                self.watcher = watcher;
             }
-        } else {
             self.watcher.handle(event.clone())
         }
     }
 
+    /// Whether the event is for a path whose watches are being removed, so nobody expects it.
+    fn is_removing(&self, event: &WatchedEvent) -> bool {
+        match event.path {
+            Some(ref path) => self.removing.contains(path),
+            None => false,
+        }
+    }
+
+    /// Call the persistent watches of the event's path, and the recursive watches of its
+    /// ancestors, returning whether there were any.
+    fn dispatch_persistent(&mut self, event: &WatchedEvent) -> bool {
+        let path = match event.path {
+            Some(ref path) => path,
+            None => return false,
+        };
+
+        let mut dispatched = false;
+        let mut watched = path.as_str();
+        loop {
+            dispatched |= self.dispatch_persistent_at(watched, event, watched == path);
+            watched = match watched.rfind('/') {
+                Some(0) if watched.len() > 1 => "/",
+                Some(i) if i > 0 => &watched[..i],
+                _ => break,
+            };
+        }
+        dispatched
+    }
+
+    /// Call the persistent watches registered at `watched`, only the recursive ones unless the
+    /// event is for `watched` itself.
+    fn dispatch_persistent_at(&mut self,
+                              watched: &str,
+                              event: &WatchedEvent,
+                              exact: bool)
+                              -> bool {
+        let mut watches = match self.persistent.remove(watched) {
+            Some(watches) => watches,
+            None => return false,
+        };
+
+        let mut dispatched = false;
+        for watch in &watches {
+            if !watch.context.is_removed() && (exact || watch.recursive) {
+                (watch.watcher)(event.clone(), &watch.context);
+                dispatched = true;
+            }
+        }
+
+        watches.retain(|watch| !watch.context.is_removed());
+        if !watches.is_empty() {
+            self.persistent.insert(watched.to_owned(), watches);
+        } else if !self.watches.contains_key(watched) {
+            // Nothing watches the path anymore, so the server can stop sending events for it
+            self.remove_server_watches(watched);
+        }
+        dispatched
+    }
+
+    fn remove_server_watches(&mut self, path: &str) {
+        let requests = match self.requests {
+            Some(ref requests) => requests,
+            None => return,
+        };
+        self.removing.insert(path.to_owned());
+        let removed = WatchMessage::Removed(path.to_owned());
+        let path = match self.chroot {
+            Some(ref chroot) => format!("{}{}", chroot, path),
+            None => path.to_owned(),
+        };
+        let req = RemoveWatchesRequest {
+            path,
+            typ: WatcherType::Any as i32,
+        };
+        let result = requests.send(Xid::Next, |xid| {
+            let header = RequestHeader {
+                xid,
+                opcode: OpCode::RemoveWatches,
+            };
+            let data = try!(to_len_prefixed_buf(header, req)
+                                .map_err(|_| ZkError::MarshallingError));
+            Ok(RawRequest {
+                opcode: OpCode::RemoveWatches,
+                data,
+                listener: None,
+                completion: None,
+                // confirms the removal once all events sent before it were dispatched
+                watch: Some(removed),
+                cancel: None,
+                queue_slot: None,
+            })
+        });
+        if let Err(err) = result {
+            warn!("error removing watches: {:?}", err);
+        }
+    }
+
+    /// Check the registered watches against the nodes, delivering the events of the changes the
+    /// server did not notify about. See `ZooKeeperBuilder::watch_audit_interval`.
+    fn audit(&mut self, timeout: Duration) {
+        if !self.is_connected() {
+            debug!("Skipping the watch audit while not connected");
+            return;
+        }
+        let paths: Vec<String> = self.watches.keys().cloned().collect();
+        for path in paths {
+            let stat = match self.audit_exists(&path, timeout) {
+                Ok(stat) => stat,
+                Err(err) => {
+                    debug!("Skipping the watch audit: {:?}", err);
+                    return;
+                }
+            };
+            // Events sent before the response are queued already, so deliver them first
+            while let Ok(msg) = self.rx.try_recv() {
+                self.process_message(msg);
+            }
+
+            let mut missed = Vec::new();
+            for &(ref watch, zxid) in self.watches.get(&path).into_iter().flatten() {
+                if let Some(event_type) = missed_event(watch.watch_type, zxid, stat.as_ref()) {
+                    if !missed.contains(&event_type) {
+                        missed.push(event_type);
+                    }
+                }
+            }
+            for event_type in missed {
+                warn!("Watch audit found a missed {:?} event for {}", event_type, path);
+                let event = WatchedEvent {
+                    event_type,
+                    keeper_state: KeeperState::SyncConnected,
+                    path: Some(path.clone()),
+                    watch_type: None,
+                };
+                let gate = self.gate.clone();
+                gate.deliver(|| self.dispatch(&event));
+            }
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.session.as_ref().is_some_and(|session| {
+            session.lock().unwrap_or_else(|e| e.into_inner()).state == ConnectionState::Connected
+        })
+    }
+
+    /// Read the `Stat` of the node of `path`, `None` if it does not exist.
+    fn audit_exists(&self, path: &str, timeout: Duration) -> Result<Option<Stat>, ZkError> {
+        let requests = match self.requests {
+            Some(ref requests) => requests,
+            None => return Err(ZkError::ConnectionLoss),
+        };
+        let req = ExistsRequest {
+            path: match self.chroot {
+                Some(ref chroot) => format!("{}{}", chroot, path),
+                None => path.to_owned(),
+            },
+            watch: false,
+        };
+
+        let (tx, rx) = sync_channel(1);
+        try!(requests.send(Xid::Next, |xid| {
+            let header = RequestHeader {
+                xid,
+                opcode: OpCode::Exists,
+            };
+            let data = try!(to_len_prefixed_buf(header, req)
+                                .map_err(|_| ZkError::MarshallingError));
+            Ok(RawRequest {
+                opcode: OpCode::Exists,
+                data,
+                listener: Some(tx),
+                completion: None,
+                watch: None,
+                cancel: None,
+                queue_slot: None,
+            })
+        }));
+        let mut response = try!(rx.recv_timeout(timeout).map_err(|_| ZkError::OperationTimeout));
+        match response.header.err {
+            0 => {
+                let response = try!(StatResponse::read_from(&mut response.data)
+                                        .map_err(|_| ZkError::MarshallingError));
+                Ok(Some(response.stat))
+            }
+            err => {
+                match ZkError::from(err) {
+                    ZkError::NoNode => Ok(None),
+                    err => Err(err),
+                }
+            }
+        }
+    }
+
     fn find_watches(&mut self, event: &WatchedEvent) -> Option<Vec<Watch>> {
         if let Some(ref path) = event.path {
             match self.watches.remove(path) {
                 Some(watches) => {
 
-                    let (matching, left): (_, Vec<Watch>) = watches.into_iter().partition(|w| {
-                        match event.event_type {
+                    let (matching, left): (Vec<_>, Vec<_>) =
+                        watches.into_iter().partition(|(w, _)| match event.event_type {
                             NodeChildrenChanged => w.watch_type == WatchType::Child,
                             NodeCreated | NodeDataChanged => {
                                 w.watch_type == WatchType::Data || w.watch_type == WatchType::Exist
                             }
                             NodeDeleted => true,
                             _ => false,
-                        }
-                    });
+                        });
 
                     // put back the remaining watches
                     if !left.is_empty() {
@@ -159,7 +805,7 @@ impl<W: Watcher> ZkWatch<W> {
                     if matching.is_empty() {
                         None
                     } else {
-                        Some(matching)
+                        Some(matching.into_iter().map(|(watch, _)| watch).collect())
                     }
                 }
                 None => None,
@@ -169,3 +815,482 @@ impl<W: Watcher> ZkWatch<W> {
         }
     }
 }
+
+/// The event a watch set at `zxid` missed, judging by the current `Stat` of its node.
+fn missed_event(watch_type: WatchType, zxid: i64, stat: Option<&Stat>) -> Option<WatchedEventType> {
+    match (watch_type, stat) {
+        // It can't be told whether the node existed while the watch was set
+        (WatchType::Exist, None) => None,
+        (_, None) => Some(NodeDeleted),
+        (WatchType::Exist, Some(stat)) if stat.czxid > zxid => Some(NodeCreated),
+        (_, Some(stat)) if stat.czxid > zxid => Some(NodeDeleted),
+        (WatchType::Child, Some(stat)) if stat.pzxid > zxid => Some(NodeChildrenChanged),
+        (WatchType::Data, Some(stat)) |
+        (WatchType::Exist, Some(stat)) if stat.mzxid > zxid => Some(NodeDataChanged),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use consts::{AddWatchMode, WatchedEventType, WatcherType, ZkState};
+    use proto::{StringReader, WriteTo};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::thread;
+    use std::time::Duration;
+    use super::{WatchType, WatchedEvent};
+    use testing::{connected, data_changed, memory_pipes, reply_data, stat, MemoryServer};
+    use zookeeper::{ZooKeeper, ZooKeeperBuilder};
+
+    #[test]
+    fn recursive_watch_receives_descendant_events() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx = Mutex::new(event_tx);
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            zk1.add_watch("/app", AddWatchMode::PersistentRecursive, move |event, _| {
+                   event_tx.lock().unwrap().send(event).unwrap();
+               })
+               .unwrap();
+        });
+
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 106);
+        server.reply(xid, 10, 0);
+        client.join().unwrap();
+
+        let events = [(1, WatchedEventType::NodeCreated, "/app/a"),
+                      (3, WatchedEventType::NodeDataChanged, "/app/a/b"),
+                      (2, WatchedEventType::NodeDeleted, "/other"),
+                      (3, WatchedEventType::NodeDataChanged, "/app")];
+        for (zxid, &(raw_type, _, path)) in events.iter().enumerate() {
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(-1).unwrap();
+            frame.write_i64::<BigEndian>(11 + zxid as i64).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            frame.write_i32::<BigEndian>(raw_type).unwrap();
+            frame.write_i32::<BigEndian>(3).unwrap(); // SyncConnected
+            path.to_owned().write_to(&mut frame).unwrap();
+            server.write_frame(&frame);
+        }
+
+        // the watch stays registered, and only gets the events of /app and its descendants
+        for &(_, event_type, path) in events.iter().filter(|&&(_, _, path)| path != "/other") {
+            let event = event_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!((event.event_type, event.path), (event_type, Some(path.to_owned())));
+        }
+        assert!(event_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 20, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn removed_persistent_watch_is_removed_on_the_server() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            zk1.add_watch("/node", AddWatchMode::Persistent, |_, context| context.remove_watch())
+               .unwrap();
+        });
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 106);
+        server.reply(xid, 10, 0);
+        client.join().unwrap();
+
+        // the watch removes itself on its first event, with the next xid of the session
+        data_changed(&server, 11, "/node");
+        let mut request = server.read_frame();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), xid + 1);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 18);
+        assert_eq!(request.read_string().unwrap(), "/node");
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), WatcherType::Any as i32);
+        server.reply(xid + 1, 12, 0);
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 13, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn remove_watches_drops_the_watcher() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+        let (event_tx, event_rx) = mpsc::channel();
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            let event_tx = Mutex::new(event_tx);
+            zk1.get_data_w("/node", move |event: WatchedEvent| {
+                   event_tx.lock().unwrap().send(event).unwrap();
+               })
+               .unwrap();
+        });
+        reply_data(&server, 10, 0, b"");
+        client.join().unwrap();
+
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.remove_watches("/node", WatcherType::Data));
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 18);
+        assert_eq!(request.read_string().unwrap(), "/node");
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 2);
+        server.reply(xid, 11, 0);
+        assert_eq!(client.join().unwrap(), Ok(()));
+
+        // the watcher is dropped without being called
+        data_changed(&server, 12, "/node");
+        assert_eq!(event_rx.recv_timeout(Duration::from_secs(5)).err(),
+                   Some(mpsc::RecvTimeoutError::Disconnected));
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 20, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watch_audit_detects_missed_change() {
+        let builder = ZooKeeperBuilder::new().watch_audit_interval(Some(Duration::from_millis(50)));
+        let (zk, server) = connected(builder);
+        let zk = Arc::new(zk);
+
+        let (event_tx, event_rx) = mpsc::channel();
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            let event_tx = Mutex::new(event_tx);
+            zk1.get_data_w("/node", move |event: WatchedEvent| {
+                   event_tx.lock().unwrap().send(event).unwrap();
+               })
+               .unwrap();
+        });
+
+        // getData sets the watch at zxid 10
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(10).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap(); // data
+        stat(5, 5).write_to(&mut frame).unwrap();
+        server.write_frame(&frame);
+        client.join().unwrap();
+
+        // the node changed at zxid 20, but the notification got lost; the audit finds out
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 3);
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(21).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        stat(5, 20).write_to(&mut frame).unwrap();
+        server.write_frame(&frame);
+
+        let event = event_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+        assert_eq!(event.path, Some("/node".to_owned()));
+        assert_eq!(event.watch_type, Some(WatchType::Data));
+
+        // the watch is consumed, so the next request is the close
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 22, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watch_audit_is_skipped_while_not_connected() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .watch_audit_interval(Some(Duration::from_millis(200)))
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.get_data_w("/node", |_: WatchedEvent| {}));
+        reply_data(&first, 10, 0, b"");
+        client.join().unwrap().unwrap();
+        first.close();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+
+        // while the next server does not answer the handshake, nothing else is written
+        let second = servers.remove(0);
+        thread::sleep(Duration::from_millis(600));
+        {
+            let inbound: Vec<u8> = second.inbound.lock().unwrap().iter().cloned().collect();
+            let len = (&inbound[..4]).read_i32::<BigEndian>().unwrap() as usize;
+            assert_eq!(inbound.len(), 4 + len);
+        }
+
+        second.accept();
+        let server = thread::spawn(move || {
+            loop {
+                let mut request = second.read_frame();
+                let xid = request.read_i32::<BigEndian>().unwrap();
+                if request.read_i32::<BigEndian>().unwrap() == -11 {
+                    return second.reply(xid, 20, 0);
+                }
+                // the audit finds the node unchanged
+                let mut frame = Vec::new();
+                frame.write_i32::<BigEndian>(xid).unwrap();
+                frame.write_i64::<BigEndian>(11).unwrap();
+                frame.write_i32::<BigEndian>(0).unwrap();
+                stat(5, 10).write_to(&mut frame).unwrap();
+                second.write_frame(&frame);
+            }
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn set_watches_in_one_request() {
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx = Mutex::new(event_tx);
+        let (zk, server) = connected(ZooKeeperBuilder::new().watcher(move |event: WatchedEvent| {
+            event_tx.lock().unwrap().send(event).unwrap();
+        }));
+        let zk = Arc::new(zk);
+
+        let data_paths: Vec<_> = (0..100).map(|i| format!("/node{}", i)).collect();
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            let data_paths: Vec<_> = data_paths.iter().map(|path| path.as_str()).collect();
+            zk1.set_watches(&data_paths, &["/missing"], &["/"], 42)
+        });
+        let mut request = server.read_frame();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), -8);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 101);
+        assert_eq!(request.read_i64::<BigEndian>().unwrap(), 42);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 100);
+        for i in 0..100 {
+            assert_eq!(request.read_string().unwrap(), format!("/node{}", i));
+        }
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 1);
+        assert_eq!(request.read_string().unwrap(), "/missing");
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 1);
+        assert_eq!(request.read_string().unwrap(), "/");
+        server.reply(-8, 43, 0);
+        assert_eq!(client.join().unwrap(), Ok(()));
+
+        // the watches trigger the default watcher
+        data_changed(&server, 44, "/node7");
+        let event = event_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+        assert_eq!(event.path, Some("/node7".to_owned()));
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 45, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watch_data_rearms_until_the_receiver_is_dropped() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.watch_data("/node"));
+        reply_data(&server, 1, 0, b"v1");
+        let (data, _, events) = client.join().unwrap().unwrap();
+        assert_eq!(data, b"v1".to_vec());
+
+        for zxid in 2..4 {
+            data_changed(&server, zxid, "/node");
+            let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+            assert_eq!(event.path, Some("/node".to_owned()));
+            // the watch is left again, with the next xid of the session
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(xid, zxid as i32);
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 3);
+            assert_eq!(request.read_string().unwrap(), "/node");
+            assert_eq!(request.read_u8().unwrap(), 1);
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(zxid).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            stat(5, zxid).write_to(&mut frame).unwrap();
+            server.write_frame(&frame);
+        }
+
+        // without a receiver, the next event isn't followed by another request
+        drop(events);
+        data_changed(&server, 4, "/node");
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 5, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    /// Answer the exists request which leaves the watch on `/node` again.
+    fn reply_rearm(server: &MemoryServer, zxid: i64, err: i32) {
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 3);
+        assert_eq!(request.read_string().unwrap(), "/node");
+        assert_eq!(request.read_u8().unwrap(), 1);
+        if err != 0 {
+            return server.reply(xid, zxid, err);
+        }
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(zxid).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        stat(5, zxid).write_to(&mut frame).unwrap();
+        server.write_frame(&frame);
+    }
+
+    #[test]
+    fn watch_data_rearms_once_reconnected() {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.watch_data("/node"));
+        reply_data(&first, 10, 0, b"v1");
+        let (_, _, events) = client.join().unwrap().unwrap();
+
+        // the server drops the idle watch along with the connection
+        first.close();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+        let second = servers.remove(0);
+        second.accept();
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event_type, WatchedEventType::None);
+        assert_eq!(event.path, Some("/node".to_owned()));
+        reply_rearm(&second, 11, 0);
+
+        // the watch left before the disconnect fires as well, but the event is passed on once
+        data_changed(&second, 12, "/node");
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+        reply_rearm(&second, 13, 0);
+        assert!(events.try_recv().is_err());
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 14, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watch_data_closes_the_channel_once_rearming_fails() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.watch_data("/node"));
+        reply_data(&server, 1, 0, b"v1");
+        let (_, _, events) = client.join().unwrap().unwrap();
+
+        // a lost connection is retried, any other error closes the channel
+        data_changed(&server, 2, "/node");
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+        reply_rearm(&server, 3, -4);
+        reply_rearm(&server, 4, -102);
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)),
+                   Err(RecvTimeoutError::Disconnected));
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 5, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn default_watcher_replaces_itself() {
+        let (event_tx, event_rx) = mpsc::channel();
+        let event_tx = Mutex::new(event_tx);
+        let handle: Arc<Mutex<Option<Arc<ZooKeeper>>>> = Arc::new(Mutex::new(None));
+        let handle1 = handle.clone();
+        let (zk, server) = connected(ZooKeeperBuilder::new().watcher(move |_: WatchedEvent| {
+            // the first event hands the following ones to a new watcher
+            if let Some(zk) = handle1.lock().unwrap().take() {
+                let event_tx = Mutex::new(event_tx.lock().unwrap().clone());
+                zk.set_default_watcher(move |event: WatchedEvent| {
+                    event_tx.lock().unwrap().send(event.path).unwrap();
+                });
+            }
+        }));
+        let zk = Arc::new(zk);
+        *handle.lock().unwrap() = Some(zk.clone());
+
+        data_changed(&server, 10, "/a");
+        data_changed(&server, 11, "/b");
+        assert_eq!(event_rx.recv_timeout(Duration::from_secs(5)), Ok(Some("/b".to_owned())));
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 12, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+}
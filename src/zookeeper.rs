@@ -1,42 +1,655 @@
 use acl::*;
+use byteorder::{BigEndian, ByteOrder};
+use backpressure::Backpressure;
+use cancel::CancellationToken;
+use connector::{Connector, TcpConnector};
 use consts::*;
 use data::*;
+use future::{Completion, ZkFuture};
+use multi::{Op, OpResult};
+use offline_queue::{OfflineQueue, QueueOverflowPolicy, QueueSlot};
 use proto::*;
-use io::ZkIo;
+use quorum::CONFIG_NODE;
+use rate_limit::RateLimiter;
+use server_role::{query_server_role, ServerRole};
+use transaction::Transaction;
+use view::ConsistentView;
+use hosts::{HostProvider, HostStat, Resolver, SystemResolver};
+use io::{ConnectionState, IoControl, IoFailurePolicy, IoOptions, ReconnectEvent, ReconnectPolicy,
+         SessionState, ZkIo};
 use listeners::{ListenerSet, Subscription};
+use metrics::Metrics;
+use paths::{find_protected, glob_match, make_path, protected_prefix, split_path, strip_chroot,
+            within_chroot};
 use mio_extras::channel::Sender as MioSender;
-use watch::{Watch, Watcher, WatchType, ZkWatch};
-use std::convert::From;
-use std::net::{SocketAddr, ToSocketAddrs};
+use watch::{BoxedWatcher, ChannelWatcher, DefaultWatcher, EventGate, NoopWatcher, PersistentWatch,
+            Rearmer, RegisteredWatches, Watch, WatchContext, WatchedEvent, Watcher, WatchMessage,
+            WatchType, ZkWatch};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::convert::{From, TryFrom};
+use std::fmt;
+use std::net::SocketAddr;
+use std::hash::{BuildHasher, Hasher};
 use std::result;
 use std::string::ToString;
-use std::sync::atomic::{AtomicIsize, Ordering};
-use std::sync::mpsc::{sync_channel, SyncSender};
-use std::sync::Mutex;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, channel, sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
 
+/// The source of the ids telling apart the handles whose operations failed on a thread.
+static NEXT_HANDLE_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The most recent operation of the current thread which failed, along with the id of its
+    /// handle. See `ZooKeeper::last_error`.
+    static LAST_ERROR: RefCell<Option<(usize, ZkErrorWithContext)>> = const { RefCell::new(None) };
+}
+
+/// How many times `create_with_token` tries to create a node before giving up.
+const MAX_CREATE_ATTEMPTS: usize = 3;
+
+/// How long `consistent_view` waits before syncing again.
+const CONSISTENT_VIEW_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// The default of `ZooKeeperBuilder::max_update_attempts`.
+const DEFAULT_MAX_UPDATE_ATTEMPTS: usize = 10;
+
+/// The default of `ZooKeeperBuilder::max_path_length`.
+const DEFAULT_MAX_PATH_LENGTH: usize = 4096;
+
+/// The default of `ZooKeeperBuilder::max_request_size`, the default `jute.maxbuffer` of the
+/// server.
+const DEFAULT_MAX_REQUEST_SIZE: usize = 0xfffff;
+
+/// The default of `ZooKeeperBuilder::avoid_host_cooldown`.
+const DEFAULT_AVOID_HOST_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// The longest TTL of a node in milliseconds, as the server keeps it in 40 bits of the ephemeral
+/// owner.
+const MAX_TTL_MILLIS: i64 = 0xff_ffff_ffff;
+
+/// A random number, for ids and jitter.
+pub(crate) fn random_u64() -> u64 {
+    let nanos = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.subsec_nanos())
+                    .unwrap_or(0);
+    // every RandomState is randomly keyed
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(nanos);
+    hasher.finish()
+}
+
+/// Generate an id which is unique with very high probability, for `ZooKeeper::create_protected`.
+fn protected_id() -> String {
+    format!("{:016x}{:016x}", random_u64(), random_u64())
+}
+
 /// Value returned from potentially-error operations.
 pub type ZkResult<T> = result::Result<T, ZkError>;
 
+/// The nodes read by `ZooKeeper::get_data_cached`, until their watches fire.
+type ImmutableCache = Arc<Mutex<HashMap<String, (Vec<u8>, Stat)>>>;
+
 pub struct RawRequest {
     pub opcode: OpCode,
     pub data: ByteBuf,
     pub listener: Option<SyncSender<RawResponse>>,
-    pub watch: Option<Watch>,
+    /// Wakes the `ZkFuture` of the request once it is dropped, after `listener`.
+    #[allow(dead_code)] // only dropped
+    pub completion: Option<Completion>,
+    /// Passed to the event thread once the response arrived (like a `Watch` to register).
+    pub watch: Option<WatchMessage>,
+    /// Once cancelled, the request is dropped unless it has been written already.
+    pub cancel: Option<CancellationToken>,
+    /// The place of a request submitted while disconnected, claimed before writing it.
+    pub queue_slot: Option<QueueSlot>,
+}
+
+impl RawRequest {
+    pub fn is_cancelled(&self) -> bool {
+        match self.cancel {
+            Some(ref token) => token.is_cancelled(),
+            None => false,
+        }
+    }
+
+    /// The xid in the header of the request, which follows the length prefix.
+    pub fn xid(&self) -> i32 {
+        BigEndian::read_i32(&self.data.get_ref()[FrameCodec::HEADER_LEN..])
+    }
+}
+
+/// The channel of requests to the IO thread, along with the counter their xids are taken from.
+/// Shared by the `ZooKeeper` handle and the event thread, which sends requests of its own (like
+/// leaving the watch of `ZooKeeper::watch_data` again).
+pub struct RequestSender {
+    io: Mutex<MioSender<RawRequest>>,
+    xid: AtomicIsize,
+}
+
+impl RequestSender {
+    pub fn new(io: MioSender<RawRequest>) -> RequestSender {
+        RequestSender {
+            io: Mutex::new(io),
+            xid: AtomicIsize::new(1),
+        }
+    }
+
+    /// Hand the request made by `request` from its xid to the IO thread, returning the xid.
+    ///
+    /// The xid is assigned while holding the channel to the IO thread, so xids strictly increase
+    /// in the order the requests are written to the server, even when several threads send
+    /// requests concurrently. The server applies the requests of a session in that order.
+    pub(crate) fn send<F>(&self, xid: Xid, request: F) -> ZkResult<i32>
+        where F: FnOnce(i32) -> ZkResult<RawRequest>
+    {
+        let io = self.io.lock().unwrap_or_else(|e| e.into_inner());
+        let xid = match xid {
+            Xid::Next => self.xid.fetch_add(1, Ordering::Relaxed) as i32,
+            Xid::Fixed(xid) => xid,
+        };
+        let request = try!(request(xid));
+        trace!("request opcode={:?} xid={:?}", request.opcode, xid);
+        if let Err(err) = io.send(request) {
+            warn!("error sending request: {:?}", err);
+            return Err(ZkError::ConnectionLoss);
+        }
+        Ok(xid)
+    }
 }
 
 pub struct RawResponse {
     pub header: ReplyHeader,
     pub data: ByteBuf,
+    /// The size of the response on the wire, including its length prefix.
+    pub len: usize,
+}
+
+/// Result of `ZooKeeper::create_with_token`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CreateOutcome {
+    /// The node was created by this call. Contains the actual path of the created node.
+    Created(String),
+    /// The node already existed, but it carries the caller's token, so it was created by this
+    /// caller (typically by an attempt whose response was lost with the connection).
+    AlreadyOwned(String),
+    /// The node already exists and was created by someone else.
+    Conflict,
+}
+
+/// How `ZooKeeper::get_data_string` decodes the data of a node.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StringDecoding {
+    /// Data which is not valid UTF-8 is an error. This is the default.
+    #[default]
+    Strict,
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT CHARACTER`.
+    Lossy,
+}
+
+/// A failed operation, with the context it failed in. See `ZooKeeper::last_error`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZkErrorWithContext {
+    /// The error the operation failed with.
+    pub error: ZkError,
+    /// The kind of request which failed.
+    pub opcode: OpCode,
+    /// The path the request was for, `None` for requests without a path (like `commit`).
+    pub path: Option<String>,
+    /// The id of the request.
+    pub xid: i32,
+}
+
+impl ZkErrorWithContext {
+    /// The bare error, for matching.
+    pub fn kind(&self) -> ZkError {
+        self.error
+    }
+}
+
+impl fmt::Display for ZkErrorWithContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path {
+            Some(ref path) => {
+                write!(f, "{} ({:?} {}, xid {})", self.error, self.opcode, path, self.xid)
+            }
+            None => write!(f, "{} ({:?}, xid {})", self.error, self.opcode, self.xid),
+        }
+    }
+}
+
+/// Options for connecting to a ZooKeeper cluster, for when `ZooKeeper::connect` is not enough.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use zookeeper::ZooKeeperBuilder;
+///
+/// let zk = ZooKeeperBuilder::new()
+///     .connect_string("127.0.0.1:2181")
+///     .session_timeout(Duration::from_secs(15))
+///     .dns_refresh_interval(Duration::from_secs(60))
+///     .connect()
+///     .unwrap();
+/// ```
+pub struct ZooKeeperBuilder {
+    connect_string: Option<String>,
+    session_timeout: Duration,
+    watcher: Option<BoxedWatcher>,
+    dns_refresh_interval: Duration,
+    max_path_length: usize,
+    max_request_size: usize,
+    require_exact_timeout: bool,
+    min_protocol_version: Option<i32>,
+    max_update_attempts: usize,
+    rate_limit: Option<u32>,
+    connector: Option<Box<dyn Connector>>,
+    resolver: Option<Box<dyn Resolver>>,
+    host_circuit_breaker: Option<(u32, Duration)>,
+    adaptive_backpressure: bool,
+    metrics: Option<Box<dyn Metrics>>,
+    avoid_host_cooldown: Duration,
+    watch_audit_interval: Option<Duration>,
+    io_failure_policy: IoFailurePolicy,
+    offline_queue_limit: Option<usize>,
+    offline_queue_policy: QueueOverflowPolicy,
+    session: Option<(i64, Vec<u8>)>,
+    reconnect_policy: ReconnectPolicy,
+    read_only: bool,
+    chroot: Option<String>,
+    auth: Vec<(String, Vec<u8>)>,
+}
+
+impl ZooKeeperBuilder {
+    /// Create a builder with the default options. The connect string must be set before calling
+    /// `connect`.
+    pub fn new() -> ZooKeeperBuilder {
+        ZooKeeperBuilder {
+            connect_string: None,
+            session_timeout: Duration::from_secs(30),
+            watcher: None,
+            dns_refresh_interval: Duration::from_secs(0),
+            max_path_length: DEFAULT_MAX_PATH_LENGTH,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            require_exact_timeout: false,
+            min_protocol_version: None,
+            max_update_attempts: DEFAULT_MAX_UPDATE_ATTEMPTS,
+            rate_limit: None,
+            connector: None,
+            resolver: None,
+            host_circuit_breaker: None,
+            adaptive_backpressure: false,
+            metrics: None,
+            avoid_host_cooldown: DEFAULT_AVOID_HOST_COOLDOWN,
+            watch_audit_interval: None,
+            io_failure_policy: IoFailurePolicy::default(),
+            offline_queue_limit: None,
+            offline_queue_policy: QueueOverflowPolicy::default(),
+            session: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            read_only: false,
+            chroot: None,
+            auth: Vec::new(),
+        }
+    }
+
+    /// Set the servers to connect to and the optional chroot (see `ZooKeeper::connect`).
+    pub fn connect_string(mut self, connect_string: &str) -> ZooKeeperBuilder {
+        self.connect_string = Some(connect_string.to_owned());
+        self
+    }
+
+    /// Root the client at the given path, like the chroot suffix of the connect string: all paths
+    /// are relative to it. Setting a chroot both here and in the connect string fails `connect`
+    /// with `ZkError::BadArguments`.
+    pub fn chroot(mut self, chroot: &str) -> ZooKeeperBuilder {
+        self.chroot = Some(chroot.to_owned());
+        self
+    }
+
+    /// Accept connections to servers which are partitioned from the quorum and only serve reads,
+    /// in which case the state is `ZkState::ConnectedReadOnly` and writes fail with
+    /// `ZkError::NotReadOnly`. Defaults to `false`.
+    pub fn read_only(mut self, read_only: bool) -> ZooKeeperBuilder {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Add the specified `scheme`:`auth` information, like `ZooKeeper::add_auth`, but send it
+    /// with every connection handshake, ahead of any other request, as the server only keeps it
+    /// for the connection. Can be called several times to add several identities.
+    ///
+    /// As the server has to accept them, `connect` blocks until a handshake got through, trying
+    /// the next server if an attempt fails, for up to the session timeout. It fails with
+    /// `ZkError::AuthFailed` if the server rejects one. If the server rejects one on a later
+    /// handshake, the client gives up with `ZkState::AuthFailed`.
+    pub fn add_auth<S: ToString>(mut self, scheme: S, auth: Vec<u8>) -> ZooKeeperBuilder {
+        self.auth.push((scheme.to_string(), auth));
+        self
+    }
+
+    /// Set the session timeout (30 seconds by default).
+    pub fn session_timeout(mut self, timeout: Duration) -> ZooKeeperBuilder {
+        self.session_timeout = timeout;
+        self
+    }
+
+    /// Resume the session with the given id and password (see `ZooKeeper::session_id` and
+    /// `ZooKeeper::session_password`) instead of creating a new one, like after a restart of the
+    /// process. If the session has expired in the meantime, the default watcher receives a
+    /// `KeeperState::Expired` event and the client creates a new session.
+    pub fn session(mut self, session_id: i64, passwd: Vec<u8>) -> ZooKeeperBuilder {
+        self.session = Some((session_id, passwd));
+        self
+    }
+
+    /// Set the default `Watcher`, which is notified of all events without a specific `Watcher`. By
+    /// default, such events are dropped.
+    ///
+    /// This includes the state of the connection: an event of type `WatchedEventType::None` with
+    /// `KeeperState::Disconnected` is delivered as soon as the connection is lost, once until the
+    /// client reconnects, and one with `KeeperState::SyncConnected` (or `ConnectedReadOnly`)
+    /// once it has. If the server reports the session as expired while reconnecting, one with
+    /// `KeeperState::Expired` is delivered.
+    pub fn watcher<W: Watcher + 'static>(mut self, watcher: W) -> ZooKeeperBuilder {
+        self.watcher = Some(Box::new(watcher));
+        self
+    }
+
+    /// Set how often the host names of the connect string are resolved again, so that changes of
+    /// their addresses are picked up. Resolution only happens when picking a server to (re)connect
+    /// to, and at most once per `interval`. Zero (the default) disables re-resolution: the names
+    /// are resolved once by `connect`.
+    pub fn dns_refresh_interval(mut self, interval: Duration) -> ZooKeeperBuilder {
+        self.dns_refresh_interval = interval;
+        self
+    }
+
+    /// Set how long a server stays avoided after `ZooKeeper::avoid_host`. Defaults to one minute.
+    pub fn avoid_host_cooldown(mut self, cooldown: Duration) -> ZooKeeperBuilder {
+        self.avoid_host_cooldown = cooldown;
+        self
+    }
+
+    /// Periodically check the watches set with `exists_w`, `get_data_w` and `get_children_w`
+    /// against their nodes, as a safety net for long-lived watches. If a node changed after its
+    /// watch was set without the watch being triggered, the watch is triggered with the event it
+    /// missed. Each audit reads the `Stat` of every watched node, so it adds load on the server.
+    /// The audit is skipped while the client is not connected. It holds up the delivery of events
+    /// while waiting for a node, which it gives up on after a second.
+    ///
+    /// An exist watch on a node which does not exist is only triggered by the audit once the
+    /// node was created. `None` (the default) disables the audit.
+    pub fn watch_audit_interval(mut self, interval: Option<Duration>) -> ZooKeeperBuilder {
+        self.watch_audit_interval = interval;
+        self
+    }
+
+    /// Skip a server for `cooldown` while picking one to connect to, once `failures` attempts to
+    /// connect to it failed in a row, so a flapping server does not keep the client from
+    /// connecting to a healthy one. If all servers are skipped, one is connected to anyway. Off by
+    /// default; see `ZooKeeper::host_stats`.
+    pub fn host_circuit_breaker(mut self, failures: u32, cooldown: Duration) -> ZooKeeperBuilder {
+        self.host_circuit_breaker = Some((failures, cooldown));
+        self
+    }
+
+    /// Set how long the client waits between attempts to reconnect after losing the connection,
+    /// to spare a recovering ensemble. The waits are cut short by the session timeout. Defaults to
+    /// `ReconnectPolicy::Immediate`.
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> ZooKeeperBuilder {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Set what happens when the IO thread panics, instead of leaving all operations hanging.
+    /// Defaults to `IoFailurePolicy::Close`.
+    pub fn io_failure_policy(mut self, policy: IoFailurePolicy) -> ZooKeeperBuilder {
+        self.io_failure_policy = policy;
+        self
+    }
+
+    /// Limit the number of requests queued while the client is not connected to `limit`. They
+    /// pile up while it reconnects, and are kept across failed attempts until the session is
+    /// established again or expires, so a long outage could otherwise take up unbounded memory.
+    /// `offline_queue_policy` decides what happens to requests beyond the limit. By default, the
+    /// queue is not limited.
+    pub fn offline_queue_limit(mut self, limit: usize) -> ZooKeeperBuilder {
+        self.offline_queue_limit = Some(limit);
+        self
+    }
+
+    /// Set what happens to requests submitted while the queue limited by `offline_queue_limit` is
+    /// full. Defaults to `QueueOverflowPolicy::Block`.
+    pub fn offline_queue_policy(mut self, policy: QueueOverflowPolicy) -> ZooKeeperBuilder {
+        self.offline_queue_policy = policy;
+        self
+    }
+
+    /// Set the maximum length in bytes of a path (including the chroot) accepted by operations.
+    /// Longer paths fail with `ZkError::BadArguments` without contacting the server. Defaults to
+    /// 4096.
+    pub fn max_path_length(mut self, max_path_length: usize) -> ZooKeeperBuilder {
+        self.max_path_length = max_path_length;
+        self
+    }
+
+    /// Set the maximum size in bytes of a serialized request, which should match the
+    /// `jute.maxbuffer` of the servers. The server closes the connection on a larger request, so
+    /// larger requests (like a `commit` of many operations with large data) fail with
+    /// `ZkError::BadArguments` without being sent instead. Defaults to 1048575.
+    pub fn max_request_size(mut self, max_request_size: usize) -> ZooKeeperBuilder {
+        self.max_request_size = max_request_size;
+        self
+    }
+
+    /// Require the server to accept the session timeout as requested. Servers clamp the timeout
+    /// into their configured `[minSessionTimeout, maxSessionTimeout]` range; if this is `true`
+    /// and the server changes the timeout, the connection fails with `ZkError::BadArguments`. As
+    /// this can only be verified once the session is established, `connect` blocks until then.
+    ///
+    /// Defaults to `false`, which accepts the timeout chosen by the server.
+    pub fn require_exact_timeout(mut self, require_exact_timeout: bool) -> ZooKeeperBuilder {
+        self.require_exact_timeout = require_exact_timeout;
+        self
+    }
+
+    /// Require the server to negotiate at least the given protocol version, to refuse operating
+    /// against an incompatible server instead of failing to parse its responses later. If the
+    /// server negotiates an older version, the connection fails with `ZkError::BadArguments`. As
+    /// this can only be verified once connected, `connect` blocks until then.
+    ///
+    /// Defaults to `None`, which accepts any version.
+    pub fn min_protocol_version(mut self, min_protocol_version: i32) -> ZooKeeperBuilder {
+        self.min_protocol_version = Some(min_protocol_version);
+        self
+    }
+
+    /// Set how many times read-modify-write helpers like `ZooKeeper::update_acl` try to apply
+    /// their change before giving up with `ZkError::BadVersion`. Defaults to 10.
+    pub fn max_update_attempts(mut self, max_update_attempts: usize) -> ZooKeeperBuilder {
+        self.max_update_attempts = max_update_attempts;
+        self
+    }
+
+    /// Limit the number of requests sent to the cluster to `ops_per_sec`, to protect a shared
+    /// ensemble from a client sending more requests than it can handle. Calls exceeding the budget
+    /// block until they may be sent; bursts of up to one second worth of requests are let
+    /// through without waiting. Pings and closing the session are not limited. The `*_async`
+    /// operations don't block, but fail with `ZkError::Throttled` instead.
+    ///
+    /// Not limited by default.
+    pub fn rate_limit(mut self, ops_per_sec: u32) -> ZooKeeperBuilder {
+        self.rate_limit = Some(ops_per_sec);
+        self
+    }
+
+    /// Slow down the submission of requests while the ensemble is struggling, to avoid piling
+    /// more work on it. While replies take longer than 100ms on average and at least 8 requests
+    /// are pending, each new request is delayed by the average reply latency. The `*_async`
+    /// operations don't wait, but fail with `ZkError::Throttled` instead.
+    ///
+    /// Disabled by default.
+    pub fn adaptive_backpressure(mut self, enabled: bool) -> ZooKeeperBuilder {
+        self.adaptive_backpressure = enabled;
+        self
+    }
+
+    /// Report the latency and the bytes on the wire of each request to `metrics`.
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> ZooKeeperBuilder {
+        self.metrics = Some(Box::new(metrics));
+        self
+    }
+
+    /// Resolve the hosts of the connect string with `resolver` instead of the system's resolution,
+    /// for example to look the servers up in a service discovery system. `dns_refresh_interval`
+    /// applies to it as well.
+    pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> ZooKeeperBuilder {
+        self.resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Create the connections to the servers with `connector` instead of connecting via TCP, for
+    /// example to test the client over an in-memory transport.
+    #[cfg(any(test, feature = "connector"))]
+    pub fn connector<C: Connector + 'static>(mut self, connector: C) -> ZooKeeperBuilder {
+        self.connector = Some(Box::new(connector));
+        self
+    }
+
+    /// Connect to the cluster with the configured options.
+    ///
+    /// # Errors
+    /// If the connect string is missing or invalid, or one of its hosts cannot be resolved,
+    /// `Err(ZkError::BadArguments)` is returned. The same happens if the server did not accept the
+    /// session timeout while `require_exact_timeout` is set, or negotiated a protocol version below
+    /// `min_protocol_version`. If the server rejects authentication information added with
+    /// `add_auth`, `Err(ZkError::AuthFailed)` is returned, and if no handshake got through within
+    /// the session timeout, `Err(ZkError::ConnectionLoss)`.
+    pub fn connect(self) -> ZkResult<ZooKeeper> {
+        ZooKeeper::connect_with(self)
+    }
+}
+
+impl Default for ZooKeeperBuilder {
+    fn default() -> ZooKeeperBuilder {
+        ZooKeeperBuilder::new()
+    }
+}
+
+/// The xid of a request.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Xid {
+    /// The next xid of the session, assigned in the order the requests are sent.
+    Next,
+    /// A reserved xid, like the one of authentication requests.
+    Fixed(i32),
+}
+
+/// What a request does while a limit of the client applies: the rate limit, the backpressure or a
+/// full offline queue with `QueueOverflowPolicy::Block`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OnLimit {
+    /// Block until the request may be sent.
+    Wait,
+    /// Fail right away, as the `*_async` operations must not block the caller.
+    Fail,
+}
+
+/// The bookkeeping of a request, from before it is sent until its result is known: the
+/// backpressure, the metrics and the last error of the handle. Shared by the blocking and the
+/// `*_async` operations.
+struct RequestRecord {
+    handle_id: usize,
+    opcode: OpCode,
+    path: Option<String>,
+    bytes_out: usize,
+    start: Instant,
+    backpressure: Option<Arc<Backpressure>>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl RequestRecord {
+    /// Record the result of the request, which failed with `xid` 0 if it was never sent.
+    fn finish<T>(mut self, xid: i32, bytes_in: usize, result: &ZkResult<T>) {
+        let latency = self.start.elapsed();
+        if let Some(backpressure) = self.backpressure.take() {
+            backpressure.completed(latency);
+        }
+        if let Some(ref metrics) = self.metrics {
+            let err = result.as_ref().err().cloned();
+            metrics.on_response(self.opcode, latency, self.bytes_out, bytes_in, err);
+        }
+        if let Err(ref error) = *result {
+            let context = ZkErrorWithContext {
+                error: *error,
+                opcode: self.opcode,
+                path: self.path.take(),
+                xid,
+            };
+            let handle_id = self.handle_id;
+            LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some((handle_id, context)));
+        }
+    }
+}
+
+impl Drop for RequestRecord {
+    /// Keeps the backpressure balanced when the future of a request is dropped before its result
+    /// is known.
+    fn drop(&mut self) {
+        if let Some(ref backpressure) = self.backpressure {
+            backpressure.completed(self.start.elapsed());
+        }
+    }
 }
 
 /// The client interface for interacting with a ZooKeeper cluster.
+///
+/// A `ZooKeeper` is `Send + Sync`, so one handle can be shared (like in an `Arc`) by any number of
+/// threads without further locking. Their requests are pipelined over the single connection and
+/// the responses are correlated with the waiting callers by the IO thread. However many threads
+/// share the handle, it is a single session: its ephemeral nodes, watches and authentication are
+/// shared by all of them, and they all lose the session together.
+///
+/// Requests are written to the server in the order they are submitted, and the server applies the
+/// requests of a session in that order. So an operation which returned before another one was
+/// started is always applied first.
 pub struct ZooKeeper {
     chroot: Option<String>,
-    xid: AtomicIsize,
-    io: Mutex<MioSender<RawRequest>>,
+    requests: Arc<RequestSender>,
+    io_control: Mutex<MioSender<IoControl>>,
+    watch_sender: Mutex<mpsc::Sender<WatchMessage>>,
+    event_gate: Arc<EventGate>,
     listeners: ListenerSet<ZkState>,
+    reconnect_listeners: ListenerSet<ReconnectEvent>,
+    default_watcher: DefaultWatcher,
+    max_path_length: usize,
+    max_request_size: usize,
+    session_timeout: Duration,
+    session: Arc<Mutex<SessionState>>,
+    max_update_attempts: usize,
+    rate_limiter: Option<RateLimiter>,
+    /// Identifies the handle in `LAST_ERROR`.
+    id: usize,
+    /// The role of the connected server, along with the connection it was queried for.
+    server_role: Mutex<Option<(u64, ServerRole)>>,
+    backpressure: Option<Arc<Backpressure>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    avoid_host_cooldown: Duration,
+    offline_queue: Option<Arc<OfflineQueue>>,
+    immutable_cache: ImmutableCache,
+    /// Set by `detach`, so dropping the handle leaves the session open.
+    detached: bool,
+    /// Counted by the event thread.
+    registered_watches: Arc<Mutex<RegisteredWatches>>,
+    /// The re-arming watches of the recipes which use this handle.
+    rearming_watches: Arc<AtomicUsize>,
+    /// Leaves the watches of `watch_data` again.
+    rearmer: Arc<Rearmer>,
 }
 
 impl ZooKeeper {
@@ -60,34 +673,199 @@ impl ZooKeeper {
     /// - `timeout`: session timeout -- how long should a client go without receiving communication
     ///   from a server before considering it connection loss?
     /// - `watcher`: a watcher object to be notified of connection state changes.
+    ///
+    /// See `ZooKeeperBuilder` for more connection options.
     pub fn connect<W>(connect_string: &str, timeout: Duration, watcher: W) -> ZkResult<ZooKeeper>
         where W: Watcher + 'static
     {
+        ZooKeeperBuilder::new()
+            .connect_string(connect_string)
+            .session_timeout(timeout)
+            .watcher(watcher)
+            .connect()
+    }
+
+    /// Connect to a ZooKeeper cluster like `connect`, but without a watcher for connection state
+    /// changes.
+    pub fn connect_no_watch(connect_string: &str, timeout: Duration) -> ZkResult<ZooKeeper> {
+        Self::connect(connect_string, timeout, NoopWatcher)
+    }
+
+    /// Connect to a ZooKeeper cluster like `connect`, waiting between attempts to reconnect
+    /// according to `policy`, see `ZooKeeperBuilder::reconnect_policy`.
+    pub fn connect_with_policy<W>(connect_string: &str,
+                                  timeout: Duration,
+                                  watcher: W,
+                                  policy: ReconnectPolicy)
+                                  -> ZkResult<ZooKeeper>
+        where W: Watcher + 'static
+    {
+        ZooKeeperBuilder::new()
+            .connect_string(connect_string)
+            .session_timeout(timeout)
+            .watcher(watcher)
+            .reconnect_policy(policy)
+            .connect()
+    }
+
+    /// Connect to a ZooKeeper cluster like `connect`, resuming the session with the given id and
+    /// password, see `ZooKeeperBuilder::session`.
+    pub fn connect_with_session<W>(connect_string: &str,
+                                   timeout: Duration,
+                                   session_id: i64,
+                                   passwd: Vec<u8>,
+                                   watcher: W)
+                                   -> ZkResult<ZooKeeper>
+        where W: Watcher + 'static
+    {
+        ZooKeeperBuilder::new()
+            .connect_string(connect_string)
+            .session_timeout(timeout)
+            .session(session_id, passwd)
+            .watcher(watcher)
+            .connect()
+    }
 
-        let (addrs, chroot) = try!(Self::parse_connect_string(connect_string));
+    fn connect_with(builder: ZooKeeperBuilder) -> ZkResult<ZooKeeper> {
+        let connect_string = match builder.connect_string {
+            Some(connect_string) => connect_string,
+            None => return Err(ZkError::BadArguments),
+        };
+        let (hosts, chroot) = try!(Self::parse_connect_string(&connect_string));
+        let chroot = match (chroot, builder.chroot) {
+            (Some(_), Some(_)) => return Err(ZkError::BadArguments),
+            (None, Some(ref chroot)) if chroot == "/" => None,
+            (None, Some(chroot)) => Some(try!(Self::validate_path(&chroot)).to_owned()),
+            (chroot, None) => chroot,
+        };
+        let resolver = builder.resolver.unwrap_or_else(|| Box::new(SystemResolver));
+        let mut hosts = try!(HostProvider::with_resolver(hosts,
+                                                         builder.dns_refresh_interval,
+                                                         resolver));
+        if let Some((failures, cooldown)) = builder.host_circuit_breaker {
+            hosts.set_circuit_breaker(failures, cooldown);
+        }
 
         debug!("Initiating connection to {}", connect_string);
 
-        let watch = ZkWatch::new(watcher, chroot.clone());
+        let watcher = builder.watcher.unwrap_or_else(|| Box::new(NoopWatcher));
+        let mut watch = ZkWatch::new(watcher, chroot.clone(), builder.watch_audit_interval);
+        let default_watcher = watch.default_watcher();
+        let event_gate = watch.gate();
+        let registered_watches = watch.registered();
         let listeners = ListenerSet::<ZkState>::new();
         let listeners1 = listeners.clone();
-        let io = ZkIo::new(addrs.clone(), timeout, watch.sender(), listeners1);
-        let sender = io.sender();
+        let reconnect_listeners = ListenerSet::<ReconnectEvent>::new();
+        let session = Arc::new(Mutex::new(SessionState::default()));
+        let options = IoOptions {
+            session_timeout: builder.session_timeout,
+            require_exact_timeout: builder.require_exact_timeout,
+            min_protocol_version: builder.min_protocol_version,
+            connector: builder.connector.unwrap_or_else(|| Box::new(TcpConnector)),
+            failure_policy: builder.io_failure_policy,
+            session: builder.session,
+            reconnect_policy: builder.reconnect_policy,
+            read_only: builder.read_only,
+            auth: builder.auth.clone(),
+        };
+        let io = ZkIo::new(hosts,
+                           options,
+                           watch.sender(),
+                           listeners1,
+                           reconnect_listeners.clone(),
+                           session.clone());
+        let requests = Arc::new(RequestSender::new(io.sender()));
+        let io_control = io.control_sender();
+        let watch_sender = watch.sender();
+        watch.set_requests(requests.clone());
+        watch.set_session(session.clone());
+
+        let (state_tx, state_rx) = channel();
+        let state_sub = listeners.subscribe(move |state| {
+            let _ = state_tx.send(state);
+        });
+
+        let (rearmer, rearm_worker) = Rearmer::new();
+        let rearmer = Arc::new(rearmer);
+        let weak_rearmer = Arc::downgrade(&rearmer);
+        listeners.subscribe(move |state| {
+            if let (ZkState::Connected, Some(rearmer)) = (state, weak_rearmer.upgrade()) {
+                rearmer.connected();
+            }
+        });
 
         try!(Self::zk_thread("event", move || watch.run().unwrap()));
         try!(Self::zk_thread("io", move || io.run().unwrap()));
+        try!(Self::zk_thread("rearm", move || rearm_worker.run()));
 
-        trace!("Returning a ZooKeeper");
-
-        Ok(ZooKeeper {
+        let offline_queue_policy = builder.offline_queue_policy;
+        let zk = ZooKeeper {
             chroot: chroot,
-            xid: AtomicIsize::new(1),
-            io: Mutex::new(sender),
+            requests,
+            io_control: Mutex::new(io_control),
+            watch_sender: Mutex::new(watch_sender),
+            event_gate,
             listeners: listeners,
-        })
+            reconnect_listeners,
+            default_watcher: default_watcher,
+            max_path_length: builder.max_path_length,
+            max_request_size: builder.max_request_size,
+            session_timeout: builder.session_timeout,
+            session,
+            max_update_attempts: builder.max_update_attempts,
+            rate_limiter: builder.rate_limit.map(RateLimiter::new),
+            id: NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed),
+            server_role: Mutex::new(None),
+            backpressure: if builder.adaptive_backpressure {
+                Some(Arc::new(Backpressure::default()))
+            } else {
+                None
+            },
+            metrics: builder.metrics.map(Arc::from),
+            avoid_host_cooldown: builder.avoid_host_cooldown,
+            offline_queue: builder.offline_queue_limit.map(|limit| {
+                Arc::new(OfflineQueue::new(limit, offline_queue_policy))
+            }),
+            immutable_cache: Arc::new(Mutex::new(HashMap::new())),
+            detached: false,
+            registered_watches,
+            rearming_watches: Arc::new(AtomicUsize::new(0)),
+            rearmer,
+        };
+
+        // Some options can only be verified once the session is established
+        if builder.require_exact_timeout || builder.min_protocol_version.is_some() {
+            loop {
+                match state_rx.recv_timeout(builder.session_timeout) {
+                    Ok(ZkState::Connected) | Ok(ZkState::ConnectedReadOnly) => break,
+                    Ok(ZkState::Closed) => return Err(ZkError::BadArguments),
+                    Ok(_) => {}
+                    Err(_) => return Err(ZkError::ConnectionLoss),
+                }
+            }
+        }
+        zk.listeners.unsubscribe(state_sub);
+        if !builder.auth.is_empty() {
+            // The reply follows the ones to the authentication information of the handshake. A
+            // failed connection attempt drops the sync, so send it again for the next one.
+            let deadline = Instant::now() + builder.session_timeout;
+            loop {
+                match zk.sync("/") {
+                    Ok(_) => break,
+                    Err(ZkError::ConnectionLoss) if Instant::now() < deadline &&
+                                                    zk.connection_state() !=
+                                                    ConnectionState::Closed => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        trace!("Returning a ZooKeeper");
+
+        Ok(zk)
     }
 
-    fn parse_connect_string(connect_string: &str) -> ZkResult<(Vec<SocketAddr>, Option<String>)> {
+    fn parse_connect_string(connect_string: &str) -> ZkResult<(Vec<String>, Option<String>)> {
         let (chroot, end) = match connect_string.find('/') {
             Some(start) => {
                 match &connect_string[start..connect_string.len()] {
@@ -98,62 +876,234 @@ impl ZooKeeper {
             None => (None, connect_string.len()),
         };
 
-        let mut addrs = Vec::new();
-        for addr_str in connect_string[..end].split(',') {
-            let addr = match addr_str.trim().to_socket_addrs() {
-                Ok(mut addrs) => {
-                    match addrs.nth(0) {
-                        Some(addr) => addr,
-                        None => return Err(ZkError::BadArguments),
-                    }
-                }
-                Err(_) => return Err(ZkError::BadArguments),
-            };
-            addrs.push(addr);
-        }
-
-        Ok((addrs, chroot))
-    }
+        let hosts = connect_string[..end].split(',').map(|host| host.trim().to_owned()).collect();
 
-    fn xid(&self) -> i32 {
-        self.xid.fetch_add(1, Ordering::Relaxed) as i32
+        Ok((hosts, chroot))
     }
 
     fn request<Req: WriteTo, Resp: ReadFrom>(&self,
                                              opcode: OpCode,
-                                             xid: i32,
+                                             xid: Xid,
                                              req: Req,
                                              watch: Option<Watch>)
                                              -> ZkResult<Resp> {
-        trace!("request opcode={:?} xid={:?}", opcode, xid);
+        self.request_with(opcode, xid, req, watch, None)
+    }
+
+    fn request_with<Req: WriteTo, Resp: ReadFrom>(&self,
+                                                  opcode: OpCode,
+                                                  xid: Xid,
+                                                  req: Req,
+                                                  watch: Option<Watch>,
+                                                  cancel: Option<&CancellationToken>)
+                                                  -> ZkResult<Resp> {
+        let buf = try!(Self::encode(opcode, req));
+        let record = try!(self.record(opcode, &buf, OnLimit::Wait));
+        let sent = self.send(opcode, xid, buf, watch, cancel, None, OnLimit::Wait);
+        let (xid, response) = match sent {
+            Ok((xid, resp_rx)) => (xid, Self::receive_raw(resp_rx)),
+            Err(err) => (0, Err(err)),
+        };
+        let bytes_in = response.as_ref().map(|response| response.len).unwrap_or(0);
+        let result = response.and_then(Self::parse);
+        if let Some(token) = cancel {
+            token.unregister(xid);
+        }
+        record.finish(xid, bytes_in, &result);
+        result
+    }
+
+    /// Start the bookkeeping of the encoded request `buf`, blocking while backpressure applies
+    /// unless `on_limit` is `OnLimit::Fail`, which fails with `ZkError::Throttled` instead.
+    fn record(&self,
+              opcode: OpCode,
+              buf: &ByteBuf,
+              on_limit: OnLimit)
+              -> ZkResult<RequestRecord> {
+        if let Some(ref backpressure) = self.backpressure {
+            match on_limit {
+                OnLimit::Wait => backpressure.submit(),
+                OnLimit::Fail => {
+                    if !backpressure.try_submit() {
+                        return Err(ZkError::Throttled);
+                    }
+                }
+            }
+        }
+        Ok(RequestRecord {
+            handle_id: self.id,
+            opcode,
+            path: request_path(opcode, buf).map(|path| self.cut_chroot(path)),
+            bytes_out: buf.get_ref().len(),
+            // The latency of the request, not counting the wait for the backpressure limit
+            start: Instant::now(),
+            backpressure: self.backpressure.clone(),
+            metrics: self.metrics.clone(),
+        })
+    }
+
+    /// Encode a request, leaving its xid to be filled in by `send`.
+    fn encode<Req: WriteTo>(opcode: OpCode, req: Req) -> ZkResult<ByteBuf> {
         let rh = RequestHeader {
-            xid: xid,
+            xid: 0,
             opcode: opcode,
         };
-        let buf = try!(to_len_prefixed_buf(rh, req).map_err(|_| ZkError::MarshallingError));
+        to_len_prefixed_buf(rh, req).map_err(|_| ZkError::MarshallingError)
+    }
 
-        let (resp_tx, resp_rx) = sync_channel(0);
-        let request = RawRequest {
-            opcode: opcode,
-            data: buf,
-            listener: Some(resp_tx),
-            watch: watch,
+    /// Queue a request for the IO thread without waiting for its response. Requests submitted from
+    /// the same thread are written to the server in submission order, so several of them can be
+    /// pipelined and their responses collected later with `receive`.
+    pub(crate) fn submit<Req: WriteTo>(&self,
+                                       opcode: OpCode,
+                                       req: Req,
+                                       watch: Option<Watch>)
+                                       -> ZkResult<Receiver<RawResponse>> {
+        let buf = try!(Self::encode(opcode, req));
+        self.send(opcode, Xid::Next, buf, watch, None, None, OnLimit::Wait)
+            .map(|(_, resp_rx)| resp_rx)
+    }
+
+    /// Send a request without waiting for its response. The returned future resolves to the
+    /// response, turned into the result by `map`.
+    fn request_async<Req, Resp, T, F>(&self,
+                                      opcode: OpCode,
+                                      req: Req,
+                                      on_limit: OnLimit,
+                                      map: F)
+                                      -> ZkFuture<T>
+        where Req: WriteTo,
+              Resp: ReadFrom,
+              F: FnOnce(Resp) -> T + Send + 'static
+    {
+        let buf = match Self::encode(opcode, req) {
+            Ok(buf) => buf,
+            Err(err) => return ZkFuture::failed(err),
+        };
+        let record = match self.record(opcode, &buf, on_limit) {
+            Ok(record) => record,
+            Err(err) => return ZkFuture::failed(err),
         };
+        let (completion, waker) = Completion::new();
+        match self.send(opcode, Xid::Next, buf, None, None, Some(completion), on_limit) {
+            Ok((xid, resp_rx)) => {
+                ZkFuture::new(resp_rx, waker, move |response: ZkResult<RawResponse>| {
+                    let bytes_in = response.as_ref().map(|response| response.len).unwrap_or(0);
+                    let result = response.and_then(Self::parse).map(map);
+                    record.finish(xid, bytes_in, &result);
+                    result
+                })
+            }
+            Err(err) => {
+                record.finish(0, 0, &Err::<T, _>(err));
+                ZkFuture::failed(err)
+            }
+        }
+    }
 
-        self.io
-            .lock()
-            .unwrap_or_else(|e| e.into_inner())
-            .send(request)
-            .map_err(|err| {
-                warn!("error sending request: {:?}", err);
-                ZkError::ConnectionLoss
-            })?;
+    /// Hand an encoded request to the IO thread, returning its xid and the channel of its response.
+    /// The xid is assigned by `RequestSender::send`, in the order the requests are written.
+    fn send(&self,
+            opcode: OpCode,
+            xid: Xid,
+            mut buf: ByteBuf,
+            watch: Option<Watch>,
+            cancel: Option<&CancellationToken>,
+            completion: Option<Completion>,
+            on_limit: OnLimit)
+            -> ZkResult<(i32, Receiver<RawResponse>)> {
+        // The length prefix doesn't count towards the limit of the server
+        let size = buf.get_ref().len() - FrameCodec::HEADER_LEN;
+        if size > self.max_request_size {
+            warn!("{:?} request of {} bytes exceeds the limit of {} bytes, not sending it",
+                  opcode,
+                  size,
+                  self.max_request_size);
+            return Err(ZkError::BadArguments);
+        }
+        if opcode.is_write() && self.is_read_only() {
+            debug!("not sending {:?} request to a read-only server", opcode);
+            return Err(ZkError::NotReadOnly);
+        }
+
+        if let Some(ref limiter) = self.rate_limiter {
+            if opcode != OpCode::CloseSession {
+                match on_limit {
+                    OnLimit::Wait => limiter.acquire(),
+                    OnLimit::Fail => {
+                        if !limiter.try_acquire() {
+                            return Err(ZkError::Throttled);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Room for exactly one response, so the IO thread never blocks on a pipelined caller
+        let (resp_tx, resp_rx) = sync_channel(1);
+        // Reserved before taking the channel to the IO thread, so a caller blocked on a full
+        // queue doesn't hold up others, like `close`. A closed client fails the request instead.
+        let offline = || {
+            !matches!(self.connection_state(), ConnectionState::Connected | ConnectionState::Closed)
+        };
+        let queue_slot = match self.offline_queue {
+            Some(ref queue) if offline() && opcode != OpCode::CloseSession => {
+                match on_limit {
+                    OnLimit::Wait => try!(OfflineQueue::reserve(queue, &resp_tx, offline)),
+                    OnLimit::Fail => try!(OfflineQueue::try_reserve(queue, &resp_tx)),
+                }
+            }
+            _ => None,
+        };
+
+        let mut registered = None;
+        let result = self.requests.send(xid, |xid| {
+            // The xid follows the length prefix of the frame
+            BigEndian::write_i32(&mut buf.get_mut()[4..8], xid);
+            if let Some(ref slot) = queue_slot {
+                slot.assign(xid);
+            }
+
+            if let Some(token) = cancel {
+                if !token.register(xid, resp_tx.clone()) {
+                    return Err(ZkError::OperationTimeout);
+                }
+                registered = Some(xid);
+            }
+            Ok(RawRequest {
+                opcode: opcode,
+                data: buf,
+                listener: Some(resp_tx),
+                completion,
+                watch: watch.map(|watch| WatchMessage::Watch(watch, 0)),
+                cancel: cancel.cloned(),
+                queue_slot,
+            })
+        });
+
+        match result {
+            Ok(xid) => Ok((xid, resp_rx)),
+            Err(err) => {
+                if let (Some(token), Some(xid)) = (cancel, registered) {
+                    token.unregister(xid);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    pub(crate) fn receive<Resp: ReadFrom>(resp_rx: Receiver<RawResponse>) -> ZkResult<Resp> {
+        Self::receive_raw(resp_rx).and_then(Self::parse)
+    }
 
-        let mut response = try!(resp_rx.recv().map_err(|err| {
+    fn receive_raw(resp_rx: Receiver<RawResponse>) -> ZkResult<RawResponse> {
+        resp_rx.recv().map_err(|err| {
             warn!("error receiving response: {:?}", err);
             ZkError::ConnectionLoss
-        }));
+        })
+    }
 
+    fn parse<Resp: ReadFrom>(mut response: RawResponse) -> ZkResult<Resp> {
         match response.header.err {
             0 => {
                 Ok(try!(ReadFrom::read_from(&mut response.data)
@@ -177,20 +1127,27 @@ impl ZooKeeper {
     }
 
     fn path(&self, path: &str) -> ZkResult<String> {
-        match self.chroot {
+        let path = match self.chroot {
             Some(ref chroot) => {
                 match path {
-                    "/" => Ok(chroot.clone()),
-                    path => Ok(chroot.clone() + try!(Self::validate_path(path))),
+                    "/" => chroot.clone(),
+                    path => chroot.clone() + try!(Self::validate_path(path)),
                 }
             }
-            None => Ok(try!(Self::validate_path(path)).to_owned()),
+            None => try!(Self::validate_path(path)).to_owned(),
+        };
+
+        if path.len() > self.max_path_length {
+            debug!("path of {} bytes exceeds the limit of {}", path.len(), self.max_path_length);
+            Err(ZkError::BadArguments)
+        } else {
+            Ok(path)
         }
     }
 
     fn cut_chroot(&self, path: String) -> String {
         if let Some(ref chroot) = self.chroot {
-            path[chroot.len()..].to_owned()
+            strip_chroot(chroot, &path)
         } else {
             path
         }
@@ -207,7 +1164,7 @@ impl ZooKeeper {
             auth: auth,
         };
 
-        let _: EmptyResponse = try!(self.request(OpCode::Auth, -4, req, None));
+        let _: EmptyResponse = try!(self.request(OpCode::Auth, Xid::Fixed(AUTH_XID), req, None));
 
         Ok(())
     }
@@ -242,30 +1199,286 @@ impl ZooKeeper {
                   mode: CreateMode)
                   -> ZkResult<String> {
         trace!("ZooKeeper::create");
-        let req = CreateRequest {
+        self.create_future(path, data, acl, mode, OnLimit::Wait).wait()
+    }
+
+    /// Create a node with a TTL: unlike other persistent nodes, it is deleted by the server once
+    /// it has not been modified for `ttl` and has no children. The deletion happens some time
+    /// after the TTL elapsed, as the server checks for expired nodes periodically. `mode` must be
+    /// `CreateMode::PersistentWithTtl` or `CreateMode::PersistentSequentialWithTtl`.
+    ///
+    /// TTL nodes require ZooKeeper 3.5.3 or later, with the `zookeeper.extendedTypesEnabled`
+    /// system property set on the servers.
+    ///
+    /// # Errors
+    /// Same as `create`. Any other `mode`, or a `ttl` below one millisecond or above
+    /// 0xff_ffff_ffff milliseconds (about 34 years), returns `Err(ZkError::BadArguments)` without
+    /// sending the request. A server without TTL nodes enabled returns
+    /// `Err(ZkError::Unimplemented)`.
+    pub fn create_ttl(&self,
+                      path: &str,
+                      data: Vec<u8>,
+                      acl: Vec<Acl>,
+                      mode: CreateMode,
+                      ttl: Duration)
+                      -> ZkResult<String> {
+        trace!("ZooKeeper::create_ttl");
+        if !mode.is_ttl() {
+            warn!("create_ttl with {:?}, which is not a TTL mode", mode);
+            return Err(ZkError::BadArguments);
+        }
+        let ttl = match i64::try_from(ttl.as_millis()) {
+            Ok(ttl) if ttl > 0 && ttl <= MAX_TTL_MILLIS => ttl,
+            _ => {
+                warn!("create_ttl with a TTL of {:?}, which is out of range", ttl);
+                return Err(ZkError::BadArguments);
+            }
+        };
+        let req = CreateTtlRequest {
             path: self.path(path)?,
-            data: data,
-            acl: acl,
+            data,
+            acl,
             flags: mode as i32,
+            ttl,
         };
 
-        let response: CreateResponse = try!(self.request(OpCode::Create, self.xid(), req, None));
+        let response: Create2Response = try!(self.request(OpCode::CreateTtl, Xid::Next, req, None));
 
         Ok(self.cut_chroot(response.path))
     }
 
-    /// Delete the node with the given `path`. The call will succeed if such a node exists, and the
-    /// given `version` matches the node's version (if the given version is `None`, it matches any
-    /// node's versions).
-    ///
-    /// This operation, if successful, will trigger all the watches on the node of the given path
-    /// left by `exists` API calls, watches left by `get_data` API calls, and the watches on the
-    /// parent node left by `get_children` API calls.
+    /// Create a node like `create`, but without waiting for the response: the request is sent
+    /// right away, and the returned future resolves to the actual path of the created node.
     ///
     /// # Errors
-    /// If the nodes does not exist, `Err(ZkError::NoNode)` will be returned.
-    ///
-    /// If the given `version` does not match the node's version, `Err(ZkError::BadVersion)` will be
+    /// Same as `create`. Instead of blocking on a limit of the client, the future fails right
+    /// away; see `ZkFuture`.
+    pub fn create_async(&self,
+                        path: &str,
+                        data: Vec<u8>,
+                        acl: Vec<Acl>,
+                        mode: CreateMode)
+                        -> ZkFuture<String> {
+        trace!("ZooKeeper::create_async");
+        self.create_future(path, data, acl, mode, OnLimit::Fail)
+    }
+
+    fn create_future(&self,
+                     path: &str,
+                     data: Vec<u8>,
+                     acl: Vec<Acl>,
+                     mode: CreateMode,
+                     on_limit: OnLimit)
+                     -> ZkFuture<String> {
+        let req = CreateRequest {
+            path: match self.path(path) {
+                Ok(path) => path,
+                Err(err) => return ZkFuture::failed(err),
+            },
+            data,
+            acl,
+            flags: mode as i32,
+        };
+
+        let chroot = self.chroot.clone();
+        self.request_async(create_opcode(mode, OpCode::Create),
+                           req,
+                           on_limit,
+                           move |response: CreateResponse| match chroot {
+                               Some(ref chroot) => strip_chroot(chroot, &response.path),
+                               None => response.path,
+                           })
+    }
+
+    /// Create a node like `create`, unless `token` is cancelled first; see `CancellationToken`.
+    ///
+    /// # Errors
+    /// Same as `create`. If `token` is cancelled before the response arrives,
+    /// `Err(ZkError::OperationTimeout)` is returned, and the node may or may not have been
+    /// created.
+    pub fn create_cancellable(&self,
+                              path: &str,
+                              data: Vec<u8>,
+                              acl: Vec<Acl>,
+                              mode: CreateMode,
+                              token: &CancellationToken)
+                              -> ZkResult<String> {
+        trace!("ZooKeeper::create_cancellable");
+        let req = CreateRequest {
+            path: self.path(path)?,
+            data,
+            acl,
+            flags: mode as i32,
+        };
+
+        let opcode = create_opcode(mode, OpCode::Create);
+        let response: CreateResponse =
+            try!(self.request_with(opcode, Xid::Next, req, None, Some(token)));
+
+        Ok(self.cut_chroot(response.path))
+    }
+
+    /// Create a node like `create`, additionally returning the `Stat` of the created node. This
+    /// uses the `create2` request of ZooKeeper 3.5, so it needs no extra round trip; older servers
+    /// do not support it.
+    ///
+    /// # Errors
+    /// Same as `create`.
+    pub fn create_with_stat(&self,
+                            path: &str,
+                            data: Vec<u8>,
+                            acl: Vec<Acl>,
+                            mode: CreateMode)
+                            -> ZkResult<(String, Stat)> {
+        trace!("ZooKeeper::create_with_stat");
+        let req = CreateRequest {
+            path: self.path(path)?,
+            data,
+            acl,
+            flags: mode as i32,
+        };
+
+        let opcode = create_opcode(mode, OpCode::Create2);
+        let response: Create2Response = try!(self.request(opcode, Xid::Next, req, None));
+
+        Ok((self.cut_chroot(response.path), response.stat))
+    }
+
+    /// Create a node like `create`, but prefix the node data with a caller-supplied unique `token`
+    /// which is used to detect whether an existing node was created by this caller.
+    ///
+    /// Without this, a create which is retried after `ZkError::ConnectionLoss` cannot tell whether
+    /// the first attempt reached the server. Here, creates interrupted by a connection loss are
+    /// retried, and if the node already exists, its data is compared with `token`: a match means
+    /// the node is ours (`CreateOutcome::AlreadyOwned`), otherwise it is a
+    /// `CreateOutcome::Conflict`.
+    ///
+    /// The stored node data is `token` followed by `data`, so readers must strip the token. The
+    /// token is of no use with sequential modes, as those never collide.
+    pub fn create_with_token(&self,
+                             path: &str,
+                             data: Vec<u8>,
+                             token: &[u8],
+                             acl: Vec<Acl>,
+                             mode: CreateMode)
+                             -> ZkResult<CreateOutcome> {
+        trace!("ZooKeeper::create_with_token");
+        let mut node_data = token.to_vec();
+        node_data.extend(data);
+
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.create(path, node_data.clone(), acl.clone(), mode) {
+                Ok(actual_path) => return Ok(CreateOutcome::Created(actual_path)),
+                Err(ZkError::ConnectionLoss) if attempts < MAX_CREATE_ATTEMPTS => {
+                    debug!("connection lost while creating {}, retrying", path);
+                }
+                Err(ZkError::NodeExists) => {
+                    match self.get_data(path, false) {
+                        Ok((stored, _)) => {
+                            return Ok(if stored.starts_with(token) {
+                                CreateOutcome::AlreadyOwned(path.to_owned())
+                            } else {
+                                CreateOutcome::Conflict
+                            });
+                        }
+                        // deleted in the meantime, so try again
+                        Err(ZkError::NoNode) if attempts < MAX_CREATE_ATTEMPTS => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Create a node like `create`, but protect against losing track of it when the connection is
+    /// lost during the create.
+    ///
+    /// A unique id is prepended to the node name (`/parent/name` becomes
+    /// `/parent/_c_<id>-name`). When the create fails with `ZkError::ConnectionLoss`, the children
+    /// of the parent are searched for the id, and if the first attempt did reach the server, the
+    /// node created by it is returned instead of creating another one. This is most useful with
+    /// sequential modes, where a retried create would otherwise leave an orphaned node behind (for
+    /// example one that holds a lock). The returned path always is the actual path of the node,
+    /// including its sequence number (see `sequence_number`), whether it came from the response of
+    /// the create or from the search.
+    pub fn create_protected(&self,
+                            path: &str,
+                            data: Vec<u8>,
+                            acl: Vec<Acl>,
+                            mode: CreateMode)
+                            -> ZkResult<String> {
+        trace!("ZooKeeper::create_protected");
+        self.create_protected_by(path,
+                                 |path| self.create(path, data.clone(), acl.clone(), mode),
+                                 Ok)
+    }
+
+    /// Create a node like `create_protected`, additionally returning the `Stat` of the created
+    /// node like `create_with_stat` (which requires ZooKeeper 3.5). If the node was found after a
+    /// connection loss, the `Stat` is read with an additional `exists` call.
+    pub fn create_protected_with_stat(&self,
+                                      path: &str,
+                                      data: Vec<u8>,
+                                      acl: Vec<Acl>,
+                                      mode: CreateMode)
+                                      -> ZkResult<(String, Stat)> {
+        trace!("ZooKeeper::create_protected_with_stat");
+        self.create_protected_by(path,
+                                 |path| {
+                                     self.create_with_stat(path, data.clone(), acl.clone(), mode)
+                                 },
+                                 |path| match try!(self.exists(&path, false)) {
+                                     Some(stat) => Ok((path, stat)),
+                                     None => Err(ZkError::NoNode),
+                                 })
+    }
+
+    /// The protocol of `create_protected`: `create` the protected path, and on connection loss
+    /// search the parent, passing the path of the node if it was found to `found`.
+    fn create_protected_by<T, C, F>(&self, path: &str, create: C, found: F) -> ZkResult<T>
+        where C: Fn(&str) -> ZkResult<T>,
+              F: Fn(String) -> ZkResult<T>
+    {
+        let id = protected_id();
+        let (parent, name) = split_path(path);
+        let protected_path = make_path(parent, &format!("{}{}", protected_prefix(&id), name));
+
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match create(&protected_path) {
+                Err(ZkError::ConnectionLoss) if attempts < MAX_CREATE_ATTEMPTS => {
+                    debug!("connection lost while creating {}, searching", protected_path);
+                    match self.get_children(parent, false) {
+                        Ok(children) => {
+                            if let Some(child) = find_protected(&children, &id) {
+                                return found(make_path(parent, child));
+                            }
+                        }
+                        Err(ZkError::ConnectionLoss) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Delete the node with the given `path`. The call will succeed if such a node exists, and the
+    /// given `version` matches the node's version (if the given version is `None`, it matches any
+    /// node's versions).
+    ///
+    /// This operation, if successful, will trigger all the watches on the node of the given path
+    /// left by `exists` API calls, watches left by `get_data` API calls, and the watches on the
+    /// parent node left by `get_children` API calls.
+    ///
+    /// # Errors
+    /// If the nodes does not exist, `Err(ZkError::NoNode)` will be returned.
+    ///
+    /// If the given `version` does not match the node's version, `Err(ZkError::BadVersion)` will be
     /// returned.
     ///
     /// If the node has children, `Err(ZkError::NotEmpty)` will be returned.
@@ -276,11 +1489,122 @@ impl ZooKeeper {
             version: version.unwrap_or(-1),
         };
 
-        let _: EmptyResponse = try!(self.request(OpCode::Delete, self.xid(), req, None));
+        let _: EmptyResponse = try!(self.request(OpCode::Delete, Xid::Next, req, None));
+
+        Ok(())
+    }
+
+    /// Delete the given node like `delete`, unless `token` is cancelled first; see
+    /// `CancellationToken`.
+    ///
+    /// # Errors
+    /// Same as `delete`. If `token` is cancelled before the response arrives,
+    /// `Err(ZkError::OperationTimeout)` is returned, and the node may or may not have been
+    /// deleted.
+    pub fn delete_cancellable(&self,
+                              path: &str,
+                              version: Option<i32>,
+                              token: &CancellationToken)
+                              -> ZkResult<()> {
+        trace!("ZooKeeper::delete_cancellable");
+        let req = DeleteRequest {
+            path: try!(self.path(path)),
+            version: version.unwrap_or(-1),
+        };
+
+        let _: EmptyResponse =
+            try!(self.request_with(OpCode::Delete, Xid::Next, req, None, Some(token)));
 
         Ok(())
     }
 
+    /// Delete the nodes of the given `paths`, returning the result for each of them.
+    ///
+    /// All nodes are deleted in a single transaction first. If that fails because of one of the
+    /// nodes (like `ZkError::NotEmpty` or `ZkError::NoNode`), nothing was deleted, and unless
+    /// `atomic` is set, the nodes are then deleted one by one, so the result for each path tells
+    /// whether it was deleted. With `atomic`, the error of the transaction is returned instead.
+    ///
+    /// # Errors
+    /// If the transaction fails for a reason unrelated to a single node (like
+    /// `ZkError::ConnectionLoss`), that error is returned.
+    pub fn delete_many(&self, paths: &[&str], atomic: bool) -> ZkResult<Vec<ZkResult<()>>> {
+        trace!("ZooKeeper::delete_many");
+        let ops: Vec<Op> = paths.iter()
+                                .map(|path| {
+                                    Op::Delete {
+                                        path: (*path).to_owned(),
+                                        version: None,
+                                    }
+                                })
+                                .collect();
+
+        match self.commit(&ops) {
+            Ok(_) => Ok(paths.iter().map(|_| Ok(())).collect()),
+            Err(ZkError::NoNode) | Err(ZkError::NotEmpty) | Err(ZkError::NoAuth) if !atomic => {
+                debug!("deleting {} nodes one by one", paths.len());
+                Ok(paths.iter().map(|path| self.delete(path, None)).collect())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete the node with the given `path` like `delete`, but report the children blocking the
+    /// deletion instead of failing with `ZkError::NotEmpty`.
+    ///
+    /// Returns `Ok(Ok(()))` if the node was deleted and `Ok(Err(children))` if it has children.
+    /// The children are listed right after the failed deletion, so they may have changed in
+    /// between (and the list may even be empty if all of them were deleted in the meantime).
+    ///
+    /// # Errors
+    /// Same as `delete`, except for `ZkError::NotEmpty`.
+    pub fn delete_or_report(&self,
+                            path: &str,
+                            version: Option<i32>)
+                            -> ZkResult<Result<(), Vec<String>>> {
+        trace!("ZooKeeper::delete_or_report");
+        match self.delete(path, version) {
+            Ok(()) => Ok(Ok(())),
+            Err(ZkError::NotEmpty) => Ok(Err(try!(self.get_children(path, false)))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete the node with the given `path` like `delete`, returning the last `Stat` of the node
+    /// before its deletion.
+    ///
+    /// The `Stat` is read first and the node is then deleted in a transaction which checks that its
+    /// version is still the one that was read, so the node data cannot have changed in between.
+    /// This is not fully atomic: changes which do not affect the data version (like the creation of
+    /// children or ACL changes) can still happen between the read and the deletion, in which case
+    /// the returned `Stat` is stale in those fields.
+    ///
+    /// # Errors
+    /// Same as `delete`. `Err(ZkError::BadVersion)` is also returned if the node data changed
+    /// between reading the `Stat` and deleting the node.
+    pub fn delete_returning_stat(&self, path: &str, version: Option<i32>) -> ZkResult<Stat> {
+        trace!("ZooKeeper::delete_returning_stat");
+        let stat = match try!(self.exists(path, false)) {
+            Some(stat) => stat,
+            None => return Err(ZkError::NoNode),
+        };
+        match version {
+            Some(version) if version != stat.version => return Err(ZkError::BadVersion),
+            _ => {}
+        }
+
+        try!(self.commit(&[Op::Check {
+                               path: path.to_owned(),
+                               version: Some(stat.version),
+                           },
+                           Op::Delete {
+                               path: path.to_owned(),
+                               version,
+                           }]));
+
+        Ok(stat)
+    }
+
     /// Return the `Stat` of the node of the given `path` or `None` if no such node exists.
     ///
     /// If the `watch` is `true` and the call is successful (no error is returned), a watch will be
@@ -293,7 +1617,7 @@ impl ZooKeeper {
             watch: watch,
         };
 
-        match self.request::<ExistsRequest, ExistsResponse>(OpCode::Exists, self.xid(), req, None) {
+        match self.request::<ExistsRequest, ExistsResponse>(OpCode::Exists, Xid::Next, req, None) {
             Ok(response) => Ok(Some(response.stat)),
             Err(ZkError::NoNode) => Ok(None),
             Err(e) => Err(e),
@@ -321,7 +1645,7 @@ impl ZooKeeper {
         };
 
         match self.request::<ExistsRequest, ExistsResponse>(OpCode::Exists,
-                                                            self.xid(),
+                                                            Xid::Next,
                                                             req,
                                                             Some(watch)) {
             Ok(response) => Ok(Some(response.stat)),
@@ -338,11 +1662,77 @@ impl ZooKeeper {
         trace!("ZooKeeper::get_acl");
         let req = GetAclRequest { path: try!(self.path(path)) };
 
-        let response: GetAclResponse = try!(self.request(OpCode::GetAcl, self.xid(), req, None));
+        let response: GetAclResponse = try!(self.request(OpCode::GetAcl, Xid::Next, req, None));
 
         Ok(response.acl_stat)
     }
 
+    /// Return the ACLs of the node of the given `path` and of all of its descendants, like for
+    /// reviewing the permissions of a subtree. The nodes are listed breadth-first, each with its
+    /// full path.
+    ///
+    /// The `get_children` and `get_acl` calls of each level of the tree are pipelined, so this
+    /// costs two round trips per level rather than per node. Nodes which are deleted during the
+    /// traversal are left out of the result. This is not an atomic operation.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    pub fn audit_acls(&self, path: &str) -> ZkResult<Vec<(String, Vec<Acl>)>> {
+        trace!("ZooKeeper::audit_acls");
+        let mut result = Vec::new();
+        let mut level = vec![path.to_owned()];
+        while !level.is_empty() {
+            let mut pending = Vec::with_capacity(level.len());
+            for node in level {
+                let req = GetAclRequest { path: try!(self.path(&node)) };
+                let acl_rx = try!(self.submit(OpCode::GetAcl, req, None));
+                let req = GetChildrenRequest {
+                    path: try!(self.path(&node)),
+                    watch: false,
+                };
+                let children_rx = try!(self.submit(OpCode::GetChildren, req, None));
+                pending.push((node, acl_rx, children_rx));
+            }
+
+            let mut next = Vec::new();
+            for (node, acl_rx, children_rx) in pending {
+                let acl = Self::receive::<GetAclResponse>(acl_rx);
+                let children = Self::receive::<GetChildrenResponse>(children_rx);
+                match (acl, children) {
+                    (Ok(acl), Ok(children)) => {
+                        next.extend(children.children.iter().map(|child| make_path(&node, child)));
+                        result.push((node, acl.acl_stat.0));
+                    }
+                    (Err(ZkError::NoNode), _) |
+                    (_, Err(ZkError::NoNode)) if node != path => {
+                        trace!("{} deleted while auditing ACLs", node)
+                    }
+                    (Err(e), _) | (_, Err(e)) => return Err(e),
+                }
+            }
+            level = next;
+        }
+
+        Ok(result)
+    }
+
+    /// Return the ACL of the node of the given path, without its `Stat`.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    pub fn get_acl_only(&self, path: &str) -> ZkResult<Vec<Acl>> {
+        self.get_acl(path).map(|(acl, _)| acl)
+    }
+
+    /// Return the ACL version (`Stat::aversion`) of the node of the given path, which is the
+    /// version to pass to `set_acl`.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    pub fn acl_version(&self, path: &str) -> ZkResult<i32> {
+        self.get_acl(path).map(|(_, stat)| stat.aversion)
+    }
+
     /// Set the ACL for the node of the given path if such a node exists and the given version
     /// matches the version of the node. Return the `Stat` of the node.
     ///
@@ -359,11 +1749,42 @@ impl ZooKeeper {
             version: version.unwrap_or(-1),
         };
 
-        let response: SetAclResponse = try!(self.request(OpCode::SetAcl, self.xid(), req, None));
+        let response: SetAclResponse = try!(self.request(OpCode::SetAcl, Xid::Next, req, None));
 
         Ok(response.stat)
     }
 
+    /// Atomically replace the ACL of the node of the given path with `transform` applied to the
+    /// current ACL. Return the new ACL and `Stat` of the node.
+    ///
+    /// The current ACL is fetched along with its version and set with that version, so a
+    /// concurrent change makes the update fail with `ZkError::BadVersion`. In that case the ACL is
+    /// fetched and `transform` applied again, up to `ZooKeeperBuilder::max_update_attempts` times,
+    /// so `transform` may be called more than once.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    ///
+    /// If the ACL kept changing concurrently, `Err(ZkError::BadVersion)` will be returned.
+    pub fn update_acl<F>(&self, path: &str, transform: F) -> ZkResult<(Vec<Acl>, Stat)>
+        where F: Fn(&[Acl]) -> Vec<Acl>
+    {
+        trace!("ZooKeeper::update_acl");
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (acl, stat) = try!(self.get_acl(path));
+            let acl = transform(&acl);
+            match self.set_acl(path, acl.clone(), Some(stat.aversion)) {
+                Ok(stat) => return Ok((acl, stat)),
+                Err(ZkError::BadVersion) if attempts < self.max_update_attempts => {
+                    debug!("ACL of {} changed concurrently, retrying", path);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// Return the list of the children of the node of the given `path`. The returned values are not
     /// prefixed with the provided `path`; i.e. if the database contains `/path/a` and `/path/b`,
     /// the result of `get_children` for `"/path"` will be `["a", "b"]`.
@@ -385,13 +1806,85 @@ impl ZooKeeper {
         };
 
         let response: GetChildrenResponse = try!(self.request(OpCode::GetChildren,
-                                                              self.xid(),
+                                                              Xid::Next,
                                                               req,
                                                               None));
 
         Ok(response.children)
     }
 
+    /// Return the list of the children of the node of the given `path` like `get_children`,
+    /// together with the `Stat` of the node itself. Its `cversion` changes with every change of
+    /// the children, so it can be compared later to detect whether the children changed.
+    ///
+    /// This uses the `getChildren2` request, so it needs no extra round trip.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    pub fn get_children_w_stat(&self, path: &str, watch: bool) -> ZkResult<(Vec<String>, Stat)> {
+        trace!("ZooKeeper::get_children_w_stat");
+        let req = GetChildrenRequest {
+            path: try!(self.path(path)),
+            watch,
+        };
+
+        let response: GetChildren2Response = try!(self.request(OpCode::GetChildren2,
+                                                               Xid::Next,
+                                                               req,
+                                                               None));
+
+        Ok((response.children, response.stat))
+    }
+
+    /// Return the children of the node of the given `path` whose names match the glob `pattern`.
+    ///
+    /// The pattern supports `*` (any number of characters), `?` (a single character), and
+    /// character classes like `[abc]`, `[a-z]` and `[!0-9]`. ZooKeeper has no server-side
+    /// filtering, so all children are still transferred and filtered here.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    pub fn get_children_glob(&self, path: &str, pattern: &str) -> ZkResult<Vec<String>> {
+        let mut children = try!(self.get_children(path, false));
+        children.retain(|child| glob_match(pattern, child));
+        Ok(children)
+    }
+
+    /// Return the list of the children of the node of the given `path`, each paired with its
+    /// `Stat`.
+    ///
+    /// The children are listed with `get_children` and the `exists` calls for all of them are then
+    /// pipelined, so this costs two round trips instead of one per child. Children that are deleted
+    /// between the listing and the `exists` call are left out of the result.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    pub fn get_children_with_stats(&self, path: &str) -> ZkResult<Vec<(String, Stat)>> {
+        trace!("ZooKeeper::get_children_with_stats");
+        let children = try!(self.get_children(path, false));
+
+        let mut pending = Vec::with_capacity(children.len());
+        for child in children {
+            let req = ExistsRequest {
+                path: try!(self.path(&make_path(path, &child))),
+                watch: false,
+            };
+            let resp_rx = try!(self.submit(OpCode::Exists, req, None));
+            pending.push((child, resp_rx));
+        }
+
+        let mut result = Vec::with_capacity(pending.len());
+        for (child, resp_rx) in pending {
+            match Self::receive::<ExistsResponse>(resp_rx) {
+                Ok(response) => result.push((child, response.stat)),
+                Err(ZkError::NoNode) => trace!("child {} deleted while fetching stats", child),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(result)
+    }
+
     /// Return the list of the children of the node of the given `path`.
     ///
     /// Similar to `get_children`, but sets an explicit `Watcher` instead of relying on the client's
@@ -413,13 +1906,63 @@ impl ZooKeeper {
         };
 
         let response: GetChildrenResponse = try!(self.request(OpCode::GetChildren,
-                                                              self.xid(),
+                                                              Xid::Next,
                                                               req,
                                                               Some(watch)));
 
         Ok(response.children)
     }
 
+    /// Return the number of all descendants of the node of the given path, not counting the node
+    /// itself. The server counts them in a single traversal, which is much cheaper than walking
+    /// the subtree with `get_children`. This uses the `getAllChildrenNumber` request of
+    /// ZooKeeper 3.6.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned; servers
+    /// older than 3.6 return `Err(ZkError::Unimplemented)`.
+    pub fn get_all_children_number(&self, path: &str) -> ZkResult<i32> {
+        trace!("ZooKeeper::get_all_children_number");
+        let req = GetAllChildrenNumberRequest { path: try!(self.path(path)) };
+
+        let response: GetAllChildrenNumberResponse =
+            try!(self.request(OpCode::GetAllChildrenNumber, Xid::Next, req, None));
+
+        Ok(response.total)
+    }
+
+    /// Return the paths of the ephemeral nodes owned by this session whose path starts with
+    /// `prefix`, like to clean them up after a reconnect. The paths are matched as strings, so
+    /// `"/app"` also matches `"/apple"`. An empty `prefix` returns all ephemeral nodes of the
+    /// session. This uses the `getEphemerals` request of ZooKeeper 3.6.
+    ///
+    /// # Errors
+    /// An invalid `prefix` returns `Err(ZkError::BadArguments)`; servers older than 3.6 return
+    /// `Err(ZkError::Unimplemented)`.
+    pub fn get_ephemerals(&self, prefix: &str) -> ZkResult<Vec<String>> {
+        trace!("ZooKeeper::get_ephemerals");
+        let prefix = match prefix {
+            "" => "/",
+            prefix => prefix,
+        };
+        let req = GetEphemeralsRequest { prefix: try!(self.path(prefix)) };
+
+        let response: GetEphemeralsResponse =
+            try!(self.request(OpCode::GetEphemerals, Xid::Next, req, None));
+
+        let ephemerals = match self.chroot {
+            // The chroot itself is a prefix of its siblings with longer names
+            Some(ref chroot) => {
+                response.ephemerals
+                        .into_iter()
+                        .filter_map(|path| within_chroot(chroot, &path))
+                        .collect()
+            }
+            None => response.ephemerals,
+        };
+        Ok(ephemerals)
+    }
+
     /// Return the data and the `Stat` of the node of the given path.
     ///
     /// If `watch` is `true` and the call is successful (no error is returned), a watch will be left
@@ -430,16 +1973,99 @@ impl ZooKeeper {
     /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
     pub fn get_data(&self, path: &str, watch: bool) -> ZkResult<(Vec<u8>, Stat)> {
         trace!("ZooKeeper::get_data");
+        self.get_data_future(path, watch, OnLimit::Wait).wait()
+    }
+
+    /// Return the data and the `Stat` of the node of the given path like `get_data`, but without
+    /// waiting for the response: the request is sent right away, and the returned future resolves
+    /// to the data and the `Stat`.
+    ///
+    /// # Errors
+    /// Same as `get_data`. Instead of blocking on a limit of the client, the future fails right
+    /// away; see `ZkFuture`.
+    pub fn get_data_async(&self, path: &str, watch: bool) -> ZkFuture<(Vec<u8>, Stat)> {
+        trace!("ZooKeeper::get_data_async");
+        self.get_data_future(path, watch, OnLimit::Fail)
+    }
+
+    fn get_data_future(&self,
+                       path: &str,
+                       watch: bool,
+                       on_limit: OnLimit)
+                       -> ZkFuture<(Vec<u8>, Stat)> {
+        let req = GetDataRequest {
+            path: match self.path(path) {
+                Ok(path) => path,
+                Err(err) => return ZkFuture::failed(err),
+            },
+            watch,
+        };
+
+        self.request_async(OpCode::GetData,
+                           req,
+                           on_limit,
+                           |response: GetDataResponse| response.data_stat)
+    }
+
+    /// Return the data and the `Stat` of the node of the given path like `get_data`, but keep
+    /// apart a node without data (`None`, created with null data, e.g. by other clients) from a
+    /// node with empty data (`Some(vec![])`). `get_data` returns an empty `Vec` for both.
+    pub fn get_data_raw(&self, path: &str, watch: bool) -> ZkResult<(Option<Vec<u8>>, Stat)> {
+        trace!("ZooKeeper::get_data_raw");
         let req = GetDataRequest {
             path: try!(self.path(path)),
-            watch: watch,
+            watch,
+        };
+
+        let response: GetDataRawResponse =
+            try!(self.request(OpCode::GetData, Xid::Next, req, None));
+
+        Ok(response.data_stat)
+    }
+
+    /// Return the data and the `Stat` of the node of the given path like `get_data`, without
+    /// setting a watch, unless `token` is cancelled first; see `CancellationToken`.
+    ///
+    /// # Errors
+    /// Same as `get_data`. If `token` is cancelled before the response arrives,
+    /// `Err(ZkError::OperationTimeout)` is returned.
+    pub fn get_data_cancellable(&self,
+                                path: &str,
+                                token: &CancellationToken)
+                                -> ZkResult<(Vec<u8>, Stat)> {
+        trace!("ZooKeeper::get_data_cancellable");
+        let req = GetDataRequest {
+            path: try!(self.path(path)),
+            watch: false,
         };
 
-        let response: GetDataResponse = try!(self.request(OpCode::GetData, self.xid(), req, None));
+        let response: GetDataResponse =
+            try!(self.request_with(OpCode::GetData, Xid::Next, req, None, Some(token)));
 
         Ok(response.data_stat)
     }
 
+    /// Return the data of the node of the given path as a string, along with its `Stat`, like
+    /// `get_data`.
+    ///
+    /// # Errors
+    /// Same as `get_data`. With `StringDecoding::Strict`, data which is not valid UTF-8 returns
+    /// `Err(ZkError::MarshallingError)`.
+    pub fn get_data_string(&self,
+                           path: &str,
+                           watch: bool,
+                           decoding: StringDecoding)
+                           -> ZkResult<(String, Stat)> {
+        let (data, stat) = try!(self.get_data(path, watch));
+        let data = match decoding {
+            StringDecoding::Strict => {
+                try!(String::from_utf8(data).map_err(|_| ZkError::MarshallingError))
+            }
+            StringDecoding::Lossy => String::from_utf8_lossy(&data).into_owned(),
+        };
+        Ok((data, stat))
+    }
+
     /// Return the data and the `Stat` of the node of the given path.
     ///
     /// Similar to `get_data`, but sets an explicit `Watcher` instead of relying on the client's
@@ -461,69 +2087,849 @@ impl ZooKeeper {
         };
 
         let response: GetDataResponse = try!(self.request(OpCode::GetData,
-                                                          self.xid(),
+                                                          Xid::Next,
                                                           req,
                                                           Some(watch)));
 
         Ok(response.data_stat)
     }
 
-    /// Set the data for the node of the given `path` if such a node exists and the given version
-    /// matches the version of the node (if the given version is `None`, it matches any node's
-    /// versions). Return the `Stat` of the node.
+    /// Return the data and the `Stat` of the node of the given path, along with a channel which
+    /// receives the events of the node from then on.
     ///
-    /// This operation, if successful, will trigger all the watches on the node of the given `path`
-    /// left by `get_data` calls.
+    /// Unlike the watch of `get_data_w`, which is triggered once, the watch is left again after
+    /// each event until the receiver is dropped. Changes made while it is left again (a single
+    /// round trip) trigger no event of their own. The events include the deletion of the node and
+    /// its creation again afterwards.
     ///
-    /// # Errors
-    /// If no node with the given `path` exists, `Err(ZkError::NoNode)` will be returned.
+    /// The server drops the watch along with the connection, so once the client is connected
+    /// again, the receiver gets an event of type `WatchedEventType::None` (as changes in the
+    /// meantime trigger no event), and the watch is left again. If the watch can't be left again
+    /// for another reason, the channel is closed.
     ///
-    /// If the given version does not match the node's version, `Err(ZkError::BadVersion)` will be
-    /// returned.
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    pub fn watch_data(&self, path: &str) -> ZkResult<(Vec<u8>, Stat, Receiver<WatchedEvent>)> {
+        trace!("ZooKeeper::watch_data");
+        let (tx, rx) = channel();
+        let watcher = ChannelWatcher::new(self.requests.clone(),
+                                          &self.rearmer,
+                                          path.to_owned(),
+                                          try!(self.path(path)),
+                                          tx);
+        let (data, stat) = try!(self.get_data_w(path, watcher.clone()));
+        self.rearmer.armed(watcher);
+        Ok((data, stat, rx))
+    }
+
+    /// Return the data and the `Stat` of the node at `path` like `get_data`, asserting that the
+    /// node never changes once created, like write-once configuration. The first read is cached,
+    /// so later reads of `path` do not contact the server.
     ///
-    /// The maximum allowable size of the `data` array is 1 MiB (1,048,576 bytes). Arrays larger
-    /// than this will return `Err(ZkError::BadArguments)`.
-    pub fn set_data(&self, path: &str, data: Vec<u8>, version: Option<i32>) -> ZkResult<Stat> {
-        trace!("ZooKeeper::set_data");
-        let req = SetDataRequest {
-            path: try!(self.path(path)),
-            data: data,
-            version: version.unwrap_or(-1),
+    /// The read leaves a data watch, which drops the node from the cache if it changes or is
+    /// deleted after all, so the next read fetches it again.
+    pub fn get_data_cached(&self, path: &str) -> ZkResult<(Vec<u8>, Stat)> {
+        trace!("ZooKeeper::get_data_cached");
+        if let Some(cached) = self.immutable_cache
+                                  .lock()
+                                  .unwrap_or_else(|e| e.into_inner())
+                                  .get(path) {
+            return Ok(cached.clone());
+        }
+
+        // Keeps a response from being cached after its watch fired already
+        let changed = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let cache = Arc::downgrade(&self.immutable_cache);
+            let changed = changed.clone();
+            let path = path.to_owned();
+            move |event: WatchedEvent| {
+                warn!("{} changed although it was cached as immutable: {:?}", path, event);
+                changed.store(true, Ordering::SeqCst);
+                if let Some(cache) = cache.upgrade() {
+                    cache.lock().unwrap_or_else(|e| e.into_inner()).remove(&path);
+                }
+            }
         };
+        let result = try!(self.get_data_w(path, watcher));
 
-        let response: SetDataResponse = try!(self.request(OpCode::SetData, self.xid(), req, None));
+        let mut cache = self.immutable_cache.lock().unwrap_or_else(|e| e.into_inner());
+        if !changed.load(Ordering::SeqCst) {
+            cache.insert(path.to_owned(), result.clone());
+        }
+        Ok(result)
+    }
 
-        Ok(response.stat)
+    /// Bring the server this client is connected to up to date with the leader, as far as `path`
+    /// is concerned. Return the path echoed by the server.
+    ///
+    /// Servers other than the leader may lag behind, so a read right after a write of another
+    /// client may not observe that write yet. Reads issued after `sync` returns observe every
+    /// write which was committed before `sync` was called.
+    pub fn sync(&self, path: &str) -> ZkResult<String> {
+        trace!("ZooKeeper::sync");
+        let req = SyncRequest { path: try!(self.path(path)) };
+
+        let response: SyncResponse = try!(self.request(OpCode::Sync, Xid::Next, req, None));
+
+        Ok(self.cut_chroot(response.path))
     }
 
-    /// Adds a state change `Listener`, which will be notified of changes to the client's `ZkState`.
-    /// A unique identifier is returned, which is used in `remove_listener` to un-subscribe.
-    pub fn add_listener<Listener: Fn(ZkState) + Send + 'static>(&self,
-                                                                listener: Listener)
-                                                                -> Subscription {
-        trace!("ZooKeeper::add_listener");
-        self.listeners.subscribe(listener)
+    /// Return a view for reads which reflect at least the transaction with the given `min_zxid`,
+    /// like the `Stat::mzxid` of a previous write.
+    ///
+    /// Servers other than the leader may lag behind, so the server this client is connected to is
+    /// synced with the leader first. If that still does not reach `min_zxid` (because the
+    /// transaction was committed after the sync), it is synced again until it does, for at most
+    /// the requested session timeout.
+    ///
+    /// As zxids only ever grow, all reads through the view reflect at least `min_zxid`, even if the
+    /// client reconnects to another server in between (the server refuses a session with a client
+    /// which has seen a newer zxid than itself).
+    ///
+    /// # Errors
+    /// If the server does not reach `min_zxid` in time, `Err(ZkError::OperationTimeout)` will be
+    /// returned.
+    pub fn consistent_view(&self, min_zxid: i64) -> ZkResult<ConsistentView<'_>> {
+        trace!("ZooKeeper::consistent_view");
+        let deadline = Instant::now() + self.session_timeout;
+        loop {
+            try!(self.sync("/"));
+            let zxid = self.last_zxid_seen();
+            if zxid >= min_zxid {
+                return Ok(ConsistentView::new(self, zxid));
+            }
+            if Instant::now() >= deadline {
+                return Err(ZkError::OperationTimeout);
+            }
+            debug!("server at zxid {} has not reached {} yet, syncing again", zxid, min_zxid);
+            thread::sleep(CONSISTENT_VIEW_RETRY_DELAY);
+        }
     }
 
-    /// Removes a state change `Listener` and closes the channel.
-    pub fn remove_listener(&self, sub: Subscription) {
-        trace!("ZooKeeper::remove_listener");
-        self.listeners.unsubscribe(sub);
+    /// Return the zxid of the latest response received from the server.
+    pub fn last_zxid_seen(&self) -> i64 {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).last_zxid
     }
 
-    /// Close this client object. Once the client is closed, its session becomes invalid. All the
+    /// Return the epoch of the latest zxid received from the server (its high 32 bits), which
+    /// increases with every election of a new leader. Comparing it before and after an operation
+    /// tells whether the leadership changed in between, like for fencing.
+    ///
+    /// Only the zxids of the responses this client received are taken into account, so a change
+    /// of the leader is noticed with the next response after it.
+    pub fn current_epoch(&self) -> i64 {
+        self.last_zxid_seen() >> 32
+    }
+
+    /// Add a persistent watch on the node of the given `path`, which, unlike the watches left by
+    /// `exists`, `get_data` and `get_children`, is not removed when it is triggered. It is
+    /// triggered by the creation, deletion and data changes of the node, and changes of its
+    /// children. Requires ZooKeeper 3.6.
+    ///
+    /// Same as `add_watch` with `AddWatchMode::Persistent`.
+    pub fn add_persistent_watch<W>(&self, path: &str, watcher: W) -> ZkResult<()>
+        where W: Fn(WatchedEvent, &WatchContext) + Send + 'static
+    {
+        self.add_watch(path, AddWatchMode::Persistent, watcher)
+    }
+
+    /// Add a watch on the node of the given `path` which is not removed when it is triggered,
+    /// calling `watcher` for every event until the watch is removed. Requires ZooKeeper 3.6.
+    ///
+    /// With `AddWatchMode::PersistentRecursive`, the watch is triggered by the creation, deletion
+    /// and data changes of all descendants of the node as well, at any depth, including the ones
+    /// created after the watch was added. The `path` of each event is the path of the changed
+    /// node.
+    ///
+    /// The `watcher` gets a `WatchContext` along with each event, which it can use to remove the
+    /// watch from within the callback (`WatchContext::remove_watch`).
+    pub fn add_watch<W>(&self, path: &str, mode: AddWatchMode, watcher: W) -> ZkResult<()>
+        where W: Fn(WatchedEvent, &WatchContext) + Send + 'static
+    {
+        trace!("ZooKeeper::add_watch");
+        let req = AddWatchRequest {
+            path: try!(self.path(path)),
+            mode: mode as i32,
+        };
+
+        // Registered before the request, so no event can be missed
+        let context = WatchContext::default();
+        let watch = PersistentWatch {
+            path: path.to_owned(),
+            watcher: Box::new(watcher),
+            context: context.clone(),
+            recursive: mode == AddWatchMode::PersistentRecursive,
+        };
+        try!(self.watch_sender
+                 .lock()
+                 .unwrap_or_else(|e| e.into_inner())
+                 .send(WatchMessage::Persistent(watch))
+                 .map_err(|_| ZkError::ConnectionLoss));
+
+        let result: ZkResult<EmptyResponse> = self.request(OpCode::AddWatch, Xid::Next, req, None);
+        if let Err(e) = result {
+            context.remove_watch();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Return the configuration of the ensemble, as stored in the data of the `/zookeeper/config`
+    /// node, and the `Stat` of that node. The node is read regardless of the chroot. Requires
+    /// ZooKeeper 3.5. See `QuorumConfig::parse` to parse the configuration.
+    ///
+    /// If `watch` is `true` and the call is successful (no error is returned), a watch will be
+    /// left on the node, which is triggered when the configuration changes, like `get_data`.
+    pub fn get_config(&self, watch: bool) -> ZkResult<(Vec<u8>, Stat)> {
+        trace!("ZooKeeper::get_config");
+        let req = GetDataRequest {
+            path: CONFIG_NODE.to_owned(),
+            watch,
+        };
+
+        let response: GetDataResponse = try!(self.request(OpCode::GetData, Xid::Next, req, None));
+
+        Ok(response.data_stat)
+    }
+
+    /// Change the members of the ensemble at runtime. Return the new configuration, as stored
+    /// in the data of the `/zookeeper/config` node, and the `Stat` of that node. Requires
+    /// ZooKeeper 3.5 with `reconfigEnabled`, and usually a superuser.
+    ///
+    /// Either add the servers `joining` and remove the servers `leaving` (an incremental
+    /// reconfiguration), or replace all members with `new_members` (a bulk reconfiguration).
+    /// Servers are given as comma separated lists, like `server.4=host4:2888:3888;2181` to add
+    /// and `4` to remove a server. `from_config` is the version of the configuration the change
+    /// is based on, as found in its `version=` line (in hex), or -1 to apply it to any
+    /// configuration.
+    ///
+    /// # Errors
+    /// If the configuration changed since `from_config`, `Err(ZkError::BadVersion)` will be
+    /// returned. If the server has reconfiguration disabled, `Err(ZkError::ReconfigDisabled)` will
+    /// be returned.
+    pub fn reconfig(&self,
+                    joining: Option<&str>,
+                    leaving: Option<&str>,
+                    new_members: Option<&str>,
+                    from_config: i64)
+                    -> ZkResult<(Vec<u8>, Stat)> {
+        trace!("ZooKeeper::reconfig");
+        let req = ReconfigRequest {
+            joining_servers: joining.unwrap_or("").to_owned(),
+            leaving_servers: leaving.unwrap_or("").to_owned(),
+            new_members: new_members.unwrap_or("").to_owned(),
+            cur_config_id: from_config,
+        };
+
+        let response: GetDataResponse = try!(self.request(OpCode::Reconfig, Xid::Next, req, None));
+
+        Ok(response.data_stat)
+    }
+
+    /// Leave data watches on `data_paths`, exists watches on `exists_paths` and child watches on
+    /// `child_paths` in a single request, instead of a round trip per watch. Like the watches of
+    /// `get_data`, `exists` and `get_children` without a watcher, they trigger the default
+    /// watcher.
+    ///
+    /// Unlike those reads, this does not return the current state of the nodes. Instead, the
+    /// server triggers the watches right away for changes after `relative_zxid`, like a node whose
+    /// data was modified since, so reading the nodes before with `relative_zxid` set to
+    /// `last_zxid_seen` misses no change.
+    ///
+    /// # Errors
+    /// If the paths exceed `ZooKeeperBuilder::max_request_size` together,
+    /// `Err(ZkError::BadArguments)` is returned without sending them.
+    pub fn set_watches(&self,
+                       data_paths: &[&str],
+                       exists_paths: &[&str],
+                       child_paths: &[&str],
+                       relative_zxid: i64)
+                       -> ZkResult<()> {
+        trace!("ZooKeeper::set_watches");
+        let paths = |paths: &[&str]| -> ZkResult<Vec<String>> {
+            paths.iter().map(|path| self.path(path)).collect()
+        };
+        let req = SetWatchesRequest {
+            relative_zxid,
+            data_watches: try!(paths(data_paths)),
+            exist_watches: try!(paths(exists_paths)),
+            child_watches: try!(paths(child_paths)),
+        };
+
+        // The xid the official clients send it with
+        let _: EmptyResponse = try!(self.request(OpCode::SetWatches, Xid::Fixed(-8), req, None));
+        Ok(())
+    }
+
+    /// Remove the watches of the given type left on the node of the given `path`, without
+    /// triggering them. Their watchers are dropped, and no events are delivered to them anymore.
+    /// Requires ZooKeeper 3.5.
+    ///
+    /// # Errors
+    /// If no watch of the given type is left on the path, `Err(ZkError::NoWatcher)` will be
+    /// returned. The watches this client still knows of (like ones which were triggered in the
+    /// meantime) are dropped nevertheless.
+    pub fn remove_watches(&self, path: &str, watcher_type: WatcherType) -> ZkResult<()> {
+        trace!("ZooKeeper::remove_watches");
+        let req = RemoveWatchesRequest {
+            path: try!(self.path(path)),
+            typ: watcher_type as i32,
+        };
+
+        let result: ZkResult<EmptyResponse> =
+            self.request(OpCode::RemoveWatches, Xid::Next, req, None);
+        match result {
+            Ok(_) | Err(ZkError::NoWatcher) => {
+                // The events sent before the response are queued already, so they are delivered
+                // before the watches are dropped
+                let unregister = WatchMessage::Unregister(path.to_owned(), watcher_type);
+                let watch_sender = self.watch_sender.lock().unwrap_or_else(|e| e.into_inner());
+                let _ = watch_sender.send(unregister);
+            }
+            Err(_) => {}
+        }
+        result.map(|_| ())
+    }
+
+    /// Set the data for the node of the given `path` if such a node exists and the given version
+    /// matches the version of the node (if the given version is `None`, it matches any node's
+    /// versions). Return the `Stat` of the node.
+    ///
+    /// This operation, if successful, will trigger all the watches on the node of the given `path`
+    /// left by `get_data` calls.
+    ///
+    /// # Errors
+    /// If no node with the given `path` exists, `Err(ZkError::NoNode)` will be returned.
+    ///
+    /// If the given version does not match the node's version, `Err(ZkError::BadVersion)` will be
+    /// returned.
+    ///
+    /// The maximum allowable size of the `data` array is 1 MiB (1,048,576 bytes). Arrays larger
+    /// than this will return `Err(ZkError::BadArguments)`.
+    pub fn set_data(&self, path: &str, data: Vec<u8>, version: Option<i32>) -> ZkResult<Stat> {
+        trace!("ZooKeeper::set_data");
+        self.set_data_future(path, data, version, OnLimit::Wait).wait()
+    }
+
+    /// Atomically replace the data of the node of the given path with `transform` applied to the
+    /// current data. Return the new `Stat` of the node.
+    ///
+    /// The current data is fetched along with its version and set with that version, so a
+    /// concurrent change makes the update fail with `ZkError::BadVersion`. In that case the data
+    /// is fetched and `transform` applied again, up to `ZooKeeperBuilder::max_update_attempts`
+    /// times, so `transform` may be called more than once.
+    ///
+    /// # Errors
+    /// If no node with the given path exists, `Err(ZkError::NoNode)` will be returned.
+    ///
+    /// If the data kept changing concurrently, `Err(ZkError::BadVersion)` will be returned.
+    pub fn update_data<F>(&self, path: &str, mut transform: F) -> ZkResult<Stat>
+        where F: FnMut(Vec<u8>) -> Vec<u8>
+    {
+        trace!("ZooKeeper::update_data");
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (data, stat) = try!(self.get_data(path, false));
+            match self.set_data(path, transform(data), Some(stat.version)) {
+                Ok(stat) => return Ok(stat),
+                Err(ZkError::BadVersion) if attempts < self.max_update_attempts => {
+                    debug!("data of {} changed concurrently, retrying", path);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Set the data for the node of the given `path` like `set_data`, but without waiting for the
+    /// response: the request is sent right away, and the returned future resolves to the `Stat`
+    /// of the node.
+    ///
+    /// # Errors
+    /// Same as `set_data`. Instead of blocking on a limit of the client, the future fails right
+    /// away; see `ZkFuture`.
+    pub fn set_data_async(&self,
+                          path: &str,
+                          data: Vec<u8>,
+                          version: Option<i32>)
+                          -> ZkFuture<Stat> {
+        trace!("ZooKeeper::set_data_async");
+        self.set_data_future(path, data, version, OnLimit::Fail)
+    }
+
+    fn set_data_future(&self,
+                       path: &str,
+                       data: Vec<u8>,
+                       version: Option<i32>,
+                       on_limit: OnLimit)
+                       -> ZkFuture<Stat> {
+        let req = SetDataRequest {
+            path: match self.path(path) {
+                Ok(path) => path,
+                Err(err) => return ZkFuture::failed(err),
+            },
+            data,
+            version: version.unwrap_or(-1),
+        };
+
+        self.request_async(OpCode::SetData,
+                           req,
+                           on_limit,
+                           |response: SetDataResponse| response.stat)
+    }
+
+    /// Set the data for the node of the given `path` regardless of its version, like `set_data`
+    /// with `None`, and check that no other write interleaved: the version of the node after the
+    /// write must be exactly `expected_prev_version + 1`. Return the `Stat` of the node.
+    ///
+    /// Unlike a conditional `set_data`, the write is always applied; the check only detects that
+    /// another client wrote the node since its version was `expected_prev_version`.
+    ///
+    /// # Errors
+    /// If no node with the given `path` exists, `Err(ZkError::NoNode)` will be returned.
+    ///
+    /// If the version of the node after the write is not `expected_prev_version + 1`,
+    /// `Err(ZkError::BadVersion)` will be returned. The data has been written nevertheless.
+    pub fn set_data_checked(&self,
+                            path: &str,
+                            data: Vec<u8>,
+                            expected_prev_version: i32)
+                            -> ZkResult<Stat> {
+        trace!("ZooKeeper::set_data_checked");
+        let stat = try!(self.set_data(path, data, None));
+        if stat.version != expected_prev_version.wrapping_add(1) {
+            warn!("{} is at version {} after the write, another write interleaved after version {}",
+                  path,
+                  stat.version,
+                  expected_prev_version);
+            return Err(ZkError::BadVersion);
+        }
+        Ok(stat)
+    }
+
+    /// Set the data of the given node like `set_data`, unless `token` is cancelled first; see
+    /// `CancellationToken`.
+    ///
+    /// # Errors
+    /// Same as `set_data`. If `token` is cancelled before the response arrives,
+    /// `Err(ZkError::OperationTimeout)` is returned, and the data may or may not have been set.
+    pub fn set_data_cancellable(&self,
+                                path: &str,
+                                data: Vec<u8>,
+                                version: Option<i32>,
+                                token: &CancellationToken)
+                                -> ZkResult<Stat> {
+        trace!("ZooKeeper::set_data_cancellable");
+        let req = SetDataRequest {
+            path: try!(self.path(path)),
+            data,
+            version: version.unwrap_or(-1),
+        };
+
+        let response: SetDataResponse =
+            try!(self.request_with(OpCode::SetData, Xid::Next, req, None, Some(token)));
+
+        Ok(response.stat)
+    }
+
+    /// Execute the given operations atomically: either all of them succeed, or none of them is
+    /// applied. On success, the results are returned in the order of `ops`: `results[i]` is the
+    /// result of `ops[i]`.
+    ///
+    /// # Errors
+    /// If any of the operations fails, the error of the first failing operation is returned (for
+    /// example `Err(ZkError::BadVersion)` for an `Op::Check` with a mismatching version).
+    ///
+    /// A response which cannot be parsed results in `Err(ZkError::MarshallingError)`. Only that
+    /// response is discarded; the connection stays usable.
+    ///
+    /// Read operations can't be part of a transaction of writes, and return
+    /// `Err(ZkError::BadArguments)`; see `commit_read`.
+    ///
+    /// If the serialized operations exceed `ZooKeeperBuilder::max_request_size`,
+    /// `Err(ZkError::BadArguments)` is returned without sending them, and the connection stays
+    /// usable.
+    pub fn commit(&self, ops: &[Op]) -> ZkResult<Vec<OpResult>> {
+        trace!("ZooKeeper::commit");
+        if let Some(op) = ops.iter().find(|op| op.is_read()) {
+            debug!("{:?} is only valid in a read transaction", op);
+            return Err(ZkError::BadArguments);
+        }
+        let req = TransactionRequest { ops: try!(self.chroot_ops(ops)) };
+
+        let response: TransactionResponse = try!(self.request(OpCode::Multi,
+                                                              Xid::Next,
+                                                              req,
+                                                              None));
+        // `results[i]` must be the result of `ops[i]`, so any other count is a parse bug
+        if response.responses.len() != ops.len() {
+            error!("multi response has {} results for {} operations",
+                   response.responses.len(),
+                   ops.len());
+            return Err(ZkError::MarshallingError);
+        }
+
+        // When a transaction is aborted, the operations which did not fail themselves report
+        // `RuntimeInconsistency`, so prefer the error of the operation which actually failed.
+        let mut results = Vec::with_capacity(response.responses.len());
+        let mut failure = None;
+        for result in response.responses {
+            match result {
+                Ok(OpResult::Create { path }) => {
+                    results.push(OpResult::Create { path: self.cut_chroot(path) })
+                }
+                Ok(result) => results.push(result),
+                Err(ZkError::RuntimeInconsistency) => {
+                    failure = failure.or(Some(ZkError::RuntimeInconsistency))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(results),
+        }
+    }
+
+    /// Set the data of the node at `path` only if its version is `expected_version`, returning
+    /// the new `Stat` of the node. The version check and the update are a single transaction, so
+    /// the server applies both or neither.
+    ///
+    /// # Errors
+    /// If the version of the node differs, `Err(ZkError::BadVersion)` is returned and the node is
+    /// left unchanged. If no node exists at `path`, `Err(ZkError::NoNode)` is returned.
+    pub fn compare_and_set(&self,
+                           path: &str,
+                           expected_version: i32,
+                           new_data: Vec<u8>)
+                           -> ZkResult<Stat> {
+        trace!("ZooKeeper::compare_and_set");
+        let results = try!(self.commit(&[Op::Check {
+                                            path: path.to_owned(),
+                                            version: Some(expected_version),
+                                        },
+                                        Op::SetData {
+                                            path: path.to_owned(),
+                                            data: new_data,
+                                            version: Some(expected_version),
+                                        }]));
+        match results.into_iter().nth(1) {
+            Some(OpResult::SetData { stat }) => Ok(stat),
+            result => {
+                error!("unexpected result of SetData in a transaction: {:?}", result);
+                Err(ZkError::MarshallingError)
+            }
+        }
+    }
+
+    /// Start a transaction, which collects operations through a fluent API and executes them
+    /// atomically with `commit`, like `ZooKeeper::commit`.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Execute the given read operations (`Op::GetData` and `Op::GetChildren`) in a single round
+    /// trip, as a read transaction. Requires ZooKeeper 3.6. The results are returned in the order
+    /// of `ops`: `results[i]` is the result of `ops[i]`.
+    ///
+    /// Unlike the writes of `commit`, the reads are independent of each other: the server reads
+    /// all nodes even if some read fails.
+    ///
+    /// # Errors
+    /// If `ops` contains a write operation, `Err(ZkError::BadArguments)` is returned without
+    /// contacting the server. If any of the reads fails, the error of the first failing read is
+    /// returned (for example `Err(ZkError::NoNode)` for a missing node).
+    pub fn commit_read(&self, ops: &[Op]) -> ZkResult<Vec<OpResult>> {
+        trace!("ZooKeeper::commit_read");
+        if let Some(op) = ops.iter().find(|op| !op.is_read()) {
+            debug!("{:?} is not valid in a read transaction", op);
+            return Err(ZkError::BadArguments);
+        }
+        let req = TransactionRequest { ops: try!(self.chroot_ops(ops)) };
+
+        let response: TransactionResponse = try!(self.request(OpCode::MultiRead,
+                                                              Xid::Next,
+                                                              req,
+                                                              None));
+        if response.responses.len() != ops.len() {
+            error!("multi read response has {} results for {} operations",
+                   response.responses.len(),
+                   ops.len());
+            return Err(ZkError::MarshallingError);
+        }
+        response.responses.into_iter().collect()
+    }
+
+    /// Prefix the paths of the operations with the chroot.
+    fn chroot_ops(&self, ops: &[Op]) -> ZkResult<Vec<Op>> {
+        let mut chrooted = Vec::with_capacity(ops.len());
+        for op in ops {
+            let mut op = op.clone();
+            match op {
+                Op::Create { ref mut path, .. } |
+                Op::Delete { ref mut path, .. } |
+                Op::SetData { ref mut path, .. } |
+                Op::Check { ref mut path, .. } |
+                Op::GetData { ref mut path } |
+                Op::GetChildren { ref mut path } => *path = try!(self.path(path)),
+            }
+            chrooted.push(op);
+        }
+        Ok(chrooted)
+    }
+
+    /// Replace the default `Watcher` (the one given to `connect`) with `watcher`. The new watcher
+    /// receives all subsequent events which are not handled by an explicit `Watcher` (such as the
+    /// ones set with `exists_w`). The connection is not affected.
+    pub fn set_default_watcher<W: Watcher + 'static>(&self, watcher: W) {
+        trace!("ZooKeeper::set_default_watcher");
+        *self.default_watcher.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(watcher));
+    }
+
+    /// Return the context of the most recent operation which failed on the current thread, if it
+    /// was an operation of this handle, or `None` otherwise. Like `errno`, the failure is kept
+    /// per thread, so threads sharing the handle don't see each other's failures, and a failed
+    /// operation of another handle on the same thread replaces it.
+    ///
+    /// Operations only return the bare `ZkError` so it can be matched easily, this adds the kind
+    /// of request, the path and the xid for logging. Errors of pipelined calls like
+    /// `get_children_with_stats` are not recorded.
+    pub fn last_error(&self) -> Option<ZkErrorWithContext> {
+        LAST_ERROR.with(|last_error| match *last_error.borrow() {
+            Some((id, ref context)) if id == self.id => Some(context.clone()),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn max_update_attempts(&self) -> usize {
+        self.max_update_attempts
+    }
+
+    /// Return the role of the server this client is connected to, for example to avoid observers,
+    /// which may lag behind.
+    ///
+    /// The role is queried with the `srvr` four letter word command on a separate connection to
+    /// the server, and cached until the client reconnects.
+    ///
+    /// # Errors
+    /// If no connection is established or the server cannot be queried,
+    /// `Err(ZkError::ConnectionLoss)` will be returned. If the server does not report its role
+    /// (like when `srvr` is not in its `4lw.commands.whitelist`), `Err(ZkError::MarshallingError)`
+    /// will be returned.
+    pub fn connected_server_role(&self) -> ZkResult<ServerRole> {
+        let (server, connections) = {
+            let session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+            (session.server, session.connections)
+        };
+        let server = match server {
+            Some(server) => server,
+            None => return Err(ZkError::ConnectionLoss),
+        };
+
+        let mut cached = self.server_role.lock().unwrap_or_else(|e| e.into_inner());
+        match *cached {
+            Some((queried_for, role)) if queried_for == connections => Ok(role),
+            _ => {
+                let role = try!(query_server_role(&server, self.session_timeout));
+                *cached = Some((connections, role));
+                Ok(role)
+            }
+        }
+    }
+
+    /// Return the connection history of the servers the client tried to connect to so far, like
+    /// how often connecting to them failed.
+    pub fn host_stats(&self) -> Vec<HostStat> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).hosts.clone()
+    }
+
+    /// Return when the current session was established, or `None` if no session has been
+    /// established yet. Reconnecting within the session keeps it, while a new session (like after
+    /// the previous one expired) resets it.
+    pub fn session_established_at(&self) -> Option<Instant> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).established_at
+    }
+
+    /// Return how long the current session has been alive, or `None` if no session has been
+    /// established yet. See `session_established_at`.
+    pub fn session_uptime(&self) -> Option<Duration> {
+        self.session_established_at().map(|established_at| established_at.elapsed())
+    }
+
+    /// Return the server of the latest established connection, or `None` if no session has been
+    /// established yet.
+    pub fn connected_server(&self) -> Option<SocketAddr> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).server
+    }
+
+    /// Return the state of the connection to the ensemble, like to serve cached data instead of
+    /// queueing operations while the client is reconnecting.
+    ///
+    /// Unlike the `ZkState` passed to the state listeners, this tells a reconnect apart from the
+    /// first connection, and an expired session apart from a closed handle. The state is updated
+    /// before the state listeners are notified.
+    pub fn connection_state(&self) -> ConnectionState {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).state
+    }
+
+    /// Return how many watches the client has registered. The event thread counts them once it
+    /// got the response which left a watch, or the event which triggered it.
+    pub fn registered_watches(&self) -> RegisteredWatches {
+        let mut registered = *self.registered_watches.lock().unwrap_or_else(|e| e.into_inner());
+        registered.rearming = self.rearming_watches.load(Ordering::SeqCst);
+        registered
+    }
+
+    /// The count of `RegisteredWatches::rearming`, kept by the recipes.
+    pub(crate) fn rearming_watches(&self) -> Arc<AtomicUsize> {
+        self.rearming_watches.clone()
+    }
+
+    /// Return whether the client is connected to a read-only server, which is partitioned from the
+    /// quorum (see `ZooKeeperBuilder::read_only`). Writes fail with `ZkError::NotReadOnly` without
+    /// being sent until the client reconnects to a server of the quorum.
+    pub fn is_read_only(&self) -> bool {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).read_only
+    }
+
+    /// Stop connecting to the server at `addr` for a while (see
+    /// `ZooKeeperBuilder::avoid_host_cooldown`), like while it is drained for maintenance. If the
+    /// client is connected to it, it moves the session to another server right away. Requests
+    /// pending at that moment fail with `ZkError::ConnectionLoss`.
+    ///
+    /// If all servers are avoided, the client connects to them anyway.
+    pub fn avoid_host(&self, addr: SocketAddr) -> ZkResult<()> {
+        self.io_control
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .send(IoControl::AvoidHost(addr, self.avoid_host_cooldown))
+            .map_err(|err| {
+                warn!("error avoiding host: {:?}", err);
+                ZkError::ConnectionLoss
+            })
+    }
+
+    /// Return the session timeout requested when connecting.
+    pub fn requested_session_timeout(&self) -> Duration {
+        self.session_timeout
+    }
+
+    /// Return the session timeout negotiated with the server, or `None` if no session has been
+    /// established yet. The server may have clamped the requested timeout into its configured
+    /// bounds (see `ZooKeeperBuilder::require_exact_timeout`).
+    pub fn negotiated_session_timeout(&self) -> Option<Duration> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).timeout
+    }
+
+    /// Return the session timeout negotiated with the server, or the requested one until a session
+    /// is established. Like `negotiated_session_timeout`, for lease logic which has to go by the
+    /// timeout the server enforces.
+    pub fn session_timeout(&self) -> Duration {
+        self.negotiated_session_timeout().unwrap_or(self.session_timeout)
+    }
+
+    /// Return the id of the session, or 0 if no session has been established yet. It changes when
+    /// the session expires and the client establishes a new one.
+    pub fn session_id(&self) -> i64 {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).session_id
+    }
+
+    /// Return the password of the session, or an empty one if no session has been established
+    /// yet. Along with `session_id`, it allows resuming the session with `connect_with_session`.
+    pub fn session_password(&self) -> Vec<u8> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).passwd.clone()
+    }
+
+    /// Return the protocol version negotiated with the server, or `None` if no connection has been
+    /// established yet (see `ZooKeeperBuilder::min_protocol_version`).
+    pub fn protocol_version(&self) -> Option<i32> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner()).protocol_version
+    }
+
+    /// Adds a state change `Listener`, which will be notified of changes to the client's `ZkState`.
+    /// A unique identifier is returned, which is used in `remove_listener` to un-subscribe.
+    pub fn add_listener<Listener: Fn(ZkState) + Send + 'static>(&self,
+                                                                listener: Listener)
+                                                                -> Subscription {
+        trace!("ZooKeeper::add_listener");
+        self.listeners.subscribe(listener)
+    }
+
+    /// Removes a state change `Listener` and closes the channel.
+    pub fn remove_listener(&self, sub: Subscription) {
+        trace!("ZooKeeper::remove_listener");
+        self.listeners.unsubscribe(sub);
+    }
+
+    /// Adds a `Listener` which is notified of every attempt to reconnect to a server after the
+    /// connection was lost: when the attempt starts, and when it succeeds or fails. A unique
+    /// identifier is returned, which is used in `remove_reconnect_listener` to un-subscribe.
+    ///
+    /// The listener is called from the IO thread, so it should return quickly.
+    pub fn add_reconnect_listener<Listener: Fn(ReconnectEvent) + Send + 'static>(&self,
+                                                                               listener: Listener)
+                                                                               -> Subscription {
+        trace!("ZooKeeper::add_reconnect_listener");
+        self.reconnect_listeners.subscribe(listener)
+    }
+
+    /// Removes a reconnect `Listener` added with `add_reconnect_listener`.
+    pub fn remove_reconnect_listener(&self, sub: Subscription) {
+        trace!("ZooKeeper::remove_reconnect_listener");
+        self.reconnect_listeners.unsubscribe(sub);
+    }
+
+    /// Close this client object. Once the client is closed, its session becomes invalid. All the
     /// ephemeral nodes in the ZooKeeper server associated with the session will be removed. The
     /// watches left on those nodes (and on their parents) will be triggered.
+    ///
+    /// Watches of this client no longer fire once it is closing: pending events are discarded, and
+    /// a watcher already running is waited for, so no watcher runs after `close` returns (unless
+    /// `close` is called from a watcher itself).
     pub fn close(&self) -> ZkResult<()> {
         trace!("ZooKeeper::close");
-        let _: EmptyResponse = try!(self.request(OpCode::CloseSession, 0, EmptyRequest, None));
+        self.event_gate.close();
+        let _: EmptyResponse = try!(self.request(OpCode::CloseSession,
+                                                 Xid::Fixed(0),
+                                                 EmptyRequest,
+                                                 None));
 
         Ok(())
     }
+
+    /// Stop this client without closing its session, like a process exiting. The session and
+    /// its ephemeral nodes live on until the session times out, so another client can resume it
+    /// with `connect_with_session` in the meantime. Pending operations fail with
+    /// `ZkError::ConnectionLoss`.
+    pub fn detach(mut self) {
+        trace!("ZooKeeper::detach");
+        self.event_gate.close();
+        self.detached = true;
+        if let Err(err) = self.io_control
+                              .lock()
+                              .unwrap_or_else(|e| e.into_inner())
+                              .send(IoControl::Detach) {
+            warn!("error detaching: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+impl ZooKeeper {
+    /// Send an instruction to the IO thread.
+    pub(crate) fn io_control(&self, control: IoControl) {
+        self.io_control.lock().unwrap().send(control).unwrap();
+    }
 }
 
 impl Drop for ZooKeeper {
     fn drop(&mut self) {
+        if self.detached {
+            return;
+        }
         if let Err(err) = self.close() {
             error!("error closing zookeeper connection in drop: {:?}", err);
         }
@@ -532,39 +2938,36 @@ impl Drop for ZooKeeper {
 
 #[cfg(test)]
 mod tests {
-    use super::ZooKeeper;
+    use super::{CreateOutcome, ZooKeeper, ZooKeeperBuilder};
+    use acl::Acl;
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use cancel::CancellationToken;
+    use consts::{CreateMode, ZkError, ZkState};
+    use metrics::Metrics;
+    use multi::Op;
+    use offline_queue::QueueOverflowPolicy;
+    use paths::sequence_number;
+    use proto::{BufferReader, OpCode, StringReader, WriteTo};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use testing::{block_on, connected, data_changed, memory_pipe, memory_pipes, reply_data, stat,
+                  MemoryServer};
 
-    // TODO This is flaky on Travis, it works on my Linux box though.
     #[test]
-    #[cfg(target_os = "macos")]
     fn parse_connect_string() {
-        use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-
-        let (addrs, chroot) = ZooKeeper::parse_connect_string("127.0.0.1:2181,::1:2181/mesos")
+        let (hosts, chroot) = ZooKeeper::parse_connect_string("127.0.0.1:2181,::1:2181/mesos")
                                   .ok()
                                   .expect("Parse 1");
-        assert_eq!(addrs,
-                   vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 2181)),
-                        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
-                                                         2181,
-                                                         0,
-                                                         0))]);
+        assert_eq!(hosts, vec!["127.0.0.1:2181".to_owned(), "::1:2181".to_owned()]);
         assert_eq!(chroot, Some("/mesos".to_owned()));
 
-        let (addrs, chroot) = ZooKeeper::parse_connect_string("::1:2181").ok().expect("Parse 2");
-        assert_eq!(addrs,
-                   vec![SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
-                                                         2181,
-                                                         0,
-                                                         0))]);
+        let (hosts, chroot) = ZooKeeper::parse_connect_string("::1:2181").ok().expect("Parse 2");
+        assert_eq!(hosts, vec!["::1:2181".to_owned()]);
         assert_eq!(chroot, None);
 
-        let (addrs, chroot) = ZooKeeper::parse_connect_string("::1:2181/").ok().expect("Parse 3");
-        assert_eq!(addrs,
-                   vec![SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
-                                                         2181,
-                                                         0,
-                                                         0))]);
+        let (hosts, chroot) = ZooKeeper::parse_connect_string("::1:2181/").ok().expect("Parse 3");
+        assert_eq!(hosts, vec!["::1:2181".to_owned()]);
         assert_eq!(chroot, None);
     }
 
@@ -574,4 +2977,1047 @@ mod tests {
         // This fails with ZooKeeper.java: Path must not end with / character
         ZooKeeper::parse_connect_string("127.0.0.1:2181/mesos/").unwrap();
     }
+
+    #[test]
+    fn cancel_request_over_memory_pipe() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+
+        // the server doesn't answer the request until it is cancelled
+        let token = CancellationToken::new();
+        let (result_tx, result_rx) = mpsc::channel();
+        let (zk1, token1) = (zk.clone(), token.clone());
+        thread::spawn(move || {
+            result_tx.send(zk1.get_data_cancellable("/slow", &token1)).unwrap();
+        });
+        let mut request = server.read_frame();
+        let slow_xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+        assert!(result_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let cancelled = Instant::now();
+        token.cancel();
+        assert_eq!(result_rx.recv_timeout(Duration::from_secs(5)),
+                   Ok(Err(ZkError::OperationTimeout)));
+        assert!(cancelled.elapsed() < Duration::from_secs(1));
+
+        // the late reply is discarded, without confusing the next request
+        server.reply(slow_xid, 10, -101);
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 3);
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(11).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            frame.extend(&[0; 68]); // Stat
+            server.write_frame(&frame);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 12, 0);
+        });
+        assert!(zk.exists("/next", false).unwrap().is_some());
+        assert_eq!(zk.last_zxid_seen(), 11);
+
+        // a cancelled token fails without sending the request
+        assert_eq!(zk.delete_cancellable("/gone", None, &token), Err(ZkError::OperationTimeout));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    /// The opcode, bytes out, bytes in and error of a response.
+    type Recorded = (OpCode, usize, usize, Option<ZkError>);
+
+    struct RecordingMetrics(Mutex<mpsc::Sender<Recorded>>);
+
+    impl Metrics for RecordingMetrics {
+        fn on_response(&self,
+                       opcode: OpCode,
+                       _: Duration,
+                       bytes_out: usize,
+                       bytes_in: usize,
+                       err: Option<ZkError>) {
+            self.0.lock().unwrap().send((opcode, bytes_out, bytes_in, err)).unwrap();
+        }
+    }
+
+    #[test]
+    fn metrics_report_bytes_on_the_wire() {
+        let (metrics_tx, metrics_rx) = mpsc::channel();
+        let metrics = RecordingMetrics(Mutex::new(metrics_tx));
+        let (zk, server) = connected(ZooKeeperBuilder::new().metrics(metrics));
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(10).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            frame.write_i32::<BigEndian>(1000).unwrap();
+            frame.extend(&[7; 1000]); // data
+            frame.extend(&[0; 68]); // Stat
+            server.write_frame(&frame);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 11, 0);
+        });
+
+        let (data, _) = zk.get_data("/node", false).unwrap();
+        assert_eq!(data.len(), 1000);
+        // length prefix, RequestHeader, path "/node" and the watch flag
+        let expected_out = 4 + 8 + 4 + 5 + 1;
+        // length prefix, ReplyHeader, data and Stat
+        let expected_in = 4 + 16 + 4 + 1000 + 68;
+        assert_eq!(metrics_rx.recv_timeout(Duration::from_secs(5)),
+                   Ok((OpCode::GetData, expected_out, expected_in, None)));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn async_requests_are_recorded_like_blocking_ones() {
+        let (metrics_tx, metrics_rx) = mpsc::channel();
+        let metrics = RecordingMetrics(Mutex::new(metrics_tx));
+        let (zk, server) = connected(ZooKeeperBuilder::new().metrics(metrics));
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 5);
+            server.reply(xid, 10, -103);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 11, 0);
+        });
+
+        let future = zk.set_data_async("/node", vec![1], Some(3));
+        assert_eq!(block_on(future), Err(ZkError::BadVersion));
+        // length prefix, RequestHeader, path "/node", data and version
+        let expected_out = 4 + 8 + 4 + 5 + 4 + 1 + 4;
+        // length prefix and ReplyHeader
+        assert_eq!(metrics_rx.recv_timeout(Duration::from_secs(5)),
+                   Ok((OpCode::SetData, expected_out, 4 + 16, Some(ZkError::BadVersion))));
+        let error = zk.last_error().unwrap();
+        assert_eq!((error.opcode, error.path), (OpCode::SetData, Some("/node".to_owned())));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    /// A client connected to the first server, which is going to reconnect to the second one.
+    fn reconnecting() -> (Arc<ZooKeeper>, MemoryServer, MemoryServer) {
+        let (connector, mut servers) = memory_pipes(2);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let first = servers.remove(0);
+        first.accept();
+        (Arc::new(zk), first, servers.remove(0))
+    }
+
+    #[test]
+    fn create_with_token_owns_the_node_after_a_connection_loss() {
+        let (zk, first, second) = reconnecting();
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            zk1.create_with_token("/owned",
+                                  b"data".to_vec(),
+                                  b"token",
+                                  Acl::open_unsafe().clone(),
+                                  CreateMode::Ephemeral)
+        });
+
+        // the create reaches the server, but the connection is lost before the response
+        let mut request = first.read_frame();
+        request.set_position(4);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 1);
+        first.close();
+
+        // so the retried create finds the node, which holds the token
+        second.accept();
+        let mut request = second.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 1);
+        assert_eq!(request.read_string().unwrap(), "/owned");
+        second.reply(xid, 10, ZkError::NodeExists as i32);
+        reply_data(&second, 11, 0, b"tokendata");
+        assert_eq!(client.join().unwrap(),
+                   Ok(CreateOutcome::AlreadyOwned("/owned".to_owned())));
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 12, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn create_protected_finds_the_sequence_after_a_connection_loss() {
+        let (zk, first, second) = reconnecting();
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            zk1.create_protected("/locks/lock-",
+                                 vec![],
+                                 Acl::open_unsafe().clone(),
+                                 CreateMode::EphemeralSequential)
+        });
+
+        // the create reaches the server, but the connection is lost before the response
+        let mut request = first.read_frame();
+        request.set_position(4);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 1);
+        let name = request.read_string().unwrap()["/locks/".len()..].to_owned();
+        assert!(name.starts_with("_c_") && name.ends_with("-lock-"), "{}", name);
+        first.close();
+
+        // the search finds the node among the children of the parent
+        second.accept();
+        let mut request = second.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 8);
+        assert_eq!(request.read_string().unwrap(), "/locks");
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(10).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        vec!["lock-0000000006".to_owned(), format!("{}0000000007", name)]
+            .write_to(&mut frame)
+            .unwrap();
+        second.write_frame(&frame);
+        let path = client.join().unwrap().unwrap();
+        assert_eq!(path, format!("/locks/{}0000000007", name));
+        assert_eq!(sequence_number(&path), Some(7));
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 11, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn get_data_raw_keeps_null_apart_from_empty() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let server = thread::spawn(move || {
+            // a node created with null data, then one created with empty data
+            for (zxid, len) in [(10, -1), (11, 0)] {
+                let mut request = server.read_frame();
+                let xid = request.read_i32::<BigEndian>().unwrap();
+                assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+                let mut frame = Vec::new();
+                frame.write_i32::<BigEndian>(xid).unwrap();
+                frame.write_i64::<BigEndian>(zxid).unwrap();
+                frame.write_i32::<BigEndian>(0).unwrap();
+                frame.write_i32::<BigEndian>(len).unwrap(); // data
+                stat(5, 5).write_to(&mut frame).unwrap();
+                server.write_frame(&frame);
+            }
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 12, 0);
+        });
+
+        assert_eq!(zk.get_data_raw("/null", false), Ok((None, stat(5, 5))));
+        assert_eq!(zk.get_data_raw("/empty", false), Ok((Some(vec![]), stat(5, 5))));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn last_error_is_kept_per_thread() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+        let server = thread::spawn(move || {
+            for zxid in 10..12 {
+                let mut request = server.read_frame();
+                let xid = request.read_i32::<BigEndian>().unwrap();
+                server.reply(xid, zxid, -101);
+            }
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 12, 0);
+        });
+
+        assert_eq!(zk.get_data("/main", false), Err(ZkError::NoNode));
+        let zk1 = zk.clone();
+        let other = thread::spawn(move || {
+            assert_eq!(zk1.last_error(), None);
+            assert_eq!(zk1.delete("/other", None), Err(ZkError::NoNode));
+            zk1.last_error().unwrap()
+        });
+        let other = other.join().unwrap();
+        assert_eq!((other.opcode, other.path), (OpCode::Delete, Some("/other".to_owned())));
+
+        // the failure of the other thread didn't replace the one of this thread
+        let error = zk.last_error().unwrap();
+        assert_eq!((error.opcode, error.path), (OpCode::GetData, Some("/main".to_owned())));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reconfig_sends_the_members_and_returns_the_new_config() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 16);
+            assert_eq!(request.read_string().unwrap(), "server.4=host4:2888:3888;2181");
+            assert_eq!(request.read_string().unwrap(), "2");
+            // an absent list is sent as an empty string
+            assert_eq!(request.read_string().unwrap(), "");
+            assert_eq!(request.read_i64::<BigEndian>().unwrap(), 0x100000000);
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(10).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            b"version=10000000a".to_vec().write_to(&mut frame).unwrap();
+            stat(5, 10).write_to(&mut frame).unwrap();
+            server.write_frame(&frame);
+
+            // a concurrent change of the configuration
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 16);
+            server.reply(xid, 11, -103);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 12, 0);
+        });
+
+        assert_eq!(zk.reconfig(Some("server.4=host4:2888:3888;2181"), Some("2"), None, 0x100000000),
+                   Ok((b"version=10000000a".to_vec(), stat(5, 10))));
+        assert_eq!(zk.reconfig(None, Some("4"), None, 0x100000000), Err(ZkError::BadVersion));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn too_large_request_is_not_sent() {
+        let (zk, server) = connected(ZooKeeperBuilder::new().max_request_size(1024));
+        let ops = vec![Op::SetData {
+                           path: "/node".to_owned(),
+                           data: vec![0; 600],
+                           version: None,
+                       };
+                       2];
+        assert_eq!(zk.commit(&ops), Err(ZkError::BadArguments));
+
+        // the next request is the first one the server sees
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 3);
+            server.reply(xid, 10, -101);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 11, 0);
+        });
+        assert_eq!(zk.exists("/node", false), Ok(None));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn create_ttl_sends_the_ttl() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let ttl = Duration::from_secs(60);
+        assert_eq!(zk.create_ttl("/ttl", vec![], vec![], CreateMode::Persistent, ttl),
+                   Err(ZkError::BadArguments));
+        assert_eq!(zk.create_ttl("/ttl",
+                                 vec![],
+                                 vec![],
+                                 CreateMode::PersistentWithTtl,
+                                 Duration::from_micros(10)),
+                   Err(ZkError::BadArguments));
+        for ttl in &[Duration::from_millis(0xff_ffff_ffff + 1), Duration::from_secs(u64::MAX)] {
+            assert_eq!(zk.create_ttl("/ttl", vec![], vec![], CreateMode::PersistentWithTtl, *ttl),
+                       Err(ZkError::BadArguments));
+        }
+
+        // the next request is the first one the server sees
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 21);
+            assert_eq!(request.read_string().unwrap(), "/ttl");
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0); // data
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0); // acl
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 6); // flags
+            assert_eq!(request.read_i64::<BigEndian>().unwrap(), 60_000);
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(10).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            "/ttl0000000001".to_owned().write_to(&mut frame).unwrap();
+            stat(10, 10).write_to(&mut frame).unwrap();
+            server.write_frame(&frame);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 11, 0);
+        });
+        let mode = CreateMode::PersistentSequentialWithTtl;
+        assert_eq!(zk.create_ttl("/ttl", vec![], vec![], mode, ttl),
+                   Ok("/ttl0000000001".to_owned()));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn get_all_children_number() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 104);
+            assert_eq!(request.read_string().unwrap(), "/tree");
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(10).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            frame.write_i32::<BigEndian>(42).unwrap();
+            server.write_frame(&frame);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 104);
+            server.reply(xid, 11, -101);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 12, 0);
+        });
+        assert_eq!(zk.get_all_children_number("/tree"), Ok(42));
+        assert_eq!(zk.get_all_children_number("/missing"), Err(ZkError::NoNode));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn get_ephemerals_within_chroot() {
+        let (connector, server) = memory_pipe();
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181/app")
+                     .connector(connector)
+                     .connect()
+                     .unwrap();
+        let server = thread::spawn(move || {
+            server.accept();
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 103);
+            assert_eq!(request.read_string().unwrap(), "/app");
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(10).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            vec!["/app/a".to_owned(), "/apple/b".to_owned(), "/app/c/d".to_owned()]
+                .write_to(&mut frame)
+                .unwrap();
+            server.write_frame(&frame);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 11, 0);
+        });
+        // the sibling of the chroot is left out
+        assert_eq!(zk.get_ephemerals(""), Ok(vec!["/a".to_owned(), "/c/d".to_owned()]));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn containers_are_created_with_their_own_opcode() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 19);
+            assert_eq!(request.read_string().unwrap(), "/container");
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0); // data
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0); // acl
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4); // flags
+            let mut frame = Vec::new();
+            frame.write_i32::<BigEndian>(xid).unwrap();
+            frame.write_i64::<BigEndian>(10).unwrap();
+            frame.write_i32::<BigEndian>(0).unwrap();
+            "/container".to_owned().write_to(&mut frame).unwrap();
+            stat(10, 10).write_to(&mut frame).unwrap();
+            server.write_frame(&frame);
+
+            // a server without containers
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 19);
+            server.reply(xid, 11, -6);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 12, 0);
+        });
+        assert_eq!(zk.create("/container", vec![], vec![], CreateMode::Container),
+                   Ok("/container".to_owned()));
+        assert_eq!(zk.create("/container", vec![], vec![], CreateMode::Container),
+                   Err(ZkError::Unimplemented));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn concurrent_async_creates() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let server = thread::spawn(move || {
+            for _ in 0..100 {
+                let mut request = server.read_frame();
+                let xid = request.read_i32::<BigEndian>().unwrap();
+                assert_eq!(request.read_i32::<BigEndian>().unwrap(), 1);
+                let path = request.read_string().unwrap();
+                let mut frame = Vec::new();
+                frame.write_i32::<BigEndian>(xid).unwrap();
+                frame.write_i64::<BigEndian>(i64::from(xid)).unwrap();
+                frame.write_i32::<BigEndian>(0).unwrap();
+                path.write_to(&mut frame).unwrap();
+                server.write_frame(&frame);
+            }
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 200, 0);
+        });
+
+        // all requests are in flight before the first response is awaited
+        let futures = (0..100)
+                          .map(|i| {
+                              zk.create_async(&format!("/node{}", i),
+                                              vec![],
+                                              Acl::open_unsafe().clone(),
+                                              CreateMode::Persistent)
+                          })
+                          .collect::<Vec<_>>();
+        for (i, future) in futures.into_iter().enumerate() {
+            assert_eq!(block_on(future), Ok(format!("/node{}", i)));
+        }
+
+        // a request which can't be sent resolves right away
+        assert_eq!(block_on(zk.get_data_async("invalid/", false)), Err(ZkError::BadArguments));
+
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    /// A client which has not established its session yet, queueing at most one request.
+    fn offline_client(policy: QueueOverflowPolicy) -> (ZooKeeper, MemoryServer) {
+        let (connector, server) = memory_pipe();
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .offline_queue_limit(1)
+                     .offline_queue_policy(policy)
+                     .connect()
+                     .unwrap();
+        (zk, server)
+    }
+
+    /// Establish the session, answer the next request with NoNode, and close the session.
+    fn serve_queued(server: MemoryServer) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            server.accept();
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+            assert_eq!(request.read_string().unwrap(), "/queued");
+            server.reply(xid, 1, -101);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 2, 0);
+        })
+    }
+
+    #[test]
+    fn offline_queue_rejects_the_newest_request() {
+        let (zk, server) = offline_client(QueueOverflowPolicy::RejectNewest);
+        let queued = zk.get_data_async("/queued", false);
+        let rejected = zk.get_data_async("/rejected", false);
+        assert_eq!(block_on(rejected), Err(ZkError::ConnectionLoss));
+
+        let server = serve_queued(server);
+        assert_eq!(block_on(queued), Err(ZkError::NoNode));
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn offline_queue_rejects_the_oldest_request() {
+        let (zk, server) = offline_client(QueueOverflowPolicy::RejectOldest);
+        let rejected = zk.get_data_async("/rejected", false);
+        let queued = zk.get_data_async("/queued", false);
+        assert_eq!(block_on(rejected), Err(ZkError::ConnectionLoss));
+
+        // the rejected request is never written
+        let server = serve_queued(server);
+        assert_eq!(block_on(queued), Err(ZkError::NoNode));
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn offline_queue_survives_failed_reconnect_attempts() {
+        let (connector, mut servers) = memory_pipes(3);
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .offline_queue_limit(1)
+                     .offline_queue_policy(QueueOverflowPolicy::RejectNewest)
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = servers.remove(0);
+        first.accept();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connected));
+
+        // the connection drops in the middle of the session
+        first.close();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+        let queued = zk.get_data_async("/queued", false);
+
+        // the next server goes away during the handshake, the request stays queued
+        let second = servers.remove(0);
+        second.read_frame();
+        second.close();
+
+        let server = serve_queued(servers.remove(0));
+        assert_eq!(block_on(queued), Err(ZkError::NoNode));
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn offline_queue_blocks_until_connected() {
+        let (zk, server) = offline_client(QueueOverflowPolicy::Block);
+        let zk = Arc::new(zk);
+        let queued = zk.get_data_async("/queued", false);
+
+        let (sent_tx, sent_rx) = mpsc::channel();
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || {
+            let blocked = zk1.get_data("/blocked", false).map(|_| ());
+            sent_tx.send(()).unwrap();
+            blocked
+        });
+        assert!(sent_rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        let server = thread::spawn(move || {
+            server.accept();
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            request.set_position(8);
+            assert_eq!(request.read_string().unwrap(), "/queued");
+            server.reply(xid, 1, -101);
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            request.set_position(8);
+            assert_eq!(request.read_string().unwrap(), "/blocked");
+            server.reply(xid, 2, -101);
+
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 3, 0);
+        });
+        assert_eq!(block_on(queued), Err(ZkError::NoNode));
+        assert_eq!(sent_rx.recv_timeout(Duration::from_secs(5)), Ok(()));
+        assert_eq!(client.join().unwrap(), Err(ZkError::NoNode));
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn offline_queue_fails_async_requests_instead_of_blocking() {
+        let (zk, server) = offline_client(QueueOverflowPolicy::Block);
+        let queued = zk.get_data_async("/queued", false);
+        assert_eq!(block_on(zk.get_data_async("/rejected", false)), Err(ZkError::ConnectionLoss));
+
+        let server = serve_queued(server);
+        assert_eq!(block_on(queued), Err(ZkError::NoNode));
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rate_limited_async_requests_fail_instead_of_blocking() {
+        let (zk, server) = connected(ZooKeeperBuilder::new().rate_limit(1));
+        let sent = zk.get_data_async("/sent", false);
+        assert_eq!(block_on(zk.get_data_async("/throttled", false)), Err(ZkError::Throttled));
+
+        let server = thread::spawn(move || {
+            reply_data(&server, 10, 0, b"1");
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 11, 0);
+        });
+        assert_eq!(block_on(sent).unwrap().0, b"1".to_vec());
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn offline_queue_blocking_does_not_hold_up_close() {
+        let (zk, server) = offline_client(QueueOverflowPolicy::Block);
+        let zk = Arc::new(zk);
+        let queued = zk.get_data_async("/queued", false);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.get_data("/blocked", false).map(|_| ()));
+        thread::sleep(Duration::from_millis(100));
+
+        // close is handed to the IO thread while the other request is still blocked
+        let (closed_tx, closed_rx) = mpsc::channel();
+        let zk2 = zk.clone();
+        thread::spawn(move || closed_tx.send(zk2.close()).unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let server = thread::spawn(move || {
+            server.accept();
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            request.set_position(8);
+            assert_eq!(request.read_string().unwrap(), "/queued");
+            server.reply(xid, 1, -101);
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 2, 0);
+        });
+        assert_eq!(block_on(queued), Err(ZkError::NoNode));
+        assert_eq!(closed_rx.recv_timeout(Duration::from_secs(5)), Ok(Ok(())));
+        assert_eq!(client.join().unwrap(), Err(ZkError::ConnectionLoss));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn cached_reads_until_the_node_changes() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        let zk = Arc::new(zk);
+
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.get_data_cached("/static"));
+        reply_data(&server, 10, 0, b"v1");
+        assert_eq!(client.join().unwrap(), Ok((b"v1".to_vec(), stat(5, 10))));
+        // served from the cache, the server would see the read otherwise
+        assert_eq!(zk.get_data_cached("/static"), Ok((b"v1".to_vec(), stat(5, 10))));
+
+        // the node changes after all, and the cache serves v1 until the event arrives
+        let server = thread::spawn(move || {
+            data_changed(&server, 11, "/static");
+            reply_data(&server, 12, 0, b"v2");
+            server
+        });
+        loop {
+            let (data, _) = zk.get_data_cached("/static").unwrap();
+            if data == b"v2" {
+                break;
+            }
+            assert_eq!(data, b"v1");
+        }
+        let server = server.join().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 13, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn detach_leaves_the_session_open() {
+        let (zk, server) = connected(ZooKeeperBuilder::new());
+        assert_eq!(zk.session_id(), 0x1234);
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+
+        zk.detach();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Closed));
+        assert!(server.inbound.lock().unwrap().is_empty());
+    }
+
+    /// Answer the sync request which `connect` sends after authentication information.
+    fn reply_sync(server: &MemoryServer, path: &str) {
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 9);
+        assert_eq!(request.read_string().unwrap(), path);
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(1).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        path.to_owned().write_to(&mut frame).unwrap();
+        server.write_frame(&frame);
+    }
+
+    /// Read the authentication request of the handshake.
+    fn read_auth(server: &MemoryServer) -> (String, Vec<u8>) {
+        let mut request = server.read_frame();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), -4);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 100);
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0);
+        (request.read_string().unwrap(), request.read_buffer().unwrap())
+    }
+
+    #[test]
+    fn auth_is_sent_again_with_every_handshake() {
+        let (connector, mut servers) = memory_pipes(2);
+        let first = servers.remove(0);
+        let server = thread::spawn(move || {
+            first.accept();
+            assert_eq!(read_auth(&first), ("digest".to_owned(), b"a:1".to_vec()));
+            assert_eq!(read_auth(&first), ("digest".to_owned(), b"b:2".to_vec()));
+            first.reply(-4, 0, 0);
+            first.reply(-4, 0, 0);
+            reply_sync(&first, "/");
+            first
+        });
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .add_auth("digest", b"a:1".to_vec())
+                     .add_auth("digest", b"b:2".to_vec())
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        let first = server.join().unwrap();
+
+        // a request waiting for the next connection follows the authentication information
+        first.close();
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)), Ok(ZkState::Connecting));
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.get_data("/node", false));
+        let second = servers.remove(0);
+        second.accept();
+        assert_eq!(read_auth(&second), ("digest".to_owned(), b"a:1".to_vec()));
+        assert_eq!(read_auth(&second), ("digest".to_owned(), b"b:2".to_vec()));
+        second.reply(-4, 0, 0);
+        second.reply(-4, 0, 0);
+        reply_data(&second, 2, 0, b"data");
+        assert_eq!(client.join().unwrap().unwrap().0, b"data".to_vec());
+
+        let server = thread::spawn(move || {
+            let mut request = second.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            second.reply(xid, 3, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rejected_auth_fails_connect() {
+        let (connector, server) = memory_pipe();
+        let server = thread::spawn(move || {
+            server.accept();
+            read_auth(&server);
+            server.reply(-4, 0, -115);
+            server.close();
+        });
+        let result = ZooKeeperBuilder::new()
+                         .connect_string("127.0.0.1:2181")
+                         .connector(connector)
+                         .add_auth("digest", b"user:wrong".to_vec())
+                         .connect();
+        assert_eq!(result.err(), Some(ZkError::AuthFailed));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn auth_connect_tries_the_next_server() {
+        let (connector, mut servers) = memory_pipes(2);
+        let server = thread::spawn(move || {
+            // the first server goes away during the handshake
+            let first = servers.remove(0);
+            first.read_frame();
+            first.close();
+
+            let second = servers.remove(0);
+            second.accept();
+            assert_eq!(read_auth(&second), ("digest".to_owned(), b"user:secret".to_vec()));
+            second.reply(-4, 0, 0);
+            reply_sync(&second, "/");
+            second
+        });
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .add_auth("digest", b"user:secret".to_vec())
+                     .connect()
+                     .unwrap();
+        let server = server.join().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 2, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn builder_options_take_effect() {
+        let (connector, server) = memory_pipe();
+        let server = thread::spawn(move || {
+            let mut connect = server.accept();
+            connect.set_position(connect.get_ref().len() as u64 - 1);
+            assert_eq!(connect.read_u8().unwrap(), 1); // read-only allowed
+            let mut request = server.read_frame();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -4);
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 100);
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), 0);
+            assert_eq!(request.read_string().unwrap(), "digest");
+            assert_eq!(request.read_buffer().unwrap(), b"user:secret".to_vec());
+            server.reply(-4, 0, 0);
+            reply_sync(&server, "/app");
+            server
+        });
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .chroot("/app")
+                     .read_only(true)
+                     .add_auth("digest", b"user:secret".to_vec())
+                     .connect()
+                     .unwrap();
+        let server = server.join().unwrap();
+
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.get_data("/node", false));
+        let mut request = server.read_frame();
+        let xid = request.read_i32::<BigEndian>().unwrap();
+        assert_eq!(request.read_i32::<BigEndian>().unwrap(), 4);
+        assert_eq!(request.read_string().unwrap(), "/app/node");
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(xid).unwrap();
+        frame.write_i64::<BigEndian>(1).unwrap();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        b"data".to_vec().write_to(&mut frame).unwrap();
+        stat(5, 1).write_to(&mut frame).unwrap();
+        server.write_frame(&frame);
+        assert_eq!(client.join().unwrap().unwrap().0, b"data".to_vec());
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 2, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn conflicting_chroots() {
+        let (connector, _server) = memory_pipe();
+        let result = ZooKeeperBuilder::new()
+                         .connect_string("127.0.0.1:2181/a")
+                         .connector(connector)
+                         .chroot("/b")
+                         .connect();
+        assert_eq!(result.err(), Some(ZkError::BadArguments));
+    }
+
+    #[test]
+    fn read_only_rejects_writes_locally() {
+        let (connector, server) = memory_pipe();
+        let zk = ZooKeeperBuilder::new()
+                     .connect_string("127.0.0.1:2181")
+                     .connector(connector)
+                     .read_only(true)
+                     .connect()
+                     .unwrap();
+        let (state_tx, state_rx) = mpsc::channel();
+        zk.add_listener(move |state| state_tx.send(state).unwrap());
+        server.read_frame();
+        let mut frame = Vec::new();
+        frame.write_i32::<BigEndian>(0).unwrap();
+        frame.write_i32::<BigEndian>(6000).unwrap();
+        frame.write_i64::<BigEndian>(0x1234).unwrap();
+        frame.write_i32::<BigEndian>(16).unwrap();
+        frame.extend(&[0; 16]);
+        frame.write_u8(1).unwrap();
+        server.write_frame(&frame);
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(5)),
+                   Ok(ZkState::ConnectedReadOnly));
+        assert!(zk.is_read_only());
+
+        assert_eq!(zk.create("/node", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent),
+                   Err(ZkError::NotReadOnly));
+        assert_eq!(zk.set_data("/node", vec![], None).err(), Some(ZkError::NotReadOnly));
+        assert_eq!(zk.delete("/node", None), Err(ZkError::NotReadOnly));
+
+        // reads still go to the server, which never saw the writes
+        let zk = Arc::new(zk);
+        let zk1 = zk.clone();
+        let client = thread::spawn(move || zk1.get_data("/node", false));
+        reply_data(&server, 1, 0, b"data");
+        assert_eq!(client.join().unwrap().unwrap().0, b"data".to_vec());
+
+        let server = thread::spawn(move || {
+            let mut request = server.read_frame();
+            let xid = request.read_i32::<BigEndian>().unwrap();
+            assert_eq!(request.read_i32::<BigEndian>().unwrap(), -11);
+            server.reply(xid, 2, 0);
+        });
+        drop(zk);
+        server.join().unwrap();
+    }
 }
@@ -1,5 +1,7 @@
 use acl::*;
 use consts::{CreateMode, ZkError};
+use data::Stat;
+use paths::{make_path, split_path};
 use zookeeper::{ZkResult, ZooKeeper};
 use std::iter::once;
 use std::collections::VecDeque;
@@ -7,25 +9,39 @@ use std::collections::VecDeque;
 /// Extended ZooKeeper operations that are not needed for the "core."
 pub trait ZooKeeperExt {
     /// Ensure that `path` exists and create all potential paths leading up to it if it does not.
-    /// This operates in a manner similar to `mkdir -p`.
+    /// This operates in a manner similar to `mkdir -p`. Missing nodes are created as persistent
+    /// nodes with `Acl::open_unsafe`, and nodes created concurrently by other clients are fine.
+    /// A relative `path` fails with `ZkError::BadArguments`.
     fn ensure_path(&self, path: &str) -> ZkResult<()>;
 
+    /// Ensure that `path` exists like `ensure_path`, returning the `Stat` of the node at `path`.
+    ///
+    /// Nodes are created with `ZooKeeper::create_with_stat` (requires ZooKeeper 3.5), so the
+    /// `Stat` costs an extra round trip only if the node at `path` already existed.
+    fn ensure_path_with_stat(&self, path: &str) -> ZkResult<Stat>;
+
     /// Performs a breadth-first tree traversal of the tree starting at `path`,
     /// returning a list of fully prefixed child nodes.
     /// *NOTE*: This is not an atomic operation.
     fn get_children_recursive(&self, path: &str) -> ZkResult<Vec<String>>;
 
-    /// Deletes the node at `path` and all its children.
-    /// *NOTE*: This is not an atomic operation.
+    /// Deletes the node at `path` and all its children, leaves first.
+    /// *NOTE*: This is not an atomic operation. Descendants deleted concurrently are skipped, and
+    /// children created concurrently are deleted as well, retrying up to
+    /// `ZooKeeperBuilder::max_update_attempts` times before failing with `ZkError::NotEmpty`.
+    /// Fails with `ZkError::NoNode` if `path` does not exist.
     fn delete_recursive(&self, path: &str) -> ZkResult<()>;
 }
 
 impl ZooKeeperExt for ZooKeeper {
     fn ensure_path(&self, path: &str) -> ZkResult<()> {
         trace!("ensure_path {}", path);
-        for (i, _) in path.chars()
-                          .chain(once('/'))
-                          .enumerate()
+        if !path.starts_with('/') {
+            return Err(ZkError::BadArguments);
+        }
+        // Byte offsets, as `path` is sliced at them
+        for (i, _) in path.char_indices()
+                          .chain(once((path.len(), '/')))
                           .skip(1)
                           .filter(|c| c.1 == '/') {
             match self.create(&path[..i],
@@ -39,6 +55,26 @@ impl ZooKeeperExt for ZooKeeper {
         Ok(())
     }
 
+    fn ensure_path_with_stat(&self, path: &str) -> ZkResult<Stat> {
+        trace!("ensure_path_with_stat {}", path);
+        let (parent, _) = split_path(path);
+        try!(self.ensure_path(parent));
+        match self.create_with_stat(path,
+                                    vec![],
+                                    Acl::open_unsafe().clone(),
+                                    CreateMode::Persistent) {
+            Ok((_, stat)) => Ok(stat),
+            Err(ZkError::NodeExists) => {
+                match try!(self.exists(path, false)) {
+                    Some(stat) => Ok(stat),
+                    // deleted in the meantime
+                    None => Err(ZkError::NoNode),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     fn get_children_recursive(&self, path: &str) -> ZkResult<Vec<String>> {
         trace!("get_children_recursive {}", path);
         let mut queue: VecDeque<String> = VecDeque::new();
@@ -61,11 +97,39 @@ impl ZooKeeperExt for ZooKeeper {
 
     fn delete_recursive(&self, path: &str) -> ZkResult<()> {
         trace!("delete_recursive {}", path);
-        let children = self.get_children_recursive(path)?;
-        for child in children.iter().rev() {
-            self.delete(child, None)?;
-        }
+        // Only a missing `path` itself is an error, descendants may disappear concurrently
+        let children = self.get_children(path, false)?;
+        delete_tree(self, path, children)
+    }
+}
 
-        Ok(())
+/// Delete the subtree of `path` bottom-up, starting with its `children`. Nodes deleted
+/// concurrently are skipped. If children are created concurrently, deleting the parent fails with
+/// `ZkError::NotEmpty`, and the new children are deleted first, up to
+/// `ZooKeeperBuilder::max_update_attempts` times.
+fn delete_tree(zk: &ZooKeeper, path: &str, mut children: Vec<String>) -> ZkResult<()> {
+    let mut attempts = 1;
+    loop {
+        for child in children {
+            let child = make_path(path, &child);
+            match zk.get_children(&child, false) {
+                Ok(grandchildren) => delete_tree(zk, &child, grandchildren)?,
+                Err(ZkError::NoNode) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match zk.delete(path, None) {
+            Ok(()) | Err(ZkError::NoNode) => return Ok(()),
+            Err(ZkError::NotEmpty) if attempts < zk.max_update_attempts() => {
+                debug!("{} gained children while deleting it", path);
+                attempts += 1;
+                children = match zk.get_children(path, false) {
+                    Ok(children) => children,
+                    Err(ZkError::NoNode) => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+            }
+            Err(e) => return Err(e),
+        }
     }
 }
@@ -5,7 +5,14 @@ extern crate env_logger;
 extern crate zookeeper;
 
 mod test_zk;
+mod test_atomic;
 mod test_cache;
+mod test_chroot;
+mod test_config;
+mod test_discovery;
+mod test_leader;
+mod test_lock;
+mod test_multi;
 mod test_recursive;
 
 use std::io::{BufRead, BufReader, Write};
@@ -52,6 +59,11 @@ impl ZkCluster {
         self.process.stdin.as_mut().unwrap().write(b"k").unwrap();
     }
 
+    /// Restart the instance killed last.
+    fn restart_an_instance(&mut self) {
+        self.process.stdin.as_mut().unwrap().write_all(b"r").unwrap();
+    }
+
     fn shutdown(&mut self) {
         if !self.closed {
             self.process.stdin.as_mut().unwrap().write(b"q").unwrap();
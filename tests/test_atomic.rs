@@ -0,0 +1,43 @@
+use zookeeper::{Acl, CreateMode, ZooKeeper};
+use zookeeper::recipes::atomic::CachedAtomicValue;
+
+use ZkCluster;
+
+use std::sync::Arc;
+use std::time::Duration;
+use env_logger;
+
+
+#[test]
+fn cached_atomic_value_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = Arc::new(ZooKeeper::connect_no_watch(&cluster.connect_string,
+                                                  Duration::from_secs(30))
+                          .unwrap());
+
+    zk.create("/counter", vec![0], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let counter = CachedAtomicValue::new(zk.clone(), "/counter").unwrap();
+
+    // A single writer always has the current version cached, so each update sees the last one
+    for i in 0..10 {
+        let stat = counter.update(|data| {
+                              assert_eq!(data, &[i][..]);
+                              vec![data[0] + 1]
+                          })
+                          .unwrap();
+        assert_eq!(stat.version, i as i32 + 1);
+    }
+    assert_eq!(counter.get(), Some(vec![10]));
+    assert_eq!(zk.get_data("/counter", false).unwrap().0, vec![10]);
+
+    // A write of another client is noticed on the next update
+    zk.set_data("/counter", vec![20], None).unwrap();
+    let stat = counter.update(|data| vec![data[0] + 1]).unwrap();
+    assert_eq!(stat.version, 12);
+    assert_eq!(zk.get_data("/counter", false).unwrap().0, vec![21]);
+}
@@ -0,0 +1,74 @@
+use zookeeper::{Acl, CreateMode, WatchedEvent, WatchedEventType, ZooKeeper, ZooKeeperBuilder};
+
+use ZkCluster;
+
+use std::sync::mpsc;
+use std::time::Duration;
+use env_logger;
+
+#[test]
+fn chroot_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster, once at the root and once rooted at /test
+    let root = ZooKeeper::connect(&cluster.connect_string,
+                                  Duration::from_secs(30),
+                                  |_: WatchedEvent| {})
+                   .unwrap();
+    root.create("/test", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let zk = ZooKeeper::connect(&format!("{}/test", cluster.connect_string),
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    let path = zk.create("/node", vec![1], Acl::open_unsafe().clone(), CreateMode::Persistent)
+                 .unwrap();
+    assert_eq!(path, "/node");
+    assert_eq!(root.get_data("/test/node", false).unwrap().0, vec![1]);
+    assert_eq!(zk.get_children("/", false).unwrap(), vec!["node".to_owned()]);
+    assert!(zk.exists("/test/node", false).unwrap().is_none());
+
+    // Watch events are relative to the chroot as well
+    let (event_tx, event_rx) = mpsc::channel();
+    zk.get_data_w("/node", move |event: WatchedEvent| event_tx.send(event).unwrap()).unwrap();
+    zk.set_data("/node", vec![2], None).unwrap();
+    let event = event_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+    assert_eq!(event.path, Some("/node".to_owned()));
+    assert_eq!(root.get_data("/test/node", false).unwrap().0, vec![2]);
+
+    let (event_tx, event_rx) = mpsc::channel();
+    zk.exists_w("/", move |event: WatchedEvent| event_tx.send(event).unwrap()).unwrap();
+    root.set_data("/test", vec![3], None).unwrap();
+    let event = event_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(event.path, Some("/".to_owned()));
+
+    zk.delete("/node", None).unwrap();
+    assert!(root.exists("/test/node", false).unwrap().is_none());
+}
+
+#[test]
+fn builder_chroot_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let root = ZooKeeper::connect(&cluster.connect_string,
+                                  Duration::from_secs(30),
+                                  |_: WatchedEvent| {})
+                   .unwrap();
+    root.create("/built", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let zk = ZooKeeperBuilder::new()
+                 .connect_string(&cluster.connect_string)
+                 .chroot("/built")
+                 .read_only(true)
+                 .connect()
+                 .unwrap();
+
+    zk.create("/node", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    assert!(root.exists("/built/node", false).unwrap().is_some());
+}
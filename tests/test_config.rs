@@ -0,0 +1,66 @@
+use zookeeper::{Acl, CreateMode, ZkError, ZkResult, ZooKeeper};
+use zookeeper::recipes::config::{ConfigEvent, WatchedConfig};
+
+use ZkCluster;
+
+use std::str;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::Duration;
+use env_logger;
+
+fn decode_port(data: &[u8]) -> ZkResult<u16> {
+    str::from_utf8(data)
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .ok_or(ZkError::MarshallingError)
+}
+
+#[test]
+fn watched_config_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = Arc::new(ZooKeeper::connect_no_watch(&cluster.connect_string,
+                                                  Duration::from_secs(30))
+                          .unwrap());
+
+    zk.create("/port", b"8080".to_vec(), Acl::open_unsafe().clone(), CreateMode::Persistent)
+      .unwrap();
+    let config = WatchedConfig::new(zk.clone(), "/port", decode_port).unwrap();
+    assert_eq!(*config.current(), 8080);
+
+    let (events_tx, events_rx) = mpsc::channel();
+    config.on_change(move |event| events_tx.send(event).unwrap());
+
+    // A change is decoded into the new value
+    zk.set_data("/port", b"9090".to_vec(), None).unwrap();
+    match events_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ConfigEvent::Changed(port) => assert_eq!(*port, 9090),
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert_eq!(*config.current(), 9090);
+
+    // Data which can't be decoded keeps the previous value
+    zk.set_data("/port", b"not a port".to_vec(), None).unwrap();
+    match events_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ConfigEvent::Error(err) => assert_eq!(err, ZkError::MarshallingError),
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert_eq!(*config.current(), 9090);
+
+    // A later valid change is picked up again
+    zk.set_data("/port", b"7070".to_vec(), None).unwrap();
+    match events_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ConfigEvent::Changed(port) => assert_eq!(*port, 7070),
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert_eq!(*config.current(), 7070);
+
+    // A missing node can't be loaded
+    assert_eq!(WatchedConfig::new(zk.clone(), "/missing", decode_port).err(),
+               Some(ZkError::NoNode));
+}
@@ -0,0 +1,58 @@
+use zookeeper::ZooKeeper;
+use zookeeper::recipes::discovery::{ServiceDiscovery, ServiceInstance};
+
+use ZkCluster;
+
+use std::sync::Arc;
+use std::time::Duration;
+use env_logger;
+
+fn instance(id: &str, port: u16, payload: &str) -> ServiceInstance {
+    ServiceInstance {
+        name: "api".to_owned(),
+        id: id.to_owned(),
+        host: "10.0.0.1".to_owned(),
+        port,
+        payload: payload.to_owned(),
+    }
+}
+
+#[test]
+fn service_discovery_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk1 = Arc::new(ZooKeeper::connect_no_watch(&cluster.connect_string,
+                                                   Duration::from_secs(30))
+                           .unwrap());
+    let zk2 = Arc::new(ZooKeeper::connect_no_watch(&cluster.connect_string,
+                                                   Duration::from_secs(30))
+                           .unwrap());
+
+    let registry = ServiceDiscovery::new(zk1.clone());
+    let discovery = ServiceDiscovery::new(zk2.clone());
+    assert_eq!(discovery.query("api"), Ok(vec![]));
+
+    registry.register(instance("one", 8080, "{\"zone\":\"a\"}")).unwrap();
+    registry.register(instance("two", 8081, "")).unwrap();
+
+    let mut instances = discovery.query("api").unwrap();
+    instances.sort_by(|a, b| a.id.cmp(&b.id));
+    assert_eq!(instances,
+               vec![instance("one", 8080, "{\"zone\":\"a\"}"), instance("two", 8081, "")]);
+
+    // The cached instances are refreshed once an instance goes away
+    registry.unregister("api", "one").unwrap();
+    let mut remaining = discovery.query("api").unwrap();
+    for _ in 0..50 {
+        if remaining.len() == 1 {
+            break;
+        }
+        ::std::thread::sleep(Duration::from_millis(100));
+        remaining = discovery.query("api").unwrap();
+    }
+    assert_eq!(remaining, vec![instance("two", 8081, "")]);
+}
@@ -0,0 +1,72 @@
+use zookeeper::{WatchedEvent, ZooKeeper};
+use zookeeper::recipes::leader::LeaderLatch;
+
+use ZkCluster;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use env_logger;
+
+/// Wait until exactly one of the latches is the leader, and it is not `old`, returning its index.
+fn wait_for_leader(latches: &[LeaderLatch], old: Option<usize>) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let leaders: Vec<_> = (0..latches.len()).filter(|&i| latches[i].is_leader()).collect();
+        if leaders.len() == 1 && Some(leaders[0]) != old {
+            return leaders[0];
+        }
+        assert!(Instant::now() < deadline, "no single leader: {:?}", leaders);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn leader_latch_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Three clients take part in the election
+    let clients: Vec<_> = (0..3)
+                              .map(|_| {
+                                  Arc::new(ZooKeeper::connect_no_watch(&cluster.connect_string,
+                                                                       Duration::from_secs(30))
+                                               .unwrap())
+                              })
+                              .collect();
+    let latches: Vec<_> = clients.iter()
+                                 .map(|zk| LeaderLatch::new(zk.clone(), "/election"))
+                                 .collect();
+    for latch in &latches {
+        latch.start().unwrap();
+    }
+    let leader = wait_for_leader(&latches, None);
+    assert_eq!(leader, 0);
+
+    // Closing the session of the leader from another client expires it
+    let killer = ZooKeeper::connect_with_session(&cluster.connect_string,
+                                                 Duration::from_secs(30),
+                                                 clients[leader].session_id(),
+                                                 clients[leader].session_password(),
+                                                 |_: WatchedEvent| {})
+                     .unwrap();
+    killer.exists("/", false).unwrap();
+    drop(killer);
+
+    // The old leader only learns of the expiry once it reconnects
+    let new_leader = wait_for_leader(&latches, Some(leader));
+    // The old leader joins the election again once it has a new session, as the last one
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while clients[leader].get_children("/election", false).map(|c| c.len()).unwrap_or(0) != 3 {
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(wait_for_leader(&latches, None), new_leader);
+
+    // Closing the leader hands the leadership to the next latch
+    latches[new_leader].close().unwrap();
+    assert!(!latches[new_leader].is_leader());
+    wait_for_leader(&latches, Some(new_leader));
+}
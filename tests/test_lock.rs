@@ -0,0 +1,70 @@
+use zookeeper::ZooKeeper;
+use zookeeper::recipes::lock::ZkLock;
+
+use ZkCluster;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use env_logger;
+
+#[test]
+fn lock_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Three clients contend for the same lock
+    let holders = Arc::new(AtomicUsize::new(0));
+    let acquired = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..3)
+                              .map(|_| {
+                                  let connect_string = cluster.connect_string.clone();
+                                  let (holders, acquired) = (holders.clone(), acquired.clone());
+                                  thread::spawn(move || {
+                                      let zk = ZooKeeper::connect_no_watch(&connect_string,
+                                                                           Duration::from_secs(30))
+                                                   .unwrap();
+                                      let lock = ZkLock::new(Arc::new(zk), "/locks/shared");
+                                      for _ in 0..5 {
+                                          let _guard = lock.lock().unwrap();
+                                          assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+                                          thread::sleep(Duration::from_millis(10));
+                                          holders.fetch_sub(1, Ordering::SeqCst);
+                                          acquired.fetch_add(1, Ordering::SeqCst);
+                                      }
+                                  })
+                              })
+                              .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(acquired.load(Ordering::SeqCst), 15);
+}
+
+#[test]
+fn try_lock_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let connect = || {
+        Arc::new(ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                     .unwrap())
+    };
+    let (zk1, zk2) = (connect(), connect());
+    let lock1 = ZkLock::new(zk1, "/locks/try");
+    let lock2 = ZkLock::new(zk2.clone(), "/locks/try");
+
+    let guard = lock1.lock().unwrap();
+    assert!(lock2.try_lock(Duration::from_millis(200)).unwrap().is_none());
+    // The contender which gave up left no node behind
+    assert_eq!(zk2.get_children("/locks/try", false).unwrap().len(), 1);
+
+    drop(guard);
+    let guard = lock2.try_lock(Duration::from_secs(5)).unwrap().unwrap();
+    assert!(guard.node().starts_with("/locks/try/"));
+}
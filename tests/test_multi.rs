@@ -0,0 +1,330 @@
+use zookeeper::{Acl, CreateMode, Op, OpResult, WatchedEvent, ZkError, ZooKeeper};
+
+use ZkCluster;
+
+use std::time::Duration;
+use env_logger;
+
+#[test]
+fn multi_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    let results = zk.commit(&[Op::Create {
+                                  path: "/multi".to_owned(),
+                                  data: vec![1],
+                                  acl: Acl::open_unsafe().clone(),
+                                  mode: CreateMode::Persistent,
+                              },
+                              Op::Check {
+                                  path: "/multi".to_owned(),
+                                  version: Some(0),
+                              },
+                              Op::Delete {
+                                  path: "/multi".to_owned(),
+                                  version: None,
+                              }])
+                    .unwrap();
+    assert_eq!(results,
+               vec![OpResult::Create { path: "/multi".to_owned() },
+                    OpResult::Empty,
+                    OpResult::Empty]);
+    assert!(zk.exists("/multi", false).unwrap().is_none());
+
+    // An aborted transaction reports the failing operation's error and changes nothing
+    let aborted = zk.commit(&[Op::Create {
+                                  path: "/multi".to_owned(),
+                                  data: vec![],
+                                  acl: Acl::open_unsafe().clone(),
+                                  mode: CreateMode::Persistent,
+                              },
+                              Op::Delete {
+                                  path: "/missing".to_owned(),
+                                  version: None,
+                              }]);
+    assert_eq!(aborted, Err(ZkError::NoNode));
+    assert!(zk.exists("/multi", false).unwrap().is_none());
+}
+
+#[test]
+fn delete_returning_stat_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    zk.create("/deleted", vec![1, 2, 3], Acl::open_unsafe().clone(), CreateMode::Persistent)
+      .unwrap();
+    zk.set_data("/deleted", vec![4, 5], None).unwrap();
+    let before = zk.exists("/deleted", false).unwrap().unwrap();
+
+    assert_eq!(zk.delete_returning_stat("/deleted", Some(0)), Err(ZkError::BadVersion));
+    let stat = zk.delete_returning_stat("/deleted", Some(1)).unwrap();
+    assert_eq!(stat, before);
+    assert!(zk.exists("/deleted", false).unwrap().is_none());
+}
+
+#[test]
+fn delete_many_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    for path in &["/a", "/b", "/c", "/b/child"] {
+        zk.create(path, vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    }
+
+    // The transaction fails as a whole
+    assert_eq!(zk.delete_many(&["/a", "/b", "/c"], true), Err(ZkError::NotEmpty));
+    assert!(zk.exists("/a", false).unwrap().is_some());
+
+    // The others are deleted one by one
+    let results = zk.delete_many(&["/a", "/b", "/c"], false).unwrap();
+    assert_eq!(results, vec![Ok(()), Err(ZkError::NotEmpty), Ok(())]);
+    assert_eq!(zk.exists("/a", false), Ok(None));
+    assert!(zk.exists("/b", false).unwrap().is_some());
+    assert_eq!(zk.exists("/c", false), Ok(None));
+
+    assert_eq!(zk.delete_many(&["/b/child", "/b"], true), Ok(vec![Ok(()), Ok(())]));
+}
+
+#[test]
+fn multi_result_order_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    let results = zk.commit(&[Op::Create {
+                                  path: "/order".to_owned(),
+                                  data: vec![],
+                                  acl: Acl::open_unsafe().clone(),
+                                  mode: CreateMode::Persistent,
+                              },
+                              Op::SetData {
+                                  path: "/order".to_owned(),
+                                  data: vec![1],
+                                  version: Some(0),
+                              },
+                              Op::Check {
+                                  path: "/order".to_owned(),
+                                  version: Some(1),
+                              },
+                              Op::Create {
+                                  path: "/order/child".to_owned(),
+                                  data: vec![],
+                                  acl: Acl::open_unsafe().clone(),
+                                  mode: CreateMode::Persistent,
+                              },
+                              Op::Delete {
+                                  path: "/order/child".to_owned(),
+                                  version: None,
+                              }])
+                    .unwrap();
+
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0], OpResult::Create { path: "/order".to_owned() });
+    match results[1] {
+        OpResult::SetData { ref stat } => assert_eq!(stat.version, 1),
+        ref result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(results[2], OpResult::Empty);
+    assert_eq!(results[3], OpResult::Create { path: "/order/child".to_owned() });
+    assert_eq!(results[4], OpResult::Empty);
+}
+
+#[test]
+fn multi_sequential_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    let results = zk.commit(&[Op::Create {
+                                  path: "/seq-".to_owned(),
+                                  data: vec![],
+                                  acl: Acl::open_unsafe().clone(),
+                                  mode: CreateMode::PersistentSequential,
+                              }])
+                    .unwrap();
+
+    let path = match results[0] {
+        OpResult::Create { ref path } => path.clone(),
+        ref result => panic!("unexpected result {:?}", result),
+    };
+    assert!(path.starts_with("/seq-"));
+    let suffix = &path["/seq-".len()..];
+    assert_eq!(suffix.len(), 10);
+    assert!(suffix.chars().all(|c| c.is_ascii_digit()));
+    assert!(zk.exists(&path, false).unwrap().is_some());
+}
+
+#[test]
+fn multi_too_large_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    zk.create("/big", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    // each write fits into the server's default jute.maxbuffer, but not both together
+    let ops = (0..2).map(|_| {
+                        Op::SetData {
+                            path: "/big".to_owned(),
+                            data: vec![0; 600 * 1024],
+                            version: None,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+    assert_eq!(zk.commit(&ops), Err(ZkError::BadArguments));
+
+    // the request was not sent, so the connection is still usable
+    assert_eq!(zk.exists("/big", false).unwrap().unwrap().version, 0);
+    zk.commit(&ops[..1]).unwrap();
+    assert_eq!(zk.exists("/big", false).unwrap().unwrap().version, 1);
+}
+
+#[test]
+fn multi_read_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    zk.create("/siblings", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    for i in 0..3u8 {
+        zk.create(&format!("/siblings/{}", i),
+                  vec![i],
+                  Acl::open_unsafe().clone(),
+                  CreateMode::Persistent)
+          .unwrap();
+    }
+
+    let mut ops = (0..3)
+                      .map(|i| Op::GetData { path: format!("/siblings/{}", i) })
+                      .collect::<Vec<_>>();
+    ops.push(Op::GetChildren { path: "/siblings".to_owned() });
+    let results = zk.commit_read(&ops).unwrap();
+    for (i, result) in results[..3].iter().enumerate() {
+        match *result {
+            OpResult::GetData { ref data, ref stat } => {
+                assert_eq!((data.clone(), stat.data_length), (vec![i as u8], 1))
+            }
+            ref result => panic!("unexpected result {:?}", result),
+        }
+    }
+    match results[3] {
+        OpResult::GetChildren { ref children } => {
+            let mut children = children.clone();
+            children.sort();
+            assert_eq!(children, vec!["0", "1", "2"]);
+        }
+        ref result => panic!("unexpected result {:?}", result),
+    }
+
+    // a missing node fails the read
+    assert_eq!(zk.commit_read(&[Op::GetData { path: "/siblings/missing".to_owned() }]),
+               Err(ZkError::NoNode));
+
+    // reads and writes don't mix
+    let mixed = [Op::GetData { path: "/siblings/0".to_owned() },
+                 Op::Delete {
+                     path: "/siblings/0".to_owned(),
+                     version: None,
+                 }];
+    assert_eq!(zk.commit_read(&mixed), Err(ZkError::BadArguments));
+    assert_eq!(zk.commit(&mixed), Err(ZkError::BadArguments));
+    assert!(zk.exists("/siblings/0", false).unwrap().is_some());
+}
+
+#[test]
+fn transaction_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    let transaction = zk.transaction()
+                        .create("/transaction", vec![1])
+                        .check("/transaction", Some(0))
+                        .set_data("/transaction", vec![2], None)
+                        .delete("/transaction", Some(1));
+    assert_eq!(transaction.ops().len(), 4);
+    let results = transaction.commit().unwrap();
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0], OpResult::Create { path: "/transaction".to_owned() });
+    assert_eq!(results[1], OpResult::Empty);
+    match results[2] {
+        OpResult::SetData { ref stat } => assert_eq!(stat.version, 1),
+        ref result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(results[3], OpResult::Empty);
+    assert!(zk.exists("/transaction", false).unwrap().is_none());
+
+    // An aborted transaction changes nothing
+    let aborted = zk.transaction()
+                    .create("/transaction", vec![])
+                    .delete("/missing", None)
+                    .commit();
+    assert_eq!(aborted, Err(ZkError::NoNode));
+    assert!(zk.exists("/transaction", false).unwrap().is_none());
+}
+
+#[test]
+fn compare_and_set_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    zk.create("/cas", vec![1], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let stat = zk.compare_and_set("/cas", 0, vec![2]).unwrap();
+    assert_eq!(stat.version, 1);
+    assert_eq!(zk.get_data("/cas", false).unwrap(), (vec![2], stat.clone()));
+
+    // A stale version leaves the node unchanged
+    assert_eq!(zk.compare_and_set("/cas", 0, vec![3]), Err(ZkError::BadVersion));
+    assert_eq!(zk.get_data("/cas", false).unwrap(), (vec![2], stat));
+
+    assert_eq!(zk.compare_and_set("/missing", 0, vec![]), Err(ZkError::NoNode));
+}
@@ -1,8 +1,10 @@
-use zookeeper::{WatchedEvent, ZkError, ZooKeeper, ZooKeeperExt};
+use zookeeper::{Acl, CreateMode, WatchedEvent, ZkError, ZooKeeper, ZooKeeperExt};
 
 use ZkCluster;
 
 use std::iter::once;
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::Duration;
 
 #[test]
@@ -113,6 +115,46 @@ fn delete_recursive_test() {
     assert!(zk.exists("/root/b", false).unwrap().is_some());
 }
 
+#[test]
+fn delete_recursive_concurrent_child_test() {
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = Arc::new(ZooKeeper::connect(
+        &cluster.connect_string,
+        Duration::from_secs(30),
+        |_: WatchedEvent| {},
+    ).unwrap());
+
+    for path in ["/tree/a/1", "/tree/a/2", "/tree/b/1"].iter() {
+        zk.ensure_path(path).unwrap();
+    }
+
+    // A child of the root appears once the walk has started deleting leaves
+    let (late_tx, late_rx) = mpsc::channel();
+    let zk1 = zk.clone();
+    zk.exists_w("/tree/a/1", move |_: WatchedEvent| {
+        let zk1 = zk1.clone();
+        let late_tx = late_tx.clone();
+        thread::spawn(move || {
+            let created = zk1.create("/tree/late",
+                                     vec![],
+                                     Acl::open_unsafe().clone(),
+                                     CreateMode::Persistent);
+            late_tx.send(created).unwrap();
+        });
+    }).unwrap();
+
+    zk.delete_recursive("/tree").unwrap();
+    match late_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+        // created before the root was deleted and deleted by the walk, or created too late
+        Ok(_) | Err(ZkError::NoNode) => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+    }
+    assert!(zk.exists("/tree", false).unwrap().is_none());
+}
+
 #[test]
 fn delete_recursive_invalid_path_test() {
     // Create a test cluster
@@ -128,3 +170,51 @@ fn delete_recursive_invalid_path_test() {
     let result = zk.delete_recursive("/bad");
     assert_eq!(result, Err(ZkError::NoNode))
 }
+
+#[test]
+fn ensure_path_concurrent_test() {
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Two clients race to create the same path
+    let clients: Vec<_> = (0..2).map(|_| {
+        let connect_string = cluster.connect_string.clone();
+        thread::spawn(move || {
+            let zk = ZooKeeper::connect(
+                &connect_string,
+                Duration::from_secs(30),
+                |_: WatchedEvent| {},
+            ).unwrap();
+            zk.ensure_path("/x/y/z")
+        })
+    }).collect();
+    for client in clients {
+        assert_eq!(client.join().unwrap(), Ok(()));
+    }
+
+    let zk = ZooKeeper::connect(
+        &cluster.connect_string,
+        Duration::from_secs(30),
+        |_: WatchedEvent| {},
+    ).unwrap();
+    assert!(zk.exists("/x/y/z", false).unwrap().is_some());
+    assert_eq!(zk.ensure_path("x/y"), Err(ZkError::BadArguments));
+}
+
+#[test]
+fn ensure_path_non_ascii_test() {
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let zk = ZooKeeper::connect(
+        &cluster.connect_string,
+        Duration::from_secs(30),
+        |_: WatchedEvent| {},
+    ).unwrap();
+
+    // Multi-byte characters before a separator
+    zk.ensure_path("/ünï/cödé/z").unwrap();
+    assert!(zk.exists("/ünï", false).unwrap().is_some());
+    assert!(zk.exists("/ünï/cödé", false).unwrap().is_some());
+    assert!(zk.exists("/ünï/cödé/z", false).unwrap().is_some());
+}
@@ -1,11 +1,17 @@
-use zookeeper::{Acl, CreateMode, WatchedEvent, ZooKeeper};
-use zookeeper::KeeperState;
+use zookeeper::{Acl, AddWatchMode, ConnectionState, CreateMode, CreateOutcome, Permission,
+                WatchedEvent, ZkError, ZooKeeper};
+use zookeeper::{KeeperState, NoopWatcher, ReconnectEvent, ReconnectOutcome, ReconnectPolicy,
+                WatchedEventType, WatcherType, WatchType};
+use zookeeper::{sequence_number, OpCode, QuorumConfig, QuorumRole, ServerRole, StringDecoding,
+                ZooKeeperBuilder, ZooKeeperExt};
 
 use ZkCluster;
 
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::mem;
+use std::net::ToSocketAddrs;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::thread;
 use env_logger;
 
@@ -84,6 +90,7 @@ fn zk_test() {
 
     // After closing the client all operations return Err
     zk.close().unwrap();
+    assert_eq!(zk.connection_state(), ConnectionState::Closed);
 
     let exists = zk.exists("/test", true);
     assert!(exists.is_err());
@@ -92,3 +99,1296 @@ fn zk_test() {
     // Close the whole cluster
     cluster.shutdown();
 }
+
+#[test]
+fn get_children_with_stats_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    zk.create("/stats", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    for (i, name) in ["a", "b", "c", "d"].iter().enumerate() {
+        zk.create(&format!("/stats/{}", name),
+                  vec![0; i],
+                  Acl::open_unsafe().clone(),
+                  CreateMode::Persistent)
+          .unwrap();
+    }
+
+    let mut children = zk.get_children_with_stats("/stats").unwrap();
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let names: Vec<&str> = children.iter().map(|c| c.0.as_str()).collect();
+    assert_eq!(names, vec!["a", "b", "c", "d"]);
+    for (name, stat) in children {
+        let expected = zk.exists(&format!("/stats/{}", name), false).unwrap();
+        assert_eq!(Some(stat), expected);
+    }
+}
+
+#[test]
+fn set_default_watcher_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let (old_tx, old_rx) = mpsc::channel();
+    let old_tx = Mutex::new(old_tx);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                move |event: WatchedEvent| {
+                                    old_tx.lock().unwrap().send(event).unwrap();
+                                })
+                 .unwrap();
+
+    let (new_tx, new_rx) = mpsc::channel();
+    let new_tx = Mutex::new(new_tx);
+    zk.set_default_watcher(move |event: WatchedEvent| {
+        new_tx.lock().unwrap().send(event).unwrap();
+    });
+
+    assert!(zk.exists("/watched", true).unwrap().is_none());
+    zk.create("/watched", vec![], Acl::open_unsafe().clone(), CreateMode::Ephemeral).unwrap();
+
+    let event = new_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(event.event_type, WatchedEventType::NodeCreated);
+    assert_eq!(event.path, Some("/watched".to_owned()));
+    assert!(old_rx.try_recv().is_err());
+}
+
+#[test]
+fn create_with_token_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    let token = b"7c0f4a39";
+    let created = zk.create_with_token("/owned",
+                                       vec![1, 2],
+                                       token,
+                                       Acl::open_unsafe().clone(),
+                                       CreateMode::Ephemeral);
+    assert_eq!(created, Ok(CreateOutcome::Created("/owned".to_owned())));
+    assert_eq!(zk.get_data("/owned", false).unwrap().0, b"7c0f4a39\x01\x02".to_vec());
+
+    // A retry after a lost response finds the node created by the first attempt
+    let retried = zk.create_with_token("/owned",
+                                       vec![1, 2],
+                                       token,
+                                       Acl::open_unsafe().clone(),
+                                       CreateMode::Ephemeral);
+    assert_eq!(retried, Ok(CreateOutcome::AlreadyOwned("/owned".to_owned())));
+
+    let other = zk.create_with_token("/owned",
+                                     vec![1, 2],
+                                     b"d31e88b0",
+                                     Acl::open_unsafe().clone(),
+                                     CreateMode::Ephemeral);
+    assert_eq!(other, Ok(CreateOutcome::Conflict));
+}
+
+#[test]
+fn max_path_length_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeperBuilder::new()
+                 .connect_string(&cluster.connect_string)
+                 .max_path_length(16)
+                 .connect()
+                 .unwrap();
+
+    let create = zk.create("/a-path-longer-than-16",
+                           vec![],
+                           Acl::open_unsafe().clone(),
+                           CreateMode::Ephemeral);
+    assert_eq!(create, Err(ZkError::BadArguments));
+
+    let create = zk.create("/short", vec![], Acl::open_unsafe().clone(), CreateMode::Ephemeral);
+    assert_eq!(create, Ok("/short".to_owned()));
+}
+
+#[test]
+fn create_protected_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    zk.create("/locks", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let first = zk.create_protected("/locks/lock-",
+                                    vec![],
+                                    Acl::open_unsafe().clone(),
+                                    CreateMode::EphemeralSequential)
+                  .unwrap();
+    let second = zk.create_protected("/locks/lock-",
+                                     vec![],
+                                     Acl::open_unsafe().clone(),
+                                     CreateMode::EphemeralSequential)
+                   .unwrap();
+
+    assert!(first.starts_with("/locks/_c_"));
+    assert_eq!(sequence_number(&first), Some(0));
+    assert_eq!(sequence_number(&second), Some(1));
+
+    let mut children = zk.get_children("/locks", false).unwrap();
+    children.sort();
+    let mut expected = vec![first[7..].to_owned(), second[7..].to_owned()];
+    expected.sort();
+    assert_eq!(children, expected);
+}
+
+#[test]
+fn consistent_view_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(3);
+
+    // Connect to the test cluster
+    let writer = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                     .unwrap();
+    let reader = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                     .unwrap();
+
+    writer.create("/view", vec![1], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let stat = writer.set_data("/view", vec![2], None).unwrap();
+
+    let view = reader.consistent_view(stat.mzxid).unwrap();
+    assert!(view.zxid() >= stat.mzxid);
+    let (data, read_stat) = view.get_data("/view").unwrap();
+    assert_eq!(data, vec![2]);
+    assert_eq!(read_stat.mzxid, stat.mzxid);
+}
+
+#[test]
+fn connected_server_role_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(3);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    zk.exists("/", false).unwrap();
+
+    // The test cluster has no observers
+    let role = zk.connected_server_role().unwrap();
+    assert!(role == ServerRole::Leader || role == ServerRole::Follower);
+    assert_eq!(zk.connected_server_role(), Ok(role));
+}
+
+#[test]
+fn sync_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(3);
+
+    // Connect the writer and the reader to different servers
+    let hosts: Vec<&str> = cluster.connect_string.split(',').collect();
+    let writer = ZooKeeper::connect_no_watch(hosts[0], Duration::from_secs(30)).unwrap();
+    let reader = ZooKeeper::connect_no_watch(hosts[1], Duration::from_secs(30)).unwrap();
+
+    writer.create("/synced", vec![1], Acl::open_unsafe().clone(), CreateMode::Persistent)
+          .unwrap();
+    writer.set_data("/synced", vec![2], None).unwrap();
+
+    assert_eq!(reader.sync("/synced"), Ok("/synced".to_owned()));
+    assert_eq!(reader.get_data("/synced", false).unwrap().0, vec![2]);
+}
+
+#[test]
+fn get_all_children_number_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    let paths = ["/count", "/count/a", "/count/a/1", "/count/a/2", "/count/b", "/count/b/1"];
+    for path in &paths {
+        zk.create(path, vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    }
+    assert_eq!(zk.get_all_children_number("/count"), Ok(paths.len() as i32 - 1));
+    assert_eq!(zk.get_all_children_number("/count/b/1"), Ok(0));
+    assert_eq!(zk.get_all_children_number("/missing"), Err(ZkError::NoNode));
+}
+
+#[test]
+fn get_ephemerals_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    let other = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                    .unwrap();
+
+    zk.create("/eph", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    for name in &["/eph/a", "/eph/b"] {
+        zk.create(name, vec![], Acl::open_unsafe().clone(), CreateMode::Ephemeral).unwrap();
+    }
+    // owned by another session
+    other.create("/eph/c", vec![], Acl::open_unsafe().clone(), CreateMode::Ephemeral).unwrap();
+
+    let mut ephemerals = zk.get_ephemerals("/eph").unwrap();
+    ephemerals.sort();
+    assert_eq!(ephemerals, vec!["/eph/a".to_owned(), "/eph/b".to_owned()]);
+    assert_eq!(zk.get_ephemerals("").unwrap().len(), 2);
+    assert_eq!(other.get_ephemerals("/eph/a"), Ok(vec![]));
+}
+
+#[test]
+fn container_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    let path = zk.create("/container", vec![], Acl::open_unsafe().clone(), CreateMode::Container)
+                 .unwrap();
+    assert_eq!(path, "/container");
+    zk.create("/container/child", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent)
+      .unwrap();
+    zk.delete("/container/child", None).unwrap();
+
+    // the test cluster checks for empty containers every 100ms
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while zk.exists("/container", false).unwrap().is_some() {
+        assert!(Instant::now() < deadline, "the container was not removed");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn no_watch_after_close_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    let other = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                    .unwrap();
+
+    zk.create("/closing", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let fired_after_close = Arc::new(AtomicBool::new(false));
+    let (closed1, fired_after_close1) = (closed.clone(), fired_after_close.clone());
+    zk.exists_w("/closing", move |_| {
+          // slow enough for close to be called while this runs
+          thread::sleep(Duration::from_millis(100));
+          if closed1.load(Ordering::SeqCst) {
+              fired_after_close1.store(true, Ordering::SeqCst);
+          }
+      })
+      .unwrap();
+
+    other.set_data("/closing", vec![1], None).unwrap();
+    zk.close().unwrap();
+    closed.store(true, Ordering::SeqCst);
+
+    thread::sleep(Duration::from_millis(500));
+    assert!(!fired_after_close.load(Ordering::SeqCst));
+}
+
+#[test]
+fn delete_or_report_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    zk.create("/parent", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    zk.create("/parent/a", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    zk.create("/parent/b", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    let mut children = zk.delete_or_report("/parent", None).unwrap().unwrap_err();
+    children.sort();
+    assert_eq!(children, vec!["a".to_owned(), "b".to_owned()]);
+
+    assert_eq!(zk.delete_or_report("/parent/a", None), Ok(Ok(())));
+    assert_eq!(zk.delete_or_report("/parent/a", None), Err(ZkError::NoNode));
+}
+
+#[test]
+fn last_error_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    assert_eq!(zk.last_error(), None);
+
+    assert_eq!(zk.get_data("/missing", false), Err(ZkError::NoNode));
+    let error = zk.last_error().unwrap();
+    assert_eq!(error.kind(), ZkError::NoNode);
+    assert_eq!(error.opcode, OpCode::GetData);
+    assert_eq!(error.path, Some("/missing".to_owned()));
+}
+
+#[test]
+fn rate_limit_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeperBuilder::new()
+                 .connect_string(&cluster.connect_string)
+                 .rate_limit(20)
+                 .connect()
+                 .unwrap();
+
+    // 20 requests pass as a burst, the other 20 are paced to 20 per second
+    let start = Instant::now();
+    for _ in 0..40 {
+        zk.exists("/", false).unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(900));
+}
+
+#[test]
+fn persistent_watch_remove_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let (default_tx, default_rx) = mpsc::channel();
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                move |event: WatchedEvent| {
+                                    if event.path.is_some() {
+                                        default_tx.send(event).unwrap();
+                                    }
+                                })
+                 .unwrap();
+
+    zk.create("/persistent", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    // Stop watching after the second event
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let count = AtomicUsize::new(0);
+    zk.add_persistent_watch("/persistent", move |event, ctx| {
+          tx.lock().unwrap().send(event).unwrap();
+          if count.fetch_add(1, Ordering::SeqCst) == 1 {
+              ctx.remove_watch();
+          }
+      })
+      .unwrap();
+
+    for i in 0..3 {
+        zk.set_data("/persistent", vec![i], None).unwrap();
+    }
+
+    for _ in 0..2 {
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+        assert_eq!(event.path, Some("/persistent".to_owned()));
+    }
+    assert!(rx.recv_timeout(Duration::from_secs(1)).is_err());
+    assert!(default_rx.try_recv().is_err());
+}
+
+#[test]
+fn recursive_watch_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    zk.create("/tree", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    zk.add_watch("/tree", AddWatchMode::PersistentRecursive, move |event, _| {
+          tx.lock().unwrap().send(event).unwrap();
+      })
+      .unwrap();
+
+    for child in &["/tree/a", "/tree/b", "/tree/a/c"] {
+        zk.create(child, vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    }
+    zk.set_data("/tree/a/c", vec![1], None).unwrap();
+
+    let mut events = Vec::new();
+    for _ in 0..4 {
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        events.push((event.event_type, event.path.unwrap()));
+    }
+    assert_eq!(events,
+               vec![(WatchedEventType::NodeCreated, "/tree/a".to_owned()),
+                    (WatchedEventType::NodeCreated, "/tree/b".to_owned()),
+                    (WatchedEventType::NodeCreated, "/tree/a/c".to_owned()),
+                    (WatchedEventType::NodeDataChanged, "/tree/a/c".to_owned())]);
+    // no child events for a recursive watch
+    assert!(rx.recv_timeout(Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn remove_watches_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let (default_tx, default_rx) = mpsc::channel();
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                move |event: WatchedEvent| {
+                                    if event.path.is_some() {
+                                        default_tx.send(event).unwrap();
+                                    }
+                                })
+                 .unwrap();
+    zk.create("/unwatched", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    zk.get_data_w("/unwatched", move |event: WatchedEvent| {
+          tx.lock().unwrap().send(event).unwrap();
+      })
+      .unwrap();
+    zk.remove_watches("/unwatched", WatcherType::Data).unwrap();
+    assert_eq!(zk.remove_watches("/unwatched", WatcherType::Data), Err(ZkError::NoWatcher));
+
+    zk.set_data("/unwatched", vec![1], None).unwrap();
+    // the watcher was dropped without being called, and the server sent no event
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)).err(),
+               Some(mpsc::RecvTimeoutError::Disconnected));
+    assert!(default_rx.recv_timeout(Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn audit_acls_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    let nodes = vec![("/audit", Acl::open_unsafe()),
+                     ("/audit/open", Acl::open_unsafe()),
+                     ("/audit/open/read", Acl::read_unsafe()),
+                     ("/audit/read", Acl::read_unsafe())];
+    for &(path, acl) in &nodes {
+        zk.create(path, vec![], acl.clone(), CreateMode::Persistent).unwrap();
+    }
+
+    let mut audit = zk.audit_acls("/audit").unwrap();
+    assert_eq!(audit[0].0, "/audit");
+    audit.sort_by(|a, b| a.0.cmp(&b.0));
+    let expected = nodes.iter()
+                        .map(|&(path, acl)| (path.to_owned(), acl.clone()))
+                        .collect::<Vec<_>>();
+    assert_eq!(audit, expected);
+
+    // the nodes anyone can write to
+    let writable = audit.iter()
+                        .filter(|(_, acl)| acl.iter().any(|acl| acl.perms.can(Permission::WRITE)))
+                        .map(|(path, _)| path.as_str())
+                        .collect::<Vec<_>>();
+    assert_eq!(writable, vec!["/audit", "/audit/open"]);
+
+    assert_eq!(zk.audit_acls("/missing"), Err(ZkError::NoNode));
+}
+
+#[test]
+fn async_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    zk.create("/async", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    let creates = (0..100)
+                      .map(|i| {
+                          zk.create_async(&format!("/async/{}", i),
+                                          vec![i as u8],
+                                          Acl::open_unsafe().clone(),
+                                          CreateMode::Persistent)
+                      })
+                      .collect::<Vec<_>>();
+    for (i, create) in creates.into_iter().enumerate() {
+        assert_eq!(create.wait(), Ok(format!("/async/{}", i)));
+    }
+    assert_eq!(zk.get_children("/async", false).unwrap().len(), 100);
+
+    let stat = zk.set_data_async("/async/7", vec![70], Some(0)).wait().unwrap();
+    assert_eq!(stat.version, 1);
+    assert_eq!(zk.get_data_async("/async/7", false).wait().unwrap().0, vec![70]);
+    assert_eq!(zk.get_data_async("/async/missing", false).wait(), Err(ZkError::NoNode));
+}
+
+#[test]
+fn reconfig_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    let (config, _) = zk.get_config(false).unwrap();
+    let version = QuorumConfig::parse(&config).unwrap().version;
+    let config = String::from_utf8(config).unwrap();
+    // a bulk reconfiguration to the current members changes nothing
+    let members = config.lines()
+                        .filter(|line| line.starts_with("server."))
+                        .collect::<Vec<_>>()
+                        .join(",");
+    match zk.reconfig(None, None, Some(&members), version) {
+        Ok((new_config, _)) => {
+            let new_config = String::from_utf8(new_config).unwrap();
+            assert!(members.split(',').all(|member| new_config.contains(member)));
+        }
+        // unless enabled on the server
+        Err(err) => assert_eq!(err, ZkError::ReconfigDisabled),
+    }
+    // the connection is still usable
+    assert!(zk.exists("/zookeeper/config", false).unwrap().is_some());
+}
+
+#[test]
+fn create_ttl_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    let ttl = Duration::from_secs(3600);
+
+    let path = zk.create_ttl("/ttl",
+                             vec![1],
+                             Acl::open_unsafe().clone(),
+                             CreateMode::PersistentWithTtl,
+                             ttl)
+                 .unwrap();
+    assert_eq!(path, "/ttl");
+    // the server keeps the TTL in the ephemeral owner, tagged with 0xff in the high byte
+    let stat = zk.exists("/ttl", false).unwrap().unwrap();
+    assert_eq!(stat.ephemeral_owner as u64, 0xff00_0000_0000_0000 | 3_600_000);
+
+    let path = zk.create_ttl("/ttl/seq-",
+                             vec![],
+                             Acl::open_unsafe().clone(),
+                             CreateMode::PersistentSequentialWithTtl,
+                             ttl)
+                 .unwrap();
+    assert_eq!(sequence_number(&path), Some(0));
+
+    let mode = CreateMode::Persistent;
+    assert_eq!(zk.create_ttl("/other", vec![], Acl::open_unsafe().clone(), mode, ttl),
+               Err(ZkError::BadArguments));
+}
+
+#[test]
+fn create_with_stat_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    let (path, stat) = zk.create_with_stat("/stat",
+                                           vec![1, 2, 3],
+                                           Acl::open_unsafe().clone(),
+                                           CreateMode::Persistent)
+                         .unwrap();
+    assert_eq!(path, "/stat");
+    assert_eq!(stat.data_length, 3);
+    assert_eq!(Some(stat), zk.exists("/stat", false).unwrap());
+
+    let (path, stat) = zk.create_protected_with_stat("/stat/seq-",
+                                                     vec![],
+                                                     Acl::open_unsafe().clone(),
+                                                     CreateMode::EphemeralSequential)
+                         .unwrap();
+    assert_eq!(sequence_number(&path), Some(0));
+    assert!(stat.ephemeral_owner != 0);
+    assert_eq!(Some(stat), zk.exists(&path, false).unwrap());
+
+    let stat = zk.ensure_path_with_stat("/stat/a/b").unwrap();
+    assert_eq!(Some(stat.clone()), zk.exists("/stat/a/b", false).unwrap());
+    assert_eq!(zk.ensure_path_with_stat("/stat/a/b"), Ok(stat));
+}
+
+#[test]
+fn noop_watcher_test() {
+    let _ = env_logger::try_init();
+
+    assert_eq!(mem::size_of::<NoopWatcher>(), 0);
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string, Duration::from_secs(30), NoopWatcher)
+                 .unwrap();
+    assert!(zk.exists("/", false).unwrap().is_some());
+
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    assert!(zk.exists("/", false).unwrap().is_some());
+}
+
+#[test]
+fn acl_version_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    zk.create("/acl", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    assert_eq!(zk.acl_version("/acl"), Ok(0));
+
+    zk.set_acl("/acl", Acl::read_unsafe().clone(), Some(0)).unwrap();
+    let (acl, stat) = zk.get_acl("/acl").unwrap();
+    assert_eq!(zk.acl_version("/acl"), Ok(stat.aversion));
+    assert_eq!(stat.aversion, 1);
+    assert_eq!(zk.get_acl_only("/acl"), Ok(acl));
+
+    assert_eq!(zk.acl_version("/missing"), Err(ZkError::NoNode));
+}
+
+#[test]
+fn update_acl_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster
+    let zk = Arc::new(ZooKeeper::connect(&cluster.connect_string,
+                                         Duration::from_secs(30),
+                                         |_: WatchedEvent| {})
+                          .unwrap());
+
+    zk.create("/acl", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    let threads: Vec<_> = ["10.0.0.1", "10.0.0.2"]
+                              .iter()
+                              .map(|ip| {
+                                  let zk = zk.clone();
+                                  thread::spawn(move || {
+                                      zk.update_acl("/acl", |acl| {
+                                            let mut acl = acl.to_vec();
+                                            acl.push(Acl::new(Permission::READ, "ip", ip));
+                                            acl
+                                        })
+                                        .unwrap()
+                                  })
+                              })
+                              .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let (acl, stat) = zk.get_acl("/acl").unwrap();
+    assert_eq!(stat.aversion, 2);
+    assert_eq!(acl.len(), 3);
+    assert!(acl.contains(&Acl::new(Permission::READ, "ip", "10.0.0.1")));
+    assert!(acl.contains(&Acl::new(Permission::READ, "ip", "10.0.0.2")));
+}
+
+#[test]
+fn update_data_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // Connect to the test cluster, retrying as often as it takes
+    let zk = Arc::new(ZooKeeperBuilder::new()
+                          .connect_string(&cluster.connect_string)
+                          .max_update_attempts(usize::MAX)
+                          .connect()
+                          .unwrap());
+
+    zk.create("/counter", b"0".to_vec(), Acl::open_unsafe().clone(), CreateMode::Persistent)
+      .unwrap();
+
+    let threads: Vec<_> = (0..2)
+                              .map(|_| {
+                                  let zk = zk.clone();
+                                  thread::spawn(move || {
+                                      for _ in 0..50 {
+                                          zk.update_data("/counter", |data| {
+                                                let count: u32 = String::from_utf8(data)
+                                                                     .unwrap()
+                                                                     .parse()
+                                                                     .unwrap();
+                                                (count + 1).to_string().into_bytes()
+                                            })
+                                            .unwrap();
+                                      }
+                                  })
+                              })
+                              .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let (data, stat) = zk.get_data("/counter", false).unwrap();
+    assert_eq!(data, b"100".to_vec());
+    assert_eq!(stat.version, 100);
+    assert_eq!(zk.update_data("/missing", |data| data), Err(ZkError::NoNode));
+}
+
+#[test]
+fn session_timeout_and_id_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let connect = |timeout| {
+        let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, timeout).unwrap();
+        // wait for the session
+        zk.exists("/", false).unwrap();
+        zk
+    };
+    // The server clamps the requested timeouts into its bounds
+    let min = connect(Duration::from_millis(1)).session_timeout();
+    let max = connect(Duration::from_secs(3600)).session_timeout();
+
+    let zk = connect(Duration::from_secs(5));
+    let timeout = zk.session_timeout();
+    assert_eq!(zk.negotiated_session_timeout(), Some(timeout));
+    assert!(min <= timeout && timeout <= max, "{:?} not in {:?}..{:?}", timeout, min, max);
+    assert!(zk.session_id() != 0);
+    assert!(zk.session_id() != connect(Duration::from_secs(5)).session_id());
+}
+
+#[test]
+fn resume_session_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    zk.create("/resumed", vec![], Acl::open_unsafe().clone(), CreateMode::Ephemeral).unwrap();
+    let (session_id, passwd) = (zk.session_id(), zk.session_password());
+    zk.detach();
+
+    let zk = ZooKeeper::connect_with_session(&cluster.connect_string,
+                                             Duration::from_secs(30),
+                                             session_id,
+                                             passwd,
+                                             |_: WatchedEvent| {})
+                 .unwrap();
+    let stat = zk.exists("/resumed", false).unwrap().unwrap();
+    assert_eq!(stat.ephemeral_owner, session_id);
+    assert_eq!(zk.session_id(), session_id);
+}
+
+#[test]
+fn reconnect_policy_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let mut cluster = ZkCluster::start(1);
+
+    let (state_tx, state_rx) = mpsc::channel();
+    let state_tx = Mutex::new(state_tx);
+    let zk = ZooKeeper::connect_with_policy(&cluster.connect_string,
+                                            Duration::from_secs(30),
+                                            move |event: WatchedEvent| {
+                                                state_tx.lock()
+                                                        .unwrap()
+                                                        .send(event.keeper_state)
+                                                        .unwrap();
+                                            },
+                                            ReconnectPolicy::ExponentialBackoff {
+                                                base: Duration::from_millis(100),
+                                                max: Duration::from_secs(2),
+                                                jitter: false,
+                                            })
+                 .unwrap();
+    zk.exists("/", false).unwrap();
+    let (started_tx, started_rx) = mpsc::channel();
+    let started_tx = Mutex::new(started_tx);
+    zk.add_reconnect_listener(move |event: ReconnectEvent| {
+        if event.outcome == ReconnectOutcome::Started {
+            started_tx.lock().unwrap().send(Instant::now()).unwrap();
+        }
+    });
+
+    // The whole ensemble goes down, so the client retries ever more slowly
+    cluster.kill_an_instance();
+    let started: Vec<_> = (0..4)
+                              .map(|_| started_rx.recv_timeout(Duration::from_secs(10)).unwrap())
+                              .collect();
+    let intervals: Vec<_> = started.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    assert!(intervals[0] < intervals[1] && intervals[1] < intervals[2], "{:?}", intervals);
+
+    cluster.restart_an_instance();
+    loop {
+        let state = state_rx.recv_timeout(Duration::from_secs(30)).unwrap();
+        if state == KeeperState::SyncConnected {
+            break;
+        }
+    }
+    zk.exists("/", false).unwrap();
+}
+
+#[test]
+fn set_watches_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let (event_tx, event_rx) = mpsc::channel();
+    let event_tx = Mutex::new(event_tx);
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                move |event: WatchedEvent| {
+                                    if event.event_type == WatchedEventType::NodeDataChanged {
+                                        event_tx.lock().unwrap().send(event.path).unwrap();
+                                    }
+                                })
+                 .unwrap();
+    zk.create("/bulk", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let paths: Vec<_> = (0..100).map(|i| format!("/bulk/{}", i)).collect();
+    for path in &paths {
+        zk.create(path, vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    }
+
+    let data_paths: Vec<_> = paths.iter().map(|path| path.as_str()).collect();
+    zk.set_watches(&data_paths, &[], &[], zk.last_zxid_seen()).unwrap();
+    for path in &paths {
+        zk.set_data(path, vec![1], None).unwrap();
+    }
+    let mut changed: Vec<_> = (0..100)
+                                  .map(|_| event_rx.recv_timeout(Duration::from_secs(5)).unwrap())
+                                  .map(Option::unwrap)
+                                  .collect();
+    changed.sort();
+    let mut expected = paths.clone();
+    expected.sort();
+    assert_eq!(changed, expected);
+}
+
+#[test]
+fn require_exact_timeout_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    // The server clamps this to its maxSessionTimeout
+    let timeout = Duration::from_secs(600);
+
+    let zk = ZooKeeperBuilder::new()
+                 .connect_string(&cluster.connect_string)
+                 .session_timeout(timeout)
+                 .connect()
+                 .unwrap();
+    zk.exists("/", false).unwrap();
+    assert_eq!(zk.requested_session_timeout(), timeout);
+    assert!(zk.negotiated_session_timeout().unwrap() < timeout);
+
+    let zk = ZooKeeperBuilder::new()
+                 .connect_string(&cluster.connect_string)
+                 .session_timeout(timeout)
+                 .require_exact_timeout(true)
+                 .connect();
+    assert_eq!(zk.err(), Some(ZkError::BadArguments));
+}
+
+#[test]
+fn reconnect_listener_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let mut cluster = ZkCluster::start(3);
+
+    // Connect to the test cluster
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                |_: WatchedEvent| {})
+                 .unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    zk.add_reconnect_listener(move |event: ReconnectEvent| {
+        tx.lock().unwrap().send(event).unwrap();
+    });
+
+    // The client is connected to the first server, which is the one killed first
+    let first_host = cluster.connect_string
+                            .split(',')
+                            .next()
+                            .unwrap()
+                            .to_socket_addrs()
+                            .unwrap()
+                            .next()
+                            .unwrap();
+    cluster.kill_an_instance();
+
+    let mut last_attempt = 0;
+    loop {
+        let event = rx.recv_timeout(Duration::from_secs(30)).unwrap();
+        match event.outcome {
+            ReconnectOutcome::Started => {
+                assert!(event.attempt > last_attempt);
+                last_attempt = event.attempt;
+            }
+            ReconnectOutcome::Failed => assert_eq!(event.attempt, last_attempt),
+            ReconnectOutcome::Connected => {
+                assert_eq!(event.attempt, last_attempt);
+                assert!(event.host != first_host);
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn disconnected_event_test() {
+    let _ = env_logger::try_init();
+
+    let mut cluster = ZkCluster::start(3);
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                move |event: WatchedEvent| {
+                                    tx.lock().unwrap().send(event.keeper_state).unwrap();
+                                })
+                 .unwrap();
+    zk.exists("/", false).unwrap();
+
+    // The client is connected to the first server, which is the one killed first
+    let killed_at = Instant::now();
+    cluster.kill_an_instance();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(30)), Ok(KeeperState::Disconnected));
+    assert!(killed_at.elapsed() < Duration::from_secs(5));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(30)), Ok(KeeperState::SyncConnected));
+    zk.exists("/", false).unwrap();
+}
+
+#[test]
+fn avoid_host_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(3);
+    let zk = ZooKeeperBuilder::new()
+                 .connect_string(&cluster.connect_string)
+                 .session_timeout(Duration::from_secs(10))
+                 .avoid_host_cooldown(Duration::from_secs(600))
+                 .connect()
+                 .unwrap();
+    zk.exists("/", false).unwrap();
+    let avoided = zk.connected_server().unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    zk.add_reconnect_listener(move |event: ReconnectEvent| {
+        if event.outcome == ReconnectOutcome::Connected {
+            let _ = tx.send(event.host);
+        }
+    });
+    zk.avoid_host(avoided).unwrap();
+
+    let host = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert!(host != avoided);
+    assert_eq!(zk.connected_server(), Some(host));
+    zk.exists("/", false).unwrap();
+}
+
+#[test]
+fn get_data_string_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    zk.create("/text", b"hello".to_vec(), Acl::open_unsafe().clone(), CreateMode::Persistent)
+      .unwrap();
+    let (text, _) = zk.get_data_string("/text", false, StringDecoding::Strict).unwrap();
+    assert_eq!(text, "hello");
+
+    zk.create("/binary",
+              vec![b'a', 0xff, 0xfe, b'b'],
+              Acl::open_unsafe().clone(),
+              CreateMode::Persistent)
+      .unwrap();
+    assert_eq!(zk.get_data_string("/binary", false, StringDecoding::Strict),
+               Err(ZkError::MarshallingError));
+    let (lossy, _) = zk.get_data_string("/binary", false, StringDecoding::Lossy).unwrap();
+    assert_eq!(lossy, "a\u{fffd}\u{fffd}b");
+}
+
+#[test]
+fn get_children_w_stat_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    zk.create("/parent", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let (children, stat) = zk.get_children_w_stat("/parent", false).unwrap();
+    assert!(children.is_empty());
+
+    zk.create("/parent/child", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent)
+      .unwrap();
+    let (children, after) = zk.get_children_w_stat("/parent", false).unwrap();
+    assert_eq!(children, vec!["child".to_owned()]);
+    assert_eq!(after.cversion, stat.cversion + 1);
+    assert_eq!(after.num_children, 1);
+
+    assert_eq!(zk.get_children_w_stat("/missing", false), Err(ZkError::NoNode));
+}
+
+#[test]
+fn set_data_checked_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    let other = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                    .unwrap();
+
+    zk.create("/checked", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let stat = zk.set_data_checked("/checked", vec![1], 0).unwrap();
+    assert_eq!(stat.version, 1);
+
+    // another client writes between our writes
+    other.set_data("/checked", vec![2], None).unwrap();
+    assert_eq!(zk.set_data_checked("/checked", vec![3], stat.version),
+               Err(ZkError::BadVersion));
+    // the write was applied nevertheless
+    let (data, stat) = zk.get_data("/checked", false).unwrap();
+    assert_eq!((data, stat.version), (vec![3], 3));
+}
+
+#[test]
+fn get_data_raw_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+
+    // this client always sends data, so its nodes have empty rather than null data
+    zk.create("/raw", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+    let (data, stat) = zk.get_data_raw("/raw", false).unwrap();
+    assert_eq!((data, stat.data_length), (Some(vec![]), 0));
+
+    zk.set_data("/raw", vec![1, 2], None).unwrap();
+    assert_eq!(zk.get_data_raw("/raw", false).unwrap().0, Some(vec![1, 2]));
+    assert_eq!(zk.get_data_raw("/missing", false), Err(ZkError::NoNode));
+}
+
+#[test]
+fn watch_type_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(1);
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30))
+                 .unwrap();
+    zk.create("/typed", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let data_tx = Mutex::new(tx.clone());
+    zk.get_data_w("/typed", move |event: WatchedEvent| {
+          data_tx.lock().unwrap().send(event).unwrap();
+      })
+      .unwrap();
+    let exist_tx = Mutex::new(tx);
+    zk.exists_w("/typed", move |event: WatchedEvent| {
+          exist_tx.lock().unwrap().send(event).unwrap();
+      })
+      .unwrap();
+
+    zk.delete("/typed", None).unwrap();
+    let mut types = Vec::new();
+    for _ in 0..2 {
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event_type, WatchedEventType::NodeDeleted);
+        types.push(event.watch_type);
+    }
+    assert_eq!(types, vec![Some(WatchType::Data), Some(WatchType::Exist)]);
+}
+
+#[test]
+fn session_expired_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let mut cluster = ZkCluster::start(1);
+
+    let (state_tx, state_rx) = mpsc::channel();
+    let state_tx = Mutex::new(state_tx);
+    let zk = ZooKeeper::connect(&cluster.connect_string,
+                                Duration::from_secs(30),
+                                move |event: WatchedEvent| {
+                                    state_tx.lock().unwrap().send(event.keeper_state).unwrap();
+                                })
+                 .unwrap();
+    zk.exists("/", false).unwrap();
+
+    // Closing the session from a second client expires it for the first one
+    let other = ZooKeeper::connect_with_session(&cluster.connect_string,
+                                                Duration::from_secs(30),
+                                                zk.session_id(),
+                                                zk.session_password(),
+                                                |_: WatchedEvent| {})
+                    .unwrap();
+    other.exists("/", false).unwrap();
+    drop(other);
+
+    while state_rx.recv_timeout(Duration::from_secs(30)).unwrap() != KeeperState::Expired {}
+
+    // A request waiting for the connection fails once the session timed out in the meantime
+    let zk = ZooKeeper::connect_with_policy(&cluster.connect_string,
+                                            Duration::from_secs(6),
+                                            |_: WatchedEvent| {},
+                                            ReconnectPolicy::ExponentialBackoff {
+                                                base: Duration::from_secs(60),
+                                                max: Duration::from_secs(60),
+                                                jitter: false,
+                                            })
+                 .unwrap();
+    zk.exists("/", false).unwrap();
+    let (failed_tx, failed_rx) = mpsc::channel();
+    let failed_tx = Mutex::new(failed_tx);
+    zk.add_reconnect_listener(move |event: ReconnectEvent| {
+        if event.outcome == ReconnectOutcome::Failed {
+            failed_tx.lock().unwrap().send(event.attempt).unwrap();
+        }
+    });
+    cluster.kill_an_instance();
+    // The first attempt is immediate, the second one would follow the session timeout
+    assert_eq!(failed_rx.recv_timeout(Duration::from_secs(30)), Ok(1));
+    assert_eq!(zk.exists("/", false), Err(ZkError::SessionExpired));
+}
+
+#[test]
+fn watch_data_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30)).unwrap();
+    zk.create("/watched", vec![0], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    let (data, _, events) = zk.watch_data("/watched").unwrap();
+    assert_eq!(data, vec![0]);
+    for i in 1..3 {
+        zk.set_data("/watched", vec![i], None).unwrap();
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+        assert_eq!(event.path, Some("/watched".to_owned()));
+        // The watch is left again before the next change
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    zk.delete("/watched", None).unwrap();
+    let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(event.event_type, WatchedEventType::NodeDeleted);
+}
+
+#[test]
+fn multiple_data_watchers_test() {
+    let _ = env_logger::try_init();
+
+    // Create a test cluster
+    let cluster = ZkCluster::start(1);
+
+    let zk = ZooKeeper::connect_no_watch(&cluster.connect_string, Duration::from_secs(30)).unwrap();
+    zk.create("/multiwatch", vec![], Acl::open_unsafe().clone(), CreateMode::Persistent).unwrap();
+
+    // Both watchers of the path are called, each of them once
+    let (fired_tx, fired_rx) = mpsc::channel();
+    for watcher in 0..2 {
+        let fired_tx = Mutex::new(fired_tx.clone());
+        zk.get_data_w("/multiwatch", move |event: WatchedEvent| {
+              assert_eq!(event.event_type, WatchedEventType::NodeDataChanged);
+              fired_tx.lock().unwrap().send(watcher).unwrap();
+          })
+          .unwrap();
+    }
+    zk.set_data("/multiwatch", vec![1], None).unwrap();
+    zk.set_data("/multiwatch", vec![2], None).unwrap();
+
+    let mut fired: Vec<_> = (0..2)
+                                .map(|_| fired_rx.recv_timeout(Duration::from_secs(5)).unwrap())
+                                .collect();
+    fired.sort();
+    assert_eq!(fired, vec![0, 1]);
+    assert!(fired_rx.recv_timeout(Duration::from_millis(500)).is_err());
+}
+
+#[test]
+fn get_config_test() {
+    let _ = env_logger::try_init();
+
+    let cluster = ZkCluster::start(3);
+    let zk = ZooKeeper::connect_no_watch(&format!("{}/app", cluster.connect_string),
+                                         Duration::from_secs(30))
+                 .unwrap();
+
+    // The configuration node is outside the chroot
+    let (data, stat) = zk.get_config(false).unwrap();
+    assert_eq!(stat.data_length as usize, data.len());
+    let config = QuorumConfig::parse(&data).unwrap();
+    assert_eq!(config.servers.len(), 3);
+    assert!(config.servers.iter().all(|server| server.role == QuorumRole::Participant));
+}
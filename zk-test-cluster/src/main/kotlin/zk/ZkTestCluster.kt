@@ -18,6 +18,11 @@ object ZkTestCluster {
         val instanceQty = Integer.valueOf(args[0])
         var killedInstances = 0
 
+        // Allow TTL nodes
+        System.setProperty("zookeeper.extendedTypesEnabled", "true")
+        // Remove empty containers quickly
+        System.setProperty("znode.container.checkIntervalMs", "100")
+
         val cluster = TestingCluster(instanceQty)
 
         cluster.start()
@@ -33,10 +38,15 @@ object ZkTestCluster {
             val c = System.`in`.read().toChar()
             when (c) {
                 'k' -> {
-                    cluster.servers[killedInstances].close()
+                    cluster.servers[killedInstances].stop()
                     println("Server killed")
                     killedInstances++
                 }
+                'r' -> {
+                    killedInstances--
+                    cluster.servers[killedInstances].restart()
+                    println("Server restarted")
+                }
                 'q' -> {
                     zooKeeper.close()
                     cluster.close()